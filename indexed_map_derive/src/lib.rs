@@ -0,0 +1,218 @@
+//! `#[derive(Indexed)]` for `indexed_map`, enabled there by the `derive` feature.
+//!
+//! Deriving `Indexed` for a struct generates a `<Struct>IndexedMap<K>` wrapper around an
+//! `IndexedMap<K, Struct>` with one index per field marked `#[indexed]`, named after the field
+//! and indexing a clone of its value. `#[indexed(sorted)]` makes it a sorted index. The wrapper
+//! derefs to the map and adds typed accessors: `by_<field>` for every index, `range_by_<field>`
+//! for sorted ones, and `<field>_index` for the id itself.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Type};
+
+struct IndexedField {
+    name: Ident,
+    ty: Type,
+    sorted: bool,
+}
+
+#[proc_macro_derive(Indexed, attributes(indexed))]
+pub fn derive_indexed(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn indexed_fields(input: &DeriveInput) -> Result<Vec<IndexedField>, Error> {
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    input,
+                    "Indexed can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                input,
+                "Indexed can only be derived for structs",
+            ))
+        }
+    };
+    let mut indexed = Vec::new();
+    for field in fields {
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("indexed")) {
+            let mut sorted = false;
+            if !matches!(attr.meta, syn::Meta::Path(_)) {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("sorted") {
+                        sorted = true;
+                        Ok(())
+                    } else {
+                        Err(meta.error("expected `sorted`"))
+                    }
+                })?;
+            }
+            indexed.push(IndexedField {
+                name: field.ident.clone().expect("named field"),
+                ty: field.ty.clone(),
+                sorted,
+            });
+        }
+    }
+    Ok(indexed)
+}
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "Indexed cannot be derived for generic structs",
+        ));
+    }
+    let fields = indexed_fields(input)?;
+    let vis = &input.vis;
+    let value = &input.ident;
+    let wrapper = Ident::new(&format!("{}IndexedMap", value), Span::call_site());
+    let names: Vec<&Ident> = fields.iter().map(|f| &f.name).collect();
+
+    let id_fields = fields.iter().map(|f| {
+        let (name, ty) = (&f.name, &f.ty);
+        if f.sorted {
+            quote!(#name: ::indexed_map::SortedIndexId<#ty>)
+        } else {
+            quote!(#name: ::indexed_map::IndexId<#ty>)
+        }
+    });
+
+    let registrations = fields.iter().map(|f| {
+        let name = &f.name;
+        let label = name.to_string();
+        let add = if f.sorted {
+            quote!(add_sorted_index)
+        } else {
+            quote!(add_index)
+        };
+        quote! {
+            let #name = map.#add(#label.to_string(), |_, value: &#value| {
+                vec![::std::clone::Clone::clone(&value.#name)]
+            });
+        }
+    });
+
+    let accessors = fields.iter().map(|f| {
+        let (name, ty) = (&f.name, &f.ty);
+        let by = Ident::new(&format!("by_{}", name), Span::call_site());
+        let index = Ident::new(&format!("{}_index", name), Span::call_site());
+        let (id_ty, plain_id) = if f.sorted {
+            (quote!(::indexed_map::SortedIndexId<#ty>), quote!(&*self.#name))
+        } else {
+            (quote!(::indexed_map::IndexId<#ty>), quote!(&self.#name))
+        };
+        let range = if f.sorted {
+            let range_by = Ident::new(&format!("range_by_{}", name), Span::call_site());
+            quote! {
+                /// The entries whose field value lies in `range`, ordered by it.
+                pub fn #range_by<R>(&self, range: R) -> ::std::option::Option<::std::vec::Vec<(&#ty, &K, &#value)>>
+                where
+                    R: ::std::ops::RangeBounds<#ty>,
+                {
+                    self.map.range_by_index(&self.#name, range)
+                }
+            }
+        } else {
+            quote!()
+        };
+        quote! {
+            /// The entries whose field equals `value`, or `None` if there are none.
+            pub fn #by<Q>(&self, value: &Q) -> ::std::option::Option<::std::collections::HashMap<&K, &#value>>
+            where
+                #ty: ::std::borrow::Borrow<Q>,
+                Q: ?::std::marker::Sized + ::std::cmp::Eq + ::std::hash::Hash,
+            {
+                self.map.filter_by_index(#plain_id, value)
+            }
+
+            pub fn #index(&self) -> &#id_ty {
+                &self.#name
+            }
+
+            #range
+        }
+    });
+
+    let doc = format!(
+        "An `IndexedMap` of `{}` values with the indices declared by its `#[indexed]` fields.",
+        value
+    );
+    Ok(quote! {
+        #[doc = #doc]
+        #vis struct #wrapper<K>
+        where
+            K: ::std::cmp::Eq + ::std::hash::Hash + ::std::clone::Clone,
+        {
+            map: ::indexed_map::IndexedMap<K, #value>,
+            #(#id_fields,)*
+        }
+
+        impl<K> #wrapper<K>
+        where
+            K: 'static + ::std::cmp::Eq + ::std::hash::Hash + ::std::clone::Clone,
+        {
+            pub fn new() -> #wrapper<K> {
+                #wrapper::from_map(::indexed_map::IndexedMap::new())
+            }
+
+            /// Registers every declared index on `map`, indexing the entries already in it.
+            pub fn from_map(mut map: ::indexed_map::IndexedMap<K, #value>) -> #wrapper<K> {
+                #(#registrations)*
+                #wrapper { map, #(#names,)* }
+            }
+
+            pub fn into_inner(self) -> ::indexed_map::IndexedMap<K, #value> {
+                self.map
+            }
+
+            #(#accessors)*
+        }
+
+        impl<K> ::std::default::Default for #wrapper<K>
+        where
+            K: 'static + ::std::cmp::Eq + ::std::hash::Hash + ::std::clone::Clone,
+        {
+            fn default() -> #wrapper<K> {
+                #wrapper::new()
+            }
+        }
+
+        impl<K> ::std::ops::Deref for #wrapper<K>
+        where
+            K: ::std::cmp::Eq + ::std::hash::Hash + ::std::clone::Clone,
+        {
+            type Target = ::indexed_map::IndexedMap<K, #value>;
+
+            fn deref(&self) -> &::indexed_map::IndexedMap<K, #value> {
+                &self.map
+            }
+        }
+
+        impl<K> ::std::ops::DerefMut for #wrapper<K>
+        where
+            K: ::std::cmp::Eq + ::std::hash::Hash + ::std::clone::Clone,
+        {
+            fn deref_mut(&mut self) -> &mut ::indexed_map::IndexedMap<K, #value> {
+                &mut self.map
+            }
+        }
+    })
+}
@@ -0,0 +1,65 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// The number of entries indexed under `index_key`, read off the bucket without visiting
+    /// them. Returns `None` if the index is unavailable.
+    pub fn count_by_index<A, H, Q>(&self, index_id: &IndexId<A, H>, index_key: &Q) -> Option<usize>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        H: 'static + BuildHasher + Clone,
+        Q: ?Sized + Eq + Hash,
+    {
+        let index = self.get_index(index_id)?;
+        Some(index.get(index_key).map_or(0, |keys| keys.len()))
+    }
+
+    /// Every index value with the number of entries indexed under it, in no particular order,
+    /// e.g. for a count of entries per status. Returns `None` if the index is unavailable.
+    pub fn group_counts<'m, A, H>(
+        &'m self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<impl Iterator<Item = (&'m A, usize)> + 'm>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        let index = self.get_index(index_id)?;
+        Some(
+            index
+                .iter()
+                .map(|(index_key, keys)| (index_key, keys.len())),
+        )
+    }
+
+    /// Folds `f` over the entries indexed under `index_key`, in no particular order, without
+    /// collecting them first. Returns `None` if the index is unavailable.
+    pub fn fold_by_index<A, H, Q, T, F>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+        init: T,
+        f: F,
+    ) -> Option<T>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        H: 'static + BuildHasher + Clone,
+        Q: ?Sized + Eq + Hash,
+        F: FnMut(T, (&K, &V)) -> T,
+    {
+        let index = self.get_index(index_id)?;
+        let keys = match index.get(index_key) {
+            Some(keys) => keys,
+            None => return Some(init),
+        };
+        Some(self.entries_of(keys.iter()).fold(init, f))
+    }
+}
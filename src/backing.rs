@@ -0,0 +1,150 @@
+use std::borrow::Borrow;
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+
+/// Primary storage for the entries of an `IndexedMap`, holding values under the stored key `P`
+/// (see `KeyStorage`) and looked up by the user key `K`.
+///
+/// Implemented for `HashMap`, the default, and for `BTreeMap`, which keeps entries ordered by key
+/// so that `range` queries over primary keys work through `Deref`.
+pub trait Backing<K, P, V>: Default
+where
+    P: Borrow<K>,
+{
+    type Iter<'a>: Iterator<Item = (&'a P, &'a V)>
+    where
+        Self: 'a,
+        P: 'a,
+        V: 'a;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &K) -> Option<&V>;
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V>;
+
+    fn get_key_value(&self, key: &K) -> Option<(&P, &V)>;
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn insert(&mut self, key: P, value: V) -> Option<V>;
+
+    fn remove_entry(&mut self, key: &K) -> Option<(P, V)>;
+
+    fn iter(&self) -> Self::Iter<'_>;
+
+    /// Reserves room for at least `additional` more entries, where the storage supports it.
+    fn reserve(&mut self, additional: usize);
+
+    /// Releases spare capacity, where the storage supports it.
+    fn shrink_to_fit(&mut self);
+}
+
+impl<K, P, V, S> Backing<K, P, V> for HashMap<P, V, S>
+where
+    K: Eq + Hash,
+    P: Eq + Hash + Borrow<K>,
+    S: BuildHasher + Default,
+{
+    type Iter<'a>
+        = hash_map::Iter<'a, P, V>
+    where
+        Self: 'a,
+        P: 'a,
+        V: 'a;
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn get_key_value(&self, key: &K) -> Option<(&P, &V)> {
+        HashMap::get_key_value(self, key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        HashMap::contains_key(self, key)
+    }
+
+    fn insert(&mut self, key: P, value: V) -> Option<V> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<(P, V)> {
+        HashMap::remove_entry(self, key)
+    }
+
+    fn iter(&self) -> hash_map::Iter<'_, P, V> {
+        HashMap::iter(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        HashMap::reserve(self, additional)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        HashMap::shrink_to_fit(self)
+    }
+}
+
+impl<K, P, V> Backing<K, P, V> for BTreeMap<P, V>
+where
+    K: Ord,
+    P: Ord + Borrow<K>,
+{
+    type Iter<'a>
+        = btree_map::Iter<'a, P, V>
+    where
+        Self: 'a,
+        P: 'a,
+        V: 'a;
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        BTreeMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        BTreeMap::get_mut(self, key)
+    }
+
+    fn get_key_value(&self, key: &K) -> Option<(&P, &V)> {
+        BTreeMap::get_key_value(self, key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        BTreeMap::contains_key(self, key)
+    }
+
+    fn insert(&mut self, key: P, value: V) -> Option<V> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<(P, V)> {
+        BTreeMap::remove_entry(self, key)
+    }
+
+    fn iter(&self) -> btree_map::Iter<'_, P, V> {
+        BTreeMap::iter(self)
+    }
+
+    fn reserve(&mut self, _additional: usize) {}
+
+    fn shrink_to_fit(&mut self) {}
+}
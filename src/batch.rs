@@ -0,0 +1,177 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, ClonedKeys, Entries, IndexId, IndexedMap, KeyStorage, QueryError};
+
+/// One lookup of a batch: several index keys of one index, resolved to the postings of each.
+trait Lookup<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    fn resolve<'m>(
+        &self,
+        map: &'m IndexedMap<K, V, KS, B>,
+    ) -> Result<Vec<Vec<&'m KS::Key>>, QueryError>;
+}
+
+struct IndexLookup<A, H> {
+    index_id: IndexId<A, H>,
+    index_keys: Vec<A>,
+}
+
+impl<K, V, KS, B, A, H> Lookup<K, V, KS, B> for IndexLookup<A, H>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+    A: 'static + Eq + Hash + Clone,
+    H: 'static + BuildHasher + Clone,
+{
+    fn resolve<'m>(
+        &self,
+        map: &'m IndexedMap<K, V, KS, B>,
+    ) -> Result<Vec<Vec<&'m KS::Key>>, QueryError> {
+        let index = map.try_get_index(&self.index_id)?;
+        Ok(self
+            .index_keys
+            .iter()
+            .map(|index_key| index.get(index_key).into_iter().flatten().collect())
+            .collect())
+    }
+}
+
+/// Refers to one lookup added to a `MultiQuery`, to find its results in the `MultiQueryResult`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QueryHandle {
+    position: usize,
+}
+
+/// A batch of lookups against indices of any value type, resolved together by `multi_query`.
+pub struct MultiQuery<K, V, KS = ClonedKeys, B = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    lookups: Vec<Box<dyn Lookup<K, V, KS, B>>>,
+}
+
+impl<K, V, KS, B> MultiQuery<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: 'static + Backing<K, KS::Key, V>,
+{
+    pub fn new() -> MultiQuery<K, V, KS, B> {
+        MultiQuery {
+            lookups: Vec::new(),
+        }
+    }
+
+    /// Adds a lookup of `index_keys` in one index. Its results come back in the same order.
+    pub fn add<A, H>(&mut self, index_id: &IndexId<A, H>, index_keys: Vec<A>) -> QueryHandle
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.lookups.push(Box::new(IndexLookup {
+            index_id: index_id.clone(),
+            index_keys,
+        }));
+        QueryHandle {
+            position: self.lookups.len() - 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lookups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lookups.is_empty()
+    }
+}
+
+impl<K, V, KS, B> Default for MultiQuery<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: 'static + Backing<K, KS::Key, V>,
+{
+    fn default() -> MultiQuery<K, V, KS, B> {
+        MultiQuery::new()
+    }
+}
+
+/// The results of a `MultiQuery`. Each entry matched by any lookup is resolved once and shared
+/// by every lookup that matched it.
+pub struct MultiQueryResult<'m, K: 'm, V: 'm> {
+    entries: Vec<(&'m K, &'m V)>,
+    results: Vec<Result<Vec<Vec<usize>>, QueryError>>,
+}
+
+impl<'m, K, V> MultiQueryResult<'m, K, V> {
+    /// The entries matched by the lookup behind `handle`, one list per index key in the order
+    /// they were added, or why its index could not be queried.
+    ///
+    /// Panics if `handle` was not returned by the query this result came from.
+    pub fn get(&self, handle: QueryHandle) -> Result<Vec<Vec<(&'m K, &'m V)>>, QueryError> {
+        match self.results[handle.position] {
+            Ok(ref per_key) => Ok(per_key
+                .iter()
+                .map(|ordinals| ordinals.iter().map(|&i| self.entries[i]).collect())
+                .collect()),
+            Err(ref err) => Err(err.clone()),
+        }
+    }
+
+    /// Every distinct entry matched by the batch.
+    pub fn entries(&self) -> &[(&'m K, &'m V)] {
+        &self.entries
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Resolves every lookup of `query` in one pass, looking each matched key up in the map only
+    /// once however many lookups share it. Suited to resolvers that fan out into many small
+    /// index queries per request.
+    pub fn multi_query(&self, query: &MultiQuery<K, V, KS, B>) -> MultiQueryResult<'_, K, V> {
+        let mut entries = Vec::new();
+        let mut ordinals: HashMap<&KS::Key, Option<usize>> = HashMap::new();
+        let results = query
+            .lookups
+            .iter()
+            .map(|lookup| {
+                lookup.resolve(self).map(|per_key| {
+                    per_key
+                        .into_iter()
+                        .map(|keys| {
+                            keys.into_iter()
+                                .filter_map(|stored| {
+                                    *ordinals.entry(stored).or_insert_with(|| {
+                                        let key: &K = stored.borrow();
+                                        self.inner.get(key).map(|value| {
+                                            entries.push((key, value));
+                                            entries.len() - 1
+                                        })
+                                    })
+                                })
+                                .collect()
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        MultiQueryResult { entries, results }
+    }
+}
@@ -0,0 +1,278 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use {Backing, IndexedMap, KeyStorage};
+
+type Weigher<K, V> = Rc<dyn Fn(&K, &V) -> usize>;
+
+type OnEvict<K, V> = Box<dyn FnMut(K, V)>;
+
+/// How large a map with `with_capacity_policy` may grow before it evicts its least recently
+/// used entries. Either bound, or both, can be set.
+pub struct CapacityPolicy<K, V> {
+    max_entries: Option<usize>,
+    max_weight: Option<(usize, Weigher<K, V>)>,
+    on_evict: Option<OnEvict<K, V>>,
+}
+
+impl<K, V> CapacityPolicy<K, V> {
+    /// Keeps at most `limit` entries.
+    pub fn max_entries(limit: usize) -> CapacityPolicy<K, V> {
+        CapacityPolicy {
+            max_entries: Some(limit),
+            max_weight: None,
+            on_evict: None,
+        }
+    }
+
+    /// Keeps the entries' total weight, e.g. their size in bytes, at most `limit`.
+    pub fn max_weight<F>(limit: usize, weigher: F) -> CapacityPolicy<K, V>
+    where
+        F: 'static + Fn(&K, &V) -> usize,
+    {
+        CapacityPolicy {
+            max_entries: None,
+            max_weight: None,
+            on_evict: None,
+        }
+        .with_max_weight(limit, weigher)
+    }
+
+    pub fn with_max_entries(self, limit: usize) -> CapacityPolicy<K, V> {
+        CapacityPolicy {
+            max_entries: Some(limit),
+            ..self
+        }
+    }
+
+    pub fn with_max_weight<F>(self, limit: usize, weigher: F) -> CapacityPolicy<K, V>
+    where
+        F: 'static + Fn(&K, &V) -> usize,
+    {
+        CapacityPolicy {
+            max_weight: Some((limit, Rc::new(weigher))),
+            ..self
+        }
+    }
+
+    /// Calls `on_evict` with every entry evicted to satisfy the policy, after it has been
+    /// removed from the map and all of its indices.
+    pub fn on_evict<F>(self, on_evict: F) -> CapacityPolicy<K, V>
+    where
+        F: 'static + FnMut(K, V),
+    {
+        CapacityPolicy {
+            on_evict: Some(Box::new(on_evict)),
+            ..self
+        }
+    }
+}
+
+/// The recency and weight of every entry of a bounded map.
+pub(crate) struct Bounds<K, V, KS: KeyStorage<K>> {
+    policy: CapacityPolicy<K, V>,
+    clock: u64,
+    by_recency: BTreeMap<u64, KS::Key>,
+    last_used: HashMap<KS::Key, u64>,
+    weights: HashMap<KS::Key, usize>,
+    total_weight: usize,
+    /// Set while a transaction commits, so that nothing is evicted until it is known whether
+    /// the transaction stands.
+    deferred: bool,
+}
+
+/// The recency tick and weight of one entry, from `Bounds::save`.
+pub(crate) type SavedBounds = (u64, Option<usize>);
+
+impl<K, V, KS> Bounds<K, V, KS>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    fn new(policy: CapacityPolicy<K, V>) -> Bounds<K, V, KS> {
+        Bounds {
+            policy,
+            clock: 0,
+            by_recency: BTreeMap::new(),
+            last_used: HashMap::new(),
+            weights: HashMap::new(),
+            total_weight: 0,
+            deferred: false,
+        }
+    }
+
+    /// A copy for a clone of the map. The eviction callback stays with the original.
+    pub(crate) fn duplicate(&self) -> Bounds<K, V, KS> {
+        Bounds {
+            policy: CapacityPolicy {
+                max_entries: self.policy.max_entries,
+                max_weight: self.policy.max_weight.clone(),
+                on_evict: None,
+            },
+            clock: self.clock,
+            by_recency: self.by_recency.clone(),
+            last_used: self.last_used.clone(),
+            weights: self.weights.clone(),
+            total_weight: self.total_weight,
+            deferred: false,
+        }
+    }
+
+    /// Marks `key` as the most recently used entry.
+    fn touch(&mut self, key: &KS::Key) {
+        self.clock += 1;
+        if let Some(tick) = self.last_used.insert(key.clone(), self.clock) {
+            self.by_recency.remove(&tick);
+        }
+        self.by_recency.insert(self.clock, key.clone());
+    }
+
+    /// Marks `key` as the most recently used entry and weighs its new value.
+    pub(crate) fn record(&mut self, key: &KS::Key, value: &V) {
+        self.touch(key);
+        if let Some((_, ref weigher)) = self.policy.max_weight {
+            let weight = weigher(key.borrow(), value);
+            let previous = self.weights.insert(key.clone(), weight).unwrap_or(0);
+            self.total_weight = self.total_weight - previous + weight;
+        }
+    }
+
+    pub(crate) fn forget(&mut self, key: &K) {
+        if let Some(tick) = self.last_used.remove(key) {
+            self.by_recency.remove(&tick);
+        }
+        if let Some(weight) = self.weights.remove(key) {
+            self.total_weight -= weight;
+        }
+    }
+
+    pub(crate) fn save(&self, key: &K) -> Option<SavedBounds> {
+        let tick = *self.last_used.get(key)?;
+        Some((tick, self.weights.get(key).cloned()))
+    }
+
+    /// Puts an entry back at the recency and weight it had when it was saved.
+    pub(crate) fn restore(&mut self, key: &KS::Key, (tick, weight): SavedBounds) {
+        self.forget(key.borrow());
+        self.last_used.insert(key.clone(), tick);
+        self.by_recency.insert(tick, key.clone());
+        if let Some(weight) = weight {
+            self.weights.insert(key.clone(), weight);
+            self.total_weight += weight;
+        }
+    }
+
+    pub(crate) fn set_deferred(&mut self, deferred: bool) {
+        self.deferred = deferred;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.by_recency.clear();
+        self.last_used.clear();
+        self.weights.clear();
+        self.total_weight = 0;
+    }
+
+    fn exceeded(&self, len: usize) -> bool {
+        self.policy.max_entries.is_some_and(|limit| len > limit)
+            || self
+                .policy
+                .max_weight
+                .as_ref()
+                .is_some_and(|&(limit, _)| self.total_weight > limit)
+    }
+}
+
+impl<K, V> IndexedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    /// Creates a map that evicts its least recently used entries, from the map and every index,
+    /// whenever a write takes it past `policy`'s bounds. Writes count as uses; reads through
+    /// the map's `Deref` cannot, so readers that should keep entries alive use `touch` or
+    /// `get_and_touch`. Pinned entries count towards the bounds but are never evicted.
+    pub fn with_capacity_policy(policy: CapacityPolicy<K, V>) -> IndexedMap<K, V> {
+        let mut map = IndexedMap::new();
+        map.set_capacity_policy(Some(policy));
+        map
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Bounds the map by `policy`, or removes the bounds with `None`. Entries already in the
+    /// map are ranked in arbitrary order, and evicted straight away if they exceed the bounds.
+    pub fn set_capacity_policy(&mut self, policy: Option<CapacityPolicy<K, V>>) {
+        self.bounds = policy.map(|policy| {
+            let mut bounds = Bounds::new(policy);
+            for (key, value) in self.inner.iter() {
+                bounds.record(key, value);
+            }
+            bounds
+        });
+        self.enforce_bounds();
+    }
+
+    /// Marks the entry under `key` as just used, so it is evicted last. Returns `false` if
+    /// there is no such entry.
+    pub fn touch(&mut self, key: &K) -> bool {
+        match (self.bounds.as_mut(), self.inner.get_key_value(key)) {
+            (Some(bounds), Some((stored, _))) => {
+                bounds.touch(stored);
+                true
+            }
+            (None, Some(_)) => true,
+            (_, None) => false,
+        }
+    }
+
+    /// Looks up `key` and marks its entry as just used.
+    pub fn get_and_touch(&mut self, key: &K) -> Option<&V> {
+        self.touch(key);
+        self.inner.get(key)
+    }
+
+    /// The total weight of the entries by the capacity policy's weigher, or `None` if the map
+    /// is not bounded by weight.
+    pub fn total_weight(&self) -> Option<usize> {
+        let bounds = self.bounds.as_ref()?;
+        bounds.policy.max_weight.as_ref()?;
+        Some(bounds.total_weight)
+    }
+
+    /// Evicts the least recently used unpinned entries until the map is within its bounds or
+    /// only pinned entries are left. Runs after every write, so it also lets a map of `Weak`
+    /// values collect its dead entries first.
+    pub(crate) fn enforce_bounds(&mut self) {
+        self.reap_dead();
+        loop {
+            let victim = match self.bounds {
+                Some(ref bounds) if !bounds.deferred && bounds.exceeded(self.inner.len()) => bounds
+                    .by_recency
+                    .values()
+                    .find(|key| !self.pinned.contains::<K>(Borrow::borrow(*key)))
+                    .map(|key| Borrow::<K>::borrow(key).clone()),
+                _ => return,
+            };
+            let evicted = match victim {
+                Some(key) => self.remove_unchecked(&key),
+                None => return,
+            };
+            let on_evict = self
+                .bounds
+                .as_mut()
+                .and_then(|bounds| bounds.policy.on_evict.as_mut());
+            if let (Some(on_evict), Some((key, value))) = (on_evict, evicted) {
+                on_evict(key, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, Instant};
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// How much work a budgeted query may do before it stops and hands back what it has so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    max_visits: Option<usize>,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    pub fn unlimited() -> Budget {
+        Budget::default()
+    }
+
+    /// Stops after visiting `visits` entries, whether or not they matched.
+    pub fn with_max_visits(self, visits: usize) -> Budget {
+        Budget {
+            max_visits: Some(visits),
+            ..self
+        }
+    }
+
+    pub fn with_deadline(self, deadline: Instant) -> Budget {
+        Budget {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Budget {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Reading the clock on every visit would dominate cheap predicates, so the deadline is only
+    /// checked every few visits.
+    fn exhausted(&self, visits: usize) -> bool {
+        const DEADLINE_CHECK_INTERVAL: usize = 32;
+        self.max_visits.is_some_and(|max| visits >= max)
+            || (visits.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+                && self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline))
+    }
+}
+
+/// Where a budgeted query stopped. Passing it back resumes the query after the last entry it
+/// visited. Resuming is only exact if the map was not modified in between; otherwise entries
+/// may be skipped or returned twice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Continuation {
+    position: usize,
+}
+
+/// The results of a budgeted query, with a continuation if the budget ran out before the query
+/// finished.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partial<T> {
+    pub results: Vec<T>,
+    pub continuation: Option<Continuation>,
+}
+
+impl<T> Partial<T> {
+    pub fn is_complete(&self) -> bool {
+        self.continuation.is_none()
+    }
+}
+
+fn run_budgeted<I, T, F>(
+    items: I,
+    budget: &Budget,
+    from: Option<Continuation>,
+    mut f: F,
+) -> Partial<T>
+where
+    I: Iterator,
+    F: FnMut(I::Item) -> Option<T>,
+{
+    let start = from.map_or(0, |continuation| continuation.position);
+    let mut items = items.skip(start).peekable();
+    let mut results = Vec::new();
+    let mut visits = 0;
+    while items.peek().is_some() {
+        if budget.exhausted(visits) {
+            return Partial {
+                results,
+                continuation: Some(Continuation {
+                    position: start + visits,
+                }),
+            };
+        }
+        if let Some(result) = items.next().and_then(&mut f) {
+            results.push(result);
+        }
+        visits += 1;
+    }
+    Partial {
+        results,
+        continuation: None,
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Scans every entry for those matching `predicate`, stopping early once `budget` is spent.
+    pub fn scan_with_budget<F>(
+        &self,
+        budget: &Budget,
+        from: Option<Continuation>,
+        mut predicate: F,
+    ) -> Partial<(&K, &V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        run_budgeted(self.inner.iter(), budget, from, |(key, value)| {
+            let key: &K = key.borrow();
+            if predicate(key, value) {
+                Some((key, value))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Like `filter_by_index`, but stops early once `budget` is spent. Returns `None` if the
+    /// index is unavailable.
+    pub fn filter_by_index_with_budget<A, Q, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+        budget: &Budget,
+        from: Option<Continuation>,
+    ) -> Option<Partial<(&K, &V)>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        let index = self.get_index(index_id)?;
+        let keys = index.get(index_key).into_iter().flatten();
+        Some(run_budgeted(keys, budget, from, |key| {
+            let key: &K = key.borrow();
+            self.inner.get(key).map(|value| (key, value))
+        }))
+    }
+}
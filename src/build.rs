@@ -0,0 +1,142 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use metadata::metadata_of;
+use {Backing, BuildError, IndexId, IndexState, IndexedMap, KeyStorage};
+
+/// Asks a running index build to stop. Clones share the same flag, so a clone can be handed to
+/// another thread or a UI callback that cancels the build.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// How far an index build has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// Entries indexed so far.
+    pub processed: usize,
+    /// Entries in the map when the build started.
+    pub total: usize,
+}
+
+/// Builds an index over the existing entries with progress reports and cancellation, created by
+/// `IndexedMap::build_index`. The index is only registered once every entry has been indexed.
+pub struct IndexBuild<'a, K, V, KS, B, A>
+where
+    K: 'a + Eq + Hash,
+    V: 'a,
+    KS: 'a + KeyStorage<K>,
+    B: 'a,
+{
+    map: &'a mut IndexedMap<K, V, KS, B>,
+    name: String,
+    index_state: IndexState<K, V, A, KS>,
+    cancellation: Option<CancellationToken>,
+    on_progress: Option<Box<dyn FnMut(BuildProgress) + 'a>>,
+    report_every: usize,
+}
+
+impl<'a, K, V, KS, B, A> IndexBuild<'a, K, V, KS, B, A>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+    A: 'static + Eq + Hash + Clone,
+{
+    /// Registers a callback invoked every `report_every` entries and once more at the end.
+    pub fn on_progress<F>(mut self, on_progress: F) -> IndexBuild<'a, K, V, KS, B, A>
+    where
+        F: 'a + FnMut(BuildProgress),
+    {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// How many entries to index between progress reports. Defaults to 1024.
+    pub fn report_every(mut self, entries: usize) -> IndexBuild<'a, K, V, KS, B, A> {
+        self.report_every = entries.max(1);
+        self
+    }
+
+    /// Checks `cancellation` before every entry, abandoning the build once it is cancelled.
+    pub fn cancel_on(mut self, cancellation: CancellationToken) -> IndexBuild<'a, K, V, KS, B, A> {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Indexes every entry and registers the index. If cancelled, the partial index is discarded
+    /// and the map is left as it was.
+    pub fn run(mut self) -> Result<IndexId<A>, BuildError> {
+        let mut progress = BuildProgress {
+            processed: 0,
+            total: self.map.inner.len(),
+        };
+        for (key, value) in self.map.inner.iter() {
+            if self
+                .cancellation
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled)
+            {
+                return Err(BuildError::Cancelled {
+                    name: self.name,
+                    processed: progress.processed,
+                });
+            }
+            let metadata = metadata_of::<K, KS>(&self.map.metadata, key.borrow());
+            self.index_state.insert(key, value, metadata);
+            progress.processed += 1;
+            if progress.processed.is_multiple_of(self.report_every) {
+                if let Some(ref mut on_progress) = self.on_progress {
+                    on_progress(progress);
+                }
+            }
+        }
+        if let Some(ref mut on_progress) = self.on_progress {
+            on_progress(progress);
+        }
+        Ok(self.map.register_index(self.name, self.index_state))
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Like `add_index`, but returns a build that can report progress and be cancelled before it
+    /// is `run`, for indices over maps large enough that building takes noticeable time.
+    pub fn build_index<A, F>(&mut self, name: String, index_fn: F) -> IndexBuild<'_, K, V, KS, B, A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        IndexBuild {
+            map: self,
+            name,
+            index_state: IndexState::empty(index_fn),
+            cancellation: None,
+            on_progress: None,
+            report_every: 1024,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::iter::FromIterator;
+
+use metadata::metadata_of;
+use {Backing, IndexedMap, InsertError, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Inserts many entries at once, much faster than one `insert` at a time for bulk loads.
+    /// Room for the entries is reserved up front in the map and every index, and each index then
+    /// takes the whole batch in one pass rather than every entry visiting every index.
+    ///
+    /// Returns the number of entries written, or the first one rejected by the capacity limit;
+    /// entries before it stay inserted and indexed. Unique and fallible indices have to check
+    /// each entry against the ones before it, so a map with any of those inserts entry by entry.
+    pub fn insert_batch<I>(&mut self, entries: I) -> Result<usize, InsertError<K, V>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let entries = entries.into_iter();
+        if self.validating() {
+            let mut written = 0;
+            for (key, value) in entries {
+                self.try_insert(key, value)?;
+                written += 1;
+            }
+            return Ok(written);
+        }
+        let additional = entries.size_hint().0;
+        self.inner.reserve(additional);
+        self.indices
+            .values_mut()
+            .flat_map(|by_type| by_type.values_mut())
+            .for_each(|updater| updater.reserve(additional));
+        let mut written = 0;
+        let mut staged = Vec::with_capacity(additional);
+        let mut rejected = None;
+        for (key, value) in entries {
+            if let Some(limit) = self.capacity_limit {
+                if self.inner.len() >= limit && !self.inner.contains_key(&key) {
+                    rejected = Some(InsertError::CapacityExceeded { key, value, limit });
+                    break;
+                }
+            }
+            written += 1;
+            if self.unchanged(&key, &value) {
+                self.overwrite_unchanged(&key, value);
+                continue;
+            }
+            let (key, replacing) = self.record_insert(key);
+            self.store_entry(key.clone(), value);
+            staged.push((key, replacing));
+        }
+        for updater in self
+            .indices
+            .values_mut()
+            .flat_map(|by_type| by_type.values_mut())
+        {
+            for &(ref key, replacing) in &staged {
+                let k: &K = key.borrow();
+                let value = match self.inner.get(k) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let metadata = metadata_of::<K, KS>(&self.metadata, k);
+                if replacing {
+                    updater.replace(key, value, metadata)
+                } else {
+                    updater.insert(key, value, metadata)
+                }
+            }
+        }
+        self.enforce_bounds();
+        match rejected {
+            Some(err) => Err(err),
+            None => Ok(written),
+        }
+    }
+}
+
+impl<K, V, KS, B> Extend<(K, V)> for IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Inserts every entry with `insert_batch`.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `insert` if an entry is rejected, leaving the entries before it inserted. Use
+    /// `insert_batch` on maps with a capacity limit, unique indices or fallible indices.
+    fn extend<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        if let Err(err) = self.insert_batch(entries) {
+            panic!("{}", err);
+        }
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for IndexedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    /// Collects entries into a map without indices; indices added afterwards cover them.
+    fn from_iter<I>(entries: I) -> IndexedMap<K, V>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut map = IndexedMap::new();
+        map.extend(entries);
+        map
+    }
+}
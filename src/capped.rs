@@ -0,0 +1,226 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::rc::Rc;
+
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage};
+
+type Score<K, V> = Rc<dyn Fn(&K, &V) -> i64>;
+
+/// Which posting a full bucket of a capped index drops to make room.
+pub enum BucketEviction<K, V> {
+    /// The posting that has been in the bucket longest.
+    Oldest,
+    /// The posting whose entry has the lowest score, computed when the posting is added. Ties
+    /// drop the older posting.
+    LowestScore(Score<K, V>),
+    /// A posting chosen at random, from `BucketCap::with_seed`'s seed if one is given.
+    Random,
+}
+
+impl<K, V> BucketEviction<K, V> {
+    pub fn lowest_score<F>(score: F) -> BucketEviction<K, V>
+    where
+        F: 'static + Fn(&K, &V) -> i64,
+    {
+        BucketEviction::LowestScore(Rc::new(score))
+    }
+}
+
+impl<K, V> Clone for BucketEviction<K, V> {
+    fn clone(&self) -> BucketEviction<K, V> {
+        match *self {
+            BucketEviction::Oldest => BucketEviction::Oldest,
+            BucketEviction::LowestScore(ref score) => BucketEviction::LowestScore(score.clone()),
+            BucketEviction::Random => BucketEviction::Random,
+        }
+    }
+}
+
+type OnEvict<K, A> = Box<dyn FnMut(&A, &K)>;
+
+/// How many postings each bucket of a capped index keeps, and what happens to the rest.
+pub struct BucketCap<K, V, A> {
+    limit: usize,
+    eviction: BucketEviction<K, V>,
+    seed: Option<u64>,
+    on_evict: Option<OnEvict<K, A>>,
+}
+
+impl<K, V, A> BucketCap<K, V, A> {
+    /// Keeps at most `limit` postings per bucket, dropping the oldest by default.
+    pub fn new(limit: usize) -> BucketCap<K, V, A> {
+        BucketCap {
+            limit,
+            eviction: BucketEviction::Oldest,
+            seed: None,
+            on_evict: None,
+        }
+    }
+
+    pub fn with_eviction(self, eviction: BucketEviction<K, V>) -> BucketCap<K, V, A> {
+        BucketCap { eviction, ..self }
+    }
+
+    /// Draws `BucketEviction::Random`'s choices from `seed`, so the same writes always drop the
+    /// same postings, e.g. in tests.
+    pub fn with_seed(self, seed: u64) -> BucketCap<K, V, A> {
+        BucketCap {
+            seed: Some(seed),
+            ..self
+        }
+    }
+
+    /// Calls `on_evict` with the bucket and key of every dropped posting, so downstream systems
+    /// know the bucket is no longer complete. The entry itself stays in the map.
+    pub fn on_evict<F>(self, on_evict: F) -> BucketCap<K, V, A>
+    where
+        F: 'static + FnMut(&A, &K),
+    {
+        BucketCap {
+            on_evict: Some(Box::new(on_evict)),
+            ..self
+        }
+    }
+}
+
+/// Orders postings by score, breaking ties by arrival.
+type Rank = (i64, u64);
+
+/// The postings of every bucket of a capped index ordered by rank, lowest first, so the posting
+/// to drop is always at the front.
+pub(crate) struct BucketLimit<K, V, A, KS>
+where
+    KS: KeyStorage<K>,
+{
+    cap: BucketCap<K, V, A>,
+    ranked: HashMap<A, BTreeMap<Rank, KS::Key>>,
+    ranks: HashMap<KS::Key, Vec<(A, Rank)>>,
+    arrivals: u64,
+    rng: u64,
+}
+
+impl<K, V, A, KS> BucketLimit<K, V, A, KS>
+where
+    K: Eq + Hash,
+    A: Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+{
+    fn new(cap: BucketCap<K, V, A>) -> BucketLimit<K, V, A, KS> {
+        let seed = cap.seed.unwrap_or_else(|| RandomState::new().hash_one(0u8));
+        BucketLimit {
+            cap,
+            ranked: HashMap::new(),
+            ranks: HashMap::new(),
+            arrivals: 0,
+            rng: seed | 1,
+        }
+    }
+
+    /// A copy for a clone of the map. The eviction callback stays with the original.
+    pub(crate) fn duplicate(&self) -> BucketLimit<K, V, A, KS> {
+        BucketLimit {
+            cap: BucketCap {
+                limit: self.cap.limit,
+                eviction: self.cap.eviction.clone(),
+                seed: self.cap.seed,
+                on_evict: None,
+            },
+            ranked: self.ranked.clone(),
+            ranks: self.ranks.clone(),
+            arrivals: self.arrivals,
+            rng: self.rng,
+        }
+    }
+
+    pub(crate) fn added(&mut self, index_key: &A, key: &KS::Key, value: &V) {
+        self.arrivals += 1;
+        let score = match self.cap.eviction {
+            BucketEviction::Oldest => 0,
+            BucketEviction::LowestScore(ref score) => score(key.borrow(), value),
+            BucketEviction::Random => {
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                self.rng as i64
+            }
+        };
+        let rank = (score, self.arrivals);
+        self.ranked
+            .entry(index_key.clone())
+            .or_default()
+            .insert(rank, key.clone());
+        self.ranks
+            .entry(key.clone())
+            .or_default()
+            .push((index_key.clone(), rank));
+    }
+
+    pub(crate) fn removed(&mut self, index_key: &A, key: &K) {
+        let rank = match self.ranks.get_mut(key) {
+            Some(ranks) => match ranks.iter().position(|(a, _)| a == index_key) {
+                Some(position) => ranks.swap_remove(position).1,
+                None => return,
+            },
+            None => return,
+        };
+        if self.ranks.get(key).is_some_and(Vec::is_empty) {
+            self.ranks.remove(key);
+        }
+        let now_empty = self.ranked.get_mut(index_key).is_some_and(|ranked| {
+            ranked.remove(&rank);
+            ranked.is_empty()
+        });
+        if now_empty {
+            self.ranked.remove(index_key);
+        }
+    }
+
+    pub(crate) fn cleared(&mut self) {
+        self.ranked.clear();
+        self.ranks.clear();
+    }
+
+    /// Picks the posting to drop if the bucket has grown past the limit, reporting it to the
+    /// eviction callback. The caller removes it from the index.
+    pub(crate) fn victim(&mut self, index_key: &A) -> Option<KS::Key> {
+        let rank = match self.ranked.get(index_key) {
+            Some(ranked) if ranked.len() > self.cap.limit => *ranked.keys().next()?,
+            _ => return None,
+        };
+        let victim = self.ranked.get(index_key)?.get(&rank)?.clone();
+        self.removed(index_key, victim.borrow());
+        if let Some(ref mut on_evict) = self.cap.on_evict {
+            on_evict(index_key, victim.borrow());
+        }
+        Some(victim)
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index whose buckets hold at most `cap`'s limit of postings, dropping postings as
+    /// the cap's eviction ordering chooses. Dropped entries stay in the map and in other buckets;
+    /// they are only missing from the full bucket, which therefore no longer lists every
+    /// matching entry.
+    pub fn add_capped_index<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+        cap: BucketCap<K, V, A>,
+    ) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state.bucket_limit = Some(BucketLimit::new(cap));
+        self.register_new_index(name, index_state)
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::sync::mpsc::Sender;
+
+use shared::{Chunked, Mirror};
+use {ApplyError, Backing, IndexedMap, InsertError, KeyStorage, MapEvent};
+
+/// A single mutation of a map, as recorded for event sourcing or replication.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChangeEvent<K, V> {
+    /// `key` was inserted or its value replaced by `value`, including in-place updates.
+    Insert { key: K, value: V },
+    /// `key` was removed.
+    Remove { key: K },
+}
+
+impl<K, V> ChangeEvent<K, V> {
+    pub fn key(&self) -> &K {
+        match *self {
+            ChangeEvent::Insert { ref key, .. } | ChangeEvent::Remove { ref key } => key,
+        }
+    }
+
+    /// A clone of the event, copying the value with `copy`.
+    fn copied(&self, copy: fn(&V) -> V) -> ChangeEvent<K, V>
+    where
+        K: Clone,
+    {
+        match *self {
+            ChangeEvent::Insert { ref key, ref value } => ChangeEvent::Insert {
+                key: key.clone(),
+                value: copy(value),
+            },
+            ChangeEvent::Remove { ref key } => ChangeEvent::Remove { key: key.clone() },
+        }
+    }
+}
+
+/// A change event numbered by the map that recorded it, from `events_since`. Sequence numbers
+/// start at 1 and increase by one per event, so a follower can tell whether it missed any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequencedEvent<K, V> {
+    pub sequence: u64,
+    pub event: ChangeEvent<K, V>,
+}
+
+/// The most recent events of a map, kept for followers to catch up from.
+pub(crate) struct Journal<K, V> {
+    events: VecDeque<SequencedEvent<K, V>>,
+    retain: usize,
+    last: u64,
+}
+
+impl<K, V> Journal<K, V> {
+    fn record(&mut self, event: ChangeEvent<K, V>) {
+        self.last += 1;
+        if self.events.len() == self.retain {
+            self.events.pop_front();
+        }
+        if self.retain > 0 {
+            self.events.push_back(SequencedEvent {
+                sequence: self.last,
+                event,
+            });
+        }
+    }
+}
+
+/// The mutations recorded since the previous call to `take_changes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeBatch<K, V> {
+    pub events: Vec<ChangeEvent<K, V>>,
+    /// Set if mutations were dropped because the buffer was full. The batch is then incomplete
+    /// and a consumer must resynchronize from the full map state.
+    pub overflowed: bool,
+}
+
+pub(crate) struct ChangeLog<K, V> {
+    events: Vec<ChangeEvent<K, V>>,
+    capacity: usize,
+    overflowed: bool,
+}
+
+impl<K, V> ChangeLog<K, V> {
+    fn new(capacity: usize) -> ChangeLog<K, V> {
+        ChangeLog {
+            events: Vec::new(),
+            capacity,
+            overflowed: false,
+        }
+    }
+
+    /// Records an event, built lazily so a full buffer costs no clones.
+    pub(crate) fn record<F>(&mut self, event: F)
+    where
+        F: FnOnce() -> ChangeEvent<K, V>,
+    {
+        if self.events.len() < self.capacity {
+            self.events.push(event());
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    fn take(&mut self) -> ChangeBatch<K, V> {
+        ChangeBatch {
+            events: ::std::mem::take(&mut self.events),
+            overflowed: ::std::mem::replace(&mut self.overflowed, false),
+        }
+    }
+}
+
+type Listener<K, V> = Box<dyn FnMut(MapEvent<K, V>)>;
+
+/// Everywhere a map's mutations are delivered: the `take_changes` buffer, any replica feeds and
+/// any `on_change` listeners.
+pub(crate) struct ChangeSinks<K, V> {
+    log: Option<ChangeLog<K, V>>,
+    feeds: Vec<Sender<ChangeEvent<K, V>>>,
+    listeners: Vec<Listener<K, V>>,
+    journal: Option<Journal<K, V>>,
+    /// The chunked copy of the entries that shared snapshots are taken from, from the first one
+    /// until a write finds that none is left.
+    shared: Option<Mirror<K, V>>,
+    /// The sequence number of the last event applied with `apply_event`.
+    applied: u64,
+    /// Copies values for the sinks that keep them, once one is attached.
+    copy: Option<fn(&V) -> V>,
+}
+
+impl<K, V> ChangeSinks<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    pub(crate) fn new() -> ChangeSinks<K, V> {
+        ChangeSinks {
+            log: None,
+            feeds: Vec::new(),
+            listeners: Vec::new(),
+            journal: None,
+            shared: None,
+            applied: 0,
+            copy: None,
+        }
+    }
+
+    /// A copy of `value` if anything attached so far needs values copied, which only `V: Clone`
+    /// maps can attach.
+    pub(crate) fn copy(&self, value: &V) -> Option<V> {
+        self.copy.map(|copy| copy(value))
+    }
+
+    fn recording(&self) -> bool {
+        self.log.is_some()
+            || !self.feeds.is_empty()
+            || self.journal.is_some()
+            || self.shared.is_some()
+    }
+
+    /// Stops keeping the shared entries current once no snapshot holds them.
+    fn release_shared(&mut self) {
+        if self.shared.as_ref().is_some_and(|shared| !shared.is_read()) {
+            self.shared = None;
+        }
+    }
+
+    /// Delivers an insert or overwrite to every sink, copying it only if someone is listening.
+    pub(crate) fn record_insert(&mut self, key: &K, value: &V) {
+        self.release_shared();
+        if let Some(copy) = self.copy.filter(|_| self.recording()) {
+            let event = ChangeEvent::Insert {
+                key: key.clone(),
+                value: copy(value),
+            };
+            self.record(event, copy);
+        }
+    }
+
+    pub(crate) fn record_remove(&mut self, key: &K) {
+        self.release_shared();
+        if let Some(copy) = self.copy.filter(|_| self.recording()) {
+            self.record(ChangeEvent::Remove { key: key.clone() }, copy);
+        }
+    }
+
+    /// Feeds whose receiver has gone away are dropped.
+    fn record(&mut self, event: ChangeEvent<K, V>, copy: fn(&V) -> V) {
+        if let Some(shared) = self.shared.as_mut().map(Mirror::chunked_mut) {
+            match event {
+                ChangeEvent::Insert { ref key, ref value } => {
+                    shared.insert(key.clone(), copy(value))
+                }
+                ChangeEvent::Remove { ref key } => shared.remove(key),
+            }
+        }
+        self.feeds
+            .retain(|feed| feed.send(event.copied(copy)).is_ok());
+        if let Some(ref mut journal) = self.journal {
+            journal.record(event.copied(copy));
+        }
+        if let Some(ref mut log) = self.log {
+            log.record(|| event);
+        }
+    }
+
+    /// Hands a value about to be changed in place back as a copy if listeners will need it as
+    /// the old value of the update.
+    pub(crate) fn previous(&self, value: &V) -> Option<V> {
+        if self.listeners.is_empty() {
+            None
+        } else {
+            self.copy(value)
+        }
+    }
+
+    /// Tells listeners that `key` was written with `value`, over `old` if it had a value.
+    pub(crate) fn notify_write(&mut self, key: &K, old: Option<&V>, value: &V) {
+        if let Some(copy) = self.copy.filter(|_| !self.listeners.is_empty()) {
+            let event = match old {
+                Some(old) => MapEvent::Update {
+                    key: key.clone(),
+                    old: copy(old),
+                    new: copy(value),
+                },
+                None => MapEvent::Insert {
+                    key: key.clone(),
+                    value: copy(value),
+                },
+            };
+            self.notify(event, copy);
+        }
+    }
+
+    /// Tells listeners that `key` was changed in place from `old`, the copy `previous` made, to
+    /// `new`.
+    pub(crate) fn notify_update(&mut self, key: &K, old: V, new: &V) {
+        if let Some(copy) = self.copy.filter(|_| !self.listeners.is_empty()) {
+            let event = MapEvent::Update {
+                key: key.clone(),
+                old,
+                new: copy(new),
+            };
+            self.notify(event, copy);
+        }
+    }
+
+    pub(crate) fn notify_remove(&mut self, key: &K, value: &V) {
+        if let Some(copy) = self.copy.filter(|_| !self.listeners.is_empty()) {
+            let event = MapEvent::Remove {
+                key: key.clone(),
+                value: copy(value),
+            };
+            self.notify(event, copy);
+        }
+    }
+
+    fn notify(&mut self, event: MapEvent<K, V>, copy: fn(&V) -> V) {
+        self.listeners
+            .iter_mut()
+            .for_each(|listener| listener(event.copied(copy)));
+    }
+}
+
+impl<K, V> ChangeSinks<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    /// Lets the sinks copy values from now on. Everything that needs values copied calls it
+    /// before it starts to, since only then is `V: Clone` known.
+    pub(crate) fn keep_copies(&mut self) {
+        self.copy = Some(V::clone);
+    }
+
+    /// The sinks for a clone of the map: an empty log of the same capacity if this one keeps a
+    /// log, a copy of the journal, the shared entries, which the two maps go on sharing until
+    /// either writes, though the original's snapshots do not keep the clone updating them, and
+    /// no feeds or listeners, which stay with the original.
+    pub(crate) fn detached(&self) -> ChangeSinks<K, V> {
+        ChangeSinks {
+            log: self.log.as_ref().map(|log| ChangeLog::new(log.capacity)),
+            feeds: Vec::new(),
+            listeners: Vec::new(),
+            journal: self.journal.as_ref().map(|journal| Journal {
+                events: journal.events.clone(),
+                retain: journal.retain,
+                last: journal.last,
+            }),
+            shared: self.shared.as_ref().map(Mirror::detached),
+            applied: self.applied,
+            copy: self.copy,
+        }
+    }
+
+    pub(crate) fn shared_entries(&self) -> Option<&Mirror<K, V>> {
+        self.shared.as_ref()
+    }
+
+    /// Keeps `entries` current from now on, for shared snapshots to be taken from.
+    pub(crate) fn share_entries(&mut self, entries: Chunked<K, V>) {
+        self.keep_copies();
+        self.shared = Some(Mirror::new(entries));
+    }
+
+    pub(crate) fn add_feed(&mut self, feed: Sender<ChangeEvent<K, V>>) {
+        self.keep_copies();
+        self.feeds.push(feed);
+    }
+
+    pub(crate) fn add_listener(&mut self, listener: Listener<K, V>) {
+        self.keep_copies();
+        self.listeners.push(listener);
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Applies a stream of events in order, maintaining every index as it goes. Replaying a
+    /// complete log into an empty map reconstructs the state that produced it; indices can be
+    /// added before the replay or afterwards.
+    ///
+    /// Returns the number of events applied, or the first insert rejected by the capacity limit.
+    /// Events before the rejected one stay applied.
+    pub fn replay<I>(&mut self, events: I) -> Result<usize, InsertError<K, V>>
+    where
+        I: IntoIterator<Item = ChangeEvent<K, V>>,
+    {
+        let mut applied = 0;
+        for event in events {
+            match event {
+                ChangeEvent::Insert { key, value } => {
+                    self.try_insert(key, value)?;
+                }
+                ChangeEvent::Remove { key } => {
+                    self.remove_unchecked(&key);
+                }
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Starts buffering every mutation for `take_changes`, holding at most `capacity` events
+    /// between calls. Calling it again changes the bound and keeps buffered events.
+    pub fn capture_changes(&mut self, capacity: usize) {
+        self.changes.keep_copies();
+        match self.changes.log {
+            Some(ref mut log) => log.capacity = capacity,
+            None => self.changes.log = Some(ChangeLog::new(capacity)),
+        }
+    }
+
+    /// Stops buffering mutations and discards any that were not taken.
+    pub fn stop_capturing_changes(&mut self) {
+        self.changes.log = None;
+    }
+
+    /// Returns the mutations made since the last call, so an outbox or replication pump can poll
+    /// at its own pace. Returns an empty batch if changes are not being captured.
+    pub fn take_changes(&mut self) -> ChangeBatch<K, V> {
+        match self.changes.log {
+            Some(ref mut log) => log.take(),
+            None => ChangeBatch {
+                events: Vec::new(),
+                overflowed: false,
+            },
+        }
+    }
+
+    /// Starts numbering every mutation and keeping the latest `retain` of them, for followers to
+    /// fetch with `events_since`. Calling it again changes how many are kept.
+    pub fn enable_journal(&mut self, retain: usize) {
+        self.changes.keep_copies();
+        match self.changes.journal {
+            Some(ref mut journal) => {
+                journal.retain = retain;
+                while journal.events.len() > retain {
+                    journal.events.pop_front();
+                }
+            }
+            None => {
+                self.changes.journal = Some(Journal {
+                    events: VecDeque::new(),
+                    retain,
+                    last: 0,
+                })
+            }
+        }
+    }
+
+    /// The sequence number of the latest journaled mutation, 0 if there is none.
+    pub fn last_sequence(&self) -> u64 {
+        self.changes
+            .journal
+            .as_ref()
+            .map_or(0, |journal| journal.last)
+    }
+
+    /// The journaled mutations after `sequence`, oldest first. Returns `None` if the journal is
+    /// off or no longer holds every event after `sequence`, in which case a follower has to
+    /// resynchronize from a full copy of the map.
+    pub fn events_since(&self, sequence: u64) -> Option<Vec<SequencedEvent<K, V>>> {
+        let journal = self.changes.journal.as_ref()?;
+        let oldest = journal.last + 1 - journal.events.len() as u64;
+        if sequence + 1 < oldest {
+            return None;
+        }
+        Some(
+            journal
+                .events
+                .iter()
+                .filter(|event| event.sequence > sequence)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Applies an event from a leader's journal, keeping the entries and every index in sync
+    /// with it. Events must arrive in sequence; one already applied is skipped and returns
+    /// `Ok(false)`, so a batch can safely be fetched again after a failure.
+    pub fn apply_event(&mut self, event: SequencedEvent<K, V>) -> Result<bool, ApplyError<K, V>> {
+        let expected = self.changes.applied + 1;
+        if event.sequence < expected {
+            return Ok(false);
+        }
+        if event.sequence > expected {
+            return Err(ApplyError::Gap {
+                expected,
+                found: event.sequence,
+            });
+        }
+        self.replay(Some(event.event))
+            .map_err(ApplyError::Rejected)?;
+        self.changes.applied = event.sequence;
+        Ok(true)
+    }
+
+    /// The sequence number of the last event applied with `apply_event`, 0 if none was.
+    pub fn applied_sequence(&self) -> u64 {
+        self.changes.applied
+    }
+}
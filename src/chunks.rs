@@ -0,0 +1,114 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// An iterator that groups the items of another iterator into `Vec`s of a fixed size. The last
+/// chunk may be shorter.
+pub struct Chunks<I> {
+    inner: I,
+    size: usize,
+}
+
+impl<I> Chunks<I> {
+    fn new(inner: I, size: usize) -> Chunks<I> {
+        assert!(size != 0, "chunk size must be non-zero");
+        Chunks { inner, size }
+    }
+}
+
+impl<I> Iterator for Chunks<I>
+where
+    I: Iterator,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Vec<I::Item>> {
+        let chunk: Vec<I::Item> = self.inner.by_ref().take(self.size).collect();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Iterates over the entries in batches of `size`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn iter_chunks<'a>(
+        &'a self,
+        size: usize,
+    ) -> Chunks<impl Iterator<Item = (&'a K, &'a V)> + 'a> {
+        Chunks::new(self.inner.iter().map(|(k, v)| (k.borrow(), v)), size)
+    }
+
+    /// Calls `f` with successive batches of at most `size` entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn for_each_chunk<F>(&self, size: usize, mut f: F)
+    where
+        F: FnMut(&[(&K, &V)]),
+    {
+        self.iter_chunks(size).for_each(|chunk| f(&chunk));
+    }
+
+    /// Iterates over the entries indexed under `index_key` in batches of `size`. Yields nothing if
+    /// the index is missing or has no such key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn iter_chunks_by_index<'a, A, Q>(
+        &'a self,
+        index_id: &IndexId<A>,
+        index_key: &Q,
+        size: usize,
+    ) -> Chunks<impl Iterator<Item = (&'a K, &'a V)> + 'a>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let inner = &self.inner;
+        let entries = self
+            .keys_by_index(index_id, index_key)
+            .into_iter()
+            .flat_map(|keys| keys.iter())
+            .filter_map(move |k| {
+                let k: &K = k.borrow();
+                inner.get(k).map(|v| (k, v))
+            });
+        Chunks::new(entries, size)
+    }
+
+    /// Calls `f` with successive batches of at most `size` entries indexed under `index_key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn for_each_chunk_by_index<A, Q, F>(
+        &self,
+        index_id: &IndexId<A>,
+        index_key: &Q,
+        size: usize,
+        mut f: F,
+    ) where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        F: FnMut(&[(&K, &V)]),
+    {
+        self.iter_chunks_by_index(index_id, index_key, size)
+            .for_each(|chunk| f(&chunk));
+    }
+}
@@ -0,0 +1,225 @@
+//! The standard collection traits for `IndexedMap`. Iteration yields user keys, whatever the
+//! key storage, and equality compares entries only, not index definitions.
+
+use std::borrow::Borrow;
+use std::fmt::{self, Debug, Formatter};
+use std::hash::Hash;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+
+use bounded::Bounds;
+use negative::NegativeCache;
+use {Backing, IndexedMap, KeyStorage};
+
+/// The entries of a map by reference, from `IndexedMap::iter`.
+pub struct Iter<'m, K: 'm, V: 'm, KS: 'm, B: 'm>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    inner: B::Iter<'m>,
+    _key: PhantomData<(&'m K, KS)>,
+}
+
+impl<'m, K, V, KS, B> Iterator for Iter<'m, K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    type Item = (&'m K, &'m V);
+
+    fn next(&mut self) -> Option<(&'m K, &'m V)> {
+        self.inner.next().map(|(key, value)| (key.borrow(), value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// The entries of a map by value, from `IntoIterator`.
+pub struct IntoIter<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: IntoIterator<Item = (KS::Key, V)>,
+{
+    inner: B::IntoIter,
+    _key: PhantomData<(K, KS)>,
+}
+
+impl<K, V, KS, B> Iterator for IntoIter<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: IntoIterator<Item = (KS::Key, V)>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.inner
+            .next()
+            .map(|(key, value)| (KS::into_key(key), value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V, KS, B> FusedIterator for IntoIter<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: IntoIterator<Item = (KS::Key, V)>,
+    B::IntoIter: FusedIterator,
+{
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Every entry, in the order of the backing map. Unlike iterating the backing map through
+    /// `Deref`, this yields user keys rather than stored ones.
+    pub fn iter(&self) -> Iter<'_, K, V, KS, B> {
+        Iter {
+            inner: self.inner.iter(),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<'m, K, V, KS, B> IntoIterator for &'m IndexedMap<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    type Item = (&'m K, &'m V);
+    type IntoIter = Iter<'m, K, V, KS, B>;
+
+    fn into_iter(self) -> Iter<'m, K, V, KS, B> {
+        self.iter()
+    }
+}
+
+impl<K, V, KS, B> IntoIterator for IndexedMap<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: IntoIterator<Item = (KS::Key, V)>,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, KS, B>;
+
+    /// Consumes the map, dropping its indices without recording any removals.
+    fn into_iter(self) -> IntoIter<K, V, KS, B> {
+        IntoIter {
+            inner: self.inner.into_iter(),
+            _key: PhantomData,
+        }
+    }
+}
+
+struct DebugEntries<'m, K: 'm, V: 'm, KS: 'm, B: 'm>(&'m IndexedMap<K, V, KS, B>)
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>;
+
+impl<'m, K, V, KS, B> Debug for DebugEntries<'m, K, V, KS, B>
+where
+    K: Eq + Hash + Debug,
+    V: Debug,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+impl<K, V, KS, B> Debug for IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + Debug,
+    V: 'static + Debug,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Shows the entries and the names of the indices.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("IndexedMap")
+            .field("entries", &DebugEntries(self))
+            .field("indices", &self.index_names())
+            .finish()
+    }
+}
+
+impl<K, V, KS, B> Clone for IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V> + Clone,
+{
+    /// Copies the entries and every index with its postings, so the clone answers queries
+    /// without reindexing. Change listeners, feeds and eviction callbacks stay with the
+    /// original, and the clone starts with empty change log and negative cache buffers.
+    fn clone(&self) -> IndexedMap<K, V, KS, B> {
+        IndexedMap {
+            inner: self.inner.clone(),
+            indices: self
+                .indices
+                .iter()
+                .map(|(name, by_type)| {
+                    let by_type = by_type
+                        .iter()
+                        .map(|(type_id, updater)| (*type_id, updater.duplicate()))
+                        .collect();
+                    (name.clone(), by_type)
+                })
+                .collect(),
+            capacity_limit: self.capacity_limit,
+            metadata: self.metadata.clone(),
+            version: self.version,
+            changes: self.changes.detached(),
+            tombstones: self.tombstones.clone(),
+            value_eq: self.value_eq.clone(),
+            negative: self.negative.as_ref().map(NegativeCache::emptied),
+            pinned: self.pinned.clone(),
+            expirations: self.expirations.clone(),
+            bounds: self.bounds.as_ref().map(Bounds::duplicate),
+            key_indices: self.key_indices.duplicate(),
+            reaper: self.reaper.clone(),
+        }
+    }
+}
+
+impl<K, V, KS, B> PartialEq for IndexedMap<K, V, KS, B>
+where
+    K: Eq + Hash,
+    V: PartialEq,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Maps are equal when they hold the same entries, whatever their indices.
+    fn eq(&self, other: &IndexedMap<K, V, KS, B>) -> bool {
+        self.inner.len() == other.inner.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.inner.get(key) == Some(value))
+    }
+}
+
+impl<K, V, KS, B> Eq for IndexedMap<K, V, KS, B>
+where
+    K: Eq + Hash,
+    V: Eq,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+}
@@ -0,0 +1,123 @@
+//! Columnar export of indexed attributes.
+//!
+//! Exports lay index values out the way Arrow lays out a `LargeList` column: one flat buffer of
+//! values plus `i64` offsets delimiting each row's values, with every column of an export in the
+//! same row order. Handing them to an Arrow implementation is a matter of wrapping the buffers,
+//! with no per-entry extraction code.
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, IndexId, IndexedMap, KeyStorage, QueryError};
+
+/// The values of one index for every row of an export. Row `i` holds
+/// `values[offsets[i] as usize..offsets[i + 1] as usize]`; rows the index has no values for are
+/// empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListColumn<A> {
+    pub name: String,
+    pub offsets: Vec<i64>,
+    pub values: Vec<A>,
+}
+
+impl<A> ListColumn<A> {
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn row(&self, row: usize) -> &[A] {
+        &self.values[self.offsets[row] as usize..self.offsets[row + 1] as usize]
+    }
+
+    /// The column as a plain nullable column, for indices producing at most one value per entry.
+    /// Returns `None` if any row has several values.
+    pub fn to_single(&self) -> Option<Vec<Option<&A>>> {
+        (0..self.len())
+            .map(|row| match *self.row(row) {
+                [] => Some(None),
+                [ref value] => Some(Some(value)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A fixed row order over the entries of a map, from which key and index columns are exported.
+/// Created by `IndexedMap::export_columns`.
+pub struct ColumnExport<'a, K, V, KS, B>
+where
+    K: 'a + Eq + Hash,
+    V: 'a,
+    KS: 'a + KeyStorage<K>,
+    B: 'a,
+{
+    map: &'a IndexedMap<K, V, KS, B>,
+    rows: Vec<&'a KS::Key>,
+}
+
+impl<'a, K, V, KS, B> ColumnExport<'a, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The primary key of every row.
+    pub fn keys(&self) -> Vec<&'a K> {
+        self.rows.iter().map(|&key| key.borrow()).collect()
+    }
+
+    /// The values the index holds for every row, in row order.
+    pub fn column<A, H>(&self, index_id: &IndexId<A, H>) -> Result<ListColumn<A>, QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.map.try_get_index(index_id)?;
+        let state = self
+            .map
+            .get_index_state(index_id)
+            .ok_or_else(|| self.map.missing_index(index_id))?;
+        let mut offsets = Vec::with_capacity(self.rows.len() + 1);
+        let mut values = Vec::new();
+        offsets.push(0);
+        for &row in &self.rows {
+            let key: &K = row.borrow();
+            values.extend(state.indexed.get(key).into_iter().flatten().cloned());
+            offsets.push(values.len() as i64);
+        }
+        Ok(ListColumn {
+            name: index_id.name.clone(),
+            offsets,
+            values,
+        })
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Fixes a row order over the current entries for exporting index columns.
+    pub fn export_columns(&self) -> ColumnExport<'_, K, V, KS, B> {
+        ColumnExport {
+            map: self,
+            rows: self.inner.iter().map(|(key, _)| key).collect(),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use {Backing, HasPrefix, IndexId, IndexState, IndexedMap, KeyStorage, PostingObserver};
+
+/// Identifies an index over tuples that can also be queried by the tuple's leading component.
+/// Derefs to the plain `IndexId` for queries by the full tuple.
+pub struct CompositeIndexId<A> {
+    index_id: IndexId<A>,
+}
+
+impl<A> Deref for CompositeIndexId<A> {
+    type Target = IndexId<A>;
+
+    fn deref(&self) -> &IndexId<A> {
+        &self.index_id
+    }
+}
+
+impl<A> Clone for CompositeIndexId<A> {
+    fn clone(&self) -> CompositeIndexId<A> {
+        CompositeIndexId {
+            index_id: self.index_id.clone(),
+        }
+    }
+}
+
+/// The non-empty buckets of a composite index grouped by prefix, with their sizes.
+struct Prefixes<K, A>
+where
+    A: HasPrefix,
+{
+    by_prefix: HashMap<A::Prefix, HashMap<A, usize>>,
+    _key: PhantomData<K>,
+}
+
+impl<K, V, A, KS> PostingObserver<K, V, A, KS> for Prefixes<K, A>
+where
+    K: 'static,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone + HasPrefix,
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, _: &KS::Key, _: &V) {
+        *self
+            .by_prefix
+            .entry(index_key.prefix())
+            .or_default()
+            .entry(index_key.clone())
+            .or_insert(0) += 1;
+    }
+
+    fn removed(&mut self, index_key: &A, _: &K) {
+        let prefix = index_key.prefix();
+        let now_empty = self.by_prefix.get_mut(&prefix).is_some_and(|buckets| {
+            let bucket_empty = buckets.get_mut(index_key).is_some_and(|size| {
+                *size -= 1;
+                *size == 0
+            });
+            if bucket_empty {
+                buckets.remove(index_key);
+            }
+            buckets.is_empty()
+        });
+        if now_empty {
+            self.by_prefix.remove(&prefix);
+        }
+    }
+
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>> {
+        Some(Box::new(Prefixes::<K, A> {
+            by_prefix: self.by_prefix.clone(),
+            _key: PhantomData,
+        }))
+    }
+
+    fn cleared(&mut self) {
+        self.by_prefix.clear();
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index over tuples such as `(Department, Role)`, queried by the full tuple with
+    /// `filter_by_index` or by its leading component with `filter_by_prefix`, in place of
+    /// separate indices for each.
+    pub fn add_composite_index<A, F>(&mut self, name: String, index_fn: F) -> CompositeIndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone + HasPrefix,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state.observers.push(Box::new(Prefixes::<K, A> {
+            by_prefix: HashMap::new(),
+            _key: PhantomData,
+        }));
+        CompositeIndexId {
+            index_id: self.register_new_index(name, index_state),
+        }
+    }
+
+    /// The entries whose tuples start with `prefix`, each once however many of its tuples do.
+    /// Returns `None` if the index is unavailable.
+    pub fn filter_by_prefix<A>(
+        &self,
+        index_id: &CompositeIndexId<A>,
+        prefix: &A::Prefix,
+    ) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Clone + HasPrefix,
+    {
+        let state = self.get_index_state(index_id)?;
+        let prefixes = state.find_observer::<Prefixes<K, A>>()?;
+        let keys: HashSet<&KS::Key> = prefixes
+            .by_prefix
+            .get(prefix)
+            .into_iter()
+            .flat_map(|buckets| buckets.keys())
+            .filter_map(|index_key| state.index.get(index_key))
+            .flatten()
+            .collect();
+        Some(
+            keys.into_iter()
+                .filter_map(|key| {
+                    let key: &K = key.borrow();
+                    self.inner.get(key).map(|value| (key, value))
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,220 @@
+//! An indexed map that can be shared between threads.
+//!
+//! `IndexedMap` here takes `&self` everywhere, so it can sit behind an `Arc` in a worker pool.
+//! Entries are spread over shards by key hash, each behind its own lock, and every index has a
+//! lock of its own. Readers never block each other, and a writer locks only the shard holding
+//! its key and the indices it updates.
+//!
+//! Locks are always taken in the order index registry, shard, index, and readers hold at most
+//! one shard or index lock at a time. An index query therefore sees each index and each shard
+//! in a consistent state, but not all of them at the same instant: an entry written while a
+//! query runs may show up under its old or its new index values, and entries removed after
+//! the index was read are skipped. Results are returned by value, since nothing can be
+//! borrowed past the lock it was read under.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use downcast_rs::DowncastSync;
+
+use IndexId;
+
+const DEFAULT_SHARDS: usize = 16;
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().expect("lock poisoned by a panicking writer")
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().expect("lock poisoned by a panicking writer")
+}
+
+type SharedIndexFn<K, V, A> = Box<dyn Fn(&K, &V) -> Vec<A> + Send + Sync>;
+
+type Postings<K, A> = HashMap<A, HashSet<K>>;
+
+struct Index<K, V, A> {
+    index_fn: SharedIndexFn<K, V, A>,
+    postings: RwLock<Postings<K, A>>,
+}
+
+trait Updater<K, V>: DowncastSync {
+    /// Moves `key` from the index values of `old` to those of `new`, either of which may be
+    /// missing.
+    fn replace(&self, key: &K, old: Option<&V>, new: Option<&V>);
+}
+
+impl_downcast!(sync Updater<K, V> where K: 'static, V: 'static);
+
+impl<K, V, A> Updater<K, V> for Index<K, V, A>
+where
+    K: 'static + Eq + Hash + Clone + Send + Sync,
+    V: 'static + Send + Sync,
+    A: 'static + Eq + Hash + Clone + Send + Sync,
+{
+    fn replace(&self, key: &K, old: Option<&V>, new: Option<&V>) {
+        let old_values = old.map_or_else(Vec::new, |value| (self.index_fn)(key, value));
+        let new_values = new.map_or_else(Vec::new, |value| (self.index_fn)(key, value));
+        if old_values == new_values {
+            return;
+        }
+        let mut postings = write(&self.postings);
+        for index_key in old_values.iter().filter(|a| !new_values.contains(a)) {
+            let now_empty = postings.get_mut(index_key).is_some_and(|keys| {
+                keys.remove(key);
+                keys.is_empty()
+            });
+            if now_empty {
+                postings.remove(index_key);
+            }
+        }
+        for index_key in new_values {
+            postings.entry(index_key).or_default().insert(key.clone());
+        }
+    }
+}
+
+/// A sharded indexed map for concurrent readers and writers. See the module documentation for
+/// what a query can observe while writes are in flight.
+pub struct IndexedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+    indices: RwLock<HashMap<String, Arc<dyn Updater<K, V>>>>,
+}
+
+impl<K, V> IndexedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone + Send + Sync,
+    V: 'static + Clone + Send + Sync,
+{
+    pub fn new() -> IndexedMap<K, V> {
+        IndexedMap::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Spreads entries over `shards` separately locked shards. More shards let more writers
+    /// proceed at once, at the cost of more locks for queries to take.
+    pub fn with_shards(shards: usize) -> IndexedMap<K, V> {
+        IndexedMap {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+            indices: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// Adds an index over every entry, replacing any index of the same name. Writes wait while
+    /// the existing entries are indexed.
+    pub fn add_index<A, F>(&self, name: String, index_fn: F) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone + Send + Sync,
+        F: 'static + Fn(&K, &V) -> Vec<A> + Send + Sync,
+    {
+        let mut indices = write(&self.indices);
+        let index = Index {
+            index_fn: Box::new(index_fn),
+            postings: RwLock::new(HashMap::new()),
+        };
+        for shard in &self.shards {
+            for (key, value) in read(shard).iter() {
+                index.replace(key, None, Some(value));
+            }
+        }
+        indices.insert(name.clone(), Arc::new(index));
+        IndexId::new(name)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let indices = read(&self.indices);
+        let mut shard = write(self.shard(&key));
+        for index in indices.values() {
+            index.replace(&key, shard.get(&key), Some(&value));
+        }
+        shard.insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let indices = read(&self.indices);
+        let mut shard = write(self.shard(key));
+        let previous = shard.remove(key)?;
+        for index in indices.values() {
+            index.replace(key, Some(&previous), None);
+        }
+        Some(previous)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        read(self.shard(key)).get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        read(self.shard(key)).contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| read(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| read(shard).is_empty())
+    }
+
+    fn index<A>(&self, index_id: &IndexId<A>) -> Option<Arc<dyn Updater<K, V>>> {
+        read(&self.indices).get(index_id.name()).cloned()
+    }
+
+    /// The keys whose entries have `index_key` among their values in the index. Returns `None`
+    /// if there is no such index of value type `A`.
+    pub fn keys_by_index<A, Q>(&self, index_id: &IndexId<A>, index_key: &Q) -> Option<HashSet<K>>
+    where
+        A: 'static + Eq + Hash + Clone + Send + Sync + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let index = self.index(index_id)?;
+        let index = index.downcast_ref::<Index<K, V, A>>()?;
+        let keys = read(&index.postings)
+            .get(index_key)
+            .cloned()
+            .unwrap_or_default();
+        Some(keys)
+    }
+
+    /// The entries whose values have `index_key` among their values in the index. Returns
+    /// `None` if there is no such index of value type `A`.
+    pub fn filter_by_index<A, Q>(
+        &self,
+        index_id: &IndexId<A>,
+        index_key: &Q,
+    ) -> Option<HashMap<K, V>>
+    where
+        A: 'static + Eq + Hash + Clone + Send + Sync + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let keys = self.keys_by_index(index_id, index_key)?;
+        Some(
+            keys.into_iter()
+                .filter_map(|key| {
+                    let value = self.get(&key)?;
+                    Some((key, value))
+                })
+                .collect(),
+        )
+    }
+}
+
+impl<K, V> Default for IndexedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone + Send + Sync,
+    V: 'static + Clone + Send + Sync,
+{
+    fn default() -> IndexedMap<K, V> {
+        IndexedMap::new()
+    }
+}
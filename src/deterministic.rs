@@ -0,0 +1,84 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
+
+use {Backing, ClonedKeys, IndexId, IndexedMap, KeyStorage};
+
+/// A hasher with fixed keys, so that hash tables built by the same sequence of operations iterate
+/// in the same order in every run. Unlike the default `RandomState` it offers no protection
+/// against deliberately colliding input, so it is meant for tests and tooling, not for maps fed
+/// by untrusted data.
+pub type DeterministicState = BuildHasherDefault<DefaultHasher>;
+
+/// A map whose entries iterate in the same order in every run, created by
+/// `IndexedMap::deterministic`.
+pub type DeterministicMap<K, V> = IndexedMap<K, V, ClonedKeys, HashMap<K, V, DeterministicState>>;
+
+impl<K, V> DeterministicMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    /// Creates a map for reproducible output such as golden files: entries are stored with
+    /// `DeterministicState`, and indices added with `add_deterministic_index` hash both their
+    /// index values and the keys in each bucket with it too. The `sorted_*` queries list index
+    /// values and bucket contents in order for indices with any hasher.
+    pub fn deterministic() -> DeterministicMap<K, V> {
+        IndexedMap::with_backing(ClonedKeys, HashMap::default())
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index whose index values iterate in the same order in every run.
+    pub fn add_deterministic_index<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+    ) -> IndexId<A, DeterministicState>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        self.add_index_with_hasher(name, DeterministicState::default(), index_fn)
+    }
+
+    /// The index values of an index in ascending order, or `None` if it is unavailable.
+    pub fn sorted_index_keys<A, H>(&self, index_id: &IndexId<A, H>) -> Option<Vec<&A>>
+    where
+        A: 'static + Eq + Hash + Clone + Ord,
+        H: 'static + BuildHasher + Clone,
+    {
+        let mut index_keys: Vec<&A> = self.get_index(index_id)?.keys().collect();
+        index_keys.sort_unstable();
+        Some(index_keys)
+    }
+
+    /// Like `keys_by_index`, with the keys of the bucket in ascending order, which is the same
+    /// in every run even for an index whose buckets are hashed randomly.
+    pub fn sorted_keys_by_index<A, Q, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<Vec<&K>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+        K: Ord,
+    {
+        let mut keys: Vec<&K> = self
+            .keys_by_index(index_id, index_key)?
+            .iter()
+            .map(Borrow::borrow)
+            .collect();
+        keys.sort_unstable();
+        Some(keys)
+    }
+}
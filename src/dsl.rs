@@ -0,0 +1,358 @@
+//! A small string query language over named indices.
+//!
+//! Queries compare index keys for equality and combine the comparisons with `AND`, `OR`, `NOT`
+//! and parentheses:
+//!
+//! ```text
+//! status = active AND (team = core OR team = infra)
+//! NOT region = "eu west"
+//! ```
+//!
+//! Field names are bound to typed indices through `Fields`, which parses each value with the
+//! index key type's `FromStr` implementation.
+
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use {Backing, IndexId, IndexedMap, KeyStorage, QueryError};
+
+/// A parsed query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    Eq { field: String, value: String },
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+/// Why a query could not be parsed or run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DslError {
+    /// The query text is malformed at the given byte offset.
+    Parse { position: usize, message: String },
+    /// The query names a field that has not been bound.
+    UnknownField { field: String },
+    /// The value could not be parsed as the field's index key type.
+    InvalidValue { field: String, value: String },
+    /// The bound index could not be queried.
+    Query(QueryError),
+}
+
+impl fmt::Display for DslError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DslError::Parse {
+                position,
+                ref message,
+            } => write!(f, "parse error at {}: {}", position, message),
+            DslError::UnknownField { ref field } => write!(f, "unknown field `{}`", field),
+            DslError::InvalidValue {
+                ref field,
+                ref value,
+            } => write!(f, "invalid value `{}` for field `{}`", value, field),
+            DslError::Query(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for DslError {}
+
+impl From<QueryError> for DslError {
+    fn from(err: QueryError) -> DslError {
+        DslError::Query(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Equals,
+    Open,
+    Close,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, DslError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(position, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '=' => {
+                chars.next();
+                tokens.push((position, Token::Equals));
+            }
+            '(' => {
+                chars.next();
+                tokens.push((position, Token::Open));
+            }
+            ')' => {
+                chars.next();
+                tokens.push((position, Token::Close));
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => word.push(c),
+                            None => break,
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => {
+                            return Err(DslError::Parse {
+                                position,
+                                message: "unterminated string".to_string(),
+                            })
+                        }
+                    }
+                }
+                tokens.push((position, Token::Quoted(word)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '=' || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((position, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |&(offset, _)| offset)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, DslError> {
+        Err(DslError::Parse {
+            position: self.offset(),
+            message: message.to_string(),
+        })
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Word(word)) => word.eq_ignore_ascii_case(keyword),
+            _ => false,
+        };
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Query, DslError> {
+        let mut query = self.and()?;
+        while self.keyword("OR") {
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, DslError> {
+        let mut query = self.unary()?;
+        while self.keyword("AND") {
+            query = Query::And(Box::new(query), Box::new(self.unary()?));
+        }
+        Ok(query)
+    }
+
+    fn unary(&mut self) -> Result<Query, DslError> {
+        if self.keyword("NOT") {
+            return Ok(Query::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.position += 1;
+            let query = self.or()?;
+            if self.peek() != Some(&Token::Close) {
+                return self.error("expected `)`");
+            }
+            self.position += 1;
+            return Ok(query);
+        }
+        let field = match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            _ => return self.error("expected a field name"),
+        };
+        self.position += 1;
+        if self.peek() != Some(&Token::Equals) {
+            return self.error("expected `=`");
+        }
+        self.position += 1;
+        let value = match self.peek() {
+            Some(Token::Word(word)) | Some(Token::Quoted(word)) => word.clone(),
+            _ => return self.error("expected a value"),
+        };
+        self.position += 1;
+        Ok(Query::Eq { field, value })
+    }
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, DslError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+            end: input.len(),
+        };
+        let query = parser.or()?;
+        if parser.position != parser.tokens.len() {
+            return parser.error("unexpected input");
+        }
+        Ok(query)
+    }
+}
+
+impl FromStr for Query {
+    type Err = DslError;
+
+    fn from_str(input: &str) -> Result<Query, DslError> {
+        Query::parse(input)
+    }
+}
+
+type Resolver<K, V, KS, B> =
+    Box<dyn for<'m> Fn(&'m IndexedMap<K, V, KS, B>, &str) -> Result<Vec<&'m K>, DslError>>;
+
+/// Binds query field names to indices of a map.
+pub struct Fields<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    resolvers: HashMap<String, Resolver<K, V, KS, B>>,
+}
+
+impl<K, V, KS, B> Fields<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn new() -> Fields<K, V, KS, B> {
+        Fields {
+            resolvers: HashMap::new(),
+        }
+    }
+
+    /// Makes `field` refer to `index_id`. Values compared against the field are parsed with
+    /// `A::from_str`.
+    pub fn bind<A>(&mut self, field: &str, index_id: &IndexId<A>) -> &mut Fields<K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone + FromStr,
+    {
+        let index_id = index_id.clone();
+        let name = field.to_string();
+        self.resolvers.insert(
+            field.to_string(),
+            Box::new(move |map, value| {
+                let index_key = A::from_str(value).map_err(|_| DslError::InvalidValue {
+                    field: name.clone(),
+                    value: value.to_string(),
+                })?;
+                Ok(map
+                    .try_get_index(&index_id)?
+                    .get(&index_key)
+                    .map(|keys| keys.iter().map(|k| k.borrow()).collect())
+                    .unwrap_or_default())
+            }),
+        );
+        self
+    }
+
+    fn keys<'m>(
+        &self,
+        map: &'m IndexedMap<K, V, KS, B>,
+        query: &Query,
+    ) -> Result<HashSet<&'m K>, DslError> {
+        match *query {
+            Query::Eq {
+                ref field,
+                ref value,
+            } => match self.resolvers.get(field) {
+                Some(resolver) => Ok(resolver(map, value)?.into_iter().collect()),
+                None => Err(DslError::UnknownField {
+                    field: field.clone(),
+                }),
+            },
+            Query::And(ref left, ref right) => {
+                let left = self.keys(map, left)?;
+                let right = self.keys(map, right)?;
+                let (small, large) = if left.len() <= right.len() {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                Ok(small.into_iter().filter(|k| large.contains(k)).collect())
+            }
+            Query::Or(ref left, ref right) => {
+                let mut keys = self.keys(map, left)?;
+                keys.extend(self.keys(map, right)?);
+                Ok(keys)
+            }
+            Query::Not(ref query) => {
+                let excluded = self.keys(map, query)?;
+                Ok(map
+                    .inner
+                    .iter()
+                    .map(|(k, _)| k.borrow())
+                    .filter(|k| !excluded.contains(k))
+                    .collect())
+            }
+        }
+    }
+
+    /// Runs `query` against `map`, returning the matching entries.
+    pub fn execute<'m>(
+        &self,
+        map: &'m IndexedMap<K, V, KS, B>,
+        query: &Query,
+    ) -> Result<HashMap<&'m K, &'m V>, DslError> {
+        Ok(self
+            .keys(map, query)?
+            .into_iter()
+            .filter_map(|k| map.inner.get(k).map(|v| (k, v)))
+            .collect())
+    }
+}
+
+impl<K, V, KS, B> Default for Fields<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn default() -> Fields<K, V, KS, B> {
+        Fields::new()
+    }
+}
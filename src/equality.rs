@@ -0,0 +1,36 @@
+use std::hash::Hash;
+use std::rc::Rc;
+
+use {Backing, IndexedMap, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Makes inserts that overwrite a value with one `value_eq` considers equal skip index
+    /// maintenance entirely. Such a write only swaps the stored value: it does not count as an
+    /// update in entry metadata and emits no change event. `None` restores the default of
+    /// reindexing on every write.
+    pub fn set_value_equality<F>(&mut self, value_eq: Option<F>)
+    where
+        F: 'static + Fn(&V, &V) -> bool,
+    {
+        self.value_eq = value_eq.map(|f| Rc::new(f) as _);
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone + PartialEq,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Like `set_value_equality` with `PartialEq`.
+    pub fn skip_unchanged_writes(&mut self) {
+        self.set_value_equality(Some(|a: &V, b: &V| a == b));
+    }
+}
@@ -0,0 +1,345 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+/// The error a fallible index function failed with. It keeps the function's own error, which
+/// `downcast_ref` recovers; two failures are equal if their messages are.
+#[derive(Clone)]
+pub struct IndexFailure(Arc<dyn Error + Send + Sync>);
+
+impl IndexFailure {
+    pub(crate) fn new<E>(err: E) -> IndexFailure
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        IndexFailure(Arc::from(err.into()))
+    }
+
+    /// The index function's error, if it is an `E`.
+    pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+        self.0.downcast_ref()
+    }
+
+    pub fn get_ref(&self) -> &(dyn Error + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for IndexFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("IndexFailure").field(&self.0).finish()
+    }
+}
+
+impl fmt::Display for IndexFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl PartialEq for IndexFailure {
+    fn eq(&self, other: &IndexFailure) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl Eq for IndexFailure {}
+
+impl Error for IndexFailure {}
+
+/// The reason an entry could not be inserted. The rejected key and value are always handed back so
+/// callers can retry or route them elsewhere.
+#[derive(Debug, PartialEq)]
+pub enum InsertError<K, V> {
+    /// The map already holds as many entries as its configured capacity limit allows.
+    CapacityExceeded { key: K, value: V, limit: usize },
+    /// Another entry, `existing`, already has one of the entry's values in a unique index.
+    UniqueViolation {
+        key: K,
+        value: V,
+        index: String,
+        existing: K,
+    },
+    /// A fallible index function failed on the entry, with the error in `reason`.
+    IndexFailed {
+        key: K,
+        value: V,
+        index: String,
+        reason: IndexFailure,
+    },
+}
+
+impl<K, V> InsertError<K, V> {
+    /// Recovers the entry that was rejected.
+    pub fn into_entry(self) -> (K, V) {
+        match self {
+            InsertError::CapacityExceeded { key, value, .. }
+            | InsertError::UniqueViolation { key, value, .. }
+            | InsertError::IndexFailed { key, value, .. } => (key, value),
+        }
+    }
+}
+
+impl<K, V> fmt::Display for InsertError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InsertError::CapacityExceeded { limit, .. } => {
+                write!(f, "capacity limit of {} entries exceeded", limit)
+            }
+            InsertError::UniqueViolation { ref index, .. } => {
+                write!(
+                    f,
+                    "value already used by another entry in unique index `{}`",
+                    index
+                )
+            }
+            InsertError::IndexFailed {
+                ref index,
+                ref reason,
+                ..
+            } => write!(f, "index `{}` failed: {}", index, reason),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> Error for InsertError<K, V> {}
+
+/// Why `apply_event` did not apply a change event. The follower is left as it was before the
+/// event.
+#[derive(Debug, PartialEq)]
+pub enum ApplyError<K, V> {
+    /// The event is further ahead than the next one expected, so the events in between were
+    /// missed and the follower has to resynchronize from a full copy of the leader.
+    Gap { expected: u64, found: u64 },
+    /// The follower rejected the event's insert.
+    Rejected(InsertError<K, V>),
+}
+
+impl<K, V> fmt::Display for ApplyError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ApplyError::Gap { expected, found } => write!(
+                f,
+                "expected change event {} but got {}; events were missed",
+                expected, found
+            ),
+            ApplyError::Rejected(ref err) => write!(f, "change event rejected: {}", err),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> Error for ApplyError<K, V> {}
+
+/// Why `try_update` left an entry as it was: either the closure failed, or an index refused the
+/// value it produced. The entry holds its previous value either way.
+#[derive(Debug, PartialEq)]
+pub enum UpdateError<K, V, E> {
+    /// The closure failed with this error.
+    Failed(E),
+    /// A unique or fallible index refused the updated value, which the error hands back.
+    Rejected(InsertError<K, V>),
+}
+
+impl<K, V, E: fmt::Display> fmt::Display for UpdateError<K, V, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpdateError::Failed(ref err) => write!(f, "update failed: {}", err),
+            UpdateError::Rejected(ref err) => write!(f, "update rejected: {}", err),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, E: fmt::Debug + fmt::Display> Error for UpdateError<K, V, E> {}
+
+/// The reason keys could not be renamed. Nothing is renamed when this is returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RekeyError<K> {
+    /// The new key is already used by another entry, or would be given to several entries.
+    Occupied { key: K },
+    /// Under its new key, the entry would share one of its values in a unique index with
+    /// `existing`.
+    UniqueViolation { key: K, index: String, existing: K },
+    /// A fallible index function failed on the entry under its new key, with the error in
+    /// `reason`.
+    IndexFailed {
+        key: K,
+        index: String,
+        reason: IndexFailure,
+    },
+}
+
+impl<K> fmt::Display for RekeyError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RekeyError::Occupied { .. } => write!(f, "new key is already in use"),
+            RekeyError::UniqueViolation { ref index, .. } => {
+                write!(
+                    f,
+                    "value already used by another entry in unique index `{}`",
+                    index
+                )
+            }
+            RekeyError::IndexFailed {
+                ref index,
+                ref reason,
+                ..
+            } => write!(f, "index `{}` failed: {}", index, reason),
+        }
+    }
+}
+
+impl<K: fmt::Debug> Error for RekeyError<K> {}
+
+/// The reason a set of index definitions could not be applied. No index is added when this is
+/// returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DefinitionError {
+    /// The definition names an extractor that was never registered.
+    UnknownExtractor { name: String, extractor: String },
+    /// The extractor's factory rejected the definition's options.
+    InvalidOptions { name: String, reason: String },
+    /// The map already has an index with this name, or several definitions share it.
+    DuplicateName { name: String },
+    /// A unique index was requested, but several entries already share one of its values.
+    NotUnique { name: String },
+    /// A fallible index function failed on an entry already in the map.
+    IndexFailed { name: String, reason: IndexFailure },
+    /// A restored map had an index that was not registered again.
+    Unregistered { name: String },
+}
+
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DefinitionError::UnknownExtractor {
+                ref name,
+                ref extractor,
+            } => write!(f, "index `{}` uses unknown extractor `{}`", name, extractor),
+            DefinitionError::InvalidOptions {
+                ref name,
+                ref reason,
+            } => write!(f, "invalid options for index `{}`: {}", name, reason),
+            DefinitionError::DuplicateName { ref name } => {
+                write!(f, "index `{}` is defined more than once", name)
+            }
+            DefinitionError::NotUnique { ref name } => {
+                write!(
+                    f,
+                    "entries already share a value of unique index `{}`",
+                    name
+                )
+            }
+            DefinitionError::IndexFailed {
+                ref name,
+                ref reason,
+            } => write!(
+                f,
+                "index `{}` failed on an existing entry: {}",
+                name, reason
+            ),
+            DefinitionError::Unregistered { ref name } => {
+                write!(f, "index `{}` was not registered again", name)
+            }
+        }
+    }
+}
+
+impl Error for DefinitionError {}
+
+/// The reason a buffer could not be read as a frozen map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrozenError {
+    /// The buffer is shorter than its header says.
+    Truncated,
+    /// The buffer does not start with the frozen map header.
+    NotFrozen,
+    /// The buffer was written by an incompatible version of this crate.
+    UnsupportedVersion { version: u32 },
+    /// The header describes tables that overlap or are out of order.
+    Corrupt,
+}
+
+impl fmt::Display for FrozenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrozenError::Truncated => write!(f, "frozen map is truncated"),
+            FrozenError::NotFrozen => write!(f, "not a frozen map"),
+            FrozenError::UnsupportedVersion { version } => {
+                write!(f, "unsupported frozen map version {}", version)
+            }
+            FrozenError::Corrupt => write!(f, "frozen map header is corrupt"),
+        }
+    }
+}
+
+impl Error for FrozenError {}
+
+/// The reason an index build did not complete. The map is left without the index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The build was cancelled after indexing `processed` entries.
+    Cancelled { name: String, processed: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Cancelled {
+                ref name,
+                processed,
+            } => write!(
+                f,
+                "build of index `{}` cancelled after {} entries",
+                name, processed
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// The reason an index could not be queried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    /// No index with this name and value type is registered.
+    MissingIndex { name: String },
+    /// The index is registered but currently disabled.
+    Disabled { name: String },
+    /// The index was dropped for being unused and has not been rebuilt since.
+    Cold { name: String },
+    /// The index is lazy and has writes that `refresh_index` has not applied yet.
+    Stale { name: String },
+    /// An index with this name exists, but its values are of type `expected` rather than the
+    /// `found` type the query asked for. Several registered types are separated by `|`.
+    WrongValueType {
+        name: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryError::MissingIndex { ref name } => write!(f, "no index named `{}`", name),
+            QueryError::Disabled { ref name } => write!(f, "index `{}` is disabled", name),
+            QueryError::Cold { ref name } => {
+                write!(f, "index `{}` was dropped while unused", name)
+            }
+            QueryError::Stale { ref name } => {
+                write!(f, "lazy index `{}` has writes not yet applied", name)
+            }
+            QueryError::WrongValueType {
+                ref name,
+                ref expected,
+                ref found,
+            } => write!(
+                f,
+                "index `{}` holds values of type `{}`, not `{}`",
+                name, expected, found
+            ),
+        }
+    }
+}
+
+impl Error for QueryError {}
@@ -0,0 +1,185 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage, PostingObserver};
+
+/// Which end of a bucket's ordering to read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MinMax {
+    Min,
+    Max,
+}
+
+/// Identifies an index that also tracks the smallest and largest entry of each bucket by a
+/// projection of type `O`. Derefs to the plain `IndexId` for every other query.
+pub struct ExtremeIndexId<A, O> {
+    index_id: IndexId<A>,
+    _order: PhantomData<O>,
+}
+
+impl<A, O> Deref for ExtremeIndexId<A, O> {
+    type Target = IndexId<A>;
+
+    fn deref(&self) -> &IndexId<A> {
+        &self.index_id
+    }
+}
+
+impl<A, O> Clone for ExtremeIndexId<A, O> {
+    fn clone(&self) -> ExtremeIndexId<A, O> {
+        ExtremeIndexId {
+            index_id: self.index_id.clone(),
+            _order: PhantomData,
+        }
+    }
+}
+
+type Projection<K, V, O> = Rc<dyn Fn(&K, &V) -> O>;
+
+/// The keys of each bucket ordered by their projection. An entry in several buckets is projected
+/// once and counted per bucket so its projection can be found again on removal.
+struct Extremes<K, V, A, O, KS>
+where
+    KS: KeyStorage<K>,
+{
+    projection: Projection<K, V, O>,
+    by_bucket: HashMap<A, BTreeMap<O, HashSet<KS::Key>>>,
+    projected: HashMap<KS::Key, (O, usize)>,
+}
+
+impl<K, V, A, O, KS> PostingObserver<K, V, A, KS> for Extremes<K, V, A, O, KS>
+where
+    K: 'static + Eq + Hash,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    O: 'static + Ord + Clone,
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, key: &KS::Key, value: &V) {
+        let order = (self.projection)(key.borrow(), value);
+        let projected = self
+            .projected
+            .entry(key.clone())
+            .or_insert_with(|| (order.clone(), 0));
+        projected.0 = order.clone();
+        projected.1 += 1;
+        self.by_bucket
+            .entry(index_key.clone())
+            .or_default()
+            .entry(order)
+            .or_default()
+            .insert(key.clone());
+    }
+
+    fn removed(&mut self, index_key: &A, key: &K) {
+        let order = match self.projected.get_mut(key) {
+            Some(&mut (ref order, ref mut count)) => {
+                *count -= 1;
+                order.clone()
+            }
+            None => return,
+        };
+        if self
+            .projected
+            .get(key)
+            .is_some_and(|&(_, count)| count == 0)
+        {
+            self.projected.remove(key);
+        }
+        let bucket_empty = match self.by_bucket.get_mut(index_key) {
+            Some(bucket) => {
+                let order_empty = bucket.get_mut(&order).is_some_and(|keys| {
+                    keys.remove(key);
+                    keys.is_empty()
+                });
+                if order_empty {
+                    bucket.remove(&order);
+                }
+                bucket.is_empty()
+            }
+            None => false,
+        };
+        if bucket_empty {
+            self.by_bucket.remove(index_key);
+        }
+    }
+
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>> {
+        Some(Box::new(Extremes::<K, V, A, O, KS> {
+            projection: self.projection.clone(),
+            by_bucket: self.by_bucket.clone(),
+            projected: self.projected.clone(),
+        }))
+    }
+
+    fn cleared(&mut self) {
+        self.by_bucket.clear();
+        self.projected.clear();
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index that also keeps the entries of each bucket ordered by `projection`, so the
+    /// smallest and largest entry per index key (e.g. the leader of each group) can be read
+    /// without visiting the bucket.
+    pub fn add_index_with_extremes<A, O, F, P>(
+        &mut self,
+        name: String,
+        index_fn: F,
+        projection: P,
+    ) -> ExtremeIndexId<A, O>
+    where
+        A: 'static + Eq + Hash + Clone,
+        O: 'static + Ord + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+        P: 'static + Fn(&K, &V) -> O,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state
+            .observers
+            .push(Box::new(Extremes::<K, V, A, O, KS> {
+                projection: Rc::new(projection),
+                by_bucket: HashMap::new(),
+                projected: HashMap::new(),
+            }));
+        ExtremeIndexId {
+            index_id: self.register_new_index(name, index_state),
+            _order: PhantomData,
+        }
+    }
+
+    /// Returns the entry with the smallest or largest projection among those indexed under
+    /// `index_key`. Ties are broken arbitrarily.
+    pub fn extreme_by_index<A, Q, O>(
+        &self,
+        index_id: &ExtremeIndexId<A, O>,
+        index_key: &Q,
+        which: MinMax,
+    ) -> Option<(&K, &V)>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        O: 'static + Ord + Clone,
+    {
+        let extremes = self
+            .get_index_state(index_id)?
+            .find_observer::<Extremes<K, V, A, O, KS>>()?;
+        let bucket = extremes.by_bucket.get(index_key)?;
+        let (_, keys) = match which {
+            MinMax::Min => bucket.iter().next(),
+            MinMax::Max => bucket.iter().next_back(),
+        }?;
+        let key: &K = keys.iter().next()?.borrow();
+        self.inner.get(key).map(|value| (key, value))
+    }
+}
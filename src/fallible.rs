@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use {
+    Backing, CheckFn, DefinitionError, IndexFailure, IndexId, IndexState, IndexedMap, KeyStorage,
+};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index whose function may fail, e.g. because it parses a field. `try_insert`
+    /// rejects an entry the function fails on with `InsertError::IndexFailed`, leaving the map
+    /// unchanged, and `insert` panics. The error is kept in the `IndexFailure`, from which
+    /// `downcast_ref` recovers it. The function runs once per write: the values it returns while
+    /// checking the entry are the ones indexed.
+    ///
+    /// Updates are checked like inserts: `try_update` rejects a change the function fails on
+    /// with `UpdateError::Rejected`. Fails if the function fails on an entry already in the map,
+    /// in which case no index is added.
+    pub fn add_fallible_index<A, E, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+    ) -> Result<IndexId<A>, DefinitionError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        E: Into<Box<dyn Error + Send + Sync>>,
+        F: 'static + Fn(&K, &V) -> Result<Vec<A>, E>,
+    {
+        let index_fn = Rc::new(index_fn);
+        let check_fn = index_fn.clone();
+        let mut index_state = IndexState::<K, V, A, KS>::empty(move |key, value| {
+            index_fn(key, value).unwrap_or_default()
+        });
+        let check: CheckFn<K, V, A> =
+            Rc::new(move |key, value| check_fn(key, value).map_err(IndexFailure::new));
+        index_state.check = Some(check);
+        self.build_index_state(&name, &mut index_state)?;
+        // The index may refuse a change in place, which is undone from a copy of the value.
+        self.changes.keep_copies();
+        Ok(self.register_index(name, index_state))
+    }
+}
@@ -0,0 +1,403 @@
+//! A frozen, read-only form of a map laid out in a single byte buffer.
+//!
+//! The layout holds no pointers, only offsets into the buffer itself, so the buffer can be
+//! written to a file in shared memory (e.g. under `/dev/shm`) and mapped by any number of
+//! processes, each querying it in place through `FrozenMap` without a private copy. Mapping the
+//! file is left to the caller; `FrozenMap` only needs the mapped bytes as a slice.
+//!
+//! Keys, values and index values are stored as bytes produced by caller-supplied encoders, and
+//! queries are answered in the same encoding. Encoders must give distinct keys distinct bytes.
+//! Every read is bounds-checked, so a truncated or corrupt buffer yields errors or missing
+//! results, never a panic.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Range;
+
+use {Backing, FrozenError, IndexId, IndexedMap, KeyStorage, QueryError};
+
+const MAGIC: &[u8; 4] = b"IMFZ";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 56;
+const RECORD_LEN: usize = 32;
+const POSTING_LEN: usize = 8;
+
+/// Encoded index values, each with the ordinals of the entries indexed under it.
+type Buckets = Vec<(Vec<u8>, Vec<u64>)>;
+
+/// Collects the entries and chosen indices of a map into the frozen layout. Created by
+/// `IndexedMap::freeze`.
+pub struct FrozenBuilder<'a, K: 'a> {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    ordinals: HashMap<&'a K, u64>,
+    indices: Vec<(String, Buckets)>,
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Starts freezing the current entries, encoding them with `encode_key` and `encode_value`.
+    /// Indices are included one by one with `FrozenBuilder::add_index`.
+    pub fn freeze<EK, EV>(&self, encode_key: EK, encode_value: EV) -> FrozenBuilder<'_, K>
+    where
+        EK: Fn(&K) -> Vec<u8>,
+        EV: Fn(&V) -> Vec<u8>,
+    {
+        let entries = self.inner.iter().map(|(key, value)| (key.borrow(), value));
+        FrozenBuilder::from_entries(entries, encode_key, encode_value)
+    }
+}
+
+impl<'a, K> FrozenBuilder<'a, K>
+where
+    K: 'static + Eq + Hash + Clone,
+{
+    pub(crate) fn from_entries<V, I, EK, EV>(
+        entries: I,
+        encode_key: EK,
+        encode_value: EV,
+    ) -> FrozenBuilder<'a, K>
+    where
+        V: 'a,
+        I: Iterator<Item = (&'a K, &'a V)>,
+        EK: Fn(&K) -> Vec<u8>,
+        EV: Fn(&V) -> Vec<u8>,
+    {
+        let mut entries: Vec<(Vec<u8>, &K, &V)> = entries
+            .map(|(key, value)| (encode_key(key), key, value))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let ordinals = entries
+            .iter()
+            .enumerate()
+            .map(|(ordinal, &(_, key, _))| (key, ordinal as u64))
+            .collect();
+        FrozenBuilder {
+            entries: entries
+                .into_iter()
+                .map(|(key, _, value)| (key, encode_value(value)))
+                .collect(),
+            ordinals,
+            indices: Vec::new(),
+        }
+    }
+
+    /// Includes an index of `map`, the map this builder was created from, encoding its values
+    /// with `encode`. Fails if the index is unavailable.
+    pub fn add_index<V, KS, B, A, H, E>(
+        &mut self,
+        map: &IndexedMap<K, V, KS, B>,
+        index_id: &IndexId<A, H>,
+        encode: E,
+    ) -> Result<(), QueryError>
+    where
+        V: 'static,
+        KS: KeyStorage<K>,
+        B: Backing<K, KS::Key, V>,
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+        E: Fn(&A) -> Vec<u8>,
+    {
+        let index = map.try_get_index(index_id)?;
+        let mut buckets: Buckets = index
+            .iter()
+            .map(|(value, keys)| {
+                let postings = keys
+                    .iter()
+                    .filter_map(|key| self.ordinals.get(key.borrow()).cloned())
+                    .collect();
+                (encode(value), postings)
+            })
+            .collect();
+        buckets.sort_by(|a, b| a.0.cmp(&b.0));
+        buckets.dedup_by(|later, earlier| {
+            later.0 == earlier.0 && {
+                earlier.1.append(&mut later.1);
+                true
+            }
+        });
+        for &mut (_, ref mut postings) in buckets.iter_mut() {
+            postings.sort_unstable();
+            postings.dedup();
+        }
+        self.indices.push((index_id.name().to_string(), buckets));
+        Ok(())
+    }
+
+    /// Lays everything out in one buffer, ready to be written to shared memory.
+    pub fn finish(self) -> Vec<u8> {
+        let bucket_count: usize = self.indices.iter().map(|(_, buckets)| buckets.len()).sum();
+        let entries_at = HEADER_LEN;
+        let indices_at = entries_at + self.entries.len() * RECORD_LEN;
+        let buckets_at = indices_at + self.indices.len() * RECORD_LEN;
+        let postings_at = buckets_at + bucket_count * RECORD_LEN;
+        let posting_count: usize = self
+            .indices
+            .iter()
+            .flat_map(|(_, buckets)| buckets.iter().map(|(_, postings)| postings.len()))
+            .sum();
+        let blob_at = postings_at + posting_count * POSTING_LEN;
+
+        let mut tables = Vec::with_capacity(blob_at);
+        let mut postings = Vec::with_capacity(posting_count * POSTING_LEN);
+        let mut blob = Vec::new();
+        let mut push_blob = |bytes: &[u8]| {
+            let at = blob.len() as u64;
+            blob.extend_from_slice(bytes);
+            (at, bytes.len() as u64)
+        };
+
+        tables.extend_from_slice(MAGIC);
+        tables.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        for &at in &[
+            self.entries.len(),
+            self.indices.len(),
+            indices_at,
+            buckets_at,
+            postings_at,
+        ] {
+            tables.extend_from_slice(&(at as u64).to_le_bytes());
+        }
+        tables.extend_from_slice(&(blob_at as u64).to_le_bytes());
+        for (key, value) in &self.entries {
+            let (key_at, key_len) = push_blob(key);
+            let (value_at, value_len) = push_blob(value);
+            push_record(&mut tables, [key_at, key_len, value_at, value_len]);
+        }
+        let mut first_bucket = 0;
+        for (name, buckets) in &self.indices {
+            let (name_at, name_len) = push_blob(name.as_bytes());
+            push_record(
+                &mut tables,
+                [name_at, name_len, first_bucket, buckets.len() as u64],
+            );
+            first_bucket += buckets.len() as u64;
+        }
+        for (value, keys) in self.indices.iter().flat_map(|(_, buckets)| buckets) {
+            let (value_at, value_len) = push_blob(value);
+            let postings_from = (postings.len() / POSTING_LEN) as u64;
+            for ordinal in keys {
+                postings.extend_from_slice(&ordinal.to_le_bytes());
+            }
+            push_record(
+                &mut tables,
+                [value_at, value_len, postings_from, keys.len() as u64],
+            );
+        }
+        tables.append(&mut postings);
+        tables.append(&mut blob);
+        tables
+    }
+}
+
+fn push_record(out: &mut Vec<u8>, fields: [u64; 4]) {
+    for field in &fields {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+/// A read-only view of a buffer produced by `FrozenBuilder::finish`, queried in place.
+#[derive(Clone, Copy, Debug)]
+pub struct FrozenMap<'a> {
+    bytes: &'a [u8],
+    entry_count: usize,
+    index_count: usize,
+    indices_at: usize,
+    buckets_at: usize,
+    postings_at: usize,
+    blob_at: usize,
+    bucket_count: usize,
+    posting_count: usize,
+}
+
+/// Whether a table of `count` records of `len` bytes starting at `at` ends by `end`.
+fn fits(at: usize, count: usize, len: usize, end: usize) -> bool {
+    count
+        .checked_mul(len)
+        .and_then(|size| at.checked_add(size))
+        .is_some_and(|table_end| table_end <= end)
+}
+
+impl<'a> FrozenMap<'a> {
+    /// Checks the header of `bytes` and wraps it for querying. Every table the header describes
+    /// must fit, in order, in the buffer, so no later read can run past it.
+    pub fn new(bytes: &'a [u8]) -> Result<FrozenMap<'a>, FrozenError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(FrozenError::Truncated);
+        }
+        if &bytes[..4] != MAGIC {
+            return Err(FrozenError::NotFrozen);
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != FORMAT_VERSION {
+            return Err(FrozenError::UnsupportedVersion { version });
+        }
+        let field = |i: usize| read_u64(bytes, 8 + i * 8).ok_or(FrozenError::Truncated);
+        let (entry_count, index_count) = (field(0)?, field(1)?);
+        let (indices_at, buckets_at, postings_at, blob_at) =
+            (field(2)?, field(3)?, field(4)?, field(5)?);
+        if blob_at > bytes.len() {
+            return Err(FrozenError::Truncated);
+        }
+        if !fits(HEADER_LEN, entry_count, RECORD_LEN, indices_at)
+            || !fits(indices_at, index_count, RECORD_LEN, buckets_at)
+            || buckets_at > postings_at
+            || postings_at > blob_at
+        {
+            return Err(FrozenError::Corrupt);
+        }
+        Ok(FrozenMap {
+            bytes,
+            entry_count,
+            index_count,
+            indices_at,
+            buckets_at,
+            postings_at,
+            blob_at,
+            bucket_count: (postings_at - buckets_at) / RECORD_LEN,
+            posting_count: (blob_at - postings_at) / POSTING_LEN,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entry_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry_count == 0
+    }
+
+    /// Returns the encoded value stored under the encoded `key`.
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        let ordinal = self.search(self.entry_count, |i| self.entry(i).map(|(k, _)| k), key)?;
+        self.entry(ordinal).map(|(_, value)| value)
+    }
+
+    /// Iterates the encoded entries in order of their encoded keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        let frozen = *self;
+        (0..self.entry_count).filter_map(move |i| frozen.entry(i))
+    }
+
+    pub fn index_names(&self) -> impl Iterator<Item = &'a str> {
+        let frozen = *self;
+        (0..self.index_count).filter_map(move |i| frozen.index(i).map(|(name, _)| name))
+    }
+
+    /// Returns the encoded entries indexed under the encoded `index_key` by the index `name`, or
+    /// `None` if no frozen index has that name.
+    pub fn filter_by_index(
+        &self,
+        name: &str,
+        index_key: &[u8],
+    ) -> Option<Vec<(&'a [u8], &'a [u8])>> {
+        let buckets = (0..self.index_count)
+            .filter_map(|i| self.index(i))
+            .find(|&(index_name, _)| index_name == name)?
+            .1;
+        let first = buckets.start;
+        let found = self.search(
+            buckets.len(),
+            |i| self.bucket(first + i).map(|(value, _)| value),
+            index_key,
+        );
+        let postings = match found.and_then(|i| self.bucket(first + i)) {
+            Some((_, postings)) => postings,
+            None => return Some(Vec::new()),
+        };
+        Some(
+            postings
+                .filter_map(|i| read_u64(self.bytes, self.postings_at + i * POSTING_LEN))
+                .filter_map(|ordinal| self.entry(ordinal))
+                .collect(),
+        )
+    }
+
+    /// Binary search over `count` records sorted by the bytes `at` returns.
+    fn search<F>(&self, count: usize, at: F, wanted: &[u8]) -> Option<usize>
+    where
+        F: Fn(usize) -> Option<&'a [u8]>,
+    {
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match at(middle)?.cmp(wanted) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    fn record(&self, at: usize) -> Option<[usize; 4]> {
+        Some([
+            read_u64(self.bytes, at)?,
+            read_u64(self.bytes, at + 8)?,
+            read_u64(self.bytes, at + 16)?,
+            read_u64(self.bytes, at + 24)?,
+        ])
+    }
+
+    fn blob(&self, at: usize, len: usize) -> Option<&'a [u8]> {
+        let start = self.blob_at.checked_add(at)?;
+        self.bytes.get(start..start.checked_add(len)?)
+    }
+
+    fn entry(&self, ordinal: usize) -> Option<(&'a [u8], &'a [u8])> {
+        if ordinal >= self.entry_count {
+            return None;
+        }
+        let [key_at, key_len, value_at, value_len] =
+            self.record(HEADER_LEN + ordinal * RECORD_LEN)?;
+        Some((self.blob(key_at, key_len)?, self.blob(value_at, value_len)?))
+    }
+
+    fn index(&self, i: usize) -> Option<(&'a str, Range<usize>)> {
+        if i >= self.index_count {
+            return None;
+        }
+        let [name_at, name_len, first, count] = self.record(self.indices_at + i * RECORD_LEN)?;
+        let name = ::std::str::from_utf8(self.blob(name_at, name_len)?).ok()?;
+        Some((name, table_range(first, count, self.bucket_count)?))
+    }
+
+    fn bucket(&self, i: usize) -> Option<(&'a [u8], Range<usize>)> {
+        if i >= self.bucket_count {
+            return None;
+        }
+        let [value_at, value_len, first, count] = self.record(self.buckets_at + i * RECORD_LEN)?;
+        Some((
+            self.blob(value_at, value_len)?,
+            table_range(first, count, self.posting_count)?,
+        ))
+    }
+}
+
+/// The records `first..first + count` of a table of `len` records, if they are all in it.
+fn table_range(first: usize, count: usize, len: usize) -> Option<Range<usize>> {
+    let end = first.checked_add(count)?;
+    if end <= len {
+        Some(first..end)
+    } else {
+        None
+    }
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<usize> {
+    let field = bytes.get(at..at.checked_add(8)?)?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(field);
+    let value = u64::from_le_bytes(buf);
+    if value > usize::MAX as u64 {
+        None
+    } else {
+        Some(value as usize)
+    }
+}
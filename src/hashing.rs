@@ -0,0 +1,105 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use std::marker::PhantomData;
+
+/// An index value stored with its hash, computed once with `S` when the value is created. Index
+/// buckets keyed by `Prehashed` values built with `PrehashedState` never hash the value again,
+/// which pays off for long strings or other values that are expensive to hash and are rehashed
+/// every time a bucket map grows.
+///
+/// `S` must hash identically in every instance, as `BuildHasherDefault` does, so that values
+/// built for queries match the values built by the index function.
+pub struct Prehashed<A, S = BuildHasherDefault<DefaultHasher>> {
+    hash: u64,
+    value: A,
+    _hasher: PhantomData<S>,
+}
+
+impl<A> Prehashed<A>
+where
+    A: Hash,
+{
+    pub fn new(value: A) -> Prehashed<A> {
+        Prehashed::with_build_hasher(value)
+    }
+}
+
+impl<A, S> Prehashed<A, S>
+where
+    A: Hash,
+    S: BuildHasher + Default,
+{
+    pub fn with_build_hasher(value: A) -> Prehashed<A, S> {
+        Prehashed {
+            hash: S::default().hash_one(&value),
+            value,
+            _hasher: PhantomData,
+        }
+    }
+}
+
+impl<A, S> Prehashed<A, S> {
+    pub fn value(&self) -> &A {
+        &self.value
+    }
+
+    pub fn into_value(self) -> A {
+        self.value
+    }
+}
+
+impl<A: Clone, S> Clone for Prehashed<A, S> {
+    fn clone(&self) -> Prehashed<A, S> {
+        Prehashed {
+            hash: self.hash,
+            value: self.value.clone(),
+            _hasher: PhantomData,
+        }
+    }
+}
+
+impl<A: PartialEq, S> PartialEq for Prehashed<A, S> {
+    fn eq(&self, other: &Prehashed<A, S>) -> bool {
+        self.hash == other.hash && self.value == other.value
+    }
+}
+
+impl<A: Eq, S> Eq for Prehashed<A, S> {}
+
+impl<A, S> Hash for Prehashed<A, S> {
+    fn hash<T: Hasher>(&self, state: &mut T) {
+        state.write_u64(self.hash)
+    }
+}
+
+impl<A: fmt::Debug, S> fmt::Debug for Prehashed<A, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// A hasher that passes through the single `u64` written by `Prehashed`.
+#[derive(Default)]
+pub struct PassThroughHasher {
+    hash: u64,
+}
+
+impl Hasher for PassThroughHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash = self.hash.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.hash = hash;
+    }
+}
+
+/// The bucket hasher for indices over `Prehashed` values.
+pub type PrehashedState = BuildHasherDefault<PassThroughHasher>;
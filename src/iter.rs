@@ -0,0 +1,69 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Like `filter_by_index`, but yields the entries lazily instead of collecting them.
+    pub fn iter_by_index<'m, A, Q, H>(
+        &'m self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<impl Iterator<Item = (&'m K, &'m V)> + 'm>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        let keys = self.get_index(index_id)?.get(index_key)?;
+        Some(self.entries_of(keys.iter()))
+    }
+
+    /// The values of the entries in one bucket, yielded lazily.
+    pub fn values_by_index<'m, A, Q, H>(
+        &'m self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<impl Iterator<Item = &'m V> + 'm>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.iter_by_index(index_id, index_key)
+            .map(|entries| entries.map(|(_, value)| value))
+    }
+
+    /// Every bucket of an index with its entries, both yielded lazily, in no particular order.
+    pub fn iter_index<'m, A, H>(
+        &'m self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<impl Iterator<Item = (&'m A, impl Iterator<Item = (&'m K, &'m V)> + 'm)> + 'm>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        let index = self.get_index(index_id)?;
+        Some(
+            index
+                .iter()
+                .map(move |(index_key, keys)| (index_key, self.entries_of(keys.iter()))),
+        )
+    }
+
+    pub(crate) fn entries_of<'m, I>(&'m self, keys: I) -> impl Iterator<Item = (&'m K, &'m V)> + 'm
+    where
+        I: 'm + Iterator<Item = &'m KS::Key>,
+    {
+        keys.filter_map(move |key| {
+            let key: &K = key.borrow();
+            self.inner.get(key).map(|value| (key, value))
+        })
+    }
+}
@@ -0,0 +1,217 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use downcast_rs::Downcast;
+
+use {Backing, IndexedMap, KeyStorage};
+
+/// Identifies an index computed from keys alone, from `add_key_index`.
+pub struct KeyIndexId<A> {
+    name: String,
+    _value: PhantomData<A>,
+}
+
+impl<A> KeyIndexId<A> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<A> Clone for KeyIndexId<A> {
+    fn clone(&self) -> KeyIndexId<A> {
+        KeyIndexId {
+            name: self.name.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+type KeyIndexFn<K, A> = Rc<dyn Fn(&K) -> Vec<A>>;
+
+trait KeyIndexUpdater<K, KS: KeyStorage<K>>: Downcast {
+    fn insert(&mut self, key: &KS::Key);
+
+    fn remove(&mut self, key: &K);
+
+    fn clear(&mut self);
+
+    fn duplicate(&self) -> Box<dyn KeyIndexUpdater<K, KS>>;
+}
+
+impl_downcast!(KeyIndexUpdater<K, KS> where KS: KeyStorage<K>);
+
+struct KeyIndex<K, A, KS: KeyStorage<K>> {
+    index_fn: KeyIndexFn<K, A>,
+    index: HashMap<A, HashSet<KS::Key>>,
+}
+
+impl<K, A, KS> KeyIndexUpdater<K, KS> for KeyIndex<K, A, KS>
+where
+    K: 'static + Eq + Hash,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+{
+    fn insert(&mut self, key: &KS::Key) {
+        for index_key in (self.index_fn)(key.borrow()) {
+            self.index.entry(index_key).or_default().insert(key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        for index_key in (self.index_fn)(key) {
+            let now_empty = self.index.get_mut(&index_key).is_some_and(|keys| {
+                keys.remove(key);
+                keys.is_empty()
+            });
+            if now_empty {
+                self.index.remove(&index_key);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.index.clear();
+    }
+
+    fn duplicate(&self) -> Box<dyn KeyIndexUpdater<K, KS>> {
+        Box::new(KeyIndex::<K, A, KS> {
+            index_fn: self.index_fn.clone(),
+            index: self.index.clone(),
+        })
+    }
+}
+
+/// The key indices of a map, kept apart from the value indices. Writes that only replace a
+/// value leave them alone, and `update_with_key_indices` lends them out alongside a mutable
+/// value.
+pub struct KeyIndices<K, KS: KeyStorage<K>> {
+    by_name: HashMap<String, Box<dyn KeyIndexUpdater<K, KS>>>,
+}
+
+impl<K, KS> KeyIndices<K, KS>
+where
+    K: 'static + Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    pub(crate) fn new() -> KeyIndices<K, KS> {
+        KeyIndices {
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn duplicate(&self) -> KeyIndices<K, KS> {
+        KeyIndices {
+            by_name: self
+                .by_name
+                .iter()
+                .map(|(name, index)| (name.clone(), index.duplicate()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &KS::Key) {
+        self.by_name
+            .values_mut()
+            .for_each(|index| index.insert(key));
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        self.by_name
+            .values_mut()
+            .for_each(|index| index.remove(key));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.by_name.values_mut().for_each(|index| index.clear());
+    }
+
+    /// The keys that have `index_key` among their values in the key index. Returns `None` if
+    /// there is no such key index of value type `A`.
+    pub fn keys_matching<A, Q>(
+        &self,
+        index_id: &KeyIndexId<A>,
+        index_key: &Q,
+    ) -> Option<impl Iterator<Item = &K>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let index = self
+            .by_name
+            .get(index_id.name())?
+            .downcast_ref::<KeyIndex<K, A, KS>>()?;
+        Some(
+            index
+                .index
+                .get(index_key)
+                .into_iter()
+                .flatten()
+                .map(Borrow::borrow),
+        )
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index computed from keys alone, e.g. a tenant prefix, replacing any key index of
+    /// the same name. Key indices are stored apart from the value indices: they are only
+    /// updated when a key is added or removed, never when its value changes, and are queried
+    /// with `keys_matching` rather than the value index queries.
+    pub fn add_key_index<A, F>(&mut self, name: String, index_fn: F) -> KeyIndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K) -> Vec<A>,
+    {
+        let mut index = KeyIndex::<K, A, KS> {
+            index_fn: Rc::new(index_fn),
+            index: HashMap::new(),
+        };
+        for (key, _) in self.inner.iter() {
+            index.insert(key);
+        }
+        self.key_indices
+            .by_name
+            .insert(name.clone(), Box::new(index));
+        KeyIndexId {
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    /// The keys that have `index_key` among their values in the key index. Returns `None` if
+    /// there is no such key index of value type `A`.
+    pub fn keys_matching<A, Q>(
+        &self,
+        index_id: &KeyIndexId<A>,
+        index_key: &Q,
+    ) -> Option<impl Iterator<Item = &K>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.key_indices.keys_matching(index_id, index_key)
+    }
+
+    /// Like `update`, but `f` can also query the key indices while it holds the value, e.g. to
+    /// look up the other keys of the same tenant. Panics like `update` if an index refuses the
+    /// change.
+    pub fn update_with_key_indices<F, R>(&mut self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V, &KeyIndices<K, KS>) -> R,
+    {
+        let original = self.original(key);
+        let result = f(self.inner.get_mut(key)?, &self.key_indices);
+        if let Err(err) = self.reindex(key, original) {
+            panic!("{}", err);
+        }
+        Some(result)
+    }
+}
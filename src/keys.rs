@@ -0,0 +1,56 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Controls how keys are held by the map and by the postings and reverse maps of every index.
+///
+/// The key type is chosen when the map is constructed. `ClonedKeys` stores a plain clone of the
+/// key in each place it is needed, which is cheapest for small `Copy`-like keys. `SharedKeys`
+/// allocates each key once behind an `Arc` and hands out pointer-sized clones of it, which saves
+/// memory for large keys and maps with many indices at the cost of one allocation per entry and an
+/// indirection on lookup.
+pub trait KeyStorage<K>: 'static {
+    type Key: Eq + Hash + Clone + Borrow<K>;
+
+    fn store(key: K) -> Self::Key;
+
+    fn into_key(key: Self::Key) -> K;
+}
+
+/// Stores an owned clone of the key in the map and in every index.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClonedKeys;
+
+impl<K> KeyStorage<K> for ClonedKeys
+where
+    K: Eq + Hash + Clone,
+{
+    type Key = K;
+
+    fn store(key: K) -> K {
+        key
+    }
+
+    fn into_key(key: K) -> K {
+        key
+    }
+}
+
+/// Stores each key once behind an `Arc` shared by the map and all of its indices.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SharedKeys;
+
+impl<K> KeyStorage<K> for SharedKeys
+where
+    K: 'static + Eq + Hash + Clone,
+{
+    type Key = Arc<K>;
+
+    fn store(key: K) -> Arc<K> {
+        Arc::new(key)
+    }
+
+    fn into_key(key: Arc<K>) -> K {
+        Arc::try_unwrap(key).unwrap_or_else(|key| (*key).clone())
+    }
+}
@@ -0,0 +1,57 @@
+use std::any::TypeId;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+use metadata::metadata_of;
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage, QueryError};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index that writes only mark as dirty, for index functions too expensive to run on
+    /// every write. Nothing is indexed until `refresh_index` runs the function over the entries
+    /// written since the last refresh; until then, queries on the index find nothing and
+    /// `try_get_index` reports it as `QueryError::Stale`. Removals are still applied at once.
+    pub fn add_lazy_index<A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state.dirty = Some(self.inner.iter().map(|(key, _)| key.clone()).collect());
+        self.register_index(name, index_state)
+    }
+
+    /// Brings a lazy index up to date by re-indexing every entry written since the last refresh,
+    /// and returns how many that was. Refreshing an index that is not lazy does nothing.
+    pub fn refresh_index<A, H>(&mut self, index_id: &IndexId<A, H>) -> Result<usize, QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        let (inner, metadata, state) = match self.split_index_state_mut(index_id) {
+            Some(split) => split,
+            None => return Err(self.missing_index(index_id)),
+        };
+        let dirty = match state.dirty {
+            Some(ref mut dirty) => mem::take(dirty),
+            None => return Ok(0),
+        };
+        for key in &dirty {
+            let k: &K = key.borrow();
+            match inner.get(k) {
+                Some(value) => state.replace(key, value, metadata_of::<K, KS>(metadata, k)),
+                None => state.retract(k),
+            }
+        }
+        if !dirty.is_empty() {
+            self.forget_index_misses(&index_id.name, TypeId::of::<A>());
+        }
+        Ok(dirty.len())
+    }
+}
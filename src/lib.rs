@@ -1,58 +1,695 @@
 #[macro_use]
-
 extern crate downcast_rs;
+#[cfg(feature = "derive")]
+extern crate indexed_map_derive;
+#[cfg(feature = "test-utils")]
+extern crate proptest;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(all(test, feature = "derive"))]
+extern crate self as indexed_map;
+#[cfg(feature = "serde")]
+extern crate serde;
 
-use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
+use downcast_rs::Downcast;
+use std::any::{type_name, Any, TypeId};
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::clone::Clone;
 use std::cmp::Eq;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
 use std::marker::PhantomData;
-use std::clone::Clone;
+use std::mem;
 use std::ops::Deref;
-use std::any::TypeId;
-use downcast_rs::Downcast;
+use std::rc::Rc;
+use std::time::Instant;
+
+use bounded::Bounds;
+use capped::BucketLimit;
+use changes::ChangeSinks;
+use metadata::{metadata_of, record_write, MetadataByKey};
+use negative::NegativeCache;
+use statistics::Statistics;
+use usage::Usage;
+use weak::Reaper;
 
-pub struct IndexedMap<K, V>
+mod aggregate;
+mod backing;
+mod batch;
+mod bounded;
+mod budget;
+mod build;
+mod bulk;
+mod capped;
+mod changes;
+mod chunks;
+mod collection;
+#[cfg(feature = "columnar")]
+pub mod columnar;
+mod composite;
+#[cfg(feature = "concurrent")]
+pub mod concurrent;
+mod deterministic;
+#[cfg(feature = "dsl")]
+pub mod dsl;
+mod equality;
+mod error;
+mod extremes;
+mod fallible;
+mod frozen;
+mod hashing;
+mod iter;
+mod key_index;
+mod keys;
+mod lazy;
+mod loader;
+mod map_like;
+mod merge;
+mod metadata;
+mod negative;
+mod normalize;
+mod ordered;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "serde")]
+pub mod persist;
+mod pins;
+mod prefix;
+mod query;
+mod queue;
+mod registry;
+mod rekey;
+mod replica;
+mod sample;
+mod shared;
+mod snapshot;
+mod sorted;
+mod spec;
+mod statistics;
+mod subscribe;
+#[cfg(feature = "table")]
+pub mod table;
+mod tenancy;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+mod text;
+mod tombstones;
+mod ttl;
+mod txn;
+mod typed;
+mod unique;
+mod update;
+mod usage;
+mod view;
+#[cfg(feature = "wal")]
+mod wal;
+mod warm_start;
+mod weak;
+
+pub use backing::Backing;
+pub use batch::{MultiQuery, MultiQueryResult, QueryHandle};
+pub use bounded::CapacityPolicy;
+pub use budget::{Budget, Continuation, Partial};
+pub use build::{BuildProgress, CancellationToken, IndexBuild};
+pub use capped::{BucketCap, BucketEviction};
+pub use changes::{ChangeBatch, ChangeEvent, SequencedEvent};
+pub use chunks::Chunks;
+pub use collection::{IntoIter, Iter};
+pub use composite::CompositeIndexId;
+pub use deterministic::{DeterministicMap, DeterministicState};
+pub use error::{
+    ApplyError, BuildError, DefinitionError, FrozenError, IndexFailure, InsertError, QueryError,
+    RekeyError, UpdateError,
+};
+pub use extremes::{ExtremeIndexId, MinMax};
+pub use frozen::{FrozenBuilder, FrozenMap};
+pub use hashing::{PassThroughHasher, Prehashed, PrehashedState};
+#[cfg(feature = "derive")]
+pub use indexed_map_derive::Indexed;
+pub use key_index::{KeyIndexId, KeyIndices};
+pub use keys::{ClonedKeys, KeyStorage, SharedKeys};
+pub use loader::{LoadProgress, Loader};
+pub use map_like::MapLike;
+pub use merge::{HybridClock, HybridTimestamp, Lww, Merge};
+pub use metadata::EntryMetadata;
+pub use normalize::{NormalizedIndexId, Normalizer};
+pub use ordered::{InsertionOrder, InsertionOrderIter, OrderedIndexedMap};
+pub use prefix::HasPrefix;
+pub use query::{Bucket, KeyFilter, QueryBuilder};
+pub use queue::PriorityIndexId;
+pub use registry::{ExtractorRegistry, IndexDefinition, IndexOptions};
+pub use replica::{ReplicaFeed, StandbyReplica};
+pub use shared::{SharedSnapshot, SharedSnapshotBuilder};
+pub use snapshot::{MapSnapshot, SnapshotDiff};
+pub use sorted::SortedIndexId;
+pub use spec::{IndexSpec, SpecIndexId};
+pub use statistics::IndexStatistics;
+pub use subscribe::{IndexEvent, MapEvent};
+pub use tenancy::TenantScopedIndexedMap;
+pub use text::{TextIndexId, TextMatch, Tokenizer};
+pub use tombstones::WithDeleted;
+pub use txn::Txn;
+pub use typed::{AnyValue, TypedMap};
+pub use unique::{UniqueEntry, UniqueIndexId};
+pub use update::{Entry, IndexDiff, IndexValueDiff, IterMutReindex, ValueMut};
+pub use usage::IndexUsage;
+pub use view::{IndexView, UniqueIndexView};
+#[cfg(feature = "wal")]
+pub use wal::{DurableMap, WalCodec, WriteError};
+pub use warm_start::{IndexSnapshot, Validation};
+
+pub struct IndexedMap<K, V, KS = ClonedKeys, B = Entries<K, V, KS>>
 where
     K: Eq + Hash,
+    KS: KeyStorage<K>,
 {
-    inner: HashMap<K, V>,
-    indices: HashMap<String, HashMap<TypeId, Box<IndexUpdater<K, V>>>>,
+    inner: B,
+    indices: HashMap<String, IndicesByType<K, V, KS>>,
+    capacity_limit: Option<usize>,
+    metadata: Option<MetadataByKey<K, KS>>,
+    version: u64,
+    changes: ChangeSinks<K, V>,
+    tombstones: Entries<K, V, KS>,
+    value_eq: Option<ValueEq<V>>,
+    negative: Option<NegativeCache<K>>,
+    pinned: HashSet<KS::Key>,
+    expirations: HashMap<KS::Key, Instant>,
+    bounds: Option<Bounds<K, V, KS>>,
+    key_indices: KeyIndices<K, KS>,
+    reaper: Option<Reaper<V>>,
 }
 
-pub struct IndexId<A> {
+type ValueEq<V> = Rc<dyn Fn(&V, &V) -> bool>;
+
+type Entries<K, V, KS> = HashMap<<KS as KeyStorage<K>>::Key, V>;
+
+type EntriesAndState<'a, K, V, A, KS, B, H> = (
+    &'a B,
+    &'a Option<MetadataByKey<K, KS>>,
+    &'a mut IndexState<K, V, A, KS, H>,
+);
+
+/// The buckets of an index: the keys under each index value. The buckets hash keys with the
+/// index's hasher too, so an index with a fixed-key hasher iterates reproducibly throughout.
+pub type Buckets<K, A, H = RandomState> = HashMap<A, HashSet<K, H>, H>;
+
+type IndicesByType<K, V, KS> = HashMap<TypeId, Box<dyn IndexUpdater<K, V, KS>>>;
+
+/// Identifies an index whose values are of type `A`, hashed into buckets with `H`.
+pub struct IndexId<A, H = RandomState> {
     name: String,
-    _value: PhantomData<A>,
+    _value: PhantomData<(A, H)>,
+}
+
+impl<A, H> IndexId<A, H> {
+    /// Refers to an index by name without registering it. Maps hand out ids from `add_index`;
+    /// this is for `MapLike` implementations that resolve indices on their own.
+    pub fn new(name: String) -> IndexId<A, H> {
+        IndexId {
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<A, H> Clone for IndexId<A, H> {
+    fn clone(&self) -> IndexId<A, H> {
+        IndexId {
+            name: self.name.clone(),
+            _value: PhantomData,
+        }
+    }
 }
 
 impl<K, V> IndexedMap<K, V>
 where
     K: 'static + Eq + Hash + Clone,
-    V: 'static + Clone,
+    V: 'static,
 {
     pub fn new() -> IndexedMap<K, V> {
+        IndexedMap::with_key_storage(ClonedKeys)
+    }
+
+    /// Creates a map that refuses to grow beyond `limit` entries. Inserting a new key into a full
+    /// map fails with `InsertError::CapacityExceeded` instead of evicting anything, so write to
+    /// it with `try_insert` rather than `insert`.
+    pub fn with_capacity_limit(limit: usize) -> IndexedMap<K, V> {
+        let mut map = IndexedMap::new();
+        map.capacity_limit = Some(limit);
+        map
+    }
+}
+
+impl<K, V, KS> IndexedMap<K, V, KS>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+{
+    /// Creates a map whose indices hold keys according to `KS`, e.g.
+    /// `IndexedMap::with_key_storage(SharedKeys)` to share one allocation per key between the map
+    /// and all of its indices.
+    pub fn with_key_storage(key_storage: KS) -> IndexedMap<K, V, KS> {
+        IndexedMap::with_backing(key_storage, HashMap::new())
+    }
+}
+
+impl<K, V, S> IndexedMap<K, V, ClonedKeys, HashMap<K, V, S>>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    S: 'static + BuildHasher + Default,
+{
+    /// Creates a map whose entries are hashed with `hasher`, e.g. a faster non-cryptographic
+    /// hasher for small trusted keys. Indices choose their own hasher for index values through
+    /// `add_index_with_hasher`.
+    pub fn with_hasher(hasher: S) -> IndexedMap<K, V, ClonedKeys, HashMap<K, V, S>> {
+        IndexedMap::with_backing(ClonedKeys, HashMap::with_hasher(hasher))
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Creates a map storing its entries in `backing`, e.g.
+    /// `IndexedMap::with_backing(ClonedKeys, BTreeMap::new())` to keep entries ordered by key and
+    /// support `range` queries over primary keys. Entries already in `backing` are kept and will
+    /// be covered by any index added later.
+    pub fn with_backing(_key_storage: KS, backing: B) -> IndexedMap<K, V, KS, B> {
         IndexedMap {
-            inner: HashMap::new(),
+            inner: backing,
             indices: HashMap::new(),
+            capacity_limit: None,
+            metadata: None,
+            version: 0,
+            changes: ChangeSinks::new(),
+            tombstones: HashMap::new(),
+            value_eq: None,
+            negative: None,
+            pinned: HashSet::new(),
+            expirations: HashMap::new(),
+            bounds: None,
+            key_indices: KeyIndices::new(),
+            reaper: None,
         }
     }
 
+    pub fn capacity_limit(&self) -> Option<usize> {
+        self.capacity_limit
+    }
+
+    /// Changes the entry limit. Entries already stored above a lowered limit are kept, but no new
+    /// keys are admitted until the map shrinks below it.
+    pub fn set_capacity_limit(&mut self, limit: Option<usize>) {
+        self.capacity_limit = limit;
+    }
+
+    /// Inserts an entry, returning the value it replaced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map has a capacity limit and is full, a fallible index function fails on
+    /// the entry or a unique index already gives one of its values to another key. `insert`
+    /// cannot fail on a map without a capacity limit, unique indices or fallible indices; maps
+    /// with any of those should be written with `try_insert`.
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.try_insert(key, value) {
+            Ok(previous) => previous,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Inserts an entry unless doing so would exceed the capacity limit, fail a fallible index
+    /// function or give a unique index value to a second key, in which case the rejected key and
+    /// value are handed back in the error. Replacing the value of an existing key never exceeds
+    /// the capacity limit.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, InsertError<K, V>> {
+        if let Some(rejection) = self.admission(&key, &value) {
+            self.discard_checked();
+            return Err(rejection.into_error(key, value));
+        }
+        let previous = self.insert_unchecked(key, value);
+        self.discard_checked();
+        self.enforce_bounds();
+        Ok(previous)
+    }
+
+    /// Why `try_insert` would refuse the entry, if it would.
+    fn admission(&self, key: &K, value: &V) -> Option<Rejection<K>> {
+        if let Some(limit) = self.capacity_limit {
+            if self.inner.len() >= limit && !self.inner.contains_key(key) {
+                return Some(Rejection::Capacity { limit });
+            }
+        }
+        self.rejection(key, value, |_| true).map(Rejection::Index)
+    }
+
+    /// The first index among those whose names pass `affected` that refuses `value` under
+    /// `key`: a fallible index function failing on it, or a unique index already giving one of
+    /// its values to another key.
+    fn rejection<F>(&self, key: &K, value: &V, affected: F) -> Option<IndexRejection<K>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let failure = self
+            .indices
+            .iter()
+            .filter(|&(name, _)| affected(name))
+            .find_map(|(name, by_type)| {
+                by_type
+                    .values()
+                    .find_map(|updater| updater.check(key, value).err())
+                    .map(|reason| IndexRejection::Failed {
+                        index: name.clone(),
+                        reason,
+                    })
+            });
+        if failure.is_some() {
+            return failure;
+        }
+        let metadata = metadata_of::<K, KS>(&self.metadata, key);
+        self.indices
+            .iter()
+            .filter(|&(name, _)| affected(name))
+            .find_map(|(name, by_type)| {
+                by_type
+                    .values()
+                    .find_map(|updater| updater.conflict(key, value, metadata))
+                    .map(|existing| IndexRejection::Conflict {
+                        index: name.clone(),
+                        existing: existing.clone(),
+                    })
+            })
+    }
+
+    /// Forgets the index values fallible and unique indices computed while checking a write,
+    /// once the write is indexed or refused.
+    fn discard_checked(&self) {
+        self.indices
+            .values()
+            .flat_map(|by_type| by_type.values())
+            .for_each(|updater| updater.discard_checked());
+    }
+
+    /// Whether any index has to approve writes, because it is unique or fallible.
+    fn validating(&self) -> bool {
+        self.indices
+            .values()
+            .flat_map(|by_type| by_type.values())
+            .any(|updater| updater.validates())
+    }
+
+    /// A copy of the value under `key` before it is changed in place, if listeners will need it
+    /// as the old value or an index may refuse the change and have it put back.
+    fn original(&self, key: &K) -> Option<V> {
+        let value = self.inner.get(key)?;
+        if self.validating() {
+            self.changes.copy(value)
+        } else {
+            self.changes.previous(value)
+        }
+    }
+
+    /// Removes an entry and all of its index postings, returning its value.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.remove_unchecked(key).map(|(_, value)| value)
+    }
+
+    /// Like `remove`, but also hands back the stored key.
+    pub fn remove_entry(&mut self, key: &K) -> Option<(K, V)> {
+        self.remove_unchecked(key)
+    }
+
+    /// Checks many keys in one call, one flag per key in the order given, e.g. to validate a batch
+    /// of foreign-key references.
+    pub fn contains_keys<'k, I>(&self, keys: I) -> Vec<bool>
+    where
+        I: IntoIterator<Item = &'k K>,
+    {
+        let keys = keys.into_iter();
+        let mut present = Vec::with_capacity(keys.size_hint().0);
+        present.extend(keys.map(|key| self.inner.contains_key(key)));
+        present
+    }
+
+    fn insert_unchecked(&mut self, key: K, value: V) -> Option<V> {
+        if self.unchanged(&key, &value) {
+            return self.overwrite_unchanged(&key, value);
+        }
+        let (key, replacing) = self.record_insert(key);
+        let metadata = metadata_of::<K, KS>(&self.metadata, key.borrow());
         self.indices
             .values_mut()
             .flat_map(|x| x.values_mut())
-            .for_each(|updater| updater.insert(&key, &value));
+            .for_each(|updater| {
+                if replacing {
+                    updater.replace(&key, &value, metadata)
+                } else {
+                    updater.insert(&key, &value, metadata)
+                }
+            });
+        self.store_entry(key, value)
+    }
+
+    /// Whether `value` equals the value stored under `key` by the map's value equality, so
+    /// writing it needs no reindexing.
+    fn unchanged(&self, key: &K, value: &V) -> bool {
+        match (self.value_eq.as_ref(), self.inner.get(key)) {
+            (Some(value_eq), Some(previous)) => value_eq(previous, value),
+            _ => false,
+        }
+    }
+
+    /// Records the write of `key` in its metadata before it is indexed, so indices see the
+    /// metadata of the write. Returns the key to store and whether it replaces an existing entry.
+    fn record_insert(&mut self, key: K) -> (KS::Key, bool) {
+        let (key, replacing) = match self.inner.get_key_value(&key) {
+            Some((stored, _)) => (stored.clone(), true),
+            None => (KS::store(key), false),
+        };
+        if let Some(ref mut by_key) = self.metadata {
+            record_write::<K, KS>(by_key, &mut self.version, &key);
+        }
+        (key, replacing)
+    }
+
+    /// Stores an entry already indexed under `key`, with the bookkeeping every write shares: the
+    /// write is recorded and announced, a soft-removed entry under the key is discarded, the
+    /// bounds note the entry's recency and weight, key indices learn a new key and cached misses
+    /// for it are forgotten.
+    fn store_entry(&mut self, key: KS::Key, value: V) -> Option<V> {
+        let k: &K = key.borrow();
+        self.changes.record_insert(k, &value);
+        self.changes.notify_write(k, self.inner.get(k), &value);
+        if !self.tombstones.is_empty() {
+            self.tombstones.remove(k);
+        }
+        if let Some(ref mut bounds) = self.bounds {
+            bounds.record(&key, &value);
+        }
+        if !self.inner.contains_key(k) {
+            self.key_indices.insert(&key);
+        }
+        if self.negative.is_some() {
+            self.forget_misses(k);
+        }
         self.inner.insert(key, value)
     }
 
+    /// Overwrites the value under `key` with one `unchanged` found equal, which only needs the
+    /// bounds to note that the entry was used.
+    fn overwrite_unchanged(&mut self, key: &K, value: V) -> Option<V> {
+        if let (Some(bounds), Some((stored, _))) =
+            (self.bounds.as_mut(), self.inner.get_key_value(key))
+        {
+            bounds.record(stored, &value);
+        }
+        self.inner
+            .get_mut(key)
+            .map(|previous| mem::replace(previous, value))
+    }
+
+    /// Takes the entry under `key` out of the backing map, recording and announcing its removal.
+    /// The indices and the rest of the entry's bookkeeping are left to the caller.
+    fn take_entry(&mut self, key: &K) -> Option<(KS::Key, V)> {
+        self.changes.record_remove(key);
+        if let Some(value) = self.inner.get(key) {
+            self.changes.notify_remove(key, value);
+        }
+        self.inner.remove_entry(key)
+    }
+
+    fn remove_unchecked(&mut self, key: &K) -> Option<(K, V)> {
+        if !self.inner.contains_key(key) {
+            return None;
+        }
+        self.indices
+            .values_mut()
+            .flat_map(|x| x.values_mut())
+            .for_each(|updater| updater.remove(key));
+        if let Some(ref mut by_key) = self.metadata {
+            by_key.remove(key);
+        }
+        if !self.pinned.is_empty() {
+            self.pinned.remove(key);
+        }
+        if !self.expirations.is_empty() {
+            self.expirations.remove(key);
+        }
+        if let Some(ref mut bounds) = self.bounds {
+            bounds.forget(key);
+        }
+        self.key_indices.remove(key);
+        self.take_entry(key)
+            .map(|(key, value)| (KS::into_key(key), value))
+    }
+
+    /// Recomputes every index for an entry whose value was changed in place. `original` is the
+    /// value before the change, from `original`. If a unique or fallible index refuses the new
+    /// value, the original is put back, leaving the entry and its indices as they were, and the
+    /// new value is handed back in the error.
+    fn reindex(&mut self, key: &K, original: Option<V>) -> Result<(), InsertError<K, V>> {
+        self.reindex_where(key, original, |_| true)
+    }
+
+    /// Like `reindex`, but only checks and recomputes the indices whose names pass `affected`.
+    fn reindex_where<F>(
+        &mut self,
+        key: &K,
+        original: Option<V>,
+        affected: F,
+    ) -> Result<(), InsertError<K, V>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.validating() {
+            let rejection = self
+                .inner
+                .get(key)
+                .and_then(|value| self.rejection(key, value, &affected));
+            if let (Some(rejection), Some(value)) = (rejection, self.inner.get_mut(key)) {
+                let original = original.expect("original value is kept while indices validate");
+                let rejected = mem::replace(value, original);
+                self.discard_checked();
+                return Err(rejection.into_error(key.clone(), rejected));
+            }
+        }
+        self.reindex_unchecked(key, original, affected);
+        self.discard_checked();
+        Ok(())
+    }
+
+    /// Like `reindex_where`, but indexes the new value without asking any index to approve it.
+    fn reindex_unchecked<F>(&mut self, key: &K, previous: Option<V>, affected: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        if let Some((stored, value)) = self.inner.get_key_value(key) {
+            if let Some(ref mut by_key) = self.metadata {
+                record_write::<K, KS>(by_key, &mut self.version, stored);
+            }
+            self.changes.record_insert(key, value);
+            if let Some(old) = previous {
+                self.changes.notify_update(key, old, value);
+            }
+            let metadata = metadata_of::<K, KS>(&self.metadata, key);
+            self.indices
+                .iter_mut()
+                .filter(|&(name, _)| affected(name))
+                .flat_map(|(_, x)| x.values_mut())
+                .for_each(|updater| {
+                    updater.remove(key);
+                    updater.insert(stored, value, metadata);
+                });
+            if let Some(ref mut bounds) = self.bounds {
+                bounds.record(stored, value);
+            }
+        }
+        if self.negative.is_some() {
+            self.forget_misses(key);
+        }
+        self.enforce_bounds();
+    }
+
+    /// Shrinks the inner map and every index to the minimal capacity needed for the current
+    /// entries, releasing memory left behind by growth and churn.
+    pub fn compact(&mut self) {
+        self.inner.shrink_to_fit();
+        self.indices.shrink_to_fit();
+        self.indices.values_mut().for_each(|by_type| {
+            by_type.shrink_to_fit();
+            by_type.values_mut().for_each(|updater| updater.compact());
+        });
+    }
+
     pub fn add_index<A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
     where
         A: 'static + Eq + Hash + Clone,
         F: 'static + Fn(&K, &V) -> Vec<A>,
     {
-        let mut index_state = IndexState::<K, V, A>::empty(index_fn);
-        for (key, value) in &self.inner {
-            index_state.insert(key, value)
-        }
+        let index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        self.register_new_index(name, index_state)
+    }
+
+    /// Adds an index whose buckets hash index values with `hasher` instead of the default
+    /// DoS-resistant SipHash, e.g. a faster hasher for small integer values. Every query method
+    /// that is generic over the id's hasher accepts the returned id.
+    pub fn add_index_with_hasher<A, H, F>(
+        &mut self,
+        name: String,
+        hasher: H,
+        index_fn: F,
+    ) -> IndexId<A, H>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let index_state = IndexState::<K, V, A, KS, H>::with_hasher(
+            Rc::new(move |key, value, _| index_fn(key, value)),
+            hasher,
+        );
+        self.register_new_index(name, index_state)
+    }
+
+    /// Adds an index for an expensive index function that is only re-run for an entry when the
+    /// `fingerprint` of its value changes. Re-inserting a value with the same fingerprint keeps
+    /// the index values computed last time.
+    pub fn add_memoized_index<A, P, F>(
+        &mut self,
+        name: String,
+        fingerprint: P,
+        index_fn: F,
+    ) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        P: 'static + Fn(&K, &V) -> u64,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state.fingerprint = Some(Rc::new(fingerprint));
+        self.register_new_index(name, index_state)
+    }
+
+    fn register_index<A, H>(
+        &mut self,
+        name: String,
+        index_state: IndexState<K, V, A, KS, H>,
+    ) -> IndexId<A, H>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
         self.indices
             .entry(name.clone())
             .or_insert(HashMap::with_capacity(1))
@@ -63,125 +700,872 @@ where
         }
     }
 
-    fn get_index_state<A>(&self, index_id: &IndexId<A>) -> Option<&IndexState<K, V, A>>
+    /// Indexes the entries already in the map with an index about to be registered. An index
+    /// with a check indexes the values the check returns and fails on the first entry it
+    /// rejects, and a unique index fails if two entries share a value. An index that writes do
+    /// not maintain is left empty, to be built when it is enabled.
+    fn build_index_state<A, H>(
+        &self,
+        name: &str,
+        index_state: &mut IndexState<K, V, A, KS, H>,
+    ) -> Result<(), DefinitionError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        if !index_state.maintained() {
+            return Ok(());
+        }
+        match index_state.check.clone() {
+            Some(check) => {
+                for (key, value) in self.inner.iter() {
+                    let values = check(key.borrow(), value).map_err(|reason| {
+                        DefinitionError::IndexFailed {
+                            name: name.to_string(),
+                            reason,
+                        }
+                    })?;
+                    index_state.insert_values(key, value, values);
+                }
+            }
+            None => self.backfill(index_state),
+        }
+        if index_state.unique && index_state.index.values().any(|keys| keys.len() > 1) {
+            return Err(DefinitionError::NotUnique {
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Indexes the entries already in the map with an index that has no check, which is all it
+    /// takes to build an index that is not unique either.
+    fn backfill<A, H>(&self, index_state: &mut IndexState<K, V, A, KS, H>)
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        if !index_state.maintained() {
+            return;
+        }
+        for (key, value) in self.inner.iter() {
+            let metadata = metadata_of::<K, KS>(&self.metadata, key.borrow());
+            index_state.insert(key, value, metadata);
+        }
+    }
+
+    /// Builds and registers an index that has no check and is not unique, and so cannot fail.
+    fn register_new_index<A, H>(
+        &mut self,
+        name: String,
+        mut index_state: IndexState<K, V, A, KS, H>,
+    ) -> IndexId<A, H>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.backfill(&mut index_state);
+        self.register_index(name, index_state)
+    }
+
+    fn get_index_state<A, H>(&self, index_id: &IndexId<A, H>) -> Option<&IndexState<K, V, A, KS, H>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.find_index_state(index_id).filter(|x| x.is_current())
+    }
+
+    fn find_index_state<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<&IndexState<K, V, A, KS, H>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.index_state_named(&index_id.name)
+    }
+
+    fn index_state_named<A, H>(&self, name: &str) -> Option<&IndexState<K, V, A, KS, H>>
     where
         A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
     {
         self.indices
-            .get(&index_id.name)
+            .get(name)
             .and_then(|x| x.get(&TypeId::of::<A>()))
-            .and_then(|x| x.downcast_ref::<IndexState<K, V, A>>())
+            .and_then(|x| x.downcast_ref::<IndexState<K, V, A, KS, H>>())
+            .inspect(|state| state.usage.touch())
     }
 
-    pub fn get_index<A>(&self, index_id: &IndexId<A>) -> Option<&HashMap<A, HashSet<K>>>
+    /// Borrows the entries alongside an index's state so the state can be rebuilt from them. The
+    /// state is returned whether or not the index is enabled.
+    fn split_index_state_mut<A, H>(
+        &mut self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<EntriesAndState<'_, K, V, A, KS, B, H>>
     where
         A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
     {
-        self.get_index_state(index_id).map(|x| &x.index)
+        let inner = &self.inner;
+        let metadata = &self.metadata;
+        self.indices
+            .get_mut(&index_id.name)
+            .and_then(|x| x.get_mut(&TypeId::of::<A>()))
+            .and_then(|x| x.downcast_mut::<IndexState<K, V, A, KS, H>>())
+            .map(|state| (inner, metadata, state))
     }
 
-    pub fn filter_by_index<A>(
-        &self,
-        index_id: &IndexId<A>,
-        index_key: &A,
-    ) -> Option<HashMap<&K, &V>>
+    /// Returns the index, or `None` if it does not exist, is disabled or is a lazy index with
+    /// writes not yet applied.
+    ///
+    /// Asking for an index under the name of one with a different value type is almost always a
+    /// bug, so debug builds panic instead of returning `None`; `try_get_index` reports it as
+    /// `QueryError::WrongValueType`.
+    pub fn get_index<A, H>(&self, index_id: &IndexId<A, H>) -> Option<&Buckets<KS::Key, A, H>>
     where
         A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
     {
-        self.get_index(index_id)
-            .and_then(|x| x.get(&index_key))
-            .map(|keys| {
-                keys.iter()
-                    .flat_map(|k| self.inner.get(&k).map(|v| (k, v)).into_iter())
-                    .collect()
-            })
+        let state = self.find_index_state(index_id);
+        if cfg!(debug_assertions) && state.is_none() {
+            if let err @ QueryError::WrongValueType { .. } = self.missing_index(index_id) {
+                panic!("{}", err);
+            }
+        }
+        state.filter(|x| x.is_current()).map(|x| &x.index)
     }
 
-    pub fn keys_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<&HashSet<K>>
+    /// Like `get_index`, but explains why the index is unavailable.
+    pub fn try_get_index<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+    ) -> Result<&Buckets<KS::Key, A, H>, QueryError>
     where
         A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
     {
-        self.get_index(index_id).and_then(|x| x.get(&index_key))
+        match self.find_index_state(index_id) {
+            Some(state) if state.is_current() => Ok(&state.index),
+            Some(state) if !state.enabled => Err(QueryError::Disabled {
+                name: index_id.name.clone(),
+            }),
+            Some(state) if state.usage.cold => Err(QueryError::Cold {
+                name: index_id.name.clone(),
+            }),
+            Some(_) => Err(QueryError::Stale {
+                name: index_id.name.clone(),
+            }),
+            None => Err(self.missing_index(index_id)),
+        }
     }
-}
 
-impl<K, V> Deref for IndexedMap<K, V>
-where
-    K: Eq + Hash,
-{
-    type Target = HashMap<K, V>;
+    /// Explains why no index state matches `index_id`: either nothing is registered under its
+    /// name, or only indices of other value types are.
+    fn missing_index<A, H>(&self, index_id: &IndexId<A, H>) -> QueryError
+    where
+        A: 'static,
+    {
+        match self.indices.get(&index_id.name) {
+            Some(by_type) if !by_type.is_empty() => {
+                let mut expected: Vec<&str> = by_type
+                    .values()
+                    .map(|updater| updater.value_type())
+                    .collect();
+                expected.sort_unstable();
+                QueryError::WrongValueType {
+                    name: index_id.name.clone(),
+                    expected: expected.join(" | "),
+                    found: type_name::<A>().to_string(),
+                }
+            }
+            _ => QueryError::MissingIndex {
+                name: index_id.name.clone(),
+            },
+        }
+    }
 
-    fn deref(&self) -> &HashMap<K, V> {
-        &self.inner
+    /// Enables or disables maintenance of an index. A disabled index keeps its definition but
+    /// drops its contents and is skipped on every write; queries against it fail with
+    /// `QueryError::Disabled`. Re-enabling rebuilds it from the current entries, as does enabling
+    /// an index that went cold.
+    pub fn set_index_enabled<A, H>(
+        &mut self,
+        index_id: &IndexId<A, H>,
+        enabled: bool,
+    ) -> Result<(), QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        let (inner, metadata, state) = match self.split_index_state_mut(index_id) {
+            Some(split) => split,
+            None => return Err(self.missing_index(index_id)),
+        };
+        let maintained = state.maintained();
+        state.usage.cold = false;
+        state.enabled = enabled;
+        if maintained != enabled {
+            state.clear();
+            if enabled {
+                for (key, value) in inner.iter() {
+                    state.insert(key, value, metadata_of::<K, KS>(metadata, key.borrow()));
+                }
+            }
+        }
+        if enabled {
+            self.forget_index_misses(&index_id.name, TypeId::of::<A>());
+        }
+        Ok(())
     }
-}
 
-struct IndexState<K, V, A> {
-    index_fn: Box<Fn(&K, &V) -> Vec<A>>,
-    index: HashMap<A, HashSet<K>>,
-    indexed: HashMap<K, HashSet<A>>,
-}
+    pub fn is_index_enabled<A, H>(&self, index_id: &IndexId<A, H>) -> bool
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index_state(index_id).is_some()
+    }
 
-impl<K, V, A> IndexState<K, V, A>
-where
-    K: Eq + Hash + Clone,
-    A: Eq + Hash + Clone,
-{
-    fn empty<F>(index_fn: F) -> IndexState<K, V, A>
+    /// Drops an index and everything it holds, so writes stop maintaining it. Queries through
+    /// clones of `index_id` fail as for any missing index. Returns whether the index existed.
+    pub fn remove_index<A, H>(&mut self, index_id: IndexId<A, H>) -> bool
     where
-        F: 'static + Fn(&K, &V) -> Vec<A>,
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
     {
-        IndexState::new(index_fn, HashMap::new(), HashMap::new())
+        let type_id = TypeId::of::<A>();
+        let removed = self
+            .indices
+            .get_mut(&index_id.name)
+            .is_some_and(|by_type| by_type.remove(&type_id).is_some());
+        if self
+            .indices
+            .get(&index_id.name)
+            .is_some_and(HashMap::is_empty)
+        {
+            self.indices.remove(&index_id.name);
+        }
+        self.forget_index_misses(&index_id.name, type_id);
+        removed
     }
 
-    fn new<F>(
-        index_fn: F,
-        index: HashMap<A, HashSet<K>>,
-        indexed: HashMap<K, HashSet<A>>,
-    ) -> IndexState<K, V, A>
+    /// Recomputes an index from the current entries, e.g. to repair it after its index function
+    /// changed behavior or entries were mutated behind its back. Fails if the index is missing or
+    /// disabled. A stale lazy index is brought up to date too, as if by `refresh_index`.
+    pub fn rebuild_index<A, H>(&mut self, index_id: &IndexId<A, H>) -> Result<(), QueryError>
     where
-        F: 'static + Fn(&K, &V) -> Vec<A>,
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
     {
-        IndexState {
-            index_fn: Box::new(index_fn),
-            index,
-            indexed,
+        match self.try_get_index(index_id) {
+            Ok(_) | Err(QueryError::Stale { .. }) => {}
+            Err(err) => return Err(err),
         }
+        self.forget_index_misses(&index_id.name, TypeId::of::<A>());
+        if let Some((inner, metadata, state)) = self.split_index_state_mut(index_id) {
+            state.clear();
+            for (key, value) in inner.iter() {
+                state.insert(key, value, metadata_of::<K, KS>(metadata, key.borrow()));
+            }
+        }
+        Ok(())
     }
 
-    fn insert(&mut self, key: &K, value: &V) {
-        let mut indexed_values: HashSet<A> = HashSet::new();
-        (self.index_fn)(key, value).into_iter().for_each(|a| {
-            self.index
-                .entry(a.clone())
-                .or_insert(HashSet::new())
-                .insert(key.clone());
-            indexed_values.insert(a);
-        });
-        self.indexed.insert(key.clone(), indexed_values);
+    /// The entries indexed under `index_key`, which may be any borrowed form of the index value
+    /// type, e.g. a `&str` for an index of `String`s. Returns `None` if the index is unavailable
+    /// or has no such bucket.
+    pub fn filter_by_index<A, Q, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index(index_id)
+            .and_then(|x| x.get(index_key))
+            .map(|keys| {
+                keys.iter()
+                    .flat_map(|k| {
+                        let k: &K = k.borrow();
+                        self.inner.get(k).map(|v| (k, v)).into_iter()
+                    })
+                    .collect()
+            })
     }
-}
 
-trait IndexUpdater<K, V>: Downcast {
-    fn insert(&mut self, key: &K, value: &V);
+    pub fn keys_by_index<A, Q, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<&HashSet<KS::Key, H>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index(index_id).and_then(|x| x.get(index_key))
+    }
 }
 
-impl_downcast!(IndexUpdater<K, V>);
-
-impl<K, V, A> IndexUpdater<K, V> for IndexState<K, V, A>
+impl<K, V, KS, B> Default for IndexedMap<K, V, KS, B>
 where
     K: 'static + Eq + Hash + Clone,
     V: 'static,
-    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K> + Default,
+    B: Backing<K, KS::Key, V>,
 {
-    fn insert(&mut self, key: &K, value: &V) {
-        IndexState::insert(self, key, value)
+    fn default() -> IndexedMap<K, V, KS, B> {
+        IndexedMap::with_backing(KS::default(), B::default())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<K, V, KS, B> Deref for IndexedMap<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+type IndexFn<K, V, A> = Rc<dyn Fn(&K, &V, Option<&EntryMetadata>) -> Vec<A>>;
+
+type FingerprintFn<K, V> = Rc<dyn Fn(&K, &V) -> u64>;
+
+type CheckFn<K, V, A> = Rc<dyn Fn(&K, &V) -> Result<Vec<A>, IndexFailure>>;
+
+/// Why the map or one of its indices refused a write, before the rejected key and value are
+/// attached to make an `InsertError`.
+enum Rejection<K> {
+    Capacity { limit: usize },
+    Index(IndexRejection<K>),
+}
+
+impl<K> Rejection<K> {
+    fn into_error<V>(self, key: K, value: V) -> InsertError<K, V> {
+        match self {
+            Rejection::Capacity { limit } => InsertError::CapacityExceeded { key, value, limit },
+            Rejection::Index(rejection) => rejection.into_error(key, value),
+        }
+    }
+}
+
+/// Why a unique or fallible index refused a write.
+enum IndexRejection<K> {
+    Failed { index: String, reason: IndexFailure },
+    Conflict { index: String, existing: K },
+}
+
+impl<K> IndexRejection<K> {
+    fn into_error<V>(self, key: K, value: V) -> InsertError<K, V> {
+        match self {
+            IndexRejection::Failed { index, reason } => InsertError::IndexFailed {
+                key,
+                value,
+                index,
+                reason,
+            },
+            IndexRejection::Conflict { index, existing } => InsertError::UniqueViolation {
+                key,
+                value,
+                index,
+                existing,
+            },
+        }
+    }
+
+    fn into_rekey_error(self, key: K) -> RekeyError<K> {
+        match self {
+            IndexRejection::Failed { index, reason } => {
+                RekeyError::IndexFailed { key, index, reason }
+            }
+            IndexRejection::Conflict { index, existing } => RekeyError::UniqueViolation {
+                key,
+                index,
+                existing,
+            },
+        }
+    }
+}
+
+struct IndexState<K, V, A, KS, H = RandomState>
+where
+    KS: KeyStorage<K>,
+{
+    index_fn: IndexFn<K, V, A>,
+    hasher: H,
+    index: Buckets<KS::Key, A, H>,
+    indexed: HashMap<KS::Key, HashSet<A, H>, H>,
+    unverified: HashSet<KS::Key>,
+    fingerprint: Option<FingerprintFn<K, V>>,
+    fingerprints: HashMap<KS::Key, u64>,
+    enabled: bool,
+    observers: Vec<Box<dyn PostingObserver<K, V, A, KS>>>,
+    bucket_limit: Option<BucketLimit<K, V, A, KS>>,
+    usage: Usage,
+    unique: bool,
+    statistics: Statistics<A>,
+    check: Option<CheckFn<K, V, A>>,
+    /// The values `check` or `conflict` computed for the write being made, so it is indexed
+    /// without running the index function again.
+    checked: RefCell<Option<Vec<A>>>,
+    /// Set for lazy indices: the keys written since the index was last refreshed.
+    dirty: Option<HashSet<KS::Key>>,
+}
+
+impl<K, V, A, KS> IndexState<K, V, A, KS>
+where
+    K: 'static + Eq + Hash,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+{
+    fn empty<F>(index_fn: F) -> IndexState<K, V, A, KS>
+    where
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        IndexState::with_metadata_fn(Rc::new(move |key, value, _| index_fn(key, value)))
+    }
+
+    fn with_metadata_fn(index_fn: IndexFn<K, V, A>) -> IndexState<K, V, A, KS> {
+        IndexState::with_hasher(index_fn, RandomState::new())
+    }
+}
+
+impl<K, V, A, KS, H> IndexState<K, V, A, KS, H>
+where
+    K: 'static + Eq + Hash,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+    H: 'static + BuildHasher + Clone,
+{
+    fn with_hasher(index_fn: IndexFn<K, V, A>, hasher: H) -> IndexState<K, V, A, KS, H> {
+        IndexState {
+            index_fn,
+            index: HashMap::with_hasher(hasher.clone()),
+            indexed: HashMap::with_hasher(hasher.clone()),
+            hasher,
+            unverified: HashSet::new(),
+            fingerprint: None,
+            fingerprints: HashMap::new(),
+            enabled: true,
+            observers: Vec::new(),
+            bucket_limit: None,
+            usage: Usage::new(),
+            unique: false,
+            statistics: Statistics::new(),
+            check: None,
+            checked: RefCell::new(None),
+            dirty: None,
+        }
+    }
+
+    fn insert(&mut self, key: &KS::Key, value: &V, metadata: Option<&EntryMetadata>) {
+        if !self.unverified.is_empty() {
+            self.unverified.remove::<KS::Key>(key);
+        }
+        if let Some(ref fingerprint) = self.fingerprint {
+            let fingerprint = fingerprint(key.borrow(), value);
+            if self.fingerprints.insert(key.clone(), fingerprint) == Some(fingerprint)
+                && self.indexed.contains_key::<KS::Key>(key)
+            {
+                return;
+            }
+        }
+        let values = match self.checked.get_mut().take() {
+            Some(values) => values,
+            None => (self.index_fn)(key.borrow(), value, metadata),
+        };
+        self.insert_values(key, value, values);
+    }
+
+    fn insert_values<I>(&mut self, key: &KS::Key, value: &V, values: I)
+    where
+        I: IntoIterator<Item = A>,
+    {
+        let mut indexed_values: HashSet<A, H> = HashSet::with_hasher(self.hasher.clone());
+        values.into_iter().for_each(|a| {
+            let hasher = &self.hasher;
+            let bucket = self
+                .index
+                .entry(a.clone())
+                .or_insert_with(|| HashSet::with_hasher(hasher.clone()));
+            if bucket.insert(key.clone()) {
+                self.statistics.added(&a, bucket.len());
+            }
+            self.observers
+                .iter_mut()
+                .for_each(|observer| observer.added(&a, key, value));
+            let victim = self.bucket_limit.as_mut().and_then(|limit| {
+                limit.added(&a, key, value);
+                limit.victim(&a)
+            });
+            match victim {
+                Some(ref victim) if victim == key => self.drop_posting(&a, key.borrow()),
+                Some(victim) => {
+                    self.drop_posting(&a, victim.borrow());
+                    if let Some(values) = self.indexed.get_mut::<K>(victim.borrow()) {
+                        values.remove(&a);
+                    }
+                    indexed_values.insert(a);
+                }
+                None => {
+                    indexed_values.insert(a);
+                }
+            }
+        });
+        self.indexed.insert(key.clone(), indexed_values);
+    }
+
+    /// Takes one posting out of a bucket, leaving the entry's other postings alone.
+    fn drop_posting(&mut self, a: &A, key: &K) {
+        self.observers
+            .iter_mut()
+            .for_each(|observer| observer.removed(a, key));
+        let statistics = &mut self.statistics;
+        let now_empty = self.index.get_mut(a).is_some_and(|keys| {
+            if keys.remove(key) {
+                statistics.removed(a, keys.len());
+            }
+            keys.is_empty()
+        });
+        if now_empty {
+            self.index.remove(a);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if !self.unverified.is_empty() {
+            self.unverified.remove(key);
+        }
+        if self.fingerprint.is_some() {
+            self.fingerprints.remove(key);
+        }
+        self.retract(key);
+    }
+
+    /// Re-runs the index function for an entry whose value was overwritten, first dropping the
+    /// postings of the old value. Skipped if the fingerprint shows the value did not change.
+    fn replace(&mut self, key: &KS::Key, value: &V, metadata: Option<&EntryMetadata>) {
+        let unchanged = self.fingerprint.as_ref().is_some_and(|fingerprint| {
+            self.fingerprints.get::<KS::Key>(key) == Some(&fingerprint(key.borrow(), value))
+        });
+        if !unchanged {
+            self.retract(key.borrow());
+        }
+        self.insert(key, value, metadata);
+    }
+
+    /// Whether writes keep the index up to date: it is enabled and not cold.
+    fn maintained(&self) -> bool {
+        self.enabled && !self.usage.cold
+    }
+
+    /// Whether queries can use the index: it is maintained and, if lazy, has no pending writes.
+    fn is_current(&self) -> bool {
+        self.maintained() && self.dirty.as_ref().is_none_or(HashSet::is_empty)
+    }
+
+    /// Drops every posting of `key`, leaving its fingerprint and verification state alone.
+    fn retract(&mut self, key: &K) {
+        if let Some(indexed_values) = self.indexed.remove(key) {
+            for a in indexed_values {
+                if let Some(ref mut limit) = self.bucket_limit {
+                    limit.removed(&a, key);
+                }
+                self.drop_posting(&a, key);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.index.clear();
+        self.indexed.clear();
+        self.unverified.clear();
+        self.fingerprints.clear();
+        if let Some(ref mut dirty) = self.dirty {
+            dirty.clear();
+        }
+        self.observers
+            .iter_mut()
+            .for_each(|observer| observer.cleared());
+        if let Some(ref mut limit) = self.bucket_limit {
+            limit.cleared();
+        }
+        self.statistics.cleared();
+    }
+
+    fn find_observer<T>(&self) -> Option<&T>
+    where
+        T: PostingObserver<K, V, A, KS>,
+    {
+        self.observers
+            .iter()
+            .filter_map(|observer| observer.downcast_ref::<T>())
+            .next()
+    }
+
+    fn compact(&mut self) {
+        self.index.shrink_to_fit();
+        self.index.values_mut().for_each(HashSet::shrink_to_fit);
+        self.indexed.shrink_to_fit();
+        self.indexed.values_mut().for_each(HashSet::shrink_to_fit);
+        self.unverified.shrink_to_fit();
+        self.fingerprints.shrink_to_fit();
+    }
+}
+
+trait IndexUpdater<K, V, KS>: Downcast
+where
+    KS: KeyStorage<K>,
+{
+    fn insert(&mut self, key: &KS::Key, value: &V, metadata: Option<&EntryMetadata>);
+
+    /// Indexes the new value of a key that is already indexed, retracting the old value's
+    /// postings.
+    fn replace(&mut self, key: &KS::Key, value: &V, metadata: Option<&EntryMetadata>);
+
+    fn remove(&mut self, key: &K);
+
+    fn compact(&mut self);
+
+    /// Drops every posting, keeping the index definition.
+    fn clear(&mut self);
+
+    fn value_type(&self) -> &'static str;
+
+    /// The index values currently recorded for `key`, as a boxed `Vec<A>`.
+    fn indexed_values(&self, key: &K) -> Box<dyn Any>;
+
+    fn usage(&self) -> &Usage;
+
+    /// Whether the index is enabled, whether or not it is cold.
+    fn is_enabled(&self) -> bool;
+
+    /// Drops the index's contents and marks it cold, or marks it warm again so the caller can
+    /// rebuild it. Leaves the index enabled or disabled as it was.
+    fn set_cold(&mut self, cold: bool);
+
+    /// If the index is unique, another key already holding one of the index values of `value`.
+    /// The values it computes are kept for indexing the entry, until `discard_checked`.
+    fn conflict(&self, key: &K, value: &V, metadata: Option<&EntryMetadata>) -> Option<&K>;
+
+    /// Whether a fallible index function can index the entry, or why not. The values it computes
+    /// are kept for indexing the entry, until `discard_checked`.
+    fn check(&self, key: &K, value: &V) -> Result<(), IndexFailure>;
+
+    fn discard_checked(&self);
+
+    /// Whether indexing `value` afresh would give other values than the ones recorded for
+    /// `key`, so that debug builds can catch a `patch` leaving out an index it affects. Indices
+    /// that are not current, or do not record every value they compute because their buckets
+    /// are capped, never report it.
+    fn outdated(&self, key: &K, value: &V, metadata: Option<&EntryMetadata>) -> bool;
+
+    /// Whether `try_insert` has to consult the index before each insert, because it is unique or
+    /// fallible.
+    fn validates(&self) -> bool;
+
+    /// Makes room for `additional` more indexed entries.
+    fn reserve(&mut self, additional: usize);
+
+    /// A copy of the index, postings included, for a clone of the map.
+    fn duplicate(&self) -> Box<dyn IndexUpdater<K, V, KS>>;
+}
+
+impl_downcast!(IndexUpdater<K, V, KS> where KS: KeyStorage<K>);
+
+/// Extra per-bucket bookkeeping kept by an index, told about every posting as it is added to or
+/// removed from a bucket.
+trait PostingObserver<K, V, A, KS>: Downcast
+where
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, key: &KS::Key, value: &V);
+
+    fn removed(&mut self, index_key: &A, key: &K);
+
+    fn cleared(&mut self);
+
+    /// A copy of the observer for a clone of the map, or `None` to leave it out of the clone.
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>>;
+}
+
+impl_downcast!(PostingObserver<K, V, A, KS> where KS: KeyStorage<K>);
+
+impl<K, V, A, KS, H> IndexUpdater<K, V, KS> for IndexState<K, V, A, KS, H>
+where
+    K: 'static + Eq + Hash,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+    H: 'static + BuildHasher + Clone,
+{
+    fn insert(&mut self, key: &KS::Key, value: &V, metadata: Option<&EntryMetadata>) {
+        if !self.maintained() {
+            return;
+        }
+        match self.dirty {
+            Some(ref mut dirty) => {
+                dirty.insert(key.clone());
+            }
+            None => IndexState::insert(self, key, value, metadata),
+        }
+    }
+
+    fn replace(&mut self, key: &KS::Key, value: &V, metadata: Option<&EntryMetadata>) {
+        if !self.maintained() {
+            return;
+        }
+        match self.dirty {
+            Some(ref mut dirty) => {
+                dirty.insert(key.clone());
+            }
+            None => IndexState::replace(self, key, value, metadata),
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if self.maintained() {
+            if let Some(ref mut dirty) = self.dirty {
+                dirty.remove(key);
+            }
+            IndexState::remove(self, key)
+        }
+    }
+
+    fn compact(&mut self) {
+        IndexState::compact(self)
+    }
+
+    fn clear(&mut self) {
+        IndexState::clear(self)
+    }
+
+    fn value_type(&self) -> &'static str {
+        type_name::<A>()
+    }
+
+    fn indexed_values(&self, key: &K) -> Box<dyn Any> {
+        let values: Vec<A> = self
+            .indexed
+            .get(key)
+            .map(|values| values.iter().cloned().collect())
+            .unwrap_or_default();
+        Box::new(values)
+    }
+
+    fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn set_cold(&mut self, cold: bool) {
+        if cold {
+            self.clear();
+        }
+        self.usage.cold = cold;
+    }
+
+    fn validates(&self) -> bool {
+        self.maintained() && (self.unique || self.check.is_some())
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        if self.maintained() {
+            self.indexed.reserve(additional);
+        }
+    }
+
+    fn duplicate(&self) -> Box<dyn IndexUpdater<K, V, KS>> {
+        Box::new(IndexState::<K, V, A, KS, H> {
+            index_fn: self.index_fn.clone(),
+            hasher: self.hasher.clone(),
+            index: self.index.clone(),
+            indexed: self.indexed.clone(),
+            unverified: self.unverified.clone(),
+            fingerprint: self.fingerprint.clone(),
+            fingerprints: self.fingerprints.clone(),
+            enabled: self.enabled,
+            observers: self
+                .observers
+                .iter()
+                .filter_map(|observer| observer.duplicate())
+                .collect(),
+            bucket_limit: self.bucket_limit.as_ref().map(BucketLimit::duplicate),
+            usage: self.usage.clone(),
+            unique: self.unique,
+            statistics: self.statistics.clone(),
+            check: self.check.clone(),
+            checked: RefCell::new(None),
+            dirty: self.dirty.clone(),
+        })
+    }
+
+    fn check(&self, key: &K, value: &V) -> Result<(), IndexFailure> {
+        if let Some(ref check) = self.check {
+            if self.maintained() {
+                self.checked.replace(Some(check(key, value)?));
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_checked(&self) {
+        if self.check.is_some() || self.unique {
+            self.checked.replace(None);
+        }
+    }
+
+    fn outdated(&self, key: &K, value: &V, metadata: Option<&EntryMetadata>) -> bool {
+        if !self.is_current() || self.bucket_limit.is_some() {
+            return false;
+        }
+        let fresh: HashSet<A> = (self.index_fn)(key, value, metadata).into_iter().collect();
+        match self.indexed.get(key) {
+            Some(recorded) => {
+                recorded.len() != fresh.len() || fresh.iter().any(|a| !recorded.contains(a))
+            }
+            None => !fresh.is_empty(),
+        }
+    }
+
+    fn conflict(&self, key: &K, value: &V, metadata: Option<&EntryMetadata>) -> Option<&K> {
+        if !self.unique || !self.maintained() {
+            return None;
+        }
+        let values = self
+            .checked
+            .borrow_mut()
+            .take()
+            .unwrap_or_else(|| (self.index_fn)(key, value, metadata));
+        let existing = values
+            .iter()
+            .filter_map(|index_key| self.index.get(index_key))
+            .flatten()
+            .map(|k| k.borrow())
+            .find(|k| *k != key);
+        self.checked.replace(Some(values));
+        existing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn should_do_stuff() {
@@ -195,4 +1579,3231 @@ mod tests {
         println!("{:?}", index);
         println!("{:?}", filtered);
     }
+
+    #[test]
+    fn should_compact_without_losing_entries() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let index_id = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        for i in 0..1000 {
+            m.insert(i, i.to_string());
+        }
+        m.compact();
+        assert_eq!(m.len(), 1000);
+        assert!(m.capacity() >= 1000);
+        assert_eq!(m.keys_by_index(&index_id, &3).map(|x| x.len()), Some(900));
+    }
+
+    #[test]
+    fn should_reject_inserts_over_capacity_limit() {
+        let mut m = IndexedMap::<&str, &str>::with_capacity_limit(2);
+        let index_id = m.add_index("length".to_string(), |_, &v| vec![v.len()]);
+        assert_eq!(m.try_insert("a", "one").unwrap(), None);
+        assert_eq!(m.try_insert("b", "two").unwrap(), None);
+        match m.try_insert("c", "six") {
+            Err(InsertError::CapacityExceeded { key, limit, .. }) => {
+                assert_eq!(key, "c");
+                assert_eq!(limit, 2);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(m.try_insert("a", "uno").unwrap(), Some("one"));
+        assert_eq!(m.len(), 2);
+        assert!(!m.keys_by_index(&index_id, &3).unwrap().contains("c"));
+    }
+
+    #[test]
+    fn should_skip_and_purge_dead_weak_values() {
+        use std::sync::Arc;
+
+        let alive = Arc::new("alive".to_string());
+        let dead = Arc::new("dying".to_string());
+        let mut m = IndexedMap::new();
+        let index_id = m.add_weak_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        m.insert(1, Arc::downgrade(&alive));
+        m.insert(2, Arc::downgrade(&dead));
+        drop(dead);
+
+        let live = m.live_by_index(&index_id, &5);
+        assert_eq!(live.len(), 1);
+        assert_eq!(*live[0].0, 1);
+        assert_eq!(m.purge_dead(), 1);
+        assert_eq!(m.len(), 1);
+        assert_eq!(m.keys_by_index(&index_id, &5).map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn should_share_keys_between_map_and_indices() {
+        use std::sync::Arc;
+
+        let mut m = IndexedMap::with_key_storage(SharedKeys);
+        let length = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        let first = m.add_index("first".to_string(), |_, v: &String| {
+            v.chars().take(1).collect()
+        });
+        let key = "key".to_string();
+        m.insert(key.clone(), "value".to_string());
+
+        let (stored, _) = m.get_key_value(&key).unwrap();
+        assert_eq!(Arc::strong_count(stored), 5);
+        assert!(m.keys_by_index(&length, &5).unwrap().contains(&key));
+        assert_eq!(m.filter_by_index(&first, &'v').map(|x| x.len()), Some(1));
+        assert_eq!(m.remove_unchecked(&key), Some((key, "value".to_string())));
+    }
+
+    #[test]
+    fn should_skip_disabled_indices_and_rebuild_on_enable() {
+        let mut m = IndexedMap::<&str, &str>::new();
+        let index_id = m.add_index("length".to_string(), |_, &v| vec![v.len()]);
+        m.insert("a", "one");
+        m.set_index_enabled(&index_id, false).unwrap();
+        m.insert("b", "two");
+        assert!(!m.is_index_enabled(&index_id));
+        assert_eq!(m.get_index(&index_id), None);
+        assert_eq!(
+            m.try_get_index(&index_id),
+            Err(QueryError::Disabled {
+                name: "length".to_string()
+            })
+        );
+
+        m.set_index_enabled(&index_id, true).unwrap();
+        assert_eq!(m.keys_by_index(&index_id, &3).map(|x| x.len()), Some(2));
+    }
+
+    #[test]
+    fn should_warm_start_index_from_snapshot() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let index_id = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        m.insert(1, "one".to_string());
+        m.insert(2, "three".to_string());
+        let mut snapshot = m.export_index(&index_id).unwrap();
+        assert_eq!(snapshot.values.get(&2), Some(&vec![5]));
+
+        let mut restarted = IndexedMap::<u32, String>::new();
+        restarted.insert(1, "one".to_string());
+        restarted.insert(2, "three".to_string());
+        restarted.insert(3, "seven".to_string());
+        snapshot.values.insert(2, vec![42]);
+        let index_id = restarted.add_index_from_snapshot(
+            "length".to_string(),
+            |_, v: &String| vec![v.len()],
+            snapshot,
+        );
+        assert_eq!(
+            restarted.keys_by_index(&index_id, &42).map(|x| x.len()),
+            Some(1)
+        );
+        assert_eq!(
+            restarted.keys_by_index(&index_id, &5).map(|x| x.len()),
+            Some(1)
+        );
+
+        let validation = restarted.validate_index(&index_id, 10).unwrap();
+        assert_eq!(validation.checked, 2);
+        assert_eq!(validation.repaired, 1);
+        assert_eq!(validation.remaining, 0);
+        assert_eq!(restarted.keys_by_index(&index_id, &42), None);
+        assert_eq!(
+            restarted.keys_by_index(&index_id, &5).map(|x| x.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn should_load_entries_in_chunks() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let index_id = m.add_index("parity".to_string(), |_, &v| vec![v % 2]);
+        let mut reported = Vec::new();
+        {
+            let mut loader = m
+                .loader()
+                .expect(6)
+                .on_progress(|progress| reported.push(progress.loaded));
+            for chunk in [[0, 1], [2, 3], [4, 5]].iter() {
+                loader.load_chunk(chunk.iter().map(|&i| (i, i))).unwrap();
+            }
+            assert_eq!(
+                loader.finish(),
+                LoadProgress {
+                    loaded: 6,
+                    total: Some(6)
+                }
+            );
+        }
+        assert_eq!(reported, vec![2, 4, 6]);
+        assert_eq!(m.keys_by_index(&index_id, &0).map(|x| x.len()), Some(3));
+    }
+
+    #[test]
+    fn should_iterate_in_chunks() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let index_id = m.add_index("parity".to_string(), |_, &v| vec![v % 2]);
+        for i in 0..7 {
+            m.insert(i, i);
+        }
+        let sizes: Vec<usize> = m.iter_chunks(3).map(|chunk| chunk.len()).collect();
+        assert_eq!(sizes, vec![3, 3, 1]);
+
+        let mut seen = Vec::new();
+        m.for_each_chunk_by_index(&index_id, &0, 3, |chunk| {
+            seen.extend(chunk.iter().map(|&(&k, _)| k))
+        });
+        seen.sort();
+        assert_eq!(seen, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn should_roll_back_failed_updates() {
+        let mut m = IndexedMap::<&str, String>::new();
+        let index_id = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        m.insert("a", "one".to_string());
+        m.insert("b", "three".to_string());
+
+        let failed: Result<Option<()>, _> = m.try_update(&"a", |v| {
+            v.push_str("teen");
+            Err("nope")
+        });
+        assert_eq!(failed, Err(UpdateError::Failed("nope")));
+        assert_eq!(m.get("a").map(String::as_str), Some("one"));
+        assert_eq!(m.keys_by_index(&index_id, &3).map(|x| x.len()), Some(1));
+
+        let updated: Result<_, UpdateError<_, _, ()>> = m.try_update(&"a", |v| {
+            v.push_str("teen");
+            Ok(v.len())
+        });
+        assert_eq!(updated, Ok(Some(7)));
+        assert_eq!(m.keys_by_index(&index_id, &3), None);
+        assert_eq!(m.keys_by_index(&index_id, &7).map(|x| x.len()), Some(1));
+
+        let retained: Result<(), String> = m.try_retain(|_, v| {
+            if v.len() > 5 {
+                Ok(false)
+            } else {
+                v.push('!');
+                Ok(true)
+            }
+        });
+        assert_eq!(retained, Ok(()));
+        assert_eq!(m.len(), 1);
+        assert_eq!(m.keys_by_index(&index_id, &7), None);
+        assert_eq!(m.keys_by_index(&index_id, &6).map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn should_find_duplicate_index_keys() {
+        let mut m = IndexedMap::<u32, &str>::new();
+        let index_id = m.add_index("email".to_string(), |_, &v| vec![v]);
+        m.insert(1, "a@example.com");
+        m.insert(2, "b@example.com");
+        m.insert(3, "a@example.com");
+
+        let duplicates = m.duplicates_by(&index_id);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(*duplicates[0].0, "a@example.com");
+        let mut keys: Vec<u32> = duplicates[0].1.iter().map(|&(&k, _)| k).collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 3]);
+    }
+
+    #[test]
+    fn should_rank_entries_by_tag_similarity() {
+        let mut m = IndexedMap::<u32, Vec<&str>>::new();
+        let tags = m.add_index("tags".to_string(), |_, v: &Vec<&str>| v.clone());
+        m.insert(1, vec!["rust", "db", "index"]);
+        m.insert(2, vec!["rust", "db"]);
+        m.insert(3, vec!["rust", "web", "async", "http"]);
+        m.insert(4, vec!["cooking"]);
+
+        let similar = m.similar_to(&tags, &1, 0.1);
+        let keys: Vec<u32> = similar.iter().map(|&(&k, _, _)| k).collect();
+        assert_eq!(keys, vec![2, 3]);
+        assert!((similar[0].2 - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(m.similar_to(&tags, &1, 0.5).len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "dsl")]
+    fn should_parse_and_run_queries() {
+        use dsl::{DslError, Fields, Query};
+
+        let query = Query::parse("status = active AND (team = core OR team = \"infra ops\")");
+        assert_eq!(
+            query,
+            Ok(Query::And(
+                Box::new(Query::Eq {
+                    field: "status".to_string(),
+                    value: "active".to_string()
+                }),
+                Box::new(Query::Or(
+                    Box::new(Query::Eq {
+                        field: "team".to_string(),
+                        value: "core".to_string()
+                    }),
+                    Box::new(Query::Eq {
+                        field: "team".to_string(),
+                        value: "infra ops".to_string()
+                    })
+                ))
+            ))
+        );
+
+        let mut m = IndexedMap::<u32, (&str, &str, u32)>::new();
+        let status = m.add_index("status".to_string(), |_, v| vec![v.0.to_string()]);
+        let team = m.add_index("team".to_string(), |_, v| vec![v.1.to_string()]);
+        let age = m.add_index("age".to_string(), |_, v| vec![v.2]);
+        m.insert(1, ("active", "core", 30));
+        m.insert(2, ("active", "web", 40));
+        m.insert(3, ("inactive", "core", 30));
+        m.insert(4, ("active", "infra ops", 30));
+
+        let mut fields = Fields::new();
+        fields
+            .bind("status", &status)
+            .bind("team", &team)
+            .bind("age", &age);
+        let mut keys: Vec<u32> = fields
+            .execute(&m, &query.unwrap())
+            .unwrap()
+            .keys()
+            .map(|&&k| k)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 4]);
+
+        let query = Query::parse("age = 30 and not team = core").unwrap();
+        let matched = fields.execute(&m, &query).unwrap();
+        assert_eq!(matched.keys().collect::<Vec<_>>(), vec![&&4]);
+
+        assert_eq!(
+            fields.execute(&m, &Query::parse("age = old").unwrap()),
+            Err(DslError::InvalidValue {
+                field: "age".to_string(),
+                value: "old".to_string()
+            })
+        );
+        match Query::parse("status = active AND (team = core") {
+            Err(DslError::Parse { position, .. }) => assert_eq!(position, 32),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "table")]
+    fn should_scan_table_with_index_pushdown() {
+        use table::{ScanError, Table, Value};
+
+        let mut m = IndexedMap::<u32, (&str, u32)>::new();
+        let team = m.add_index("team".to_string(), |_, v| vec![v.0.to_string()]);
+        m.insert(1, ("core", 30));
+        m.insert(2, ("web", 40));
+        m.insert(3, ("core", 41));
+
+        let table = Table::new()
+            .column("id", |&k: &u32, _| Value::Int(k as i64))
+            .indexed_column("team", &team, |_, v: &(&str, u32)| {
+                Value::Str(v.0.to_string())
+            })
+            .column("age", |_, v| Value::Int(v.1 as i64));
+        let filters = [
+            ("age", Value::Int(41)),
+            ("team", Value::Str("core".to_string())),
+        ];
+        assert_eq!(table.supports_pushdown(&filters), vec![false, true]);
+
+        let rows = table.scan(&m, Some(&[0]), &filters).unwrap();
+        assert_eq!(rows.columns, vec!["id".to_string()]);
+        assert_eq!(rows.rows, vec![vec![Value::Int(3)]]);
+        assert_eq!(table.scan(&m, None, &[]).unwrap().rows.len(), 3);
+        assert_eq!(
+            table.scan(&m, Some(&[1, 3]), &filters),
+            Err(ScanError::UnknownColumn {
+                position: 3,
+                columns: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn should_project_entries_by_index() {
+        let mut m = IndexedMap::<u32, (&str, u32)>::new();
+        let team = m.add_index("team".to_string(), |_, v| vec![v.0]);
+        m.insert(1, ("core", 30));
+        m.insert(2, ("web", 40));
+        m.insert(3, ("core", 41));
+
+        let mut ages = m.project_by_index(&team, &"core", |_, v| v.1).unwrap();
+        ages.sort();
+        assert_eq!(ages, vec![30, 41]);
+        assert_eq!(m.project_by_index(&team, &"infra", |&k, _| k), None);
+    }
+
+    #[test]
+    fn should_skip_recomputing_memoized_index_values() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut m = IndexedMap::<u32, (u64, &str)>::new();
+        let words = m.add_memoized_index(
+            "words".to_string(),
+            |_, v| v.0,
+            move |_, v| {
+                counter.set(counter.get() + 1);
+                v.1.split(' ').map(str::to_string).collect()
+            },
+        );
+        m.insert(1, (1, "hello world"));
+        m.insert(1, (1, "hello world"));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            m.keys_by_index(&words, &"world".to_string())
+                .map(|x| x.len()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn should_query_composite_keys_by_prefix() {
+        let mut m = IndexedMap::<(&str, u32), &str>::new();
+        let tenant = m.add_prefix_index("tenant".to_string());
+        m.insert(("acme", 1), "anvil");
+        m.insert(("acme", 2), "rocket");
+        m.insert(("globex", 1), "widget");
+
+        let acme = m.filter_by_index(&tenant, &"acme").unwrap();
+        assert_eq!(acme.len(), 2);
+        assert_eq!(acme.get(&("acme", 2)), Some(&&"rocket"));
+    }
+
+    #[test]
+    fn should_support_range_queries_with_ordered_backing() {
+        use std::collections::BTreeMap;
+
+        let mut m = IndexedMap::with_backing(ClonedKeys, BTreeMap::new());
+        let parity = m.add_index("parity".to_string(), |_, &v: &u32| vec![v % 2]);
+        for i in 0..10u32 {
+            m.insert(i, i * 10);
+        }
+        let range: Vec<u32> = m.range(3..6).map(|(&k, _)| k).collect();
+        assert_eq!(range, vec![3, 4, 5]);
+        assert_eq!(m.keys_by_index(&parity, &0).map(|x| x.len()), Some(10));
+    }
+
+    #[test]
+    fn should_track_entry_metadata_for_indices() {
+        let mut m = IndexedMap::<&str, u32>::new();
+        m.insert("a", 1);
+        assert_eq!(m.metadata(&"a"), None);
+        let index_id =
+            m.add_index_with_metadata("updates".to_string(), |_, _, meta| vec![meta.update_count]);
+        m.insert("b", 2);
+        for _ in 0..2 {
+            m.try_update(&"b", |v| {
+                *v += 1;
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        }
+
+        let a = m.metadata(&"a").unwrap().clone();
+        let b = m.metadata(&"b").unwrap().clone();
+        assert_eq!(a.update_count, 0);
+        assert_eq!(b.update_count, 2);
+        assert!(b.version > a.version);
+        assert!(b.updated_at >= b.inserted_at);
+        assert_eq!(m.keys_by_index(&index_id, &0).map(|x| x.len()), Some(1));
+        assert!(m.keys_by_index(&index_id, &2).unwrap().contains("b"));
+
+        m.try_retain(|k, _| Ok::<_, ()>(*k != "b")).unwrap();
+        assert_eq!(m.metadata(&"b"), None);
+    }
+
+    #[test]
+    fn should_converge_replicas_by_merging_deltas() {
+        let mut clock_a = HybridClock::new(1);
+        let mut clock_b = HybridClock::new(2);
+        let mut a = IndexedMap::<&str, Lww<u32>>::new();
+        let mut b = IndexedMap::<&str, Lww<u32>>::new();
+        a.enable_metadata();
+        b.enable_metadata();
+        let index_id = b.add_index("value".to_string(), |_, v: &Lww<u32>| vec![v.value]);
+
+        a.insert("x", Lww::new(1, &mut clock_a));
+        b.insert("x", Lww::new(2, &mut clock_b));
+        let newest = Lww::new(3, &mut clock_a);
+        a.insert("y", newest.clone());
+        clock_b.observe(&newest.timestamp);
+        b.insert("x", Lww::new(4, &mut clock_b));
+
+        let from_a = a.delta_since(0).unwrap();
+        let from_b = b.delta_since(0).unwrap();
+        a.merge_remote(from_b).unwrap();
+        assert_eq!(b.merge_remote(from_a).unwrap(), 1);
+
+        for m in &[&a, &b] {
+            assert_eq!(m.get("x").map(|v| v.value), Some(4));
+            assert_eq!(m.get("y").map(|v| v.value), Some(3));
+        }
+        assert!(b.keys_by_index(&index_id, &3).unwrap().contains("y"));
+        let since = b.current_version();
+        assert_eq!(b.delta_since(since).map(|d| d.len()), Some(0));
+    }
+
+    #[test]
+    fn should_rebuild_map_and_indices_by_replaying_events() {
+        let events = vec![
+            ChangeEvent::Insert {
+                key: 1,
+                value: "one",
+            },
+            ChangeEvent::Insert {
+                key: 2,
+                value: "two",
+            },
+            ChangeEvent::Insert {
+                key: 3,
+                value: "three",
+            },
+            ChangeEvent::Remove { key: 2 },
+        ];
+        let mut m = IndexedMap::<u32, &str>::new();
+        let index_id = m.add_index("length".to_string(), |_, v: &&str| vec![v.len()]);
+        assert_eq!(m.replay(events).unwrap(), 4);
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.keys_by_index(&index_id, &3).map(|x| x.len()), Some(1));
+        assert_eq!(m.keys_by_index(&index_id, &5).map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn should_drain_captured_changes_in_bounded_batches() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        m.insert(0, 0);
+        m.capture_changes(2);
+        m.insert(1, 10);
+        m.replay(vec![ChangeEvent::Remove { key: 0 }]).unwrap();
+        let batch = m.take_changes();
+        assert!(!batch.overflowed);
+        assert_eq!(
+            batch.events,
+            vec![
+                ChangeEvent::Insert { key: 1, value: 10 },
+                ChangeEvent::Remove { key: 0 },
+            ]
+        );
+
+        for i in 2..5 {
+            m.insert(i, i);
+        }
+        let batch = m.take_changes();
+        assert!(batch.overflowed);
+        assert_eq!(batch.events.len(), 2);
+        assert_eq!(m.take_changes().events, vec![]);
+    }
+
+    #[test]
+    fn should_keep_standby_replica_in_sync_on_another_thread() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        let mut primary = IndexedMap::<u32, String>::new();
+        primary.insert(1, "one".to_string());
+        let feed = primary.replicate();
+        let (done_tx, done_rx) = channel();
+        let (counts_tx, counts_rx) = channel();
+        let reader = thread::spawn(move || {
+            let mut map = IndexedMap::<u32, String>::new();
+            let index_id = map.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+            let mut replica = StandbyReplica::new(map, feed).unwrap();
+            done_rx.recv().unwrap();
+            while replica.is_connected() {
+                replica.sync().unwrap();
+            }
+            let count = |a| replica.keys_by_index(&index_id, &a).map_or(0, |x| x.len());
+            counts_tx.send((replica.len(), count(3), count(5))).unwrap();
+        });
+
+        primary.insert(2, "two".to_string());
+        primary.insert(3, "three".to_string());
+        primary
+            .replay(vec![ChangeEvent::Remove { key: 1 }])
+            .unwrap();
+        done_tx.send(()).unwrap();
+        drop(primary);
+        reader.join().unwrap();
+        assert_eq!(counts_rx.recv().unwrap(), (2, 1, 1));
+    }
+
+    #[test]
+    fn should_query_through_map_like_trait() {
+        use std::any::Any;
+
+        struct Fake(HashMap<u32, &'static str>);
+
+        impl MapLike<u32, &'static str> for Fake {
+            fn entry_count(&self) -> usize {
+                self.0.len()
+            }
+
+            fn lookup(&self, key: &u32) -> Option<&&'static str> {
+                self.0.get(key)
+            }
+
+            fn entries<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a u32, &'a &'static str)> + 'a> {
+                Box::new(self.0.iter())
+            }
+
+            fn filter_by_index<A>(
+                &self,
+                _: &IndexId<A>,
+                index_key: &A,
+            ) -> Option<HashMap<&u32, &&'static str>>
+            where
+                A: 'static + Eq + Hash + Clone,
+            {
+                let len = (index_key as &dyn Any).downcast_ref::<usize>()?;
+                Some(self.0.iter().filter(|&(_, v)| v.len() == *len).collect())
+            }
+
+            fn keys_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<Vec<&u32>>
+            where
+                A: 'static + Eq + Hash + Clone,
+            {
+                self.filter_by_index(index_id, index_key)
+                    .map(|entries| entries.into_keys().collect())
+            }
+        }
+
+        fn short_names<M: MapLike<u32, &'static str>>(m: &M) -> usize {
+            m.keys_by_index(&IndexId::new("length".to_string()), &3usize)
+                .map_or(0, |keys| keys.len())
+        }
+
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        m.add_index("length".to_string(), |_, v: &&str| vec![v.len()]);
+        let mut fake = Fake(HashMap::new());
+        for &(k, v) in &[(1, "one"), (2, "two"), (3, "three")] {
+            m.insert(k, v);
+            fake.0.insert(k, v);
+        }
+        assert_eq!(short_names(&m), 2);
+        assert_eq!(short_names(&fake), 2);
+        assert_eq!(m.entries().count(), m.entry_count());
+        assert_eq!(fake.lookup(&3), Some(&"three"));
+    }
+
+    #[test]
+    fn should_index_mixed_value_types_separately() {
+        struct Position(i32, i32);
+        struct Name(&'static str);
+
+        let mut m = TypedMap::<u32>::new();
+        let by_x = m.add_typed_index("x".to_string(), |_, p: &Position| vec![p.0]);
+        let by_initial = m.add_typed_index("initial".to_string(), |_, n: &Name| {
+            n.0.chars().next().into_iter().collect()
+        });
+        m.insert_typed(1, Position(0, 5));
+        m.insert_typed(2, Position(0, 7));
+        m.insert_typed(3, Name("alice"));
+
+        assert_eq!(m.get_typed::<Name>(&3).map(|n| n.0), Some("alice"));
+        assert!(m.get_typed::<Position>(&3).is_none());
+        let mut ys: Vec<i32> = m
+            .filter_typed_by_index::<Position, _>(&by_x, &0)
+            .into_iter()
+            .map(|(_, p)| p.1)
+            .collect();
+        ys.sort();
+        assert_eq!(ys, vec![5, 7]);
+        assert_eq!(
+            m.filter_typed_by_index::<Name, _>(&by_initial, &'a').len(),
+            1
+        );
+        assert_eq!(m.len(), 3);
+    }
+
+    #[cfg(feature = "test-utils")]
+    mod properties {
+        use proptest::prelude::*;
+        use test_utils::{is_index_consistent, ArbitraryMap};
+
+        proptest! {
+            #[test]
+            fn should_generate_consistent_maps(generated in any::<ArbitraryMap<u8, u16>>()) {
+                for index_id in &generated.indices {
+                    prop_assert!(is_index_consistent(&generated.map, index_id));
+                }
+            }
+
+            #[test]
+            fn should_stay_consistent_after_updates(
+                generated in ArbitraryMap::strategy(0..16u8, any::<u16>(), 32, 3),
+                bump in any::<u16>(),
+            ) {
+                let mut m = generated.map;
+                m.try_retain(|k, v| {
+                    *v = v.wrapping_add(bump);
+                    Ok::<_, ()>(k % 3 != 0)
+                })
+                .unwrap();
+                for index_id in &generated.indices {
+                    prop_assert!(is_index_consistent(&m, index_id));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_report_querying_index_with_wrong_value_type() {
+        let mut m = IndexedMap::<&str, &str>::new();
+        m.add_index("length".to_string(), |_, &v| vec![v.len()]);
+        let wrong = IndexId::<u32>::new("length".to_string());
+        assert_eq!(
+            m.try_get_index(&wrong),
+            Err(QueryError::WrongValueType {
+                name: "length".to_string(),
+                expected: "usize".to_string(),
+                found: "u32".to_string(),
+            })
+        );
+        assert_eq!(
+            m.set_index_enabled(&IndexId::<u32>::new("nope".to_string()), false),
+            Err(QueryError::MissingIndex {
+                name: "nope".to_string()
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "holds values of type `usize`")]
+    fn should_panic_in_debug_when_filtering_with_wrong_value_type() {
+        let mut m = IndexedMap::<&str, &str>::new();
+        m.add_index("length".to_string(), |_, &v| vec![v.len()]);
+        m.filter_by_index(&IndexId::<u32>::new("length".to_string()), &3);
+    }
+
+    #[test]
+    fn should_combine_index_postings_into_key_sets() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let length = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        let initial = m.add_index("initial".to_string(), |_, v: &String| {
+            v.chars().next().into_iter().collect()
+        });
+        for (k, v) in &[
+            (1, "one"),
+            (2, "two"),
+            (3, "three"),
+            (4, "four"),
+            (6, "six"),
+        ] {
+            m.insert(*k, v.to_string());
+        }
+        let filter = KeyFilter::All(vec![
+            m.postings(&initial, &'t').unwrap(),
+            KeyFilter::Any(vec![
+                m.postings(&length, &3).unwrap(),
+                m.postings(&length, &4).unwrap(),
+            ]),
+        ]);
+        assert_eq!(m.keys_by_indices(&filter), vec![&2].into_iter().collect());
+        let filter = KeyFilter::Any(vec![
+            m.postings(&initial, &'f').unwrap(),
+            m.postings(&initial, &'z').unwrap(),
+        ]);
+        assert_eq!(m.keys_by_indices(&filter).len(), 1);
+        assert!(m.keys_by_indices(&KeyFilter::All(vec![])).is_empty());
+    }
+
+    #[test]
+    fn should_track_min_and_max_entry_per_bucket() {
+        let mut m = IndexedMap::<&str, (&str, u32)>::new();
+        m.insert("ann", ("red", 10));
+        let id = m.add_index_with_extremes(
+            "team".to_string(),
+            |_, v: &(&str, u32)| vec![v.0],
+            |_, v: &(&str, u32)| v.1,
+        );
+        m.insert("bob", ("red", 30));
+        m.insert("cat", ("red", 20));
+        m.insert("dan", ("blue", 5));
+        let name = |e: Option<(&&'static str, &(&str, u32))>| e.map(|(k, _)| *k);
+        assert_eq!(
+            name(m.extreme_by_index(&id, &"red", MinMax::Max)),
+            Some("bob")
+        );
+        assert_eq!(
+            name(m.extreme_by_index(&id, &"red", MinMax::Min)),
+            Some("ann")
+        );
+
+        m.try_update(&"bob", |v| {
+            v.1 = 1;
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        m.try_retain(|k, _| Ok::<_, ()>(*k != "ann")).unwrap();
+        assert_eq!(
+            name(m.extreme_by_index(&id, &"red", MinMax::Max)),
+            Some("cat")
+        );
+        assert_eq!(
+            name(m.extreme_by_index(&id, &"red", MinMax::Min)),
+            Some("bob")
+        );
+        assert_eq!(
+            name(m.extreme_by_index(&id, &"blue", MinMax::Min)),
+            Some("dan")
+        );
+        assert_eq!(name(m.extreme_by_index(&id, &"green", MinMax::Min)), None);
+        assert_eq!(m.keys_by_index(&id, &"red").map(|x| x.len()), Some(2));
+    }
+
+    #[test]
+    fn should_keep_bounded_sample_per_bucket() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        for i in 0..500 {
+            m.insert(i, i % 2);
+        }
+        let id = m.add_sampled_index("parity".to_string(), |_, v: &u32| vec![*v], 8);
+        for i in 500..510 {
+            m.insert(i, i % 2);
+        }
+        let sample = m.sample_by_index(&id, &1).unwrap();
+        assert_eq!(sample.len(), 8);
+        assert!(sample.iter().all(|&(_, v)| *v == 1));
+        assert!(sample.iter().any(|&(k, _)| *k < 500));
+
+        m.try_retain(|k, _| Ok::<_, ()>(*k < 5)).unwrap();
+        let mut remaining: Vec<u32> = m
+            .sample_by_index(&id, &1)
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| *k)
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![1, 3]);
+        assert_eq!(m.sample_by_index(&id, &7), Some(vec![]));
+
+        let sampled = |seed| {
+            let mut m = IndexedMap::<u32, u32>::new();
+            let id = m.add_sampled_index_with_seed("all".to_string(), |_, _| vec![()], 4, seed);
+            for i in 0..100 {
+                m.insert(i, i);
+            }
+            let mut keys: Vec<u32> = m
+                .sample_by_index(&id, &())
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| *k)
+                .collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(sampled(7), sampled(7));
+    }
+
+    #[test]
+    fn should_index_with_custom_and_cached_hashes() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let mut m = IndexedMap::<u32, String>::new();
+        let by_len = m.add_index_with_hasher(
+            "length".to_string(),
+            BuildHasherDefault::<DefaultHasher>::default(),
+            |_, v: &String| vec![v.len()],
+        );
+        let by_word = m.add_index_with_hasher(
+            "word".to_string(),
+            PrehashedState::default(),
+            |_, v: &String| vec![Prehashed::new(v.clone())],
+        );
+        for i in 0..20 {
+            m.insert(i, format!("value-{}", i % 4));
+        }
+        assert_eq!(m.keys_by_index(&by_len, &7).map(|x| x.len()), Some(20));
+        let word = Prehashed::new("value-1".to_string());
+        assert_eq!(m.filter_by_index(&by_word, &word).map(|x| x.len()), Some(5));
+        assert_eq!(m.get_index(&by_word).map(|x| x.len()), Some(4));
+        m.set_index_enabled(&by_word, false).unwrap();
+        assert!(!m.is_index_enabled(&by_word));
+    }
+
+    #[test]
+    fn should_resume_budgeted_queries_from_continuation() {
+        use std::time::Duration;
+
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for i in 0..100 {
+            m.insert(i, i);
+        }
+        let budget = Budget::unlimited().with_max_visits(30);
+        let mut found = Vec::new();
+        let mut from = None;
+        let mut rounds = 0;
+        loop {
+            let partial = m.scan_with_budget(&budget, from, |_, v| v % 10 == 0);
+            found.extend(partial.results.into_iter().map(|(k, _)| *k));
+            rounds += 1;
+            match partial.continuation {
+                Some(continuation) => from = Some(continuation),
+                None => break,
+            }
+        }
+        found.sort();
+        assert_eq!(found, (0..10).map(|i| i * 10).collect::<Vec<_>>());
+        assert_eq!(rounds, 4);
+
+        let partial = m
+            .filter_by_index_with_budget(&parity, &1, &budget, None)
+            .unwrap();
+        assert_eq!(partial.results.len(), 30);
+        let rest = m
+            .filter_by_index_with_budget(&parity, &1, &Budget::unlimited(), partial.continuation)
+            .unwrap();
+        assert!(rest.is_complete());
+        assert_eq!(rest.results.len(), 20);
+
+        let expired = Budget::unlimited().with_timeout(Duration::from_secs(0));
+        assert!(m
+            .scan_with_budget(&expired, None, |_, _| true)
+            .results
+            .is_empty());
+    }
+
+    #[test]
+    fn should_report_index_buckets_moved_by_update() {
+        let mut m = IndexedMap::<&str, String>::new();
+        let length = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        let letters = m.add_index("letters".to_string(), |_, v: &String| {
+            v.chars().collect::<Vec<char>>()
+        });
+        m.insert("a", "ab".to_string());
+        let (_, diff) = m
+            .try_update_with_diff(&"a", |v| {
+                v.push('c');
+                Ok::<_, ()>(())
+            })
+            .unwrap()
+            .unwrap();
+        let length_diff = diff.for_index(&length).unwrap();
+        assert_eq!(length_diff.removed(), vec![&2]);
+        assert_eq!(length_diff.added(), vec![&3]);
+        let letters_diff = diff.for_index(&letters).unwrap();
+        assert!(letters_diff.removed().is_empty());
+        assert_eq!(letters_diff.added(), vec![&'c']);
+        assert!(m
+            .try_update_with_diff(&"z", |_| Ok::<_, ()>(()))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn should_rename_keys_with_their_postings() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let length = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        for (k, v) in &[(1, "one"), (2, "two"), (3, "three")] {
+            m.insert(*k, v.to_string());
+        }
+        assert_eq!(m.change_key(&3, 30), Ok(true));
+        assert_eq!(m.change_key(&3, 31), Ok(false));
+        assert_eq!(m.change_key(&1, 2), Err(RekeyError::Occupied { key: 2 }));
+        assert!(m.keys_by_index(&length, &5).unwrap().contains(&30));
+
+        assert_eq!(m.rekey(|k, _| if *k == 30 { 30 } else { 3 - k }), Ok(2));
+        assert_eq!(m.get(&1).map(String::as_str), Some("two"));
+        assert_eq!(m.get(&2).map(String::as_str), Some("one"));
+        let mut short: Vec<u32> = m
+            .keys_by_index(&length, &3)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        short.sort();
+        assert_eq!(short, vec![1, 2]);
+
+        assert_eq!(m.rekey(|_, _| 0), Err(RekeyError::Occupied { key: 0 }));
+        assert_eq!(m.len(), 3);
+    }
+
+    #[test]
+    fn should_add_indices_from_definitions() {
+        let mut registry = ExtractorRegistry::<&'static str, String>::new();
+        registry.register("length_bucket".to_string(), |options: &IndexOptions| {
+            let width: usize = options
+                .get("width")
+                .ok_or("missing width")?
+                .parse()
+                .map_err(|_| "width must be a number".to_string())?;
+            Ok(move |_: &&'static str, v: &String| vec![v.len() / width])
+        });
+        let mut m = IndexedMap::new();
+        m.insert("a", "one".to_string());
+        m.insert("b", "three".to_string());
+
+        let bad = [IndexDefinition::new(
+            "by_length".to_string(),
+            "length_bucket".to_string(),
+        )];
+        assert_eq!(
+            registry.apply(&mut m, &bad),
+            Err(DefinitionError::InvalidOptions {
+                name: "by_length".to_string(),
+                reason: "missing width".to_string(),
+            })
+        );
+        let definitions = [
+            IndexDefinition::new("by_length".to_string(), "length_bucket".to_string())
+                .with_option("width".to_string(), "2".to_string()),
+            IndexDefinition::new("coarse".to_string(), "length_bucket".to_string())
+                .with_option("width".to_string(), "10".to_string())
+                .with_enabled(false),
+            IndexDefinition::new("other".to_string(), "missing".to_string()),
+        ];
+        assert!(registry.apply(&mut m, &definitions).is_err());
+        assert!(m.indices.is_empty());
+        registry.apply(&mut m, &definitions[..2]).unwrap();
+
+        let by_length = IndexId::<usize>::new("by_length".to_string());
+        assert!(m.keys_by_index(&by_length, &2).unwrap().contains(&"b"));
+        let coarse = IndexId::<usize>::new("coarse".to_string());
+        assert!(!m.is_index_enabled(&coarse));
+        assert_eq!(
+            registry.apply(&mut m, &definitions[..1]),
+            Err(DefinitionError::DuplicateName {
+                name: "by_length".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn should_hide_soft_removed_entries_until_purged() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        m.insert(1, "one");
+        m.insert(2, "two");
+        m.insert(3, "three");
+
+        assert!(m.soft_remove(&2));
+        assert!(!m.soft_remove(&2));
+        assert!(m.get(&2).is_none());
+        assert_eq!(m.keys_by_index(&length, &3).unwrap().len(), 1);
+        assert!(m.is_soft_removed(&2));
+        let view = m.include_deleted();
+        assert_eq!(view.get(&2), Some(&"two"));
+        assert_eq!(view.len(), 3);
+        assert_eq!(view.filter_by_index(&length, &3).unwrap().len(), 2);
+
+        assert_eq!(m.restore(&2), Ok(true));
+        assert_eq!(m.keys_by_index(&length, &3).unwrap().len(), 2);
+        assert_eq!(m.restore(&2), Ok(false));
+
+        m.soft_remove(&1);
+        m.soft_remove(&3);
+        m.insert(3, "drei");
+        assert_eq!(m.include_deleted().get(&3), Some(&"drei"));
+        assert_eq!(m.purge(), 1);
+        assert_eq!(m.include_deleted().len(), 2);
+    }
+
+    #[test]
+    fn should_pop_entries_in_index_order() {
+        let mut m = IndexedMap::<&'static str, u64>::new();
+        let due = m.add_priority_index("due".to_string(), |_, v: &u64| vec![*v]);
+        m.insert("later", 30);
+        m.insert("soon", 10);
+        m.insert("middle", 20);
+        m.insert("cancelled", 5);
+        m.replay(vec![ChangeEvent::Remove { key: "cancelled" }])
+            .unwrap();
+
+        assert_eq!(m.peek_min_by_index(&due), Some((&10, &"soon", &10)));
+        assert_eq!(m.pop_min_by_index(&due), Some(("soon", 10)));
+        assert_eq!(m.pop_min_by_index(&due), Some(("middle", 20)));
+        assert!(m.get("middle").is_none());
+        assert_eq!(m.pop_min_by_index(&due), Some(("later", 30)));
+        assert_eq!(m.pop_min_by_index(&due), None);
+    }
+
+    #[test]
+    fn should_drop_idle_indices_and_rebuild_them_on_demand() {
+        use std::time::Duration;
+
+        let mut m = IndexedMap::<&'static str, &'static str>::new();
+        m.insert("a", "one");
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        let first = m.add_index("first".to_string(), |_, v: &&'static str| {
+            v.chars().next().into_iter().collect()
+        });
+        m.filter_by_index(&length, &3);
+        assert_eq!(m.index_usage()[1].queries, 1);
+
+        assert_eq!(
+            m.drop_idle_indices(Duration::from_secs(0)),
+            ["first".to_string(), "length".to_string()]
+        );
+        assert!(m.drop_idle_indices(Duration::from_secs(0)).is_empty());
+        m.insert("b", "two");
+        assert_eq!(
+            m.try_get_index(&first),
+            Err(QueryError::Cold {
+                name: "first".to_string(),
+            })
+        );
+        assert_eq!(m.get_index_warming(&first).unwrap()[&'t'].len(), 1);
+        assert!(m.warm_index("length"));
+        assert!(!m.warm_index("length"));
+        assert_eq!(m.keys_by_index(&length, &3).unwrap().len(), 2);
+        assert!(m.index_usage().iter().all(|usage| !usage.cold));
+    }
+
+    #[test]
+    fn should_query_a_frozen_map_in_place() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        for (k, v) in &[(1, "one"), (2, "two"), (3, "three"), (10, "ten")] {
+            m.insert(*k, *v);
+        }
+        let mut builder = m.freeze(|k| k.to_be_bytes().to_vec(), |v| v.as_bytes().to_vec());
+        builder
+            .add_index(&m, &length, |len| (*len as u32).to_be_bytes().to_vec())
+            .unwrap();
+        let bytes = builder.finish();
+
+        let frozen = FrozenMap::new(&bytes).unwrap();
+        assert_eq!(frozen.len(), 4);
+        assert_eq!(frozen.get(&3u32.to_be_bytes()), Some(&b"three"[..]));
+        assert_eq!(frozen.get(&4u32.to_be_bytes()), None);
+        assert_eq!(frozen.index_names().collect::<Vec<_>>(), ["length"]);
+        let mut short: Vec<&[u8]> = frozen
+            .filter_by_index("length", &3u32.to_be_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        short.sort();
+        assert_eq!(short, [&b"one"[..], b"ten", b"two"]);
+        assert!(frozen.filter_by_index("missing", &[]).is_none());
+
+        assert_eq!(
+            FrozenMap::new(&bytes[..10]).unwrap_err(),
+            FrozenError::Truncated
+        );
+        for len in 0..bytes.len() {
+            if let Ok(truncated) = FrozenMap::new(&bytes[..len]) {
+                assert!(truncated.iter().count() <= 4);
+                truncated.filter_by_index("length", &3u32.to_be_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn should_resolve_a_batch_of_lookups_across_indices() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        let first = m.add_index("first".to_string(), |_, v: &&'static str| {
+            v.chars().next().into_iter().collect()
+        });
+        for (k, v) in &[(1, "one"), (2, "two"), (3, "three")] {
+            m.insert(*k, *v);
+        }
+        let mut query = MultiQuery::new();
+        let by_length = query.add(&length, vec![3, 4]);
+        let by_first = query.add(&first, vec!['t']);
+        let missing = query.add(&IndexId::<u8>::new("missing".to_string()), vec![0]);
+
+        let result = m.multi_query(&query);
+        let lengths = result.get(by_length).unwrap();
+        assert_eq!(lengths[0].len(), 2);
+        assert!(lengths[1].is_empty());
+        assert_eq!(result.get(by_first).unwrap()[0].len(), 2);
+        assert_eq!(
+            result.get(missing),
+            Err(QueryError::MissingIndex {
+                name: "missing".to_string(),
+            })
+        );
+        assert_eq!(result.entries().len(), 3);
+    }
+
+    #[test]
+    fn should_skip_reindexing_unchanged_values() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut m = IndexedMap::<u32, String>::new();
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        m.add_index("length".to_string(), move |_, v: &String| {
+            counted.set(counted.get() + 1);
+            vec![v.len()]
+        });
+        m.skip_unchanged_writes();
+        m.capture_changes(8);
+        m.insert(1, "one".to_string());
+        assert_eq!(m.insert(1, "one".to_string()), Some("one".to_string()));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(m.take_changes().events.len(), 1);
+
+        m.set_value_equality(Some(|a: &String, b: &String| a.eq_ignore_ascii_case(b)));
+        m.insert(1, "ONE".to_string());
+        assert_eq!(calls.get(), 1);
+        assert_eq!(m.get(&1).map(String::as_str), Some("ONE"));
+        m.set_value_equality(None::<fn(&String, &String) -> bool>);
+        m.insert(1, "one".to_string());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn should_cap_buckets_with_the_chosen_eviction() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let log = evicted.clone();
+        let mut m = IndexedMap::<u32, i64>::new();
+        let cap = BucketCap::new(2)
+            .with_eviction(BucketEviction::lowest_score(|_, v: &i64| *v))
+            .on_evict(move |_: &bool, k: &u32| log.borrow_mut().push(*k));
+        let top = m.add_capped_index("positive".to_string(), |_, v: &i64| vec![*v > 0], cap);
+        m.insert(1, 10);
+        m.insert(2, 30);
+        m.insert(3, 20);
+        m.insert(4, 5);
+        assert_eq!(evicted.replace(Vec::new()), [1, 4]);
+        let mut kept: Vec<u32> = m
+            .keys_by_index(&top, &true)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        kept.sort();
+        assert_eq!(kept, [2, 3]);
+
+        let oldest =
+            m.add_capped_index("all".to_string(), |_, _: &i64| vec![()], BucketCap::new(3));
+        m.insert(5, 1);
+        assert_eq!(m.keys_by_index(&oldest, &()).unwrap().len(), 3);
+        m.replay(vec![ChangeEvent::Remove { key: 5 }]).unwrap();
+        assert_eq!(m.keys_by_index(&oldest, &()).unwrap().len(), 2);
+
+        let kept = |seed| {
+            let mut m = IndexedMap::<u32, i64>::new();
+            let cap = BucketCap::new(4)
+                .with_eviction(BucketEviction::Random)
+                .with_seed(seed);
+            let all = m.add_capped_index("all".to_string(), |_, _: &i64| vec![()], cap);
+            for i in 0..100 {
+                m.insert(i, 0);
+            }
+            let mut keys: Vec<u32> = m
+                .keys_by_index(&all, &())
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(kept(7).len(), 4);
+        assert_eq!(kept(7), kept(7));
+    }
+
+    #[test]
+    fn should_keep_tenants_apart() {
+        let mut m = TenantScopedIndexedMap::<(&'static str, u8), u32, &'static str>::new();
+        m.insert(("acme", 1), 1, "one");
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        m.insert(("acme", 1), 2, "two");
+        m.insert(("acme", 2), 1, "uno");
+        m.insert(("globex", 1), 1, "eins");
+
+        assert_eq!(m.get(&("acme", 2), &1), Some(&"uno"));
+        assert_eq!(
+            m.filter_by_index(&("acme", 1), &length, &3).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            m.filter_by_index(&("acme", 2), &length, &3).unwrap().len(),
+            1
+        );
+        assert!(m.drop_tenant(&("acme", 2)).is_some());
+        assert!(m.get(&("acme", 2), &1).is_none());
+        assert_eq!(m.drop_tenants_under(&"acme"), 1);
+        assert_eq!(m.tenants().count(), 1);
+    }
+
+    #[test]
+    fn should_report_build_progress_and_cancel_cleanly() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        for i in 0..10 {
+            m.insert(i, i % 3);
+        }
+        let mut reports = Vec::new();
+        let id = m
+            .build_index("mod".to_string(), |_, v: &u32| vec![*v])
+            .report_every(4)
+            .on_progress(|progress| reports.push(progress.processed))
+            .run()
+            .unwrap();
+        assert_eq!(reports, [4, 8, 10]);
+        assert_eq!(m.keys_by_index(&id, &0).unwrap().len(), 4);
+
+        let cancellation = CancellationToken::new();
+        let cancel = cancellation.clone();
+        let result = m
+            .build_index("cancelled".to_string(), |_, v: &u32| vec![*v])
+            .report_every(3)
+            .cancel_on(cancellation)
+            .on_progress(move |_| cancel.cancel())
+            .run();
+        assert_eq!(
+            result.err(),
+            Some(BuildError::Cancelled {
+                name: "cancelled".to_string(),
+                processed: 3,
+            })
+        );
+        assert!(m
+            .try_get_index(&IndexId::<u32>::new("cancelled".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "columnar")]
+    fn should_export_index_columns_in_row_order() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        let letters = m.add_index("letters".to_string(), |_, v: &&'static str| {
+            v.chars().take(2).collect()
+        });
+        m.insert(1, "one");
+        m.insert(2, "");
+        let export = m.export_columns();
+        let keys = export.keys();
+        let lengths = export.column(&length).unwrap();
+        let letters = export.column(&letters).unwrap();
+        assert_eq!(lengths.offsets, [0, 1, 2]);
+        assert_eq!(lengths.len(), 2);
+        let row = keys.iter().position(|&&k| k == 2).unwrap();
+        assert!(letters.row(row).is_empty());
+        assert_eq!(letters.row(1 - row).len(), 2);
+        assert_eq!(lengths.to_single().unwrap()[row], Some(&0));
+        assert_eq!(letters.to_single(), None);
+        assert!(export
+            .column(&IndexId::<u8>::new("missing".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn should_cache_misses_until_a_write_fills_them() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let length = m.add_index("length".to_string(), |_, v: &&'static str| vec![v.len()]);
+        m.enable_negative_cache(2);
+        m.insert(1, "one");
+        assert_eq!(m.get_cached(&2), None);
+        assert!(m.keys_by_index_cached(&length, &5).is_none());
+        assert!(m.negative.as_ref().unwrap().keys.borrow().set.contains(&2));
+
+        m.insert(2, "three");
+        assert_eq!(m.get_cached(&2), Some(&"three"));
+        assert!(m.keys_by_index_cached(&length, &5).unwrap().contains(&2));
+
+        m.get_cached(&3);
+        m.get_cached(&4);
+        m.get_cached(&5);
+        let cache = m.negative.as_ref().unwrap();
+        assert_eq!(cache.keys.borrow().set.len(), 2);
+        assert!(!cache.keys.borrow().set.contains(&3));
+    }
+
+    #[test]
+    fn should_pin_entries_until_unpinned_or_removed() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        m.insert(1, "config");
+        m.insert(2, "data");
+        assert!(m.pin(&1));
+        assert!(!m.pin(&3));
+        assert!(m.is_pinned(&1));
+        assert!(!m.is_pinned(&2));
+
+        m.change_key(&1, 10).unwrap();
+        assert_eq!(m.pinned().collect::<Vec<_>>(), [&10]);
+        assert!(m.unpin(&10));
+        assert!(!m.unpin(&10));
+        m.pin(&2);
+        m.replay(vec![ChangeEvent::Remove { key: 2 }]).unwrap();
+        assert!(!m.is_pinned(&2));
+    }
+
+    #[test]
+    fn should_iterate_deterministic_maps_reproducibly() {
+        fn build() -> (DeterministicMap<u32, u32>, IndexId<u32, DeterministicState>) {
+            let mut m = IndexedMap::deterministic();
+            let id = m.add_deterministic_index("mod".to_string(), |_, v: &u32| vec![v % 5]);
+            for i in 0..50 {
+                m.insert(i * 7, i);
+            }
+            (m, id)
+        }
+        let (a, a_id) = build();
+        let (b, b_id) = build();
+        assert!(a.iter().eq(b.iter()));
+        assert!(a
+            .get_index(&a_id)
+            .unwrap()
+            .keys()
+            .eq(b.get_index(&b_id).unwrap().keys()));
+        for bucket in 0..5 {
+            assert!(a
+                .keys_by_index(&a_id, &bucket)
+                .unwrap()
+                .iter()
+                .eq(b.keys_by_index(&b_id, &bucket).unwrap().iter()));
+        }
+        assert_eq!(a.sorted_index_keys(&a_id).unwrap(), [&0, &1, &2, &3, &4]);
+        assert_eq!(
+            a.sorted_keys_by_index(&a_id, &0).unwrap()[..3],
+            [&0, &35, &70]
+        );
+    }
+
+    #[test]
+    fn should_normalize_index_values_and_query_keys() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let dish = m.add_normalized_index(
+            "dish".to_string(),
+            |_, v: &&'static str| vec![v.to_string()],
+            Normalizer::new()
+                .trim()
+                .unaccent()
+                .lowercase()
+                .then(|v: String| v.replace('-', " ")),
+        );
+        m.insert(1, " Crème Brûlée ");
+        m.insert(2, "creme-brulee");
+        m.insert(3, "Tarte Tatin");
+
+        assert_eq!(dish.normalize("ÆSIR ".to_string()), "aesir");
+        assert_eq!(
+            m.keys_by_normalized(&dish, "CREME BRULEE".to_string())
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(m.keys_by_index(&dish, &"tarte tatin".to_string()).is_some());
+    }
+
+    #[test]
+    fn should_address_entries_by_unique_index() {
+        let mut m = IndexedMap::<u32, (String, u32)>::new();
+        m.insert(1, ("a@b.c".to_string(), 0));
+        m.insert(2, ("a@b.c".to_string(), 0));
+        assert_eq!(
+            m.add_unique_index("email".to_string(), |_, v: &(String, u32)| vec![v
+                .0
+                .clone()])
+                .err(),
+            Some(DefinitionError::NotUnique {
+                name: "email".to_string()
+            })
+        );
+        m.remove(&2);
+
+        let email = m
+            .add_unique_index("email".to_string(), |_, v: &(String, u32)| {
+                vec![v.0.clone()]
+            })
+            .unwrap();
+        let (k, v) = m
+            .entry_by_unique(&email, "a@b.c".to_string())
+            .and_update(|v| v.1 += 1)
+            .or_insert_with(|| unreachable!())
+            .unwrap();
+        assert_eq!((*k, v.1), (1, 1));
+        let (k, _) = m
+            .entry_by_unique(&email, "x@y.z".to_string())
+            .or_insert_with(|| (3, ("x@y.z".to_string(), 0)))
+            .unwrap();
+        assert_eq!(*k, 3);
+        assert_eq!(
+            m.get_by_unique_index(&email, &"x@y.z".to_string())
+                .map(|(k, _)| *k),
+            Some(3)
+        );
+
+        match m.try_insert(4, ("a@b.c".to_string(), 0)) {
+            Err(InsertError::UniqueViolation {
+                index, existing, ..
+            }) => assert_eq!((index.as_str(), existing), ("email", 1)),
+            other => panic!("unexpected {:?}", other.is_ok()),
+        }
+        assert_eq!(
+            m.unique_conflicts(&5, &("x@y.z".to_string(), 0)),
+            vec![("email", &3)]
+        );
+        assert!(m.try_insert(1, ("a@b.c".to_string(), 9)).is_ok());
+    }
+
+    #[test]
+    fn should_restore_to_snapshot_with_minimal_changes() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for i in 0..10 {
+            m.insert(i, i);
+        }
+        let snapshot = m.snapshot();
+
+        m.try_update(&3, |v| {
+            *v = 4;
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        m.soft_remove(&4);
+        m.insert(20, 21);
+        let diff = m.diff_to(&snapshot);
+        assert_eq!(diff.inserts, vec![(4, 4)]);
+        assert_eq!(diff.updates, vec![(3, 3)]);
+        assert_eq!(diff.removes, vec![20]);
+        assert_eq!(m.snapshot().diff(&snapshot), diff);
+
+        m.capture_changes(16);
+        assert_eq!(m.restore_to(&snapshot).len(), 3);
+        assert_eq!(m.take_changes().events.len(), 3);
+        assert!(m.diff_to(&snapshot).is_empty());
+        let mut odd: Vec<u32> = m
+            .filter_by_index(&parity, &1)
+            .unwrap()
+            .into_keys()
+            .cloned()
+            .collect();
+        odd.sort();
+        assert_eq!(odd, vec![1, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    fn should_remove_entries_from_every_index() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let first = m.add_index("first".to_string(), |_, v: &String| {
+            v.chars().take(1).collect()
+        });
+        let chars = m.add_index("chars".to_string(), |_, v: &String| v.chars().collect());
+        m.insert(1, "ab".to_string());
+        m.insert(2, "bc".to_string());
+
+        assert_eq!(m.remove(&1), Some("ab".to_string()));
+        assert_eq!(m.remove(&1), None);
+        assert_eq!(m.keys_by_index(&first, &'a'), None);
+        assert_eq!(m.keys_by_index(&chars, &'a'), None);
+        assert_eq!(m.keys_by_index(&chars, &'b').map(HashSet::len), Some(1));
+        assert_eq!(m.remove_entry(&2), Some((2, "bc".to_string())));
+        assert!(m.get_index(&chars).unwrap().is_empty());
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn should_track_index_statistics_incrementally() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let digits = m.add_index("digits".to_string(), |_, v: &u32| {
+            let mut digits: Vec<u32> = v.to_string().bytes().map(|b| u32::from(b - b'0')).collect();
+            digits.dedup();
+            digits
+        });
+        for i in 0..100 {
+            m.insert(i, i % 20);
+        }
+        let stats = m.index_statistics(&digits).unwrap();
+        assert_eq!(stats.distinct, 10);
+        assert_eq!(stats.entries, 100);
+        assert_eq!(stats.postings, 10 * 5 + 9 * 5 * 2 + 5);
+        assert_eq!(stats.heavy_hitters[0], (1, 55));
+        assert_eq!(m.selectivity(&digits, &1), 0.55);
+        assert_eq!(m.selectivity(&digits, &7), 0.1);
+
+        for i in 0..100 {
+            m.remove(&i);
+        }
+        let stats = m.index_statistics(&digits).unwrap();
+        assert_eq!((stats.distinct, stats.postings), (0, 0));
+        assert!(stats.heavy_hitters.is_empty());
+        assert_eq!(m.selectivity(&digits, &1), 1.0);
+    }
+
+    #[test]
+    fn should_check_membership_in_bulk() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        m.insert(1, 1);
+        m.insert(3, 3);
+        assert_eq!(
+            m.contains_keys(&[0, 1, 2, 3, 1]),
+            vec![false, true, false, true, true]
+        );
+        assert!(m.contains_keys(&[]).is_empty());
+    }
+
+    #[test]
+    fn should_retract_old_index_values_on_overwrite() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        let top = m.add_capped_index(
+            "top".to_string(),
+            |_, v: &u32| vec![*v >= 10],
+            BucketCap::new(2),
+        );
+        m.insert(1, 1);
+        m.insert(1, 2);
+        m.insert(2, 12);
+        m.insert(2, 14);
+
+        assert_eq!(m.keys_by_index(&parity, &1), None);
+        assert_eq!(m.keys_by_index(&parity, &0).map(HashSet::len), Some(2));
+        assert_eq!(m.keys_by_index(&top, &true).map(HashSet::len), Some(1));
+        assert_eq!(m.index_statistics(&parity).unwrap().postings, 2);
+    }
+
+    #[test]
+    fn should_save_snapshots_in_the_background() {
+        let path =
+            ::std::env::temp_dir().join(format!("indexed_map_{}.imfz", ::std::process::id()));
+        let mut m = IndexedMap::<u32, String>::new();
+        for i in 0..100 {
+            m.insert(i, i.to_string());
+        }
+        let saving = m.save_async(
+            &path,
+            |k: &u32| k.to_be_bytes().to_vec(),
+            |v: &String| v.clone().into_bytes(),
+        );
+        m.insert(100, "100".to_string());
+        saving.join().unwrap().unwrap();
+
+        let bytes = ::std::fs::read(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        let frozen = FrozenMap::new(&bytes).unwrap();
+        assert_eq!(frozen.len(), 100);
+        assert_eq!(frozen.get(&7u32.to_be_bytes()), Some(&b"7"[..]));
+        assert_eq!(frozen.get(&100u32.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn should_reindex_values_mutated_in_place() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        m.insert(1, 1);
+
+        assert_eq!(m.update(&1, |v| *v += 1), Some(()));
+        assert_eq!(m.update(&9, |v| *v += 1), None);
+        assert_eq!(m.keys_by_index(&parity, &1), None);
+
+        *m.get_mut(&1).unwrap() += 1;
+        assert!(m.get_mut(&9).is_none());
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(1));
+
+        *m.entry(2).or_insert(4) += 2;
+        m.entry(2).and_modify(|v| *v += 1).or_insert(0);
+        assert_eq!(m.get(&2), Some(&7));
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(2));
+        assert_eq!(m.keys_by_index(&parity, &0), None);
+    }
+
+    #[test]
+    fn should_query_sorted_index_by_range() {
+        let mut m = IndexedMap::<&'static str, u32>::new();
+        let price = m.add_sorted_index("price".to_string(), |_, v: &u32| vec![*v]);
+        m.insert("tea", 3);
+        m.insert("cake", 9);
+        m.insert("bun", 5);
+        m.insert("pie", 5);
+
+        let in_range: Vec<u32> = m
+            .range_by_index(&price, 4..=9)
+            .unwrap()
+            .into_iter()
+            .map(|(a, _, _)| *a)
+            .collect();
+        assert_eq!(in_range, vec![5, 5, 9]);
+        assert!(m.range_by_index(&price, 10..).unwrap().is_empty());
+        assert_eq!(m.min_by_index(&price), Some((&3, vec![(&"tea", &3)])));
+        let (max, cakes) = m.max_by_index(&price).unwrap();
+        assert_eq!((*max, cakes.len()), (9, 1));
+
+        m.remove(&"cake");
+        m.insert("tea", 1);
+        assert_eq!(
+            m.max_by_index(&price).map(|(a, e)| (*a, e.len())),
+            Some((5, 2))
+        );
+        assert_eq!(m.min_by_index(&price).map(|(a, _)| *a), Some(1));
+    }
+
+    #[test]
+    fn should_remove_and_rebuild_indices() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let modulus = Rc::new(Cell::new(2));
+        let m_of = modulus.clone();
+        let mut m = IndexedMap::<u32, u32>::new();
+        let rem = m.add_index("rem".to_string(), move |_, v: &u32| vec![v % m_of.get()]);
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for i in 0..9 {
+            m.insert(i, i);
+        }
+        modulus.set(3);
+        assert_eq!(m.keys_by_index(&rem, &2), None);
+        m.rebuild_index(&rem).unwrap();
+        assert_eq!(m.keys_by_index(&rem, &2).map(HashSet::len), Some(3));
+
+        assert!(m.remove_index(parity.clone()));
+        assert!(!m.remove_index(parity.clone()));
+        assert!(m.try_get_index(&parity).is_err());
+        assert!(m.rebuild_index(&parity).is_err());
+        m.insert(9, 9);
+        assert_eq!(m.keys_by_index(&rem, &0).map(HashSet::len), Some(4));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn should_restore_persisted_map_with_rebuilt_indices() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for i in 0..6 {
+            m.insert(i, i * 3);
+        }
+        let persisted = m.persisted();
+        assert_eq!(persisted.indices, vec!["parity".to_string()]);
+        assert_eq!(persisted.entries.len(), 6);
+
+        let mut parity = None;
+        let restored = persisted
+            .clone()
+            .restore(|map| {
+                parity = Some(map.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]))
+            })
+            .unwrap();
+        let parity = parity.unwrap();
+        assert_eq!(
+            restored.keys_by_index(&parity, &1).map(HashSet::len),
+            Some(3)
+        );
+        assert_eq!(
+            persisted.restore(|_| {}).err(),
+            Some(DefinitionError::Unregistered {
+                name: "parity".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn should_index_values_that_cannot_be_cloned() {
+        use std::sync::mpsc::{channel, Sender};
+
+        struct Worker {
+            shard: u32,
+            jobs: Sender<u32>,
+        }
+
+        let (jobs, received) = channel();
+        let mut m = IndexedMap::<u32, Worker>::new();
+        let by_shard = m.add_index("shard".to_string(), |_, w: &Worker| vec![w.shard]);
+        for i in 0..4 {
+            let jobs = jobs.clone();
+            m.insert(i, Worker { shard: i % 2, jobs });
+        }
+        m.update(&0, |w| w.shard = 1);
+        m.remove(&3);
+
+        assert_eq!(m.keys_by_index(&by_shard, &1).map(HashSet::len), Some(2));
+        for (_, worker) in m.filter_by_index(&by_shard, &1).unwrap() {
+            worker.jobs.send(worker.shard).unwrap();
+        }
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), [1, 1]);
+    }
+
+    #[test]
+    fn should_stream_index_queries_lazily() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for i in 0..5 {
+            m.insert(i, i + 10);
+        }
+
+        let mut odd: Vec<(u32, u32)> = m
+            .iter_by_index(&parity, &1)
+            .unwrap()
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        odd.sort();
+        assert_eq!(odd, vec![(1, 11), (3, 13)]);
+        assert_eq!(m.values_by_index(&parity, &0).unwrap().sum::<u32>(), 36);
+        assert!(m.iter_by_index(&parity, &2).is_none());
+
+        let mut sizes: Vec<(u32, usize)> = m
+            .iter_index(&parity)
+            .unwrap()
+            .map(|(a, entries)| (*a, entries.count()))
+            .collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![(0, 3), (1, 2)]);
+    }
+
+    #[test]
+    fn should_combine_index_lookups_with_query_builder() {
+        let mut m = IndexedMap::<u32, (bool, &'static str)>::new();
+        let active = m.add_index("active".to_string(), |_, v: &(bool, &str)| vec![v.0]);
+        let region = m.add_index("region".to_string(), |_, v: &(bool, &str)| vec![v.1]);
+        m.insert(1, (true, "eu"));
+        m.insert(2, (true, "us"));
+        m.insert(3, (false, "eu"));
+        m.insert(4, (false, "apac"));
+
+        let sorted = |keys: HashSet<&u32>| {
+            let mut keys: Vec<u32> = keys.into_iter().cloned().collect();
+            keys.sort();
+            keys
+        };
+        let q = m.query().with(&active, &true).and(&region, &"eu");
+        assert_eq!(sorted(q.keys().unwrap()), vec![1]);
+        let q = m
+            .query()
+            .with(&active, &true)
+            .and(&region, &"eu")
+            .or(&region, &"apac");
+        assert_eq!(sorted(q.keys().unwrap()), vec![1, 4]);
+        let q = m.query().with(&region, &"eu").and_not(&active, &true);
+        assert_eq!(q.entries().unwrap(), vec![(&3, &(false, "eu"))]);
+        assert!(m.query().keys().unwrap().is_empty());
+
+        let ghost: IndexId<u8> = IndexId {
+            name: "ghost".to_string(),
+            _value: PhantomData,
+        };
+        assert!(m
+            .query()
+            .with(&active, &true)
+            .or(&ghost, &0)
+            .keys()
+            .is_err());
+    }
+
+    #[test]
+    fn should_query_composite_index_by_tuple_or_prefix() {
+        let mut m = IndexedMap::<u32, (&'static str, &'static str)>::new();
+        let staff = m.add_composite_index("staff".to_string(), |_, v: &(&str, &str)| vec![*v]);
+        m.insert(1, ("eng", "dev"));
+        m.insert(2, ("eng", "lead"));
+        m.insert(3, ("ops", "dev"));
+        m.insert(4, ("eng", "dev"));
+
+        assert_eq!(m.filter_by_index(&staff, &("eng", "dev")).unwrap().len(), 2);
+        assert_eq!(m.filter_by_prefix(&staff, &"eng").unwrap().len(), 3);
+        assert!(m.filter_by_prefix(&staff, &"hr").unwrap().is_empty());
+
+        m.remove(&3);
+        assert!(m.filter_by_prefix(&staff, &"ops").unwrap().is_empty());
+        m.insert(2, ("ops", "lead"));
+        assert_eq!(m.filter_by_prefix(&staff, &"eng").unwrap().len(), 2);
+        assert_eq!(m.filter_by_prefix(&staff, &"ops").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn should_retain_drain_and_clear_with_indices() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for i in 0..10 {
+            m.insert(i, i);
+        }
+
+        m.retain(|k, _| *k < 6);
+        assert_eq!(m.len(), 6);
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(3));
+
+        m.capture_changes(16);
+        let mut drained = m.drain();
+        drained.sort();
+        assert_eq!(drained.len(), 6);
+        assert_eq!(drained[5], (5, 5));
+        assert_eq!(m.take_changes().events.len(), 6);
+        assert!(m.get_index(&parity).unwrap().is_empty());
+
+        m.insert(7, 7);
+        m.clear();
+        assert!(m.is_empty());
+        assert_eq!(m.keys_by_index(&parity, &1), None);
+        m.insert(8, 8);
+        assert_eq!(m.keys_by_index(&parity, &0).map(HashSet::len), Some(1));
+    }
+
+    #[test]
+    fn should_hash_entries_and_index_values_with_custom_hashers() {
+        use std::hash::{BuildHasherDefault, Hasher};
+
+        #[derive(Default)]
+        struct Fnv(u64);
+
+        impl Hasher for Fnv {
+            fn finish(&self) -> u64 {
+                self.0
+            }
+
+            fn write(&mut self, bytes: &[u8]) {
+                for &byte in bytes {
+                    self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+                }
+            }
+        }
+
+        type FnvState = BuildHasherDefault<Fnv>;
+        let mut m = IndexedMap::<u32, u32, _, _>::with_hasher(FnvState::default());
+        let parity =
+            m.add_index_with_hasher("parity".to_string(), FnvState::default(), |_, v: &u32| {
+                vec![v % 2]
+            });
+        for i in 0..10 {
+            m.insert(i, i);
+        }
+        assert_eq!(m.get(&3), Some(&3));
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(5));
+    }
+
+    #[test]
+    fn should_reject_entries_a_fallible_index_fails_on() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        let port = m
+            .add_fallible_index("port".to_string(), |_, v: &&str| {
+                v.parse::<u16>().map(|port| vec![port])
+            })
+            .unwrap();
+        let len = m.add_index("len".to_string(), |_, v: &&str| vec![v.len()]);
+        m.insert(1, "8080");
+
+        match m.try_insert(2, "http") {
+            Err(InsertError::IndexFailed {
+                key, index, reason, ..
+            }) => {
+                assert_eq!((key, index.as_str()), (2, "port"));
+                assert!(reason.downcast_ref::<std::num::ParseIntError>().is_some());
+            }
+            other => panic!("unexpected {:?}", other.is_ok()),
+        }
+        assert_eq!(m.len(), 1);
+        assert_eq!(m.keys_by_index(&len, &4).map(HashSet::len), Some(1));
+        assert!(m.try_insert(3, "443").is_ok());
+        assert_eq!(m.keys_by_index(&port, &443).map(HashSet::len), Some(1));
+    }
+
+    #[test]
+    #[cfg(feature = "concurrent")]
+    fn should_share_a_concurrent_map_between_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let m = Arc::new(concurrent::IndexedMap::<u32, u32>::with_shards(4));
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        m.insert(t * 100 + i, i);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(m.len(), 400);
+        assert_eq!(
+            m.keys_by_index(&parity, &0).map(|keys| keys.len()),
+            Some(200)
+        );
+        m.insert(1, 2);
+        assert_eq!(m.remove(&3), Some(3));
+        let odd = m.filter_by_index(&parity, &1).unwrap();
+        assert_eq!(odd.len(), 198);
+        assert!(!odd.contains_key(&1));
+    }
+
+    #[test]
+    fn should_notify_subscribers_of_map_and_index_changes() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let index_events = Rc::new(RefCell::new(Vec::new()));
+        let sink = events.clone();
+        m.on_change(move |event| sink.borrow_mut().push(event));
+        let sink = index_events.clone();
+        m.on_index_change(&parity, move |event| sink.borrow_mut().push(event))
+            .unwrap();
+
+        m.insert(1, 10);
+        m.update(&1, |v| *v += 1);
+        m.remove(&1);
+
+        assert_eq!(
+            events.take(),
+            vec![
+                MapEvent::Insert { key: 1, value: 10 },
+                MapEvent::Update {
+                    key: 1,
+                    old: 10,
+                    new: 11
+                },
+                MapEvent::Remove { key: 1, value: 11 },
+            ]
+        );
+        assert_eq!(
+            index_events.take(),
+            vec![
+                IndexEvent::Added {
+                    index_key: 0,
+                    key: 1
+                },
+                IndexEvent::Removed {
+                    index_key: 0,
+                    key: 1
+                },
+                IndexEvent::Added {
+                    index_key: 1,
+                    key: 1
+                },
+                IndexEvent::Removed {
+                    index_key: 1,
+                    key: 1
+                },
+            ]
+        );
+        assert!(m
+            .on_index_change(&IndexId::<u32>::new("missing".to_string()), |_| {})
+            .is_err());
+    }
+
+    #[test]
+    fn should_insert_batches_and_collect_into_maps() {
+        let mut m: IndexedMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+
+        assert_eq!(
+            m.insert_batch(vec![(10, 10), (11, 11), (1, 2), (1, 4)]),
+            Ok(4)
+        );
+        m.extend(vec![(12, 13)]);
+        assert_eq!(m.len(), 13);
+        assert_eq!(m.keys_by_index(&parity, &0).map(HashSet::len), Some(7));
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(6));
+
+        m.set_capacity_limit(Some(14));
+        match m.insert_batch(vec![(13, 13), (14, 14), (15, 15)]) {
+            Err(InsertError::CapacityExceeded { key, .. }) => assert_eq!(key, 14),
+            other => panic!("unexpected {:?}", other.is_ok()),
+        }
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(7));
+    }
+
+    #[test]
+    fn should_look_up_buckets_by_borrowed_index_keys() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let by_name = m.add_index("name".to_string(), |_, v: &String| vec![v.clone()]);
+        m.insert(1, "alice".to_string());
+        m.insert(2, "bob".to_string());
+
+        assert_eq!(
+            m.keys_by_index(&by_name, "alice").map(HashSet::len),
+            Some(1)
+        );
+        assert_eq!(
+            m.filter_by_index(&by_name, "bob")
+                .map(|entries| entries.len()),
+            Some(1)
+        );
+        assert_eq!(m.selectivity(&by_name, "carol"), 0.0);
+    }
+
+    #[test]
+    fn should_register_spec_indices_once() {
+        struct ByLength;
+
+        impl IndexSpec<u32, String> for ByLength {
+            const NAME: &'static str = "length";
+            type Value = usize;
+
+            fn index(_: &u32, value: &String) -> Vec<usize> {
+                vec![value.len()]
+            }
+        }
+
+        let mut m = IndexedMap::<u32, String>::new();
+        m.insert(1, "one".to_string());
+        let by_length = m.add_spec_index::<ByLength>().unwrap();
+        m.insert(2, "two".to_string());
+        m.insert(3, "three".to_string());
+
+        assert_eq!(m.keys_by_spec(by_length, &3).map(HashSet::len), Some(2));
+        assert_eq!(m.filter_by_spec(by_length, &5).map(|e| e.len()), Some(1));
+        let index_id = m.spec_index_id(by_length);
+        assert_eq!(m.keys_by_index(&index_id, &5).map(HashSet::len), Some(1));
+        assert!(m.add_spec_index::<ByLength>().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn should_derive_index_accessors_from_fields() {
+        #[derive(Clone, Indexed)]
+        struct User {
+            #[indexed]
+            email: String,
+            #[indexed(sorted)]
+            age: u32,
+            #[allow(dead_code)]
+            name: String,
+        }
+
+        let mut users = UserIndexedMap::<u32>::new();
+        for (id, email, age) in [(1, "a@x", 30), (2, "b@x", 40), (3, "c@x", 50)] {
+            users.insert(
+                id,
+                User {
+                    email: email.to_string(),
+                    age,
+                    name: String::new(),
+                },
+            );
+        }
+
+        assert_eq!(users.by_email("b@x").map(|e| e.len()), Some(1));
+        assert_eq!(users.range_by_age(35..).map(|e| e.len()), Some(2));
+        assert_eq!(users.by_age(&50).map(|e| e.len()), Some(1));
+        let email_index = users.email_index().clone();
+        users.remove(&2);
+        assert_eq!(users.keys_by_index(&email_index, "b@x"), None);
+    }
+
+    #[test]
+    fn should_aggregate_over_index_buckets() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        (1..=5).for_each(|i| {
+            m.insert(i, i * 3);
+        });
+
+        assert_eq!(m.count_by_index(&parity, &1), Some(3));
+        assert_eq!(m.count_by_index(&parity, &7), Some(0));
+        let mut counts: Vec<(u32, usize)> = m
+            .group_counts(&parity)
+            .unwrap()
+            .map(|(a, n)| (*a, n))
+            .collect();
+        counts.sort();
+        assert_eq!(counts, vec![(0, 2), (1, 3)]);
+        assert_eq!(
+            m.fold_by_index(&parity, &0, 0, |sum, (_, v)| sum + v),
+            Some(18)
+        );
+        let missing = IndexId::<u32>::new("missing".to_string());
+        assert_eq!(m.count_by_index(&missing, &0), None);
+    }
+
+    #[test]
+    fn should_query_shared_snapshots_from_other_threads() {
+        use std::thread;
+
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        (0..10).for_each(|i| {
+            m.insert(i, i);
+        });
+        let snapshot = m.shared_snapshot().with_index(&parity).build();
+        m.insert(10, 10);
+        m.remove(&0);
+
+        let reader = snapshot.clone();
+        let by_parity = parity.clone();
+        let evens = thread::spawn(move || reader.filter_by_index(&by_parity, &0).map(|e| e.len()))
+            .join()
+            .unwrap();
+        assert_eq!(evens, Some(5));
+        assert_eq!(snapshot.len(), 10);
+        assert_eq!(snapshot.get(&0), Some(&0));
+        assert_eq!(m.keys_by_index(&parity, &0).map(HashSet::len), Some(5));
+    }
+
+    #[test]
+    fn should_index_lazily_until_refreshed() {
+        let mut map: IndexedMap<u32, String> = IndexedMap::new();
+        map.insert(1, "a".to_string());
+        let by_value = map.add_lazy_index("by_value".to_string(), |_, v: &String| vec![v.clone()]);
+        assert!(map.keys_by_index(&by_value, "a").is_none());
+        assert_eq!(
+            map.try_get_index(&by_value).err(),
+            Some(QueryError::Stale {
+                name: "by_value".to_string()
+            })
+        );
+        assert_eq!(map.refresh_index(&by_value), Ok(1));
+        assert_eq!(map.keys_by_index(&by_value, "a").map(HashSet::len), Some(1));
+
+        map.insert(1, "b".to_string());
+        map.insert(2, "b".to_string());
+        map.insert(3, "c".to_string());
+        map.remove(&3);
+        assert!(map.keys_by_index(&by_value, "b").is_none());
+        assert_eq!(map.refresh_index(&by_value), Ok(2));
+        assert!(map.keys_by_index(&by_value, "a").is_none());
+        assert_eq!(map.keys_by_index(&by_value, "b").map(HashSet::len), Some(2));
+        assert!(map.keys_by_index(&by_value, "c").is_none());
+        assert_eq!(map.refresh_index(&by_value), Ok(0));
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn should_build_and_scan_indices_in_parallel() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        (0..1000).for_each(|i| {
+            m.insert(i, i);
+        });
+        let by_mod = m.par_add_index("by_mod".to_string(), |_, v: &u32| vec![v % 10]);
+        assert_eq!(m.keys_by_index(&by_mod, &3).map(HashSet::len), Some(100));
+        m.insert(1000, 1000);
+        assert_eq!(m.keys_by_index(&by_mod, &0).map(HashSet::len), Some(101));
+        let statistics = m.index_statistics(&by_mod).unwrap();
+        assert_eq!((statistics.distinct, statistics.postings), (10, 1001));
+        assert_eq!(statistics.heavy_hitters.first(), Some(&(0, 101)));
+
+        let mut doubled = m.par_iter_by_index(&by_mod, &3, |_, v| v * 2).unwrap();
+        doubled.sort();
+        assert_eq!(doubled.first(), Some(&6));
+        assert_eq!(doubled.len(), 100);
+        let large = m.par_filter_by_index(&by_mod, &3, |_, v| *v > 900).unwrap();
+        assert_eq!(large.len(), 10);
+        assert!(m.par_filter_by_index(&by_mod, &10, |_, _| true).is_none());
+    }
+
+    #[test]
+    fn should_undo_a_transaction_that_fails_to_commit() {
+        let mut m = IndexedMap::<u32, String>::new();
+        let email = m
+            .add_unique_index("email".to_string(), |_, v: &String| vec![v.clone()])
+            .unwrap();
+        m.insert(1, "a@x".to_string());
+        m.insert(2, "b@x".to_string());
+
+        let mut txn = m.transaction();
+        txn.insert(3, "c@x".to_string())
+            .remove(1)
+            .insert(2, "d@x".to_string())
+            .insert(4, "c@x".to_string());
+        assert_eq!(txn.len(), 4);
+        match txn.commit() {
+            Err(InsertError::UniqueViolation { key, existing, .. }) => {
+                assert_eq!((key, existing), (4, 3))
+            }
+            _ => panic!("expected a unique violation"),
+        }
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.get(&1), Some(&"a@x".to_string()));
+        assert_eq!(m.get(&2), Some(&"b@x".to_string()));
+        assert_eq!(m.keys_by_index(&email, "c@x"), None);
+        assert_eq!(m.keys_by_index(&email, "d@x"), None);
+        assert_eq!(m.keys_by_index(&email, "a@x").map(HashSet::len), Some(1));
+
+        let mut txn = m.transaction();
+        txn.insert(5, "e@x".to_string());
+        txn.rollback();
+        assert!(!m.contains_key(&5));
+
+        let mut txn = m.transaction();
+        txn.remove(1).insert(3, "a@x".to_string());
+        assert!(txn.commit().is_ok());
+        assert_eq!(
+            m.keys_by_index(&email, "a@x").map(|keys| keys.contains(&3)),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn should_expire_stale_entries_from_every_index() {
+        use std::time::Duration;
+
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        m.insert_with_ttl(1, 1, Duration::from_secs(0));
+        m.insert_with_ttl(3, 3, Duration::from_secs(0));
+        m.insert_with_ttl(5, 5, Duration::from_secs(3600));
+        m.insert(7, 7);
+        m.pin(&3);
+        assert!(m.expires_at(&1).is_some());
+        assert_eq!(m.expires_at(&7), None);
+
+        assert_eq!(m.expire_stale(), vec![(1, 1)]);
+        assert!(!m.contains_key(&1));
+        assert_eq!(m.expires_at(&1), None);
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(3));
+
+        m.unpin(&3);
+        assert_eq!(m.expire_stale(), vec![(3, 3)]);
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(2));
+        assert!(m.expire_stale().is_empty());
+    }
+
+    #[test]
+    fn should_rank_text_search_results_by_matching_tokens() {
+        let mut m = IndexedMap::<u32, String>::new();
+        m.insert(1, "The quick brown fox".to_string());
+        m.insert(2, "a quick dog".to_string());
+        m.insert(3, "lazy Dog".to_string());
+        let words = m.add_text_index(
+            "words".to_string(),
+            Tokenizer::whitespace().lowercase(),
+            |_, v: &String| v.clone(),
+        );
+        let keys = |results: Vec<(&u32, &String, usize)>| -> Vec<(u32, usize)> {
+            results.into_iter().map(|(k, _, n)| (*k, n)).collect()
+        };
+        let all = m.search_text(&words, "Quick DOG", TextMatch::All).unwrap();
+        assert_eq!(keys(all), vec![(2, 2)]);
+        let mut any = keys(m.search_text(&words, "quick dog", TextMatch::Any).unwrap());
+        any.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        assert_eq!(any, vec![(2, 2), (1, 1), (3, 1)]);
+
+        let grams = m.add_text_index(
+            "grams".to_string(),
+            Tokenizer::ngrams(3),
+            |_, v: &String| v.clone(),
+        );
+        let found = m.search_text(&grams, "uic", TextMatch::All).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(m
+            .search_text(&grams, "uick ro", TextMatch::All)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            keys(m.search_text(&grams, "row", TextMatch::All).unwrap()),
+            vec![(1, 1)]
+        );
+    }
+
+    #[test]
+    fn should_describe_bucket_sizes_and_memory_of_indices() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let skewed = m.add_index("skewed".to_string(), |_, v: &u32| vec![*v.min(&3)]);
+        m.add_index("all".to_string(), |_, _: &u32| vec![()]);
+        (0..10).for_each(|i| {
+            m.insert(i, i);
+        });
+        assert_eq!(m.index_names(), vec!["all", "skewed"]);
+        assert_eq!(m.index_len(&skewed), Some(4));
+        let mut sizes: Vec<(u32, usize)> = m
+            .bucket_sizes(&skewed)
+            .unwrap()
+            .map(|(a, n)| (*a, n))
+            .collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![(0, 1), (1, 1), (2, 1), (3, 7)]);
+        let histogram: Vec<(usize, usize)> = m
+            .bucket_size_histogram(&skewed)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(histogram, vec![(1, 3), (7, 1)]);
+        assert!(m.index_memory(&skewed).unwrap() > 0);
+        let missing = IndexId::<u32>::new("missing".to_string());
+        assert_eq!(m.index_len(&missing), None);
+        assert_eq!(m.index_memory(&missing), None);
+    }
+
+    #[test]
+    fn should_iterate_clone_and_compare_maps() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        let sorted = m.add_sorted_index("value".to_string(), |_, v: &u32| vec![*v]);
+        (0..4).for_each(|i| {
+            m.insert(i, i);
+        });
+        let mut entries: Vec<(u32, u32)> = (&m).into_iter().map(|(k, v)| (*k, *v)).collect();
+        entries.sort();
+        assert_eq!(entries, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+        let mut copy = m.clone();
+        assert_eq!(copy, m);
+        assert_eq!(copy.keys_by_index(&parity, &0).map(HashSet::len), Some(2));
+        copy.insert(4, 4);
+        assert_ne!(copy, m);
+        assert_eq!(m.keys_by_index(&parity, &0).map(HashSet::len), Some(2));
+        assert_eq!(copy.keys_by_index(&parity, &0).map(HashSet::len), Some(3));
+        let top = copy.range_by_index(&sorted, 3..).unwrap();
+        assert_eq!(top.len(), 2);
+
+        let mut values = copy.iter_mut_reindex();
+        while let Some((key, value)) = values.next_entry() {
+            if *key != 0 {
+                *value *= 2;
+            }
+        }
+        drop(values);
+        assert_eq!(copy.keys_by_index(&parity, &1), None);
+        assert_eq!(copy.keys_by_index(&parity, &0).map(HashSet::len), Some(5));
+        assert_eq!(copy.range_by_index(&sorted, 7..).map(|r| r.len()), Some(1));
+
+        assert!(format!("{:?}", m).contains("indices: [\"parity\", \"value\"]"));
+        let mut owned: Vec<(u32, u32)> = m.into_iter().collect();
+        owned.sort();
+        assert_eq!(owned.len(), 4);
+    }
+
+    #[test]
+    fn should_view_an_index_as_a_map() {
+        let mut m = IndexedMap::<u32, (String, u32)>::new();
+        let team = m.add_index("team".to_string(), |_, v: &(String, u32)| vec![v.0.clone()]);
+        let badge = m
+            .add_unique_index("badge".to_string(), |_, v: &(String, u32)| vec![v.1])
+            .unwrap();
+        m.insert(1, ("red".to_string(), 10));
+        m.insert(2, ("red".to_string(), 20));
+        m.insert(3, ("blue".to_string(), 30));
+
+        let view = m.view_by_index(&team).unwrap();
+        assert_eq!(view.len(), 2);
+        assert!(view.contains_key("blue"));
+        assert_eq!(view.get("red").map(|entries| entries.len()), Some(2));
+        assert_eq!(view.get("green"), None);
+        assert_eq!(
+            view.iter().map(|(_, entries)| entries.len()).sum::<usize>(),
+            3
+        );
+
+        let badges = m.view_by_unique_index(&badge).unwrap();
+        assert_eq!(badges.get(&30).map(|(k, _)| *k), Some(3));
+        assert_eq!(badges.iter().count(), 3);
+
+        m.remove(&3);
+        let view = m.view_by_index(&team).unwrap();
+        assert!(!view.contains_key("blue"));
+    }
+
+    #[test]
+    #[cfg(feature = "wal")]
+    fn should_recover_a_durable_map_from_its_log() {
+        use std::fs::{self, OpenOptions};
+        use std::io::Write;
+
+        struct Codec;
+
+        impl WalCodec<u32, String> for Codec {
+            fn encode_key(&self, key: &u32) -> Vec<u8> {
+                key.to_le_bytes().to_vec()
+            }
+
+            fn decode_key(&self, bytes: &[u8]) -> Option<u32> {
+                Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+
+            fn encode_value(&self, value: &String) -> Vec<u8> {
+                value.as_bytes().to_vec()
+            }
+
+            fn decode_value(&self, bytes: &[u8]) -> Option<String> {
+                String::from_utf8(bytes.to_vec()).ok()
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("indexed_map_wal_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let by_len = IndexId::<usize>::new("len".to_string());
+        let register = |m: &mut IndexedMap<u32, String>| {
+            m.add_index("len".to_string(), |_, v: &String| vec![v.len()]);
+        };
+
+        let mut durable = DurableMap::open(&path, Codec, register).unwrap();
+        durable.insert(1, "a".to_string()).unwrap();
+        durable.insert(2, "bb".to_string()).unwrap();
+        durable.insert(3, "cc".to_string()).unwrap();
+        durable.remove(&1).unwrap();
+        durable.insert(2, "b".to_string()).unwrap();
+        drop(durable);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[9, 0, 0])
+            .unwrap();
+
+        let mut durable = DurableMap::open(&path, Codec, register).unwrap();
+        assert_eq!(durable.len(), 2);
+        assert_eq!(durable.get(&2), Some(&"b".to_string()));
+        assert_eq!(
+            durable.keys_by_index(&by_len, &2).map(HashSet::len),
+            Some(1)
+        );
+        durable.compact().unwrap();
+        durable.insert(4, "dddd".to_string()).unwrap();
+        drop(durable);
+
+        let mut durable = DurableMap::open(&path, Codec, register).unwrap();
+        assert_eq!(durable.len(), 3);
+        assert_eq!(
+            durable.keys_by_index(&by_len, &4).map(HashSet::len),
+            Some(1)
+        );
+        durable.map_mut().set_capacity_limit(Some(3));
+        match durable.insert(5, "e".to_string()) {
+            Err(WriteError::Rejected(InsertError::CapacityExceeded { key: 5, .. })) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(durable);
+
+        let unique = |m: &mut IndexedMap<u32, String>| {
+            m.add_unique_index("parity".to_string(), |_, v: &String| vec![v.len() % 2])
+                .unwrap();
+        };
+        let durable = DurableMap::open(&path, Codec, register).unwrap();
+        assert_eq!(durable.len(), 3);
+        drop(durable);
+        assert_eq!(
+            DurableMap::open(&path, Codec, unique)
+                .err()
+                .map(|err| err.kind()),
+            Some(std::io::ErrorKind::InvalidData)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_keep_follower_in_sync_with_journaled_events() {
+        let mut leader = IndexedMap::<u32, &str>::new();
+        leader.enable_journal(3);
+        leader.insert(1, "one");
+        leader.insert(2, "two");
+        leader.insert(1, "uno");
+        leader.remove(&2);
+        assert_eq!(leader.last_sequence(), 4);
+        assert_eq!(leader.events_since(0), None);
+
+        let mut follower = IndexedMap::<u32, &str>::new();
+        let index_id = follower.add_index("length".to_string(), |_, v: &&str| vec![v.len()]);
+        follower.insert(1, "one");
+        follower.insert(2, "two");
+        let events = leader.events_since(1).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            follower.apply_event(events[1].clone()),
+            Err(ApplyError::Gap {
+                expected: 1,
+                found: 3
+            })
+        );
+        follower
+            .apply_event(SequencedEvent {
+                sequence: 1,
+                event: ChangeEvent::Insert {
+                    key: 1,
+                    value: "one",
+                },
+            })
+            .unwrap();
+        for event in events.iter().cloned() {
+            assert_eq!(follower.apply_event(event), Ok(true));
+        }
+        assert_eq!(follower.apply_event(events[2].clone()), Ok(false));
+        assert_eq!(follower.applied_sequence(), 4);
+        assert_eq!(follower.get(&1), Some(&"uno"));
+        assert!(!follower.contains_key(&2));
+        assert_eq!(
+            follower.keys_by_index(&index_id, &3).map(|x| x.len()),
+            Some(1)
+        );
+        assert_eq!(leader.events_since(4), Some(Vec::new()));
+    }
+
+    #[test]
+    fn should_evict_least_recently_used_entries_from_every_index() {
+        use std::cell::RefCell;
+
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let sink = evicted.clone();
+        let policy = CapacityPolicy::max_entries(3)
+            .with_max_weight(10, |_, v: &String| v.len())
+            .on_evict(move |key, _| sink.borrow_mut().push(key));
+        let mut m = IndexedMap::<u32, String>::with_capacity_policy(policy);
+        let index_id = m.add_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        m.insert(1, "a".to_string());
+        m.insert(2, "b".to_string());
+        m.insert(3, "c".to_string());
+        assert!(m.pin(&1));
+        assert_eq!(m.get_and_touch(&2), Some(&"b".to_string()));
+        m.insert(4, "d".to_string());
+        assert_eq!(evicted.replace(Vec::new()), [3]);
+        assert_eq!(m.keys_by_index(&index_id, &1).map(|x| x.len()), Some(3));
+
+        m.insert(5, "eeeeeeee".to_string());
+        assert_eq!(evicted.replace(Vec::new()), [2]);
+        assert_eq!(m.total_weight(), Some(10));
+        assert!(m.contains_key(&1) && m.contains_key(&5));
+        assert_eq!(m.keys_by_index(&index_id, &1).map(|x| x.len()), Some(2));
+    }
+
+    #[test]
+    fn should_reindex_only_affected_indices_when_patching() {
+        use std::cell::Cell;
+
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut m = IndexedMap::<u32, (u32, u32)>::new();
+        let by_first = m.add_index("first".to_string(), |_, v: &(u32, u32)| vec![v.0]);
+        let by_second = m.add_index("second".to_string(), move |_, v: &(u32, u32)| {
+            counter.set(counter.get() + 1);
+            vec![v.1]
+        });
+        m.insert(1, (10, 20));
+        calls.set(0);
+
+        assert_eq!(m.patch(&1, &["first"], |v| v.0 = 11), Ok(Some(())));
+        // Debug builds run the left-out indices once to check that the patch did not affect them.
+        assert_eq!(calls.get(), if cfg!(debug_assertions) { 1 } else { 0 });
+        assert_eq!(m.keys_by_index(&by_first, &11).map(|x| x.len()), Some(1));
+        assert_eq!(m.keys_by_index(&by_first, &10), None);
+        assert_eq!(m.keys_by_index(&by_second, &20).map(|x| x.len()), Some(1));
+        assert_eq!(m.patch(&2, &["first"], |v| v.0 = 12), Ok(None));
+        assert_eq!(
+            m.patch(&1, &["frist"], |v| v.0 = 12),
+            Err(QueryError::MissingIndex {
+                name: "frist".to_string()
+            })
+        );
+        assert_eq!(m.get(&1), Some(&(11, 20)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "patch left out index `second`")]
+    fn should_catch_patches_that_leave_out_affected_indices_in_debug_builds() {
+        let mut m = IndexedMap::<u32, (u32, u32)>::new();
+        m.add_index("first".to_string(), |_, v: &(u32, u32)| vec![v.0]);
+        m.add_index("second".to_string(), |_, v: &(u32, u32)| vec![v.1]);
+        m.insert(1, (10, 20));
+        let _ = m.patch(&1, &["first"], |v| v.1 = 21);
+    }
+
+    #[test]
+    fn should_keep_insertion_order_and_indices_in_ordered_map() {
+        let mut m = OrderedIndexedMap::<&str, u32>::ordered();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        for (key, value) in [("c", 1), ("a", 2), ("d", 3), ("b", 4)] {
+            m.insert(key, value);
+        }
+        m.insert("a", 6);
+        assert_eq!(m.get_at(1), Some((&"a", &6)));
+        assert_eq!(m.get_index_of(&"b"), Some(3));
+
+        assert_eq!(m.swap_remove(&"c"), Some(1));
+        assert_eq!(
+            m.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+            ["b", "a", "d"]
+        );
+        m.remove(&"b");
+        assert_eq!(m.iter().map(|(k, _)| *k).collect::<Vec<_>>(), ["a", "d"]);
+        assert_eq!(m.swap_remove_at(0), Some(("a", 6)));
+        assert_eq!(m.get_index_of(&"d"), Some(0));
+        assert_eq!(m.keys_by_index(&parity, &0), None);
+        assert_eq!(m.keys_by_index(&parity, &1).map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn should_list_top_and_bottom_entries_of_sorted_index() {
+        let mut m = IndexedMap::<&str, u32>::new();
+        let score = m.add_sorted_index("score".to_string(), |_, v: &u32| vec![*v]);
+        for (key, value) in [("a", 30), ("b", 10), ("c", 50), ("d", 20), ("e", 40)] {
+            m.insert(key, value);
+        }
+        fn keys<'a>(entries: Vec<(&u32, &&'a str, &u32)>) -> Vec<&'a str> {
+            entries.into_iter().map(|(_, k, _)| *k).collect()
+        }
+        assert_eq!(keys(m.top_k(&score, 2).unwrap()), ["c", "e"]);
+        assert_eq!(keys(m.bottom_k(&score, 3).unwrap()), ["b", "d", "a"]);
+        assert_eq!(m.top_k(&score, 10).map(|x| x.len()), Some(5));
+        let descending: Vec<u32> = m
+            .iter_sorted(&score)
+            .unwrap()
+            .rev()
+            .map(|(a, _, _)| *a)
+            .collect();
+        assert_eq!(descending, [50, 40, 30, 20, 10]);
+    }
+
+    #[test]
+    fn should_query_key_indices_while_value_is_borrowed() {
+        let mut m = IndexedMap::<String, u32>::new();
+        let tenant = m.add_key_index("tenant".to_string(), |k: &String| {
+            k.split('/')
+                .next()
+                .map(str::to_string)
+                .into_iter()
+                .collect()
+        });
+        m.insert("acme/1".to_string(), 1);
+        m.insert("acme/2".to_string(), 2);
+        m.insert("globex/1".to_string(), 3);
+        m.insert("acme/1".to_string(), 10);
+
+        let mut acme: Vec<&String> = m.keys_matching(&tenant, "acme").unwrap().collect();
+        acme.sort();
+        assert_eq!(acme, ["acme/1", "acme/2"]);
+        let siblings = m.update_with_key_indices(&"acme/2".to_string(), |value, keys| {
+            *value += 1;
+            keys.keys_matching(&tenant, "acme").unwrap().count()
+        });
+        assert_eq!(siblings, Some(2));
+        m.remove(&"acme/1".to_string());
+        assert_eq!(
+            m.keys_matching(&tenant, "acme").map(Iterator::count),
+            Some(1)
+        );
+        assert_eq!(
+            m.clone()
+                .keys_matching(&tenant, "globex")
+                .map(Iterator::count),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn should_apply_capacity_policy_to_batch_inserts() {
+        let mut m = IndexedMap::<u32, u32>::with_capacity_policy(CapacityPolicy::max_entries(3));
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        assert_eq!(m.insert_batch((0..10).map(|i| (i, i))).unwrap(), 10);
+        assert_eq!(m.len(), 3);
+        assert!(m.contains_key(&7) && m.contains_key(&8) && m.contains_key(&9));
+        m.insert(100, 100);
+        assert_eq!(m.len(), 3);
+        assert!(m.contains_key(&100) && !m.contains_key(&7));
+        assert_eq!(m.keys_by_index(&parity, &0).map(|x| x.len()), Some(2));
+        assert_eq!(m.keys_by_index(&parity, &1).map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn should_restore_evicted_entries_when_a_transaction_fails() {
+        let mut m = IndexedMap::<u32, String>::with_capacity_policy(CapacityPolicy::max_entries(2));
+        m.add_unique_index("email".to_string(), |_, v: &String| vec![v.clone()])
+            .unwrap();
+        m.insert(1, "a@x".to_string());
+        m.insert(2, "b@x".to_string());
+
+        let mut txn = m.transaction();
+        txn.insert(3, "c@x".to_string())
+            .insert(4, "b@x".to_string());
+        assert!(txn.commit().is_err());
+        let mut keys: Vec<u32> = m.iter().map(|(k, _)| *k).collect();
+        keys.sort();
+        assert_eq!(keys, [1, 2]);
+
+        let mut txn = m.transaction();
+        txn.insert(3, "c@x".to_string());
+        assert!(txn.commit().is_ok());
+        assert_eq!(m.len(), 2);
+        assert!(!m.contains_key(&1));
+    }
+
+    #[test]
+    fn should_restore_soft_removed_entries_when_a_transaction_fails() {
+        let mut m = IndexedMap::<u32, String>::new();
+        m.add_unique_index("email".to_string(), |_, v: &String| vec![v.clone()])
+            .unwrap();
+        m.insert(1, "a@x".to_string());
+        m.insert(2, "b@x".to_string());
+        assert!(m.soft_remove(&1));
+
+        let mut txn = m.transaction();
+        txn.insert(1, "c@x".to_string())
+            .insert(3, "b@x".to_string());
+        assert!(txn.commit().is_err());
+        assert!(m.is_soft_removed(&1));
+        assert!(!m.contains_key(&1));
+        assert_eq!(m.restore(&1), Ok(true));
+        assert_eq!(m.get(&1), Some(&"a@x".to_string()));
+    }
+
+    #[test]
+    fn should_restore_pins_expiry_and_metadata_when_a_transaction_fails() {
+        use std::time::Duration;
+
+        let mut m = IndexedMap::<u32, String>::new();
+        m.add_unique_index("email".to_string(), |_, v: &String| vec![v.clone()])
+            .unwrap();
+        m.enable_metadata();
+        m.insert_with_ttl(1, "a@x".to_string(), Duration::from_secs(60));
+        m.insert(1, "a@y".to_string());
+        m.insert(2, "b@x".to_string());
+        assert!(m.pin(&1));
+        let expiry = m.expires_at(&1);
+        let metadata = m.metadata(&1).cloned();
+
+        let mut txn = m.transaction();
+        txn.remove(1).insert(3, "b@x".to_string());
+        assert!(txn.commit().is_err());
+        assert_eq!(m.get(&1), Some(&"a@y".to_string()));
+        assert!(m.is_pinned(&1));
+        assert_eq!(m.expires_at(&1), expiry);
+        assert_eq!(m.metadata(&1).cloned(), metadata);
+        assert_eq!(metadata.map(|m| m.update_count), Some(1));
+    }
+
+    #[test]
+    fn should_reindex_key_derived_indices_on_rename() {
+        let mut m = IndexedMap::<(&str, u32), &str>::new();
+        let tenant = m.add_prefix_index("tenant".to_string());
+        m.insert(("acme", 1), "anvil");
+
+        assert_eq!(m.change_key(&("acme", 1), ("globex", 1)), Ok(true));
+        assert!(m.keys_by_index(&tenant, &"acme").is_none());
+        assert!(m
+            .keys_by_index(&tenant, &"globex")
+            .unwrap()
+            .contains(&("globex", 1)));
+    }
+
+    #[test]
+    fn should_reject_updates_that_break_unique_indices() {
+        let mut m = IndexedMap::<&str, &str>::new();
+        let email = m
+            .add_unique_index("email".to_string(), |_, &v| vec![v])
+            .unwrap();
+        m.insert("ann", "ann@example.com");
+        m.insert("bob", "bob@example.com");
+
+        match m.try_update(&"bob", |v| {
+            *v = "ann@example.com";
+            Ok::<_, ()>(())
+        }) {
+            Err(UpdateError::Rejected(InsertError::UniqueViolation {
+                key,
+                value,
+                existing,
+                ..
+            })) => assert_eq!((key, value, existing), ("bob", "ann@example.com", "ann")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(m.get("bob"), Some(&"bob@example.com"));
+        assert_eq!(
+            m.get_by_unique_index(&email, &"bob@example.com"),
+            Some((&"bob", &"bob@example.com"))
+        );
+        assert_eq!(
+            m.get_by_unique_index(&email, &"ann@example.com"),
+            Some((&"ann", &"ann@example.com"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unique index `email`")]
+    fn should_panic_when_an_infallible_update_breaks_a_unique_index() {
+        let mut m = IndexedMap::<&str, &str>::new();
+        m.add_unique_index("email".to_string(), |_, &v| vec![v])
+            .unwrap();
+        m.insert("ann", "ann@example.com");
+        m.insert("bob", "bob@example.com");
+        *m.get_mut(&"bob").unwrap() = "ann@example.com";
+    }
+
+    #[test]
+    fn should_hand_back_refused_entries_from_or_try_insert_with() {
+        let mut m = IndexedMap::<&str, u32>::with_capacity_limit(1);
+        m.entry("a").or_try_insert_with(|| 1).unwrap();
+        match m.entry("b").or_try_insert_with(|| 2) {
+            Err(InsertError::CapacityExceeded { key: "b", .. }) => {}
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("full map accepted a new key"),
+        }
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn should_hand_back_refused_entries_from_try_insert_with_ttl() {
+        use std::time::Duration;
+
+        let mut m = IndexedMap::<&str, u32>::with_capacity_limit(1);
+        m.insert("a", 1);
+        assert_eq!(
+            m.try_insert_with_ttl("c", 3, Duration::from_secs(60)),
+            Err(InsertError::CapacityExceeded {
+                key: "c",
+                value: 3,
+                limit: 1
+            })
+        );
+        assert_eq!(m.expires_at(&"c"), None);
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn should_forget_cached_misses_when_an_index_is_enabled_or_refreshed() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        m.enable_negative_cache(16);
+        let value = m.add_index("value".to_string(), |_, &v| vec![v]);
+        assert_eq!(m.keys_by_index_cached(&value, &7), None);
+        m.set_index_enabled(&value, false).unwrap();
+        m.insert(1, 7);
+        m.set_index_enabled(&value, true).unwrap();
+        assert!(m.keys_by_index_cached(&value, &7).unwrap().contains(&1));
+
+        let lazy = m.add_lazy_index("lazy".to_string(), |_, &v| vec![v]);
+        m.refresh_index(&lazy).unwrap();
+        assert_eq!(m.keys_by_index_cached(&lazy, &8), None);
+        m.insert(2, 8);
+        m.refresh_index(&lazy).unwrap();
+        assert!(m.keys_by_index_cached(&lazy, &8).unwrap().contains(&2));
+    }
+
+    #[test]
+    fn should_leave_disabled_indices_disabled_when_dropping_idle_ones() {
+        use std::time::Duration;
+
+        let mut m = IndexedMap::<u32, u32>::new();
+        let value = m.add_index("value".to_string(), |_, &v| vec![v]);
+        let parity = m.add_index("parity".to_string(), |_, &v| vec![v % 2]);
+        m.insert(1, 7);
+        m.set_index_enabled(&value, false).unwrap();
+
+        assert_eq!(
+            m.drop_idle_indices(Duration::from_secs(0)),
+            vec!["parity".to_string()]
+        );
+        assert!(!m.warm_index("value"));
+        assert_eq!(
+            m.try_get_index(&value),
+            Err(QueryError::Disabled {
+                name: "value".to_string()
+            })
+        );
+        m.insert(2, 8);
+        assert!(m.warm_index("parity"));
+        assert_eq!(m.keys_by_index(&parity, &0).map(HashSet::len), Some(1));
+        assert_eq!(m.get_index(&value), None);
+
+        m.drop_idle_indices(Duration::from_secs(0));
+        m.set_index_enabled(&parity, true).unwrap();
+        m.set_index_enabled(&value, true).unwrap();
+        assert_eq!(m.keys_by_index(&parity, &1).map(HashSet::len), Some(1));
+        assert_eq!(m.keys_by_index(&value, &8).map(HashSet::len), Some(1));
+    }
+
+    #[test]
+    fn should_reject_or_tolerate_corrupt_frozen_maps() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, &v| vec![v % 2]);
+        for i in 0..8 {
+            m.insert(i, i * 3);
+        }
+        let mut builder = m.freeze(|k| k.to_be_bytes().to_vec(), |v| v.to_be_bytes().to_vec());
+        builder
+            .add_index(&m, &parity, |p| p.to_be_bytes().to_vec())
+            .unwrap();
+        let bytes = builder.finish();
+
+        let query = |corrupt: &[u8]| {
+            if let Ok(frozen) = FrozenMap::new(corrupt) {
+                assert!(frozen.iter().count() <= frozen.len());
+                frozen.get(&3u32.to_be_bytes());
+                frozen.index_names().count();
+                frozen.filter_by_index("parity", &1u32.to_be_bytes());
+            }
+        };
+        for field in 0..6 {
+            for &value in &[
+                0,
+                1,
+                7,
+                8,
+                56,
+                bytes.len() as u64,
+                1 << 40,
+                u64::MAX / 8,
+                u64::MAX,
+            ] {
+                let mut corrupt = bytes.clone();
+                let at = 8 + field * 8;
+                corrupt[at..at + 8].copy_from_slice(&value.to_le_bytes());
+                query(&corrupt);
+            }
+        }
+        for at in 8..bytes.len() {
+            for &flip in &[0x01, 0x80, 0xff] {
+                let mut corrupt = bytes.clone();
+                corrupt[at] ^= flip;
+                query(&corrupt);
+            }
+        }
+
+        let mut corrupt = bytes.clone();
+        corrupt[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(FrozenMap::new(&corrupt).unwrap_err(), FrozenError::Corrupt);
+    }
+
+    #[test]
+    fn should_run_a_fallible_index_once_per_write_and_check_updates() {
+        use std::cell::Cell;
+
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        m.insert(1, "80");
+        let port = m
+            .add_fallible_index("port".to_string(), move |_, v: &&str| {
+                counted.set(counted.get() + 1);
+                v.parse::<u16>().map(|port| vec![port])
+            })
+            .unwrap();
+        assert_eq!(calls.replace(0), 1);
+        m.try_insert(2, "443").unwrap();
+        assert_eq!(calls.replace(0), 1);
+
+        match m.try_update(&2, |v| {
+            *v = "https";
+            Ok::<_, ()>(())
+        }) {
+            Err(UpdateError::Rejected(InsertError::IndexFailed { key: 2, .. })) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(m.get(&2), Some(&"443"));
+        assert_eq!(m.keys_by_index(&port, &443).map(HashSet::len), Some(1));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn should_refuse_a_fallible_index_that_fails_on_existing_entries() {
+        let mut m = IndexedMap::<u32, &'static str>::new();
+        m.insert(1, "http");
+        match m.add_fallible_index("port".to_string(), |_, v: &&str| {
+            v.parse::<u16>().map(|port| vec![port])
+        }) {
+            Err(DefinitionError::IndexFailed { name, reason }) => {
+                assert_eq!(name, "port");
+                assert!(reason.downcast_ref::<std::num::ParseIntError>().is_some());
+            }
+            other => panic!("unexpected result: {:?}", other.is_ok()),
+        }
+        assert!(m.index_names().is_empty());
+    }
+
+    #[test]
+    fn should_keep_shared_snapshots_apart_from_later_writes() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        (0..100).for_each(|i| {
+            m.insert(i, i);
+        });
+        let before = m.shared_snapshot().with_index(&parity).build();
+        m.insert(0, 1);
+        m.remove(&2);
+        m.insert(100, 100);
+        let after = m.shared_snapshot().with_index(&parity).build();
+
+        assert_eq!(before.len(), 100);
+        assert_eq!(before.get(&0), Some(&0));
+        assert!(before.keys_by_index(&parity, &0).unwrap().contains(&2));
+        assert_eq!(after.len(), 100);
+        assert_eq!(after.get(&0), Some(&1));
+        assert_eq!(after.get(&2), None);
+        assert!(after.keys_by_index(&parity, &1).unwrap().contains(&0));
+        assert!(!after.keys_by_index(&parity, &0).unwrap().contains(&2));
+        assert_eq!(after.iter().count(), 100);
+
+        let mut copy = m.clone();
+        copy.insert(5, 6);
+        let copied = copy.shared_snapshot().with_index(&parity).build();
+        assert_eq!(copied.get(&5), Some(&6));
+        assert!(copied.keys_by_index(&parity, &0).unwrap().contains(&5));
+        assert_eq!(m.shared_snapshot().build().get(&5), Some(&5));
+    }
+
+    #[test]
+    fn should_discard_soft_removed_entries_under_renamed_keys() {
+        let mut m = IndexedMap::<u32, &str>::new();
+        m.insert(1, "one");
+        m.insert(2, "two");
+        m.soft_remove(&2);
+        assert_eq!(m.change_key(&1, 2), Ok(true));
+        assert!(!m.is_soft_removed(&2));
+        assert_eq!(m.restore(&2), Ok(false));
+        assert_eq!(m.get(&2), Some(&"one"));
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn should_refuse_renames_that_break_a_unique_index() {
+        let mut m = IndexedMap::<u32, &str>::new();
+        let slot = m
+            .add_unique_index("slot".to_string(), |k: &u32, _| vec![k % 10])
+            .unwrap();
+        m.insert(1, "one");
+        m.insert(2, "two");
+        m.insert(3, "three");
+        assert_eq!(
+            m.change_key(&1, 12),
+            Err(RekeyError::UniqueViolation {
+                key: 12,
+                index: "slot".to_string(),
+                existing: 2,
+            })
+        );
+        assert_eq!(
+            m.rekey(|k, _| if *k == 1 { 13 } else { *k }),
+            Err(RekeyError::UniqueViolation {
+                key: 13,
+                index: "slot".to_string(),
+                existing: 3,
+            })
+        );
+        for k in 1..4 {
+            assert_eq!(
+                m.get_by_unique_index(&slot, &k).map(|(key, _)| *key),
+                Some(k)
+            );
+        }
+
+        assert_eq!(m.rekey(|k, _| if *k == 3 { 3 } else { 3 - k }), Ok(2));
+        assert_eq!(m.get_by_unique_index(&slot, &1), Some((&1, &"two")));
+        assert_eq!(m.get_by_unique_index(&slot, &2), Some((&2, &"one")));
+    }
+
+    #[test]
+    fn should_refuse_renames_a_fallible_index_fails_on() {
+        let mut m = IndexedMap::<u32, &str>::new();
+        let shard = m
+            .add_fallible_index("shard".to_string(), |k: &u32, _: &&str| {
+                if *k < 100 {
+                    Ok(vec![k % 2])
+                } else {
+                    Err("key out of range")
+                }
+            })
+            .unwrap();
+        m.insert(1, "one");
+        m.insert(2, "two");
+        match m.rekey(|k, _| k * 50) {
+            Err(RekeyError::IndexFailed { key, index, .. }) => {
+                assert_eq!(key, 100);
+                assert_eq!(index, "shard");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(m.get(&1), Some(&"one"));
+        assert!(m.keys_by_index(&shard, &1).unwrap().contains(&1));
+        assert!(m.keys_by_index(&shard, &0).unwrap().contains(&2));
+        assert!(!m.keys_by_index(&shard, &0).unwrap().contains(&50));
+        assert_eq!(m.change_key(&1, 51), Ok(true));
+        assert!(m.keys_by_index(&shard, &1).unwrap().contains(&51));
+    }
+
+    #[test]
+    fn should_stop_mirroring_into_shared_snapshots_once_none_is_left() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        (0..10).for_each(|i| {
+            m.insert(i, i);
+        });
+        let snapshot = m.shared_snapshot().with_index(&parity).build();
+        m.insert(10, 10);
+        assert!(m.changes.shared_entries().is_some());
+        drop(snapshot);
+        m.insert(11, 11);
+        m.remove(&0);
+        assert!(m.changes.shared_entries().is_none());
+
+        let snapshot = m.shared_snapshot().with_index(&parity).build();
+        assert_eq!(snapshot.len(), 11);
+        assert_eq!(snapshot.get(&0), None);
+        assert_eq!(snapshot.get(&11), Some(&11));
+        assert!(snapshot.keys_by_index(&parity, &1).unwrap().contains(&11));
+        assert!(!snapshot.keys_by_index(&parity, &0).unwrap().contains(&0));
+        assert!(snapshot.keys_by_index(&parity, &0).unwrap().contains(&10));
+    }
+
+    #[test]
+    fn should_collect_dead_weak_values_as_writes_happen() {
+        use std::sync::Arc;
+
+        let values: Vec<_> = (0..8).map(|i| Arc::new(i.to_string())).collect();
+        let mut m = IndexedMap::new();
+        let index_id = m.add_weak_index("length".to_string(), |_, v: &String| vec![v.len()]);
+        for (i, value) in values.iter().enumerate() {
+            m.insert(i, Arc::downgrade(value));
+        }
+        let mut values = values;
+        values.truncate(2);
+
+        assert_eq!(m.keys_by_index(&index_id, &1).map(|x| x.len()), Some(8));
+        let mut live = m.live_keys_by_index(&index_id, &1);
+        live.sort();
+        assert_eq!(live, vec![&0, &1]);
+
+        let kept = Arc::new("kept".to_string());
+        (0..8).for_each(|_| {
+            m.insert(100, Arc::downgrade(&kept));
+        });
+        assert_eq!(m.len(), 3);
+        assert_eq!(m.keys_by_index(&index_id, &1).map(|x| x.len()), Some(2));
+        assert_eq!(m.purge_dead(), 0);
+    }
+
+    #[test]
+    fn should_rebuild_stale_lazy_indices() {
+        let mut m = IndexedMap::<u32, u32>::new();
+        let parity = m.add_lazy_index("parity".to_string(), |_, v: &u32| vec![v % 2]);
+        m.insert(1, 1);
+        m.insert(2, 2);
+        assert!(m.try_get_index(&parity).is_err());
+
+        assert_eq!(m.rebuild_index(&parity), Ok(()));
+        assert!(m.try_get_index(&parity).is_ok());
+        assert_eq!(m.keys_by_index(&parity, &1).map(|x| x.len()), Some(1));
+        assert_eq!(m.refresh_index(&parity), Ok(0));
+    }
+
+    #[test]
+    fn should_run_unique_index_functions_once_per_insert() {
+        use std::cell::Cell;
+
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let mut m = IndexedMap::<u32, u32>::new();
+        let id = m
+            .add_unique_index("value".to_string(), move |_, v: &u32| {
+                counted.set(counted.get() + 1);
+                vec![*v]
+            })
+            .unwrap();
+        m.insert(1, 10);
+        m.insert(2, 20);
+        assert_eq!(calls.get(), 2);
+        assert!(m.try_insert(3, 10).is_err());
+        assert_eq!(calls.get(), 3);
+
+        assert_eq!(m.unique_conflicts(&4, &20), vec![("value", &2)]);
+        m.insert(4, 40);
+        assert_eq!(m.get_by_unique_index(&id, &40), Some((&4, &40)));
+        assert_eq!(m.get_by_unique_index(&id, &20), Some((&2, &20)));
+    }
+
+    #[test]
+    fn should_discard_soft_removed_entries_on_clear() {
+        let mut m = IndexedMap::<u32, &str>::new();
+        m.insert(1, "one");
+        m.insert(2, "two");
+        m.soft_remove(&2);
+        assert_eq!(m.drain(), vec![(1, "one")]);
+        assert!(!m.is_soft_removed(&2));
+        assert_eq!(m.restore(&2), Ok(false));
+
+        m.insert(3, "three");
+        m.soft_remove(&3);
+        m.clear();
+        assert_eq!(m.restore(&3), Ok(false));
+        assert!(m.is_empty());
+    }
 }
@@ -0,0 +1,103 @@
+use std::hash::Hash;
+
+use {Backing, IndexedMap, InsertError, KeyStorage};
+
+/// How far a `Loader` has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadProgress {
+    /// Entries inserted so far.
+    pub loaded: usize,
+    /// The number of entries the caller said to expect, if any.
+    pub total: Option<usize>,
+}
+
+/// Loads entries into a map chunk by chunk, keeping every index up to date as it goes.
+///
+/// Nothing is buffered between chunks, so memory stays bounded by the size of the chunk the
+/// caller hands over. Chunks can come from anywhere, including an async source: read the next
+/// batch, pass it to `load_chunk`, repeat.
+pub struct Loader<'a, K, V, KS, B>
+where
+    K: 'a + Eq + Hash,
+    V: 'a,
+    KS: 'a + KeyStorage<K>,
+    B: 'a,
+{
+    map: &'a mut IndexedMap<K, V, KS, B>,
+    progress: LoadProgress,
+    on_progress: Option<Box<dyn FnMut(LoadProgress) + 'a>>,
+}
+
+impl<'a, K, V, KS, B> Loader<'a, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Sets the total number of entries expected, which is reported back in `LoadProgress`.
+    pub fn expect(mut self, total: usize) -> Loader<'a, K, V, KS, B> {
+        self.progress.total = Some(total);
+        self.map
+            .inner
+            .reserve(total.saturating_sub(self.map.inner.len()));
+        self
+    }
+
+    /// Registers a callback invoked after every chunk.
+    pub fn on_progress<F>(mut self, on_progress: F) -> Loader<'a, K, V, KS, B>
+    where
+        F: 'a + FnMut(LoadProgress),
+    {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Inserts a chunk of entries. If the map's capacity limit is reached the offending entry is
+    /// returned in the error and the rest of the chunk is left unconsumed; everything loaded
+    /// before it stays in the map.
+    pub fn load_chunk<I>(&mut self, chunk: I) -> Result<LoadProgress, InsertError<K, V>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let chunk = chunk.into_iter();
+        self.map.inner.reserve(chunk.size_hint().0);
+        for (key, value) in chunk {
+            self.map.try_insert(key, value)?;
+            self.progress.loaded += 1;
+        }
+        if let Some(ref mut on_progress) = self.on_progress {
+            on_progress(self.progress);
+        }
+        Ok(self.progress)
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        self.progress
+    }
+
+    /// Ends the load, returning the final progress.
+    pub fn finish(self) -> LoadProgress {
+        self.progress
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Starts an incremental load into this map.
+    pub fn loader(&mut self) -> Loader<'_, K, V, KS, B> {
+        Loader {
+            map: self,
+            progress: LoadProgress {
+                loaded: 0,
+                total: None,
+            },
+            on_progress: None,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use {Backing, IndexId, IndexedMap, KeyStorage, StandbyReplica};
+
+/// The read and query surface of an indexed map, so application code can accept
+/// `&impl MapLike<K, V>` and tests can substitute a lightweight fake.
+///
+/// The entry accessors are named so that they do not shadow `len` and `get` of the backing map,
+/// which `IndexedMap` exposes through `Deref`.
+///
+/// `IndexedMap` and `StandbyReplica` implement it. `FrozenMap` cannot: it decodes its entries
+/// out of a byte buffer on every read, so it has no `K` or `V` to lend references to, and its
+/// indices are looked up by encoded bytes rather than by a typed `IndexId`. Nor can
+/// `concurrent::IndexedMap`, whose entries sit behind shard locks, so its reads hand back owned
+/// values instead of references that would outlive the lock guard.
+pub trait MapLike<K, V> {
+    fn entry_count(&self) -> usize;
+
+    fn lookup(&self, key: &K) -> Option<&V>;
+
+    /// Iterates every entry in no particular order.
+    fn entries<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a K, &'a V)> + 'a>;
+
+    /// Returns the entries indexed under `index_key`, or `None` if the index is unavailable.
+    fn filter_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Clone;
+
+    /// Returns the keys indexed under `index_key`, or `None` if the index is unavailable.
+    fn keys_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<Vec<&K>>
+    where
+        A: 'static + Eq + Hash + Clone;
+}
+
+impl<K, V, KS, B> MapLike<K, V> for IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn entry_count(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn lookup(&self, key: &K) -> Option<&V> {
+        self.inner.get(key)
+    }
+
+    fn entries<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a K, &'a V)> + 'a> {
+        Box::new(self.inner.iter().map(|(key, value)| (key.borrow(), value)))
+    }
+
+    fn filter_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        IndexedMap::filter_by_index(self, index_id, index_key)
+    }
+
+    fn keys_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<Vec<&K>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        IndexedMap::keys_by_index(self, index_id, index_key)
+            .map(|keys| keys.iter().map(|key| key.borrow()).collect())
+    }
+}
+
+impl<K, V, KS, B> MapLike<K, V> for StandbyReplica<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn entry_count(&self) -> usize {
+        MapLike::entry_count(&**self)
+    }
+
+    fn lookup(&self, key: &K) -> Option<&V> {
+        MapLike::lookup(&**self, key)
+    }
+
+    fn entries<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a K, &'a V)> + 'a> {
+        MapLike::entries(&**self)
+    }
+
+    fn filter_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        MapLike::filter_by_index(&**self, index_id, index_key)
+    }
+
+    fn keys_by_index<A>(&self, index_id: &IndexId<A>, index_key: &A) -> Option<Vec<&K>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        MapLike::keys_by_index(&**self, index_id, index_key)
+    }
+}
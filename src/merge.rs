@@ -0,0 +1,171 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use {Backing, IndexedMap, InsertError, KeyStorage};
+
+/// Values that can absorb a concurrent write from another replica. Implementations must be
+/// commutative, associative and idempotent so that replicas applying the same changes in any
+/// order converge on the same value.
+pub trait Merge {
+    /// Folds `other` into `self`, returning `true` if `self` changed.
+    fn merge(&mut self, other: Self) -> bool;
+}
+
+/// A hybrid logical timestamp: wall-clock milliseconds, a logical counter to order events within
+/// the same millisecond, and the id of the node that issued it to break ties between replicas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HybridTimestamp {
+    pub wall: u64,
+    pub logical: u32,
+    pub node: u64,
+}
+
+/// Issues `HybridTimestamp`s that never go backwards, even if the wall clock does, and stay ahead
+/// of every timestamp observed from other nodes.
+#[derive(Clone, Debug)]
+pub struct HybridClock {
+    node: u64,
+    last: HybridTimestamp,
+}
+
+impl HybridClock {
+    pub fn new(node: u64) -> HybridClock {
+        HybridClock {
+            node,
+            last: HybridTimestamp {
+                wall: 0,
+                logical: 0,
+                node,
+            },
+        }
+    }
+
+    pub fn now(&mut self) -> HybridTimestamp {
+        let wall = wall_millis();
+        if wall > self.last.wall {
+            self.last = HybridTimestamp {
+                wall,
+                logical: 0,
+                node: self.node,
+            };
+        } else {
+            self.last.logical += 1;
+        }
+        self.last
+    }
+
+    /// Advances the clock past a timestamp received from another node.
+    pub fn observe(&mut self, remote: &HybridTimestamp) {
+        let wall = wall_millis().max(self.last.wall).max(remote.wall);
+        let logical = if wall == self.last.wall && wall == remote.wall {
+            self.last.logical.max(remote.logical) + 1
+        } else if wall == self.last.wall {
+            self.last.logical + 1
+        } else if wall == remote.wall {
+            remote.logical + 1
+        } else {
+            0
+        };
+        self.last = HybridTimestamp {
+            wall,
+            logical,
+            node: self.node,
+        };
+    }
+}
+
+fn wall_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A last-writer-wins register: merging keeps whichever value carries the later timestamp.
+/// Deletes can be replicated by storing `Lww<Option<T>>` and writing `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lww<T> {
+    pub value: T,
+    pub timestamp: HybridTimestamp,
+}
+
+impl<T> Lww<T> {
+    pub fn new(value: T, clock: &mut HybridClock) -> Lww<T> {
+        Lww {
+            value,
+            timestamp: clock.now(),
+        }
+    }
+}
+
+impl<T> Merge for Lww<T> {
+    fn merge(&mut self, other: Lww<T>) -> bool {
+        if other.timestamp > self.timestamp {
+            *self = other;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone + Merge,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Applies changes received from another replica. Existing entries merge the remote value in
+    /// and are reindexed only if it changed them; unknown keys are inserted as-is. Returns the
+    /// number of entries that changed, or the first change rejected by the capacity limit or by
+    /// a unique or fallible index. A rejected merge leaves the entry as it was.
+    pub fn merge_remote<I>(&mut self, changes: I) -> Result<usize, InsertError<K, V>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut changed = 0;
+        for (key, value) in changes {
+            let previous = self.original(&key);
+            let merged = match self.inner.get_mut(&key) {
+                Some(current) => current.merge(value),
+                None => {
+                    self.try_insert(key, value)?;
+                    changed += 1;
+                    continue;
+                }
+            };
+            if merged {
+                self.reindex(&key, previous)?;
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Returns the entries written after `version`, for shipping to other replicas with
+    /// `merge_remote`. Pass the `current_version` from the last exchange to send only what changed
+    /// since. Requires metadata tracking; returns `None` if it is disabled. Removals are not
+    /// reported, so replicate deletes as values (e.g. `Lww<Option<T>>`).
+    pub fn delta_since(&self, version: u64) -> Option<Vec<(K, V)>> {
+        self.metadata.as_ref().map(|by_key| {
+            self.inner
+                .iter()
+                .filter(|&(key, _)| {
+                    let k: &K = key.borrow();
+                    by_key.get(k).is_none_or(|meta| meta.version > version)
+                })
+                .map(|(key, value)| {
+                    let k: &K = key.borrow();
+                    (k.clone(), value.clone())
+                })
+                .collect()
+        })
+    }
+
+    /// The version of the most recent write recorded by metadata tracking.
+    pub fn current_version(&self) -> u64 {
+        self.version
+    }
+}
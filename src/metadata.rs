@@ -0,0 +1,135 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage};
+
+/// Bookkeeping recorded for each entry once metadata tracking is enabled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryMetadata {
+    /// When the key was first inserted, or when tracking was enabled for entries that predate it.
+    pub inserted_at: SystemTime,
+    /// When the value was last written, either by an insert or an in-place update.
+    pub updated_at: SystemTime,
+    /// Number of writes since the entry was inserted.
+    pub update_count: u64,
+    /// Map-wide write counter at the time of the last write. Versions increase across the whole
+    /// map, so comparing them orders writes to different keys.
+    pub version: u64,
+}
+
+impl EntryMetadata {
+    fn new(now: SystemTime, version: u64) -> EntryMetadata {
+        EntryMetadata {
+            inserted_at: now,
+            updated_at: now,
+            update_count: 0,
+            version,
+        }
+    }
+
+    fn touch(&mut self, now: SystemTime, version: u64) {
+        self.updated_at = now;
+        self.update_count += 1;
+        self.version = version;
+    }
+}
+
+pub(crate) type MetadataByKey<K, KS> = HashMap<<KS as KeyStorage<K>>::Key, EntryMetadata>;
+
+/// Records a write of `key`, creating its metadata on first sight.
+pub(crate) fn record_write<K, KS>(
+    metadata: &mut MetadataByKey<K, KS>,
+    version: &mut u64,
+    key: &KS::Key,
+) where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    *version += 1;
+    let now = SystemTime::now();
+    let k: &K = key.borrow();
+    match metadata.get_mut(k) {
+        Some(entry) => entry.touch(now, *version),
+        None => {
+            metadata.insert(key.clone(), EntryMetadata::new(now, *version));
+        }
+    }
+}
+
+pub(crate) fn metadata_of<'a, K, KS>(
+    metadata: &'a Option<MetadataByKey<K, KS>>,
+    key: &K,
+) -> Option<&'a EntryMetadata>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    metadata.as_ref().and_then(|by_key| by_key.get(key))
+}
+
+/// Moves the metadata recorded for `from`, if any, to `to`, for an entry that is renamed.
+pub(crate) fn move_metadata<K, KS>(
+    metadata: &mut Option<MetadataByKey<K, KS>>,
+    from: &K,
+    to: &KS::Key,
+) where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    if let Some(ref mut by_key) = *metadata {
+        if let Some(entry) = by_key.remove(from) {
+            by_key.insert(to.clone(), entry);
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Starts recording `EntryMetadata` for every entry. Entries already in the map are treated
+    /// as inserted now. Tracking costs one extra map entry per key and is off by default.
+    pub fn enable_metadata(&mut self) {
+        if self.metadata.is_some() {
+            return;
+        }
+        let mut by_key = HashMap::with_capacity(self.inner.len());
+        for (key, _) in self.inner.iter() {
+            record_write::<K, KS>(&mut by_key, &mut self.version, key);
+        }
+        self.metadata = Some(by_key);
+    }
+
+    pub fn is_metadata_enabled(&self) -> bool {
+        self.metadata.is_some()
+    }
+
+    /// Returns the metadata of an entry, or `None` if the key is absent or tracking is disabled.
+    pub fn metadata(&self, key: &K) -> Option<&EntryMetadata> {
+        metadata_of::<K, KS>(&self.metadata, key)
+    }
+
+    /// Adds an index whose function also sees the entry's metadata, e.g. to index entries by the
+    /// day they were inserted. Enables metadata tracking if it is not already on.
+    pub fn add_index_with_metadata<A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V, &EntryMetadata) -> Vec<A>,
+    {
+        self.enable_metadata();
+        let index_state = IndexState::<K, V, A, KS>::with_metadata_fn(Rc::new(
+            move |key, value, metadata: Option<&EntryMetadata>| {
+                metadata
+                    .map(|metadata| index_fn(key, value, metadata))
+                    .unwrap_or_default()
+            },
+        ));
+        self.register_new_index(name, index_state)
+    }
+}
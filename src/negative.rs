@@ -0,0 +1,193 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash};
+
+use downcast_rs::Downcast;
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// Up to `capacity` recently missed lookups. The oldest is forgotten first when full.
+pub(crate) struct Misses<T> {
+    capacity: usize,
+    pub(crate) set: HashSet<T>,
+    order: VecDeque<T>,
+}
+
+impl<T> Misses<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn new(capacity: usize) -> Misses<T> {
+        Misses {
+            capacity,
+            set: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn remember(&mut self, missed: &T) {
+        if self.capacity == 0 || !self.set.insert(missed.clone()) {
+            return;
+        }
+        self.order.push_back(missed.clone());
+        while self.set.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => self.set.remove(&oldest),
+                None => break,
+            };
+        }
+        if self.order.len() > 2 * self.capacity {
+            let set = &self.set;
+            self.order.retain(|missed| set.contains(missed));
+        }
+    }
+}
+
+/// The missed index keys of one index, forgotten once an entry is indexed under them.
+trait MissedIndexKeys: Downcast {
+    /// `values` is the boxed `Vec<A>` returned by `IndexUpdater::indexed_values`.
+    fn forget(&mut self, values: &dyn Any);
+}
+
+impl_downcast!(MissedIndexKeys);
+
+impl<A> MissedIndexKeys for Misses<A>
+where
+    A: 'static + Eq + Hash + Clone,
+{
+    fn forget(&mut self, values: &dyn Any) {
+        if let Some(values) = values.downcast_ref::<Vec<A>>() {
+            values.iter().for_each(|value| {
+                self.set.remove(value);
+            });
+        }
+    }
+}
+
+pub(crate) struct NegativeCache<K> {
+    pub(crate) keys: RefCell<Misses<K>>,
+    index_keys: RefCell<HashMap<(String, TypeId), Box<dyn MissedIndexKeys>>>,
+}
+
+impl<K> NegativeCache<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// An empty cache of the same capacity, for a clone of the map.
+    pub(crate) fn emptied(&self) -> NegativeCache<K> {
+        NegativeCache {
+            keys: RefCell::new(Misses::new(self.keys.borrow().capacity)),
+            index_keys: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Remembers up to `capacity` primary keys, and as many index keys per index, that the
+    /// `*_cached` lookups found nothing under, so repeated lookups of ids that do not exist are
+    /// answered without touching the map. Writes forget the keys they make present.
+    pub fn enable_negative_cache(&mut self, capacity: usize) {
+        self.negative = Some(NegativeCache {
+            keys: RefCell::new(Misses::new(capacity)),
+            index_keys: RefCell::new(HashMap::new()),
+        });
+    }
+
+    pub fn disable_negative_cache(&mut self) {
+        self.negative = None;
+    }
+
+    /// Like `get`, consulting the negative cache first if it is enabled.
+    pub fn get_cached(&self, key: &K) -> Option<&V> {
+        let cache = match self.negative {
+            Some(ref cache) => cache,
+            None => return self.inner.get(key),
+        };
+        if cache.keys.borrow().set.contains(key) {
+            return None;
+        }
+        let found = self.inner.get(key);
+        if found.is_none() {
+            cache.keys.borrow_mut().remember(key);
+        }
+        found
+    }
+
+    /// Like `keys_by_index`, consulting the negative cache first if it is enabled. Only missing
+    /// buckets are cached, not unavailable indices.
+    pub fn keys_by_index_cached<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &A,
+    ) -> Option<&HashSet<KS::Key, H>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        let cache = match self.negative {
+            Some(ref cache) => cache,
+            None => return self.keys_by_index(index_id, index_key),
+        };
+        let index = self.get_index(index_id)?;
+        let slot = (index_id.name.clone(), TypeId::of::<A>());
+        let missed = cache
+            .index_keys
+            .borrow()
+            .get(&slot)
+            .and_then(|misses| misses.downcast_ref::<Misses<A>>())
+            .is_some_and(|misses| misses.set.contains(index_key));
+        if missed {
+            return None;
+        }
+        let keys = index.get(index_key);
+        if keys.is_none() {
+            let capacity = cache.keys.borrow().capacity;
+            let mut index_keys = cache.index_keys.borrow_mut();
+            let misses = index_keys
+                .entry(slot)
+                .or_insert_with(|| Box::new(Misses::<A>::new(capacity)));
+            if let Some(misses) = misses.downcast_mut::<Misses<A>>() {
+                misses.remember(index_key);
+            }
+        }
+        keys
+    }
+
+    /// Forgets every cached miss of one index, after it was rebuilt or removed.
+    pub(crate) fn forget_index_misses(&mut self, name: &str, type_id: TypeId) {
+        if let Some(ref mut cache) = self.negative {
+            cache
+                .index_keys
+                .get_mut()
+                .remove(&(name.to_string(), type_id));
+        }
+    }
+
+    /// Forgets cached misses that a write of `key` may have made stale: the key itself, and the
+    /// index keys it is now indexed under.
+    pub(crate) fn forget_misses(&mut self, key: &K) {
+        let cache = match self.negative {
+            Some(ref mut cache) => cache,
+            None => return,
+        };
+        cache.keys.get_mut().set.remove(key);
+        let index_keys = cache.index_keys.get_mut();
+        if index_keys.is_empty() {
+            return;
+        }
+        for (name, by_type) in &self.indices {
+            for (type_id, updater) in by_type {
+                if let Some(misses) = index_keys.get_mut(&(name.clone(), *type_id)) {
+                    misses.forget(&*updater.indexed_values(key));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+type Step<A> = Box<dyn Fn(A) -> A>;
+
+/// A chain of steps that bring index values into a canonical form, such as trimmed lowercase
+/// text. The same chain is applied to extracted values and to query keys, so every call site
+/// matches the same way.
+pub struct Normalizer<A> {
+    steps: Vec<Step<A>>,
+}
+
+impl<A> Normalizer<A> {
+    pub fn new() -> Normalizer<A> {
+        Normalizer { steps: Vec::new() }
+    }
+
+    /// Appends a custom step, run after the steps already in the chain.
+    pub fn then<F>(mut self, step: F) -> Normalizer<A>
+    where
+        F: 'static + Fn(A) -> A,
+    {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    pub fn normalize(&self, value: A) -> A {
+        self.steps.iter().fold(value, |value, step| step(value))
+    }
+}
+
+impl<A> Default for Normalizer<A> {
+    fn default() -> Normalizer<A> {
+        Normalizer::new()
+    }
+}
+
+impl Normalizer<String> {
+    pub fn trim(self) -> Normalizer<String> {
+        self.then(|value: String| value.trim().to_string())
+    }
+
+    pub fn lowercase(self) -> Normalizer<String> {
+        self.then(|value: String| value.to_lowercase())
+    }
+
+    /// Strips diacritics from Latin letters, e.g. `"Crème Brûlée"` to `"Creme Brulee"`. Letters
+    /// outside the Latin-1 and Latin Extended-A blocks are left as they are.
+    pub fn unaccent(self) -> Normalizer<String> {
+        self.then(|value: String| value.chars().flat_map(unaccent_char).collect())
+    }
+}
+
+fn unaccent_char(c: char) -> Vec<char> {
+    let folded = match c {
+        'À'..='Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Ĉ' | 'Ċ' | 'Č' => "C",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'Ď' | 'Đ' | 'Ð' => "D",
+        'ď' | 'đ' | 'ð' => "d",
+        'È'..='Ë' | 'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' => "G",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'Ĥ' | 'Ħ' => "H",
+        'ĥ' | 'ħ' => "h",
+        'Ì'..='Ï' | 'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ' => "I",
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'Ĵ' => "J",
+        'ĵ' => "j",
+        'Ķ' => "K",
+        'ķ' => "k",
+        'Ĺ' | 'Ļ' | 'Ľ' | 'Ŀ' | 'Ł' => "L",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'Ñ' | 'Ń' | 'Ņ' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'Ò'..='Ö' | 'Ø' | 'Ō' | 'Ŏ' | 'Ő' => "O",
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ŕ' | 'Ŗ' | 'Ř' => "R",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'Ś' | 'Ŝ' | 'Ş' | 'Š' => "S",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'Ţ' | 'Ť' | 'Ŧ' => "T",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'Ù'..='Ü' | 'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų' => "U",
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'Ŵ' => "W",
+        'ŵ' => "w",
+        'Ý' | 'Ŷ' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return vec![c],
+    };
+    folded.chars().collect()
+}
+
+/// Identifies an index registered with a normalizer, which is applied to query keys through
+/// `normalize`. Derefs to the plain `IndexId`, whose queries take keys that are already
+/// normalized.
+pub struct NormalizedIndexId<A> {
+    index_id: IndexId<A>,
+    normalizer: Rc<Normalizer<A>>,
+}
+
+impl<A> NormalizedIndexId<A> {
+    pub fn normalize(&self, index_key: A) -> A {
+        self.normalizer.normalize(index_key)
+    }
+}
+
+impl<A> Deref for NormalizedIndexId<A> {
+    type Target = IndexId<A>;
+
+    fn deref(&self) -> &IndexId<A> {
+        &self.index_id
+    }
+}
+
+impl<A> Clone for NormalizedIndexId<A> {
+    fn clone(&self) -> NormalizedIndexId<A> {
+        NormalizedIndexId {
+            index_id: self.index_id.clone(),
+            normalizer: self.normalizer.clone(),
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index whose extracted values are passed through `normalizer` before bucketing.
+    pub fn add_normalized_index<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+        normalizer: Normalizer<A>,
+    ) -> NormalizedIndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let normalizer = Rc::new(normalizer);
+        let normalize = normalizer.clone();
+        let index_id = self.add_index(name, move |key, value| {
+            index_fn(key, value)
+                .into_iter()
+                .map(|a| normalize.normalize(a))
+                .collect()
+        });
+        NormalizedIndexId {
+            index_id,
+            normalizer,
+        }
+    }
+
+    /// Like `keys_by_index`, normalizing `index_key` first.
+    pub fn keys_by_normalized<A>(
+        &self,
+        index_id: &NormalizedIndexId<A>,
+        index_key: A,
+    ) -> Option<Vec<&K>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        self.keys_by_index(index_id, &index_id.normalize(index_key))
+            .map(|keys| keys.iter().map(|key| key.borrow()).collect())
+    }
+}
@@ -0,0 +1,205 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::{slice, vec};
+
+use {Backing, ClonedKeys, IndexedMap, KeyStorage};
+
+/// Entry storage that remembers the order keys were first inserted in, like the `indexmap`
+/// crate. Iteration follows that order, entries can be looked up by position, and overwriting
+/// a key keeps its position. `remove` closes the gap by shifting the later entries down, which
+/// keeps the order at a cost linear in the entries after it; `swap_remove` is constant time but
+/// moves the last entry into the gap.
+#[derive(Clone, Debug)]
+pub struct InsertionOrder<P, V> {
+    entries: Vec<(P, V)>,
+    positions: HashMap<P, usize>,
+}
+
+impl<P, V> Default for InsertionOrder<P, V> {
+    fn default() -> InsertionOrder<P, V> {
+        InsertionOrder {
+            entries: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+}
+
+impl<P, V> InsertionOrder<P, V>
+where
+    P: Eq + Hash + Clone,
+{
+    /// The entry at `position` in insertion order.
+    pub fn get_index(&self, position: usize) -> Option<(&P, &V)> {
+        self.entries.get(position).map(|(key, value)| (key, value))
+    }
+
+    pub fn get_index_of<K>(&self, key: &K) -> Option<usize>
+    where
+        K: ?Sized + Eq + Hash,
+        P: Borrow<K>,
+    {
+        self.positions.get(key).cloned()
+    }
+
+    /// Moves the entry at `position` to the end, and the last entry into its place, so that
+    /// removing it afterwards shifts nothing.
+    fn swap_to_end(&mut self, position: usize) {
+        let last = self.entries.len() - 1;
+        if position == last {
+            return;
+        }
+        self.entries.swap(position, last);
+        let (moved, _) = &self.entries[position];
+        self.positions.insert(moved.clone(), position);
+        let (removed, _) = &self.entries[last];
+        self.positions.insert(removed.clone(), last);
+    }
+}
+
+/// The entries of an `InsertionOrder` in insertion order.
+pub struct InsertionOrderIter<'a, P: 'a, V: 'a> {
+    entries: slice::Iter<'a, (P, V)>,
+}
+
+impl<'a, P, V> Iterator for InsertionOrderIter<'a, P, V> {
+    type Item = (&'a P, &'a V);
+
+    fn next(&mut self) -> Option<(&'a P, &'a V)> {
+        self.entries.next().map(|(key, value)| (key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<P, V> IntoIterator for InsertionOrder<P, V> {
+    type Item = (P, V);
+    type IntoIter = vec::IntoIter<(P, V)>;
+
+    fn into_iter(self) -> vec::IntoIter<(P, V)> {
+        self.entries.into_iter()
+    }
+}
+
+impl<K, P, V> Backing<K, P, V> for InsertionOrder<P, V>
+where
+    K: Eq + Hash,
+    P: Eq + Hash + Clone + Borrow<K>,
+{
+    type Iter<'a>
+        = InsertionOrderIter<'a, P, V>
+    where
+        Self: 'a,
+        P: 'a,
+        V: 'a;
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        let position = *self.positions.get(key)?;
+        Some(&self.entries[position].1)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let position = *self.positions.get(key)?;
+        Some(&mut self.entries[position].1)
+    }
+
+    fn get_key_value(&self, key: &K) -> Option<(&P, &V)> {
+        let position = *self.positions.get(key)?;
+        self.get_index(position)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    fn insert(&mut self, key: P, value: V) -> Option<V> {
+        match self.positions.get(key.borrow()) {
+            Some(&position) => Some(std::mem::replace(&mut self.entries[position].1, value)),
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<(P, V)> {
+        let position = self.positions.remove(key)?;
+        let removed = self.entries.remove(position);
+        for (offset, (key, _)) in self.entries[position..].iter().enumerate() {
+            self.positions.insert(key.clone(), position + offset);
+        }
+        Some(removed)
+    }
+
+    fn iter(&self) -> InsertionOrderIter<'_, P, V> {
+        InsertionOrderIter {
+            entries: self.entries.iter(),
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+        self.positions.reserve(additional);
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+        self.positions.shrink_to_fit();
+    }
+}
+
+/// A map that iterates in insertion order and supports positional access, created by
+/// `IndexedMap::ordered`.
+pub type OrderedIndexedMap<K, V> = IndexedMap<K, V, ClonedKeys, InsertionOrder<K, V>>;
+
+impl<K, V> OrderedIndexedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    pub fn ordered() -> OrderedIndexedMap<K, V> {
+        IndexedMap::with_backing(ClonedKeys, InsertionOrder::default())
+    }
+}
+
+impl<K, V, KS> IndexedMap<K, V, KS, InsertionOrder<KS::Key, V>>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+{
+    /// The entry at `position` in insertion order. Named apart from `get_index`, which looks up
+    /// a secondary index.
+    pub fn get_at(&self, position: usize) -> Option<(&K, &V)> {
+        self.inner
+            .get_index(position)
+            .map(|(key, value)| (key.borrow(), value))
+    }
+
+    /// The position of `key` in insertion order.
+    pub fn get_index_of(&self, key: &K) -> Option<usize> {
+        self.inner.get_index_of(key)
+    }
+
+    /// Removes the entry under `key` from the map and every index in constant time, moving the
+    /// last entry into its position. Use `remove` to keep the order of the remaining entries.
+    pub fn swap_remove(&mut self, key: &K) -> Option<V> {
+        let position = self.inner.get_index_of(key)?;
+        self.inner.swap_to_end(position);
+        self.remove(key)
+    }
+
+    /// Like `swap_remove`, but by position, returning the removed entry.
+    pub fn swap_remove_at(&mut self, position: usize) -> Option<(K, V)> {
+        let key: K = self.inner.get_index(position)?.0.borrow().clone();
+        self.inner.swap_to_end(position);
+        self.remove_entry(&key)
+    }
+}
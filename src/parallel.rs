@@ -0,0 +1,149 @@
+//! Index construction and bucket scans spread over every core with rayon. Results come back in
+//! the order a sequential pass would produce them.
+
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+
+use rayon::prelude::*;
+
+use statistics::Statistics;
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage};
+
+/// The buckets of an index and the values each key is indexed under, as built by one thread.
+struct Postings<A, SK> {
+    index: HashMap<A, HashSet<SK>>,
+    indexed: HashMap<SK, HashSet<A>>,
+}
+
+impl<A, SK> Postings<A, SK>
+where
+    A: Eq + Hash + Clone,
+    SK: Eq + Hash + Clone,
+{
+    fn new() -> Postings<A, SK> {
+        Postings {
+            index: HashMap::new(),
+            indexed: HashMap::new(),
+        }
+    }
+
+    fn add(mut self, key: &SK, values: Vec<A>) -> Postings<A, SK> {
+        let mut indexed = HashSet::with_capacity(values.len());
+        for a in values {
+            self.index.entry(a.clone()).or_default().insert(key.clone());
+            indexed.insert(a);
+        }
+        self.indexed.insert(key.clone(), indexed);
+        self
+    }
+
+    /// Merges two threads' postings, moving the smaller set into the larger. Every key is
+    /// indexed by exactly one thread, so only buckets can overlap.
+    fn merge(self, other: Postings<A, SK>) -> Postings<A, SK> {
+        let (mut larger, smaller) = if self.indexed.len() >= other.indexed.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        for (a, keys) in smaller.index {
+            match larger.index.get_mut(&a) {
+                Some(bucket) => bucket.extend(keys),
+                None => {
+                    larger.index.insert(a, keys);
+                }
+            }
+        }
+        larger.indexed.extend(smaller.indexed);
+        larger
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + Sync,
+    V: 'static + Sync,
+    KS: KeyStorage<K>,
+    KS::Key: Send + Sync,
+    B: Backing<K, KS::Key, V> + Sync,
+{
+    /// Like `add_index`, but builds the index on every core: each thread runs the index function
+    /// over a share of the entries and collects their postings, and the threads' postings are
+    /// then merged. Later writes index one entry at a time as usual.
+    pub fn par_add_index<A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone + Send,
+        F: 'static + Fn(&K, &V) -> Vec<A> + Sync,
+    {
+        let entries: Vec<(&KS::Key, &V)> = self.inner.iter().collect();
+        let postings = entries
+            .par_iter()
+            .fold(Postings::new, |postings, &(key, value)| {
+                postings.add(key, index_fn(key.borrow(), value))
+            })
+            .reduce(Postings::new, Postings::merge);
+        let mut index_state =
+            IndexState::<K, V, A, KS>::empty(move |key: &K, value: &V| index_fn(key, value));
+        index_state.statistics =
+            Statistics::of_buckets(postings.index.iter().map(|(a, keys)| (a, keys.len())));
+        index_state.index = postings.index;
+        index_state.indexed = postings.indexed;
+        self.register_index(name, index_state)
+    }
+
+    /// Calls `f` on every entry under `index_key` in the index, spread over every core, and
+    /// collects the results. Returns `None` if the index is unavailable or has no such bucket.
+    pub fn par_iter_by_index<A, H, Q, T, F>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+        f: F,
+    ) -> Option<Vec<T>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        H: 'static + BuildHasher + Clone,
+        Q: ?Sized + Eq + Hash,
+        T: Send,
+        F: Fn(&K, &V) -> T + Sync,
+    {
+        let keys: Vec<&KS::Key> = self.keys_by_index(index_id, index_key)?.iter().collect();
+        let inner = &self.inner;
+        let results = keys
+            .par_iter()
+            .filter_map(|&key| {
+                let key: &K = key.borrow();
+                inner.get(key).map(|value| f(key, value))
+            })
+            .collect();
+        Some(results)
+    }
+
+    /// The entries under `index_key` in the index that satisfy `predicate`, tested on every core.
+    /// Returns `None` if the index is unavailable or has no such bucket.
+    pub fn par_filter_by_index<A, H, Q, P>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+        predicate: P,
+    ) -> Option<Vec<(&K, &V)>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        H: 'static + BuildHasher + Clone,
+        Q: ?Sized + Eq + Hash,
+        P: Fn(&K, &V) -> bool + Sync,
+    {
+        let keys: Vec<&KS::Key> = self.keys_by_index(index_id, index_key)?.iter().collect();
+        let inner = &self.inner;
+        let results = keys
+            .par_iter()
+            .filter_map(|&key| {
+                let key: &K = key.borrow();
+                inner
+                    .get(key)
+                    .filter(|value| predicate(key, value))
+                    .map(|value| (key, value))
+            })
+            .collect();
+        Some(results)
+    }
+}
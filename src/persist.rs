@@ -0,0 +1,125 @@
+//! Persisting a map with serde.
+//!
+//! Index functions are closures and cannot be serialized, so a persisted map holds its entries
+//! and the names of its indices. Restoring it takes a callback that registers the indices again,
+//! which rebuilds each of them from the restored entries.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use serde::ser::SerializeStruct;
+
+use {Backing, DefinitionError, IndexedMap, KeyStorage};
+
+/// The serializable form of a map: its entries and the names of its indices.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct PersistedMap<K, V>
+where
+    K: Eq + Hash,
+{
+    pub entries: HashMap<K, V>,
+    /// Sorted and without duplicates.
+    pub indices: Vec<String>,
+}
+
+impl<K, V> PersistedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    /// Rebuilds the map, with `register` adding its indices again. Fails if `register` leaves out
+    /// an index the persisted map had, since queries against it would otherwise find nothing.
+    pub fn restore<F>(self, register: F) -> Result<IndexedMap<K, V>, DefinitionError>
+    where
+        F: FnOnce(&mut IndexedMap<K, V>),
+    {
+        let map = IndexedMap::from_map_with_indices(self.entries, register);
+        match self
+            .indices
+            .into_iter()
+            .find(|name| !map.indices.contains_key(name))
+        {
+            Some(name) => Err(DefinitionError::Unregistered { name }),
+            None => Ok(map),
+        }
+    }
+}
+
+impl<K, V> IndexedMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    /// Builds a map around existing entries, then lets `register` add indices, each built once
+    /// from all the entries.
+    pub fn from_map_with_indices<F>(entries: HashMap<K, V>, register: F) -> IndexedMap<K, V>
+    where
+        F: FnOnce(&mut IndexedMap<K, V>),
+    {
+        let mut map = IndexedMap::new();
+        map.inner = entries;
+        register(&mut map);
+        map
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn persisted(&self) -> PersistedMap<K, V> {
+        PersistedMap {
+            entries: self
+                .inner
+                .iter()
+                .map(|(key, value)| {
+                    let key: &K = key.borrow();
+                    (key.clone(), value.clone())
+                })
+                .collect(),
+            indices: self.index_names().into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+struct EntriesOf<'a, K: 'a, V: 'a, KS: 'a, B: 'a>(&'a IndexedMap<K, V, KS, B>)
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>;
+
+impl<'a, K, V, KS, B> serde::Serialize for EntriesOf<'a, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + serde::Serialize,
+    V: 'static + Clone + serde::Serialize,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.inner.iter().map(|(key, value)| {
+            let key: &K = key.borrow();
+            (key, value)
+        }))
+    }
+}
+
+/// Serializes the same way as the `PersistedMap` it would produce, without copying the entries.
+impl<K, V, KS, B> serde::Serialize for IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + serde::Serialize,
+    V: 'static + Clone + serde::Serialize,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut persisted = serializer.serialize_struct("PersistedMap", 2)?;
+        persisted.serialize_field("entries", &EntriesOf(self))?;
+        persisted.serialize_field("indices", &self.index_names())?;
+        persisted.end()
+    }
+}
@@ -0,0 +1,39 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+use {Backing, IndexedMap, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Exempts the entry under `key` from every eviction policy, e.g. for configuration entries
+    /// that must stay resident. A pinned entry is indexed and queried like any other and can
+    /// still be removed explicitly, which also unpins it. Returns `false` if there is no such
+    /// entry.
+    pub fn pin(&mut self, key: &K) -> bool {
+        match self.inner.get_key_value(key) {
+            Some((stored, _)) => {
+                self.pinned.insert(stored.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the entry under `key` evictable again, returning whether it was pinned.
+    pub fn unpin(&mut self, key: &K) -> bool {
+        self.pinned.remove(key)
+    }
+
+    pub fn is_pinned(&self, key: &K) -> bool {
+        self.pinned.contains(key)
+    }
+
+    pub fn pinned(&self) -> impl Iterator<Item = &K> {
+        self.pinned.iter().map(Borrow::borrow)
+    }
+}
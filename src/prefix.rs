@@ -0,0 +1,44 @@
+use std::hash::Hash;
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// Compound values that can be grouped by their leading component, such as the tenant in a
+/// `(TenantId, ItemId)` key.
+pub trait HasPrefix {
+    type Prefix: 'static + Eq + Hash + Clone;
+
+    fn prefix(&self) -> Self::Prefix;
+}
+
+macro_rules! tuple_prefix {
+    ($first:ident $(, $rest:ident)+) => {
+        impl<$first, $($rest),+> HasPrefix for ($first, $($rest),+)
+        where
+            $first: 'static + Eq + Hash + Clone,
+        {
+            type Prefix = $first;
+
+            fn prefix(&self) -> $first {
+                self.0.clone()
+            }
+        }
+    };
+}
+
+tuple_prefix!(A, B);
+tuple_prefix!(A, B, C);
+tuple_prefix!(A, B, C, D);
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + HasPrefix,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index over the leading component of each key, so all entries sharing a key prefix
+    /// can be found with `filter_by_index` or `keys_by_index` instead of scanning every key.
+    pub fn add_prefix_index(&mut self, name: String) -> IndexId<K::Prefix> {
+        self.add_index(name, |key: &K, _| vec![key.prefix()])
+    }
+}
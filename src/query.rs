@@ -0,0 +1,375 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, IndexId, IndexedMap, KeyStorage, QueryError};
+
+/// The keys in one index bucket, whichever hasher the index builds its buckets with, so that a
+/// `KeyFilter` can combine buckets of indices with different hashers.
+pub trait Bucket<P> {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains(&self, key: &P) -> bool;
+
+    fn keys(&self) -> Box<dyn Iterator<Item = &P> + '_>;
+}
+
+impl<P, S> Bucket<P> for HashSet<P, S>
+where
+    P: Eq + Hash,
+    S: BuildHasher,
+{
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn contains(&self, key: &P) -> bool {
+        HashSet::contains(self, key)
+    }
+
+    fn keys(&self) -> Box<dyn Iterator<Item = &P> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+/// A boolean combination of index buckets, evaluated over postings alone by
+/// `keys_by_indices`. Leaves come from `IndexedMap::postings`.
+pub enum KeyFilter<'a, P> {
+    /// The keys in one index bucket; `None` stands for a bucket with no entries.
+    Postings(Option<&'a dyn Bucket<P>>),
+    /// Keys matching every filter. An empty list matches nothing.
+    All(Vec<KeyFilter<'a, P>>),
+    /// Keys matching any filter.
+    Any(Vec<KeyFilter<'a, P>>),
+    /// Keys matching the first filter but not the second.
+    Except(Box<KeyFilter<'a, P>>, Box<KeyFilter<'a, P>>),
+}
+
+impl<'a, P> KeyFilter<'a, P>
+where
+    P: Eq + Hash,
+{
+    /// The most keys this filter can match, used to start intersections from the smallest side
+    /// and to check candidates against the most selective filters first.
+    fn upper_bound(&self) -> usize {
+        match *self {
+            KeyFilter::Postings(keys) => keys.map_or(0, |keys| keys.len()),
+            KeyFilter::All(ref filters) => filters
+                .iter()
+                .map(KeyFilter::upper_bound)
+                .min()
+                .unwrap_or(0),
+            KeyFilter::Any(ref filters) => filters.iter().map(KeyFilter::upper_bound).sum(),
+            KeyFilter::Except(ref kept, _) => kept.upper_bound(),
+        }
+    }
+
+    fn contains(&self, key: &P) -> bool {
+        match *self {
+            KeyFilter::Postings(keys) => keys.is_some_and(|keys| keys.contains(key)),
+            KeyFilter::All(ref filters) => {
+                !filters.is_empty() && filters.iter().all(|filter| filter.contains(key))
+            }
+            KeyFilter::Any(ref filters) => filters.iter().any(|filter| filter.contains(key)),
+            KeyFilter::Except(ref kept, ref excluded) => {
+                kept.contains(key) && !excluded.contains(key)
+            }
+        }
+    }
+
+    fn collect_into(&self, out: &mut HashSet<&'a P>) {
+        match *self {
+            KeyFilter::Postings(keys) => out.extend(keys.into_iter().flat_map(|keys| keys.keys())),
+            KeyFilter::All(ref filters) => {
+                let mut by_bound: Vec<&KeyFilter<'a, P>> = filters.iter().collect();
+                by_bound.sort_by_key(|filter| filter.upper_bound());
+                let (smallest, rest) = match by_bound.split_first() {
+                    Some(split) => split,
+                    None => return,
+                };
+                let mut candidates = HashSet::new();
+                smallest.collect_into(&mut candidates);
+                out.extend(
+                    candidates
+                        .into_iter()
+                        .filter(|key| rest.iter().all(|filter| filter.contains(key))),
+                );
+            }
+            KeyFilter::Any(ref filters) => {
+                filters.iter().for_each(|filter| filter.collect_into(out))
+            }
+            KeyFilter::Except(ref kept, ref excluded) => {
+                let mut candidates = HashSet::new();
+                kept.collect_into(&mut candidates);
+                out.extend(candidates.into_iter().filter(|key| !excluded.contains(key)));
+            }
+        }
+    }
+}
+
+/// Combines index lookups left to right into a `KeyFilter`, from `IndexedMap::query`. For
+/// example `with(a).and(b).or(c)` matches `(a and b) or c`. A lookup against an unavailable index
+/// fails the whole query when it is run.
+pub struct QueryBuilder<'m, K: 'm, V: 'm, KS: 'm, B: 'm>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: &'m IndexedMap<K, V, KS, B>,
+    filter: Result<Option<KeyFilter<'m, KS::Key>>, QueryError>,
+}
+
+impl<'m, K, V, KS, B> QueryBuilder<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Starts the query with a lookup, or intersects with it if the query already has one.
+    pub fn with<A, H>(
+        self,
+        index_id: &IndexId<A, H>,
+        index_key: &A,
+    ) -> QueryBuilder<'m, K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.and(index_id, index_key)
+    }
+
+    /// Keeps only the keys also in the bucket for `index_key`.
+    pub fn and<A, H>(self, index_id: &IndexId<A, H>, index_key: &A) -> QueryBuilder<'m, K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.combine(index_id, index_key, |filter, postings| match filter {
+            KeyFilter::All(mut filters) => {
+                filters.push(postings);
+                KeyFilter::All(filters)
+            }
+            filter => KeyFilter::All(vec![filter, postings]),
+        })
+    }
+
+    /// Adds the keys in the bucket for `index_key`.
+    pub fn or<A, H>(self, index_id: &IndexId<A, H>, index_key: &A) -> QueryBuilder<'m, K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.combine(index_id, index_key, |filter, postings| match filter {
+            KeyFilter::Any(mut filters) => {
+                filters.push(postings);
+                KeyFilter::Any(filters)
+            }
+            filter => KeyFilter::Any(vec![filter, postings]),
+        })
+    }
+
+    /// Drops the keys in the bucket for `index_key`.
+    pub fn and_not<A, H>(
+        self,
+        index_id: &IndexId<A, H>,
+        index_key: &A,
+    ) -> QueryBuilder<'m, K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.combine(index_id, index_key, |filter, postings| {
+            KeyFilter::Except(Box::new(filter), Box::new(postings))
+        })
+    }
+
+    fn combine<A, H, F>(
+        self,
+        index_id: &IndexId<A, H>,
+        index_key: &A,
+        f: F,
+    ) -> QueryBuilder<'m, K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+        F: FnOnce(KeyFilter<'m, KS::Key>, KeyFilter<'m, KS::Key>) -> KeyFilter<'m, KS::Key>,
+    {
+        let map = self.map;
+        let filter = self.filter.and_then(|filter| {
+            let postings = map.postings(index_id, index_key)?;
+            Ok(Some(match filter {
+                Some(filter) => f(filter, postings),
+                None => postings,
+            }))
+        });
+        QueryBuilder { map, filter }
+    }
+
+    /// The filter built so far, to combine further or run with `keys_by_indices`.
+    pub fn into_filter(self) -> Result<KeyFilter<'m, KS::Key>, QueryError> {
+        self.filter
+            .map(|filter| filter.unwrap_or_else(|| KeyFilter::All(Vec::new())))
+    }
+
+    /// The keys matching the query. A query without lookups matches nothing.
+    pub fn keys(self) -> Result<HashSet<&'m K>, QueryError> {
+        let map = self.map;
+        self.into_filter()
+            .map(|filter| map.keys_by_indices(&filter))
+    }
+
+    /// The entries matching the query.
+    pub fn entries(self) -> Result<Vec<(&'m K, &'m V)>, QueryError> {
+        let map = self.map;
+        self.keys().map(|keys| {
+            keys.into_iter()
+                .filter_map(|key| map.inner.get(key).map(|value| (key, value)))
+                .collect()
+        })
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Returns every index key shared by more than one entry, together with the entries sharing
+    /// it. Useful for checking that an attribute expected to be unique actually is.
+    pub fn duplicates_by<A>(&self, index_id: &IndexId<A>) -> Vec<(&A, Vec<(&K, &V)>)>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        self.get_index(index_id)
+            .map(|index| {
+                index
+                    .iter()
+                    .filter(|&(_, keys)| keys.len() > 1)
+                    .map(|(a, keys)| {
+                        let entries = keys
+                            .iter()
+                            .filter_map(|k| {
+                                let k: &K = k.borrow();
+                                self.inner.get(k).map(|v| (k, v))
+                            })
+                            .collect();
+                        (a, entries)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Ranks other entries by how similar their set of index keys is to that of `key`, using the
+    /// Jaccard index (shared keys over distinct keys). Only entries sharing at least one index key
+    /// are considered, and only those scoring at least `min_jaccard` are returned, best first.
+    ///
+    /// Similarity is computed from the index postings, so the cost depends on the size of the
+    /// buckets `key` appears in rather than on the size of the map.
+    pub fn similar_to<A>(
+        &self,
+        index_id: &IndexId<A>,
+        key: &K,
+        min_jaccard: f64,
+    ) -> Vec<(&K, &V, f64)>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        let state = match self.get_index_state(index_id) {
+            Some(state) => state,
+            None => return Vec::new(),
+        };
+        let own = match state.indexed.get(key) {
+            Some(own) if !own.is_empty() => own,
+            _ => return Vec::new(),
+        };
+        let mut shared: HashMap<&K, usize> = HashMap::new();
+        for a in own {
+            for other in state.index.get(a).into_iter().flat_map(|keys| keys.iter()) {
+                let other: &K = other.borrow();
+                if other != key {
+                    *shared.entry(other).or_insert(0) += 1;
+                }
+            }
+        }
+        let mut similar: Vec<(&K, &V, f64)> = shared
+            .into_iter()
+            .filter_map(|(other, shared)| {
+                let other_len = state.indexed.get(other).map_or(0, |values| values.len());
+                let jaccard = shared as f64 / (own.len() + other_len - shared) as f64;
+                if jaccard >= min_jaccard {
+                    self.inner.get(other).map(|v| (other, v, jaccard))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        similar.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
+        similar
+    }
+
+    /// Applies `projection` to every entry indexed under `index_key`, collecting just the
+    /// projected values rather than borrowing whole entries.
+    pub fn project_by_index<A, T, F>(
+        &self,
+        index_id: &IndexId<A>,
+        index_key: &A,
+        mut projection: F,
+    ) -> Option<Vec<T>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: FnMut(&K, &V) -> T,
+    {
+        self.keys_by_index(index_id, index_key).map(|keys| {
+            keys.iter()
+                .filter_map(|k| {
+                    let k: &K = k.borrow();
+                    self.inner.get(k).map(|v| projection(k, v))
+                })
+                .collect()
+        })
+    }
+
+    /// Starts a query combining several index lookups.
+    pub fn query(&self) -> QueryBuilder<'_, K, V, KS, B> {
+        QueryBuilder {
+            map: self,
+            filter: Ok(None),
+        }
+    }
+
+    /// The bucket of `index_id` for `index_key`, as a leaf of a `KeyFilter`.
+    pub fn postings<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &A,
+    ) -> Result<KeyFilter<'_, KS::Key>, QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.try_get_index(index_id).map(|index| {
+            KeyFilter::Postings(
+                index
+                    .get(index_key)
+                    .map(|keys| keys as &dyn Bucket<KS::Key>),
+            )
+        })
+    }
+
+    /// Returns the keys matching `filter`, combining index buckets without looking up any
+    /// values, for callers that only need identifiers.
+    pub fn keys_by_indices<'a>(&'a self, filter: &KeyFilter<'a, KS::Key>) -> HashSet<&'a K> {
+        let mut keys = HashSet::new();
+        filter.collect_into(&mut keys);
+        keys.into_iter().map(|key| key.borrow()).collect()
+    }
+}
@@ -0,0 +1,126 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage, PostingObserver};
+
+/// Identifies an index whose buckets are also kept in order, so the entry with the smallest
+/// index value can be taken first. Derefs to the plain `IndexId` for every other query.
+pub struct PriorityIndexId<A> {
+    index_id: IndexId<A>,
+}
+
+impl<A> Deref for PriorityIndexId<A> {
+    type Target = IndexId<A>;
+
+    fn deref(&self) -> &IndexId<A> {
+        &self.index_id
+    }
+}
+
+impl<A> Clone for PriorityIndexId<A> {
+    fn clone(&self) -> PriorityIndexId<A> {
+        PriorityIndexId {
+            index_id: self.index_id.clone(),
+        }
+    }
+}
+
+/// The non-empty buckets of an index in order, with their sizes. An ordered map rather than a
+/// binary heap, so that entries removed by other means leave nothing stale behind.
+pub(crate) struct Priorities<K, A> {
+    pub(crate) sizes: BTreeMap<A, usize>,
+    _key: PhantomData<K>,
+}
+
+impl<K, A> Priorities<K, A> {
+    pub(crate) fn new() -> Priorities<K, A> {
+        Priorities {
+            sizes: BTreeMap::new(),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K, V, A, KS> PostingObserver<K, V, A, KS> for Priorities<K, A>
+where
+    K: 'static,
+    V: 'static,
+    A: 'static + Ord + Clone,
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, _: &KS::Key, _: &V) {
+        *self.sizes.entry(index_key.clone()).or_insert(0) += 1;
+    }
+
+    fn removed(&mut self, index_key: &A, _: &K) {
+        let now_empty = self.sizes.get_mut(index_key).is_some_and(|size| {
+            *size -= 1;
+            *size == 0
+        });
+        if now_empty {
+            self.sizes.remove(index_key);
+        }
+    }
+
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>> {
+        Some(Box::new(Priorities::<K, A> {
+            sizes: self.sizes.clone(),
+            _key: PhantomData,
+        }))
+    }
+
+    fn cleared(&mut self) {
+        self.sizes.clear();
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index over an ordered attribute, such as a due time or a priority, that can hand
+    /// out its entries smallest value first. This lets the map back a scheduler or delay queue
+    /// while still being queried by key and by its other indices.
+    pub fn add_priority_index<A, F>(&mut self, name: String, index_fn: F) -> PriorityIndexId<A>
+    where
+        A: 'static + Ord + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state
+            .observers
+            .push(Box::new(Priorities::<K, A>::new()));
+        PriorityIndexId {
+            index_id: self.register_new_index(name, index_state),
+        }
+    }
+
+    /// Returns an entry with the smallest index value, along with that value. Ties are broken
+    /// arbitrarily.
+    pub fn peek_min_by_index<A>(&self, index_id: &PriorityIndexId<A>) -> Option<(&A, &K, &V)>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        let state = self.get_index_state(index_id)?;
+        let priorities = state.find_observer::<Priorities<K, A>>()?;
+        let (index_key, _) = priorities.sizes.iter().next()?;
+        let key: &K = state.index.get(index_key)?.iter().next()?.borrow();
+        self.inner.get(key).map(|value| (index_key, key, value))
+    }
+
+    /// Removes and returns an entry with the smallest index value. An entry with several index
+    /// values is removed as a whole the first time any of them comes up.
+    pub fn pop_min_by_index<A>(&mut self, index_id: &PriorityIndexId<A>) -> Option<(K, V)>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        let key = self.peek_min_by_index(index_id)?.1.clone();
+        self.remove_unchecked(&key)
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use {Backing, ClonedKeys, DefinitionError, Entries, IndexState, IndexedMap, KeyStorage};
+
+/// Free-form settings passed to an extractor factory, e.g. a field name or a bucket width.
+pub type IndexOptions = HashMap<String, String>;
+
+/// An index described as data, e.g. read from configuration, to be bound to an extractor
+/// registered under `extractor`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub extractor: String,
+    pub options: IndexOptions,
+    pub enabled: bool,
+}
+
+impl IndexDefinition {
+    pub fn new(name: String, extractor: String) -> IndexDefinition {
+        IndexDefinition {
+            name,
+            extractor,
+            options: IndexOptions::new(),
+            enabled: true,
+        }
+    }
+
+    pub fn with_option(mut self, key: String, value: String) -> IndexDefinition {
+        self.options.insert(key, value);
+        self
+    }
+
+    pub fn with_enabled(self, enabled: bool) -> IndexDefinition {
+        IndexDefinition { enabled, ..self }
+    }
+}
+
+type AddIndex<K, V, KS, B> = Box<dyn FnOnce(&mut IndexedMap<K, V, KS, B>)>;
+
+type Binder<K, V, KS, B> =
+    Box<dyn Fn(&IndexDefinition) -> Result<AddIndex<K, V, KS, B>, DefinitionError>>;
+
+/// Extractor factories by id. Each factory turns a definition's options into an index function,
+/// so the set of indices a map carries can be chosen by configuration while the extraction logic
+/// stays in code. Indices added this way are found again with `IndexId::new(name)`.
+pub struct ExtractorRegistry<K, V, KS = ClonedKeys, B = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    binders: HashMap<String, Binder<K, V, KS, B>>,
+}
+
+impl<K, V, KS, B> ExtractorRegistry<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: 'static + KeyStorage<K>,
+    B: 'static + Backing<K, KS::Key, V>,
+{
+    pub fn new() -> ExtractorRegistry<K, V, KS, B> {
+        ExtractorRegistry {
+            binders: HashMap::new(),
+        }
+    }
+
+    /// Registers `factory` under `extractor`, replacing any factory already registered there. The
+    /// factory explains rejected options with a message.
+    pub fn register<A, F, G>(&mut self, extractor: String, factory: G)
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+        G: 'static + Fn(&IndexOptions) -> Result<F, String>,
+    {
+        let binder = move |definition: &IndexDefinition| {
+            let index_fn =
+                factory(&definition.options).map_err(|reason| DefinitionError::InvalidOptions {
+                    name: definition.name.clone(),
+                    reason,
+                })?;
+            let name = definition.name.clone();
+            let enabled = definition.enabled;
+            let add: AddIndex<K, V, KS, B> = Box::new(move |map: &mut IndexedMap<K, V, KS, B>| {
+                let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+                index_state.enabled = enabled;
+                map.register_new_index(name, index_state);
+            });
+            Ok(add)
+        };
+        self.binders.insert(extractor, Box::new(binder));
+    }
+
+    pub fn contains(&self, extractor: &str) -> bool {
+        self.binders.contains_key(extractor)
+    }
+
+    /// Adds an index to `map` for each definition. Every definition is checked before any index
+    /// is added, so a bad configuration leaves the map untouched. Disabled definitions are added
+    /// disabled and can be switched on later with `set_index_enabled`.
+    pub fn apply(
+        &self,
+        map: &mut IndexedMap<K, V, KS, B>,
+        definitions: &[IndexDefinition],
+    ) -> Result<(), DefinitionError> {
+        let mut names = HashSet::with_capacity(definitions.len());
+        let mut pending = Vec::with_capacity(definitions.len());
+        for definition in definitions {
+            if map.indices.contains_key(&definition.name) || !names.insert(&definition.name) {
+                return Err(DefinitionError::DuplicateName {
+                    name: definition.name.clone(),
+                });
+            }
+            let binder = self.binders.get(&definition.extractor).ok_or_else(|| {
+                DefinitionError::UnknownExtractor {
+                    name: definition.name.clone(),
+                    extractor: definition.extractor.clone(),
+                }
+            })?;
+            pending.push(binder(definition)?);
+        }
+        for add in pending {
+            add(map);
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, KS, B> Default for ExtractorRegistry<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: 'static + KeyStorage<K>,
+    B: 'static + Backing<K, KS::Key, V>,
+{
+    fn default() -> ExtractorRegistry<K, V, KS, B> {
+        ExtractorRegistry::new()
+    }
+}
@@ -0,0 +1,164 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use metadata::{metadata_of, move_metadata};
+use {Backing, IndexRejection, IndexedMap, KeyStorage, RekeyError};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Renames the entry under `old` to `new`, moving its value without cloning it and
+    /// re-indexing it under the new key, so indices computed from keys stay correct. Returns
+    /// `Ok(false)` if `old` is not present, or hands `new` back if another entry already uses it
+    /// or a unique or fallible index refuses the entry under it, in which case nothing is renamed.
+    /// A soft-removed entry under `new` is discarded, as an insert would discard it.
+    pub fn change_key(&mut self, old: &K, new: K) -> Result<bool, RekeyError<K>> {
+        if !self.inner.contains_key(old) {
+            return Ok(false);
+        }
+        if *old != new {
+            if self.inner.contains_key(&new) {
+                return Err(RekeyError::Occupied { key: new });
+            }
+            let stored = match self.inner.get_key_value(old) {
+                Some((stored, _)) => stored.clone(),
+                None => return Ok(false),
+            };
+            self.apply_renames(vec![(stored, new)])?;
+        }
+        Ok(true)
+    }
+
+    /// Renames every entry to the key returned by `f`, as one step: either all entries are
+    /// renamed, or none are if two entries would end up with the same key or a unique or
+    /// fallible index refuses an entry under its new key. Keys may be swapped or shifted freely.
+    /// Soft-removed entries under the new keys are discarded. Returns the number of entries
+    /// whose key changed.
+    pub fn rekey<F>(&mut self, mut f: F) -> Result<usize, RekeyError<K>>
+    where
+        F: FnMut(&K, &V) -> K,
+    {
+        let mut new_keys = HashSet::with_capacity(self.inner.len());
+        let mut renames = Vec::new();
+        for (stored, value) in self.inner.iter() {
+            let key: &K = stored.borrow();
+            let new = f(key, value);
+            if !new_keys.insert(new.clone()) {
+                return Err(RekeyError::Occupied { key: new });
+            }
+            if new != *key {
+                renames.push((stored.clone(), new));
+            }
+        }
+        let renamed = renames.len();
+        self.apply_renames(renames)?;
+        Ok(renamed)
+    }
+
+    /// Moves entries to new keys in two passes, removing all of them before re-inserting any, so
+    /// a new key may be one that is being vacated by another rename. The indices are moved first
+    /// and on their own, asking unique and fallible indices to approve each entry under its new
+    /// key as `try_insert` would; if one refuses, the indices are moved back and nothing else is
+    /// touched.
+    fn apply_renames(&mut self, renames: Vec<(KS::Key, K)>) -> Result<(), RekeyError<K>> {
+        let renames: Vec<(KS::Key, KS::Key)> = renames
+            .into_iter()
+            .map(|(old, new)| (old, KS::store(new)))
+            .collect();
+        if let Some((placed, rejection)) = self.reindex_renames(&renames) {
+            self.undo_reindex_renames(&renames, placed);
+            let (_, new) = renames.into_iter().nth(placed).expect("the refused rename");
+            return Err(rejection.into_rekey_error(KS::into_key(new)));
+        }
+        let mut moving = Vec::with_capacity(renames.len());
+        for (stored, new) in renames {
+            let old: &K = stored.borrow();
+            let pinned = self.pinned.remove(old);
+            let expiry = self.expirations.remove(old);
+            if let Some(ref mut bounds) = self.bounds {
+                bounds.forget(old);
+            }
+            self.key_indices.remove(old);
+            if let Some((_, value)) = self.take_entry(old) {
+                moving.push((new, value, pinned, expiry));
+            }
+        }
+        for (stored, value, pinned, expiry) in moving {
+            if pinned {
+                self.pinned.insert(stored.clone());
+            }
+            if let Some(expiry) = expiry {
+                self.expirations.insert(stored.clone(), expiry);
+            }
+            self.store_entry(stored, value);
+        }
+        Ok(())
+    }
+
+    /// Moves the index postings and metadata of every renamed entry to its new key, in order,
+    /// stopping at the first entry an index refuses under its new key. Returns the position of
+    /// that entry and why it was refused; the entries before it are indexed under their new keys
+    /// and the rest under none.
+    fn reindex_renames(
+        &mut self,
+        renames: &[(KS::Key, KS::Key)],
+    ) -> Option<(usize, IndexRejection<K>)> {
+        for (old, new) in renames {
+            let old: &K = old.borrow();
+            self.indices
+                .values_mut()
+                .flat_map(|by_type| by_type.values_mut())
+                .for_each(|updater| updater.remove(old));
+            move_metadata::<K, KS>(&mut self.metadata, old, new);
+        }
+        let validating = self.validating();
+        for (position, (old, new)) in renames.iter().enumerate() {
+            let value = self
+                .inner
+                .get(old.borrow())
+                .expect("renamed entries are present");
+            if validating {
+                if let Some(rejection) = self.rejection(new.borrow(), value, |_| true) {
+                    self.discard_checked();
+                    return Some((position, rejection));
+                }
+            }
+            let metadata = metadata_of::<K, KS>(&self.metadata, new.borrow());
+            self.indices
+                .values_mut()
+                .flat_map(|by_type| by_type.values_mut())
+                .for_each(|updater| updater.insert(new, value, metadata));
+        }
+        self.discard_checked();
+        None
+    }
+
+    /// Undoes `reindex_renames` after the entry at `placed` was refused, indexing every entry
+    /// under its old key again.
+    fn undo_reindex_renames(&mut self, renames: &[(KS::Key, KS::Key)], placed: usize) {
+        for (_, new) in &renames[..placed] {
+            let new: &K = new.borrow();
+            self.indices
+                .values_mut()
+                .flat_map(|by_type| by_type.values_mut())
+                .for_each(|updater| updater.remove(new));
+        }
+        for (old, new) in renames {
+            move_metadata::<K, KS>(&mut self.metadata, new.borrow(), old);
+            let value = self
+                .inner
+                .get(old.borrow())
+                .expect("renamed entries are present");
+            let metadata = metadata_of::<K, KS>(&self.metadata, old.borrow());
+            self.indices
+                .values_mut()
+                .flat_map(|by_type| by_type.values_mut())
+                .for_each(|updater| updater.insert(old, value, metadata));
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+use {Backing, ChangeEvent, IndexedMap, InsertError, KeyStorage};
+
+/// Everything a standby replica needs to catch up with a primary: the entries at the moment of
+/// subscription, followed by every change made after it. Both halves are plain data, so a feed
+/// can be sent to the thread that will own the replica.
+pub struct ReplicaFeed<K, V> {
+    pub snapshot: Vec<(K, V)>,
+    pub events: Receiver<ChangeEvent<K, V>>,
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Subscribes a new replica to this map. Every later mutation is sent down the feed's
+    /// channel until its receiver is dropped.
+    pub fn replicate(&mut self) -> ReplicaFeed<K, V> {
+        let (sender, events) = channel();
+        self.changes.add_feed(sender);
+        let snapshot = self
+            .inner
+            .iter()
+            .map(|(key, value)| {
+                let k: &K = key.borrow();
+                (k.clone(), value.clone())
+            })
+            .collect();
+        ReplicaFeed { snapshot, events }
+    }
+}
+
+/// A read-only copy of a primary map kept in sync from a `ReplicaFeed`, so queries can be served
+/// from another thread than the writer's. The replica has its own indices, which need not match
+/// the primary's.
+///
+/// The replica does not apply changes by itself: whoever owns it calls `sync` to catch up, e.g.
+/// before each batch of reads.
+pub struct StandbyReplica<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: IndexedMap<K, V, KS, B>,
+    events: Receiver<ChangeEvent<K, V>>,
+    connected: bool,
+}
+
+impl<K, V, KS, B> StandbyReplica<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Loads the feed's snapshot into `map`, which would typically be empty with its indices
+    /// already registered.
+    pub fn new(
+        mut map: IndexedMap<K, V, KS, B>,
+        feed: ReplicaFeed<K, V>,
+    ) -> Result<StandbyReplica<K, V, KS, B>, InsertError<K, V>> {
+        for (key, value) in feed.snapshot {
+            map.try_insert(key, value)?;
+        }
+        Ok(StandbyReplica {
+            map,
+            events: feed.events,
+            connected: true,
+        })
+    }
+
+    /// Applies every change received so far without blocking, returning how many were applied.
+    /// A change rejected by the replica's capacity limit is returned as an error and lost.
+    pub fn sync(&mut self) -> Result<usize, InsertError<K, V>> {
+        let mut applied = 0;
+        while self.connected {
+            match self.events.try_recv() {
+                Ok(event) => applied += self.map.replay(Some(event))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.connected = false,
+            }
+        }
+        Ok(applied)
+    }
+
+    /// Whether the primary is still sending changes. Once it has been dropped the replica keeps
+    /// serving the last state it synced.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn into_inner(self) -> IndexedMap<K, V, KS, B> {
+        self.map
+    }
+}
+
+impl<K, V, KS, B> Deref for StandbyReplica<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    type Target = IndexedMap<K, V, KS, B>;
+
+    fn deref(&self) -> &IndexedMap<K, V, KS, B> {
+        &self.map
+    }
+}
@@ -0,0 +1,192 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage, PostingObserver};
+
+/// A uniform sample of one bucket, kept with reservoir sampling as postings arrive.
+#[derive(Clone)]
+struct Reservoir<P> {
+    population: u64,
+    sample: Vec<P>,
+}
+
+/// The reservoirs of every bucket of an index.
+struct Reservoirs<K, A, KS>
+where
+    KS: KeyStorage<K>,
+{
+    size: usize,
+    by_bucket: HashMap<A, Reservoir<KS::Key>>,
+    rng: u64,
+    _key: PhantomData<K>,
+}
+
+impl<K, A, KS> Reservoirs<K, A, KS>
+where
+    KS: KeyStorage<K>,
+{
+    /// A xorshift step; sampling only needs cheap, well-spread numbers, not cryptographic ones.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+impl<K, V, A, KS> PostingObserver<K, V, A, KS> for Reservoirs<K, A, KS>
+where
+    K: 'static + Eq + Hash,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, key: &KS::Key, _: &V) {
+        let size = self.size;
+        let random = self.next_random();
+        let reservoir = self
+            .by_bucket
+            .entry(index_key.clone())
+            .or_insert_with(|| Reservoir {
+                population: 0,
+                sample: Vec::with_capacity(size),
+            });
+        reservoir.population += 1;
+        if reservoir.sample.len() < size {
+            reservoir.sample.push(key.clone());
+        } else {
+            let slot = (random % reservoir.population) as usize;
+            if slot < size {
+                reservoir.sample[slot] = key.clone();
+            }
+        }
+    }
+
+    fn removed(&mut self, index_key: &A, key: &K) {
+        let now_empty = match self.by_bucket.get_mut(index_key) {
+            Some(reservoir) => {
+                reservoir.population -= 1;
+                let sampled = reservoir.sample.iter().position(|stored| {
+                    let stored: &K = stored.borrow();
+                    stored == key
+                });
+                if let Some(position) = sampled {
+                    reservoir.sample.swap_remove(position);
+                }
+                reservoir.population == 0
+            }
+            None => false,
+        };
+        if now_empty {
+            self.by_bucket.remove(index_key);
+        }
+    }
+
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>> {
+        Some(Box::new(Reservoirs::<K, A, KS> {
+            size: self.size,
+            by_bucket: self.by_bucket.clone(),
+            rng: self.rng,
+            _key: PhantomData,
+        }))
+    }
+
+    fn cleared(&mut self) {
+        self.by_bucket.clear();
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index that keeps a uniform sample of up to `sample_size` entries per bucket,
+    /// maintained as entries come and go, so `sample_by_index` answers without walking large
+    /// buckets.
+    pub fn add_sampled_index<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+        sample_size: usize,
+    ) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let seed = RandomState::new().hash_one(&name);
+        self.add_sampled_index_with_seed(name, index_fn, sample_size, seed)
+    }
+
+    /// Like `add_sampled_index`, but draws the samples from `seed`, so the same writes always
+    /// pick the same sample, e.g. in tests.
+    pub fn add_sampled_index_with_seed<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+        sample_size: usize,
+        seed: u64,
+    ) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let seed = seed | 1;
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state.observers.push(Box::new(Reservoirs::<K, A, KS> {
+            size: sample_size,
+            by_bucket: HashMap::new(),
+            rng: seed,
+            _key: PhantomData,
+        }));
+        self.register_new_index(name, index_state)
+    }
+
+    /// Returns a sample of up to the configured number of entries indexed under `index_key`, or
+    /// `None` if the index does not exist or was not added with `add_sampled_index`.
+    ///
+    /// Removing a sampled entry shrinks its bucket's sample until later inserts refill it; in the
+    /// meantime the sample is topped up with arbitrary other entries of the bucket.
+    pub fn sample_by_index<A, Q>(
+        &self,
+        index_id: &IndexId<A>,
+        index_key: &Q,
+    ) -> Option<Vec<(&K, &V)>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let state = self.get_index_state(index_id)?;
+        let reservoirs = state.find_observer::<Reservoirs<K, A, KS>>()?;
+        let (bucket, reservoir) = match (
+            state.index.get(index_key),
+            reservoirs.by_bucket.get(index_key),
+        ) {
+            (Some(bucket), Some(reservoir)) => (bucket, reservoir),
+            _ => return Some(Vec::new()),
+        };
+        let mut keys: Vec<&KS::Key> = reservoir.sample.iter().collect();
+        if keys.len() < reservoirs.size && keys.len() < bucket.len() {
+            let missing = reservoirs.size.min(bucket.len()) - keys.len();
+            let top_up: Vec<&KS::Key> = bucket
+                .iter()
+                .filter(|key| !reservoir.sample.contains(key))
+                .take(missing)
+                .collect();
+            keys.extend(top_up);
+        }
+        Some(
+            keys.into_iter()
+                .filter_map(|key| {
+                    let key: &K = key.borrow();
+                    self.inner.get(key).map(|value| (key, value))
+                })
+                .collect(),
+        )
+    }
+}
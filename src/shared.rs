@@ -0,0 +1,433 @@
+use std::any::Any;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Arc;
+
+use {Backing, ClonedKeys, Entries, IndexId, IndexedMap, KeyStorage, PostingObserver};
+
+/// How many chunks a `Chunked` map is split into.
+const CHUNKS: usize = 64;
+
+/// A hash map split by key hash into chunks that are each behind an `Arc`. Cloning it only
+/// clones the `Arc`s, and a write copies the one chunk it touches, and only while a clone still
+/// holds that chunk, so a map and its snapshots share every chunk neither has written since.
+pub(crate) struct Chunked<K, V> {
+    chunks: Vec<Arc<HashMap<K, V>>>,
+    len: usize,
+    /// Copies the values of a chunk that is written while shared.
+    copy: fn(&V) -> V,
+}
+
+impl<K, V> Clone for Chunked<K, V> {
+    fn clone(&self) -> Chunked<K, V> {
+        Chunked {
+            chunks: self.chunks.clone(),
+            len: self.len,
+            copy: self.copy,
+        }
+    }
+}
+
+fn chunk_of<Q: ?Sized + Hash>(key: &Q) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % CHUNKS as u64) as usize
+}
+
+impl<K, V> Chunked<K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) fn new(copy: fn(&V) -> V) -> Chunked<K, V> {
+        Chunked {
+            chunks: (0..CHUNKS).map(|_| Arc::new(HashMap::new())).collect(),
+            len: 0,
+            copy,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.chunks[chunk_of(key)].get(key)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+}
+
+impl<K, V> Chunked<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    /// The chunk `key` belongs in, copied first if a clone still shares it.
+    fn chunk_mut(&mut self, key: &K) -> &mut HashMap<K, V> {
+        let copy = self.copy;
+        let chunk = &mut self.chunks[chunk_of(key)];
+        if Arc::get_mut(chunk).is_none() {
+            let copied = chunk.iter().map(|(key, value)| (key.clone(), copy(value)));
+            *chunk = Arc::new(copied.collect());
+        }
+        Arc::get_mut(chunk).expect("a freshly copied chunk is not shared")
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.chunk_mut(&key).insert(key, value).is_none() {
+            self.len += 1;
+        }
+    }
+
+    /// Removes `key`, leaving its chunk shared if it was not there.
+    pub(crate) fn remove(&mut self, key: &K) {
+        if self.chunks[chunk_of(key)].contains_key(key) {
+            self.chunk_mut(key).remove(key);
+            self.len -= 1;
+        }
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if self.chunks[chunk_of(key)].contains_key(key) {
+            self.chunk_mut(key).get_mut(key)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Chunked::new(self.copy);
+    }
+}
+
+/// A `Chunked` copy kept current for shared snapshots, and a handle that every snapshot taken
+/// from it holds. Once no snapshot holds the handle, keeping the copy current only slows writes
+/// down, so the next write drops it and the next snapshot copies afresh.
+pub(crate) struct Mirror<K, V> {
+    chunked: Chunked<K, V>,
+    readers: Arc<()>,
+}
+
+impl<K, V> Mirror<K, V> {
+    pub(crate) fn new(chunked: Chunked<K, V>) -> Mirror<K, V> {
+        Mirror {
+            chunked,
+            readers: Arc::new(()),
+        }
+    }
+
+    /// Whether a snapshot taken from the copy is still alive.
+    pub(crate) fn is_read(&self) -> bool {
+        Arc::strong_count(&self.readers) > 1
+    }
+
+    pub(crate) fn chunked_mut(&mut self) -> &mut Chunked<K, V> {
+        &mut self.chunked
+    }
+
+    /// The copy as it is now, and the handle for the snapshot it goes into to hold.
+    fn share(&self) -> (Chunked<K, V>, Arc<()>) {
+        (self.chunked.clone(), self.readers.clone())
+    }
+
+    /// The same copy for a clone of the map, which none of this map's snapshots read for.
+    pub(crate) fn detached(&self) -> Mirror<K, V> {
+        Mirror::new(self.chunked.clone())
+    }
+}
+
+type SharedPostings = Box<dyn Any + Send + Sync>;
+
+/// Keeps a `Chunked` copy of an index's postings current, so that snapshots can share it.
+/// Attached by `SharedSnapshotBuilder::with_index` the first time it includes the index, and
+/// emptied by the first write after the last snapshot holding the copy is dropped.
+struct PostingsMirror<K, A> {
+    postings: Option<Mirror<A, HashSet<K>>>,
+}
+
+impl<K, A> PostingsMirror<K, A>
+where
+    K: Eq + Hash + Clone,
+    A: Eq + Hash + Clone,
+{
+    /// The copy to update for a write, unless no snapshot reads it any more.
+    fn postings_mut(&mut self) -> Option<&mut Chunked<A, HashSet<K>>> {
+        if self
+            .postings
+            .as_ref()
+            .is_some_and(|postings| !postings.is_read())
+        {
+            self.postings = None;
+        }
+        self.postings.as_mut().map(Mirror::chunked_mut)
+    }
+}
+
+impl<K, V, A, KS> PostingObserver<K, V, A, KS> for PostingsMirror<K, A>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, key: &KS::Key, _: &V) {
+        let key: &K = key.borrow();
+        let postings = match self.postings_mut() {
+            Some(postings) => postings,
+            None => return,
+        };
+        if postings
+            .get(index_key)
+            .is_some_and(|keys| keys.contains(key))
+        {
+            return;
+        }
+        match postings.get_mut(index_key) {
+            Some(keys) => {
+                keys.insert(key.clone());
+            }
+            None => {
+                let keys = Some(key.clone()).into_iter().collect();
+                postings.insert(index_key.clone(), keys);
+            }
+        }
+    }
+
+    fn removed(&mut self, index_key: &A, key: &K) {
+        let postings = match self.postings_mut() {
+            Some(postings) => postings,
+            None => return,
+        };
+        if !postings
+            .get(index_key)
+            .is_some_and(|keys| keys.contains(key))
+        {
+            return;
+        }
+        let now_empty = postings.get_mut(index_key).is_some_and(|keys| {
+            keys.remove(key);
+            keys.is_empty()
+        });
+        if now_empty {
+            postings.remove(index_key);
+        }
+    }
+
+    fn cleared(&mut self) {
+        if let Some(postings) = self.postings_mut() {
+            postings.clear();
+        }
+    }
+
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>> {
+        Some(Box::new(PostingsMirror {
+            postings: self.postings.as_ref().map(Mirror::detached),
+        }))
+    }
+}
+
+struct Contents<K, V> {
+    entries: Chunked<K, V>,
+    indices: HashMap<String, SharedPostings>,
+    /// The handles of the copies the contents came from, which keep the map updating them.
+    _readers: Vec<Arc<()>>,
+}
+
+/// An immutable view of a map and some of its indices, from `shared_snapshot`. It shares its
+/// storage with the map chunk by chunk, so taking one copies nothing, and clones share it too,
+/// so one snapshot can be handed to any number of reader threads while the map keeps changing.
+pub struct SharedSnapshot<K, V> {
+    contents: Arc<Contents<K, V>>,
+}
+
+impl<K, V> Clone for SharedSnapshot<K, V> {
+    fn clone(&self) -> SharedSnapshot<K, V> {
+        SharedSnapshot {
+            contents: self.contents.clone(),
+        }
+    }
+}
+
+impl<K, V> SharedSnapshot<K, V>
+where
+    K: 'static + Eq + Hash,
+    V: 'static,
+{
+    pub fn len(&self) -> usize {
+        self.contents.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.contents.entries.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.contents.entries.iter()
+    }
+
+    /// The keys indexed under `index_key` when the snapshot was taken. Returns `None` if the
+    /// index was not included in the snapshot or had no such bucket.
+    pub fn keys_by_index<A, H, Q>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<&HashSet<K>>
+    where
+        A: 'static + Eq + Hash + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.contents
+            .indices
+            .get(index_id.name())?
+            .downcast_ref::<Chunked<A, HashSet<K>>>()?
+            .get(index_key)
+    }
+
+    pub fn filter_by_index<A, H, Q>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let keys = self.keys_by_index(index_id, index_key)?;
+        Some(
+            keys.iter()
+                .filter_map(|key| self.get(key).map(|value| (key, value)))
+                .collect(),
+        )
+    }
+}
+
+/// Chooses the indices a `SharedSnapshot` carries. Created by `IndexedMap::shared_snapshot`.
+pub struct SharedSnapshotBuilder<'m, K: 'm, V: 'm, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: &'m mut IndexedMap<K, V, KS, B>,
+    indices: HashMap<String, SharedPostings>,
+    readers: Vec<Arc<()>>,
+}
+
+impl<'m, K, V, KS, B> SharedSnapshotBuilder<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + Send + Sync,
+    V: 'static + Clone + Send + Sync,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Includes one index in the snapshot. The first snapshot to include an index copies its
+    /// postings once, and from then on the index keeps that copy current as it changes, so
+    /// later snapshots share it like the entries, until a write finds no snapshot holding it.
+    /// An unavailable index is left out, and queries on it return `None`.
+    pub fn with_index<A, H>(self, index_id: &IndexId<A, H>) -> Self
+    where
+        A: 'static + Eq + Hash + Clone + Send + Sync,
+        H: 'static + BuildHasher + Clone,
+    {
+        let SharedSnapshotBuilder {
+            map,
+            mut indices,
+            mut readers,
+        } = self;
+        let postings = map
+            .split_index_state_mut(index_id)
+            .filter(|(_, _, state)| state.is_current())
+            .map(|(_, _, state)| {
+                if state.find_observer::<PostingsMirror<K, A>>().is_none() {
+                    state
+                        .observers
+                        .push(Box::new(PostingsMirror { postings: None }));
+                }
+                let index = &state.index;
+                let mirror = state
+                    .observers
+                    .iter_mut()
+                    .filter_map(|observer| observer.downcast_mut::<PostingsMirror<K, A>>())
+                    .next()?;
+                let postings = mirror.postings.get_or_insert_with(|| {
+                    let mut postings = Chunked::new(HashSet::clone);
+                    for (index_key, keys) in index.iter() {
+                        let keys = keys.iter().map(|key| Borrow::<K>::borrow(key).clone());
+                        postings.insert(index_key.clone(), keys.collect());
+                    }
+                    Mirror::new(postings)
+                });
+                Some(postings.share())
+            });
+        if let Some(Some((postings, handle))) = postings {
+            indices.insert(index_id.name().to_string(), Box::new(postings));
+            readers.push(handle);
+        }
+        SharedSnapshotBuilder {
+            map,
+            indices,
+            readers,
+        }
+    }
+
+    /// Finishes the snapshot, sharing the map's entries as they are now.
+    pub fn build(self) -> SharedSnapshot<K, V> {
+        let mut readers = self.readers;
+        let entries = match self.map.changes.shared_entries() {
+            Some(shared) => {
+                let (entries, handle) = shared.share();
+                readers.push(handle);
+                entries
+            }
+            None => Chunked::new(V::clone),
+        };
+        SharedSnapshot {
+            contents: Arc::new(Contents {
+                entries,
+                indices: self.indices,
+                _readers: readers,
+            }),
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone + Send + Sync,
+    V: 'static + Clone + Send + Sync,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Starts a snapshot that readers on other threads can query, including the indices chosen
+    /// with `with_index`. The first call copies the entries once into chunks that the map then
+    /// keeps current on every write, at the cost of one more clone of each written key and
+    /// value. Every snapshot after that shares those chunks and costs a reference count
+    /// increment per chunk, and a write copies the chunk it touches only while a snapshot still
+    /// holds it. The first write after every snapshot is dropped stops keeping the chunks
+    /// current, and the next call copies the entries again.
+    pub fn shared_snapshot(&mut self) -> SharedSnapshotBuilder<'_, K, V, KS, B> {
+        if self.changes.shared_entries().is_none() {
+            let mut entries = Chunked::new(V::clone);
+            for (key, value) in self.inner.iter() {
+                let key: &K = key.borrow();
+                entries.insert(key.clone(), value.clone());
+            }
+            self.changes.share_entries(entries);
+        }
+        SharedSnapshotBuilder {
+            map: self,
+            indices: HashMap::new(),
+            readers: Vec::new(),
+        }
+    }
+}
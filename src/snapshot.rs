@@ -0,0 +1,214 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use {Backing, FrozenBuilder, IndexedMap, KeyStorage};
+
+/// A copy of every entry of a map at one point in time, from `snapshot`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapSnapshot<K, V>
+where
+    K: Eq + Hash,
+{
+    entries: HashMap<K, V>,
+}
+
+impl<K, V> MapSnapshot<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + PartialEq,
+{
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    /// Writes the snapshot to `path` in the layout read by `FrozenMap`, encoding entries with
+    /// `encode_key` and `encode_value`. The file is written beside `path` and renamed over it,
+    /// so readers never see a partial checkpoint.
+    pub fn save<P, EK, EV>(&self, path: P, encode_key: EK, encode_value: EV) -> io::Result<()>
+    where
+        K: 'static,
+        P: AsRef<Path>,
+        EK: Fn(&K) -> Vec<u8>,
+        EV: Fn(&V) -> Vec<u8>,
+    {
+        write_checkpoint(path.as_ref(), self.entries.iter(), encode_key, encode_value)
+    }
+
+    /// The changes that turn this snapshot into `to`.
+    pub fn diff(&self, to: &MapSnapshot<K, V>) -> SnapshotDiff<K, V> {
+        diff(
+            self.entries.iter(),
+            |key| self.entries.contains_key(key),
+            &to.entries,
+        )
+    }
+}
+
+/// Writes `entries` to `path` in the layout read by `FrozenMap`, beside `path` first and then
+/// renamed over it.
+fn write_checkpoint<'a, K, V, I, EK, EV>(
+    path: &Path,
+    entries: I,
+    encode_key: EK,
+    encode_value: EV,
+) -> io::Result<()>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'a,
+    I: Iterator<Item = (&'a K, &'a V)>,
+    EK: Fn(&K) -> Vec<u8>,
+    EV: Fn(&V) -> Vec<u8>,
+{
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let bytes = FrozenBuilder::from_entries(entries, encode_key, encode_value).finish();
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, path)
+}
+
+/// The entries to insert, update and remove to turn one state of a map into another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotDiff<K, V> {
+    /// Entries whose keys are missing from the starting state.
+    pub inserts: Vec<(K, V)>,
+    /// Entries whose keys are present in both states with different values.
+    pub updates: Vec<(K, V)>,
+    /// Keys missing from the target state.
+    pub removes: Vec<K>,
+}
+
+impl<K, V> SnapshotDiff<K, V> {
+    /// The number of entries the diff touches.
+    pub fn len(&self) -> usize {
+        self.inserts.len() + self.updates.len() + self.removes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn diff<'a, K, V, I, C>(from: I, contains: C, to: &HashMap<K, V>) -> SnapshotDiff<K, V>
+where
+    K: 'a + Eq + Hash + Clone,
+    V: 'a + Clone + PartialEq,
+    I: Iterator<Item = (&'a K, &'a V)>,
+    C: Fn(&K) -> bool,
+{
+    let mut updates = Vec::new();
+    let mut removes = Vec::new();
+    for (key, value) in from {
+        match to.get(key) {
+            Some(target) if target != value => updates.push((key.clone(), target.clone())),
+            Some(_) => {}
+            None => removes.push(key.clone()),
+        }
+    }
+    let inserts = to
+        .iter()
+        .filter(|&(key, _)| !contains(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    SnapshotDiff {
+        inserts,
+        updates,
+        removes,
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn snapshot(&self) -> MapSnapshot<K, V> {
+        MapSnapshot {
+            entries: self
+                .inner
+                .iter()
+                .map(|(key, value)| {
+                    let key: &K = key.borrow();
+                    (key.clone(), value.clone())
+                })
+                .collect(),
+        }
+    }
+
+    /// Checkpoints the map to `path` on a background thread, as `MapSnapshot::save` would. The
+    /// entries are captured as a `shared_snapshot`, which copies nothing but the chunk pointers
+    /// once the map has taken one before: the first call copies every entry on the calling
+    /// thread. Encoding and writing happen on the background thread and do not hold up further
+    /// writes to the map. Joining the handle reports whether the save succeeded.
+    pub fn save_async<P, EK, EV>(
+        &mut self,
+        path: P,
+        encode_key: EK,
+        encode_value: EV,
+    ) -> JoinHandle<io::Result<()>>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        P: AsRef<Path>,
+        EK: 'static + Send + Fn(&K) -> Vec<u8>,
+        EV: 'static + Send + Fn(&V) -> Vec<u8>,
+    {
+        let snapshot = self.shared_snapshot().build();
+        let path = path.as_ref().to_path_buf();
+        thread::spawn(move || write_checkpoint(&path, snapshot.iter(), encode_key, encode_value))
+    }
+
+    /// The changes that would return the map to the state of `snapshot`.
+    pub fn diff_to(&self, snapshot: &MapSnapshot<K, V>) -> SnapshotDiff<K, V>
+    where
+        V: PartialEq,
+    {
+        let from = self.inner.iter().map(|(key, value)| (key.borrow(), value));
+        diff(from, |key| self.inner.contains_key(key), &snapshot.entries)
+    }
+
+    /// Returns the map to the state of `snapshot` by applying only the entries that differ,
+    /// keeping every index up to date, and returns what was applied. Entries equal in both are
+    /// left alone, so their metadata and index postings survive, which makes rolling back a few
+    /// changes much cheaper than rebuilding the map.
+    ///
+    /// The capacity limit, unique indices and fallible index functions are not consulted, since
+    /// every restored entry was in the map before. An index added since the snapshot was taken
+    /// may therefore end up with a value shared by several entries, as `duplicates_by` shows.
+    pub fn restore_to(&mut self, snapshot: &MapSnapshot<K, V>) -> SnapshotDiff<K, V>
+    where
+        V: PartialEq,
+    {
+        let diff = self.diff_to(snapshot);
+        for key in &diff.removes {
+            self.remove_unchecked(key);
+        }
+        for (key, value) in &diff.updates {
+            let previous = match self.inner.get_mut(key) {
+                Some(current) => mem::replace(current, value.clone()),
+                None => continue,
+            };
+            self.reindex_unchecked(key, Some(previous), |_| true);
+        }
+        for (key, value) in &diff.inserts {
+            self.insert_unchecked(key.clone(), value.clone());
+        }
+        diff
+    }
+}
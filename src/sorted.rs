@@ -0,0 +1,168 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::{Deref, RangeBounds};
+
+use queue::Priorities;
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage};
+
+/// Identifies an index whose buckets are also kept in order, for range and min/max queries.
+/// Derefs to the plain `IndexId` for exact-match queries.
+pub struct SortedIndexId<A> {
+    index_id: IndexId<A>,
+}
+
+impl<A> Deref for SortedIndexId<A> {
+    type Target = IndexId<A>;
+
+    fn deref(&self) -> &IndexId<A> {
+        &self.index_id
+    }
+}
+
+impl<A> Clone for SortedIndexId<A> {
+    fn clone(&self) -> SortedIndexId<A> {
+        SortedIndexId {
+            index_id: self.index_id.clone(),
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index over an ordered attribute, such as a timestamp or a price, that can also be
+    /// queried by range.
+    pub fn add_sorted_index<A, F>(&mut self, name: String, index_fn: F) -> SortedIndexId<A>
+    where
+        A: 'static + Ord + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state
+            .observers
+            .push(Box::new(Priorities::<K, A>::new()));
+        SortedIndexId {
+            index_id: self.register_new_index(name, index_state),
+        }
+    }
+
+    /// The entries with an index value in `range`, ordered by index value. Entries sharing a
+    /// value come in no particular order, and an entry with several values in the range is
+    /// returned once for each. Returns `None` if the index is unavailable.
+    pub fn range_by_index<A, R>(
+        &self,
+        index_id: &SortedIndexId<A>,
+        range: R,
+    ) -> Option<Vec<(&A, &K, &V)>>
+    where
+        A: 'static + Ord + Hash + Clone,
+        R: RangeBounds<A>,
+    {
+        let state = self.get_index_state(index_id)?;
+        let sorted = state.find_observer::<Priorities<K, A>>()?;
+        Some(
+            sorted
+                .sizes
+                .range(range)
+                .flat_map(|(index_key, _)| {
+                    state
+                        .index
+                        .get(index_key)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(move |key| {
+                            let key: &K = key.borrow();
+                            self.inner.get(key).map(|value| (index_key, key, value))
+                        })
+                })
+                .collect(),
+        )
+    }
+
+    /// Every indexed entry ordered by index value, ascending; `rev()` walks it descending.
+    /// Entries sharing a value come in no particular order, and an entry with several values is
+    /// visited once for each. Buckets are read as the iterator reaches them, so stopping early
+    /// does not walk the whole index. Returns `None` if the index is unavailable.
+    pub fn iter_sorted<A>(
+        &self,
+        index_id: &SortedIndexId<A>,
+    ) -> Option<impl DoubleEndedIterator<Item = (&A, &K, &V)>>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        let state = self.get_index_state(index_id)?;
+        let sorted = state.find_observer::<Priorities<K, A>>()?;
+        Some(sorted.sizes.keys().flat_map(move |index_key| {
+            state
+                .index
+                .get(index_key)
+                .into_iter()
+                .flatten()
+                .filter_map(|key| {
+                    let key: &K = key.borrow();
+                    self.inner.get(key).map(|value| (index_key, key, value))
+                })
+                .collect::<Vec<_>>()
+        }))
+    }
+
+    /// The `k` entries with the largest index values, largest first, e.g. for a leaderboard.
+    /// Ties at the cut-off are broken arbitrarily.
+    pub fn top_k<A>(&self, index_id: &SortedIndexId<A>, k: usize) -> Option<Vec<(&A, &K, &V)>>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        Some(self.iter_sorted(index_id)?.rev().take(k).collect())
+    }
+
+    /// The `k` entries with the smallest index values, smallest first.
+    pub fn bottom_k<A>(&self, index_id: &SortedIndexId<A>, k: usize) -> Option<Vec<(&A, &K, &V)>>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        Some(self.iter_sorted(index_id)?.take(k).collect())
+    }
+
+    /// The smallest index value and every entry that has it.
+    pub fn min_by_index<A>(&self, index_id: &SortedIndexId<A>) -> Option<(&A, Vec<(&K, &V)>)>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        self.extreme_bucket(index_id, |sorted| sorted.sizes.keys().next())
+    }
+
+    /// The largest index value and every entry that has it.
+    pub fn max_by_index<A>(&self, index_id: &SortedIndexId<A>) -> Option<(&A, Vec<(&K, &V)>)>
+    where
+        A: 'static + Ord + Hash + Clone,
+    {
+        self.extreme_bucket(index_id, |sorted| sorted.sizes.keys().next_back())
+    }
+
+    fn extreme_bucket<'m, A, F>(
+        &'m self,
+        index_id: &SortedIndexId<A>,
+        pick: F,
+    ) -> Option<(&'m A, Vec<(&'m K, &'m V)>)>
+    where
+        A: 'static + Ord + Hash + Clone,
+        F: FnOnce(&'m Priorities<K, A>) -> Option<&'m A>,
+    {
+        let state = self.get_index_state(index_id)?;
+        let index_key = pick(state.find_observer::<Priorities<K, A>>()?)?;
+        let entries = state
+            .index
+            .get(index_key)?
+            .iter()
+            .filter_map(|key| {
+                let key: &K = key.borrow();
+                self.inner.get(key).map(|value| (key, value))
+            })
+            .collect();
+        Some((index_key, entries))
+    }
+}
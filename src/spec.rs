@@ -0,0 +1,100 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use {Backing, DefinitionError, IndexId, IndexState, IndexedMap, KeyStorage};
+
+/// An index defined by a type rather than a name passed at runtime, so that every use of it
+/// refers to the same definition and a misspelt name fails to compile.
+pub trait IndexSpec<K, V>: 'static {
+    /// The name the index is registered under; it shows up in errors and in `index_usage`.
+    const NAME: &'static str;
+
+    type Value: 'static + Eq + Hash + Clone;
+
+    fn index(key: &K, value: &V) -> Vec<Self::Value>;
+}
+
+/// Identifies an index registered from an `IndexSpec`. It carries no data, so it is free to copy
+/// and looking it up allocates nothing.
+pub struct SpecIndexId<S> {
+    _spec: PhantomData<S>,
+}
+
+impl<S> Clone for SpecIndexId<S> {
+    fn clone(&self) -> SpecIndexId<S> {
+        *self
+    }
+}
+
+impl<S> Copy for SpecIndexId<S> {}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds the index described by `S`. Fails with `DefinitionError::DuplicateName` if the map
+    /// already has an index of that name, whether from the same spec or not, instead of
+    /// shadowing it.
+    pub fn add_spec_index<S>(&mut self) -> Result<SpecIndexId<S>, DefinitionError>
+    where
+        S: IndexSpec<K, V>,
+    {
+        if self.indices.contains_key(S::NAME) {
+            return Err(DefinitionError::DuplicateName {
+                name: S::NAME.to_string(),
+            });
+        }
+        let index_state = IndexState::<K, V, S::Value, KS>::empty(S::index);
+        self.register_new_index(S::NAME.to_string(), index_state);
+        Ok(SpecIndexId { _spec: PhantomData })
+    }
+
+    /// The name-based id of a spec index, for the query methods that take one.
+    pub fn spec_index_id<S>(&self, _: SpecIndexId<S>) -> IndexId<S::Value>
+    where
+        S: IndexSpec<K, V>,
+    {
+        IndexId::new(S::NAME.to_string())
+    }
+
+    /// Like `keys_by_index`, without building a name-based id.
+    pub fn keys_by_spec<S, Q>(&self, _: SpecIndexId<S>, index_key: &Q) -> Option<&HashSet<KS::Key>>
+    where
+        S: IndexSpec<K, V>,
+        S::Value: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.index_state_named::<S::Value, RandomState>(S::NAME)
+            .filter(|state| state.is_current())?
+            .index
+            .get(index_key)
+    }
+
+    /// Like `filter_by_index`, without building a name-based id.
+    pub fn filter_by_spec<S, Q>(
+        &self,
+        spec_id: SpecIndexId<S>,
+        index_key: &Q,
+    ) -> Option<HashMap<&K, &V>>
+    where
+        S: IndexSpec<K, V>,
+        S::Value: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let keys = self.keys_by_spec(spec_id, index_key)?;
+        Some(
+            keys.iter()
+                .filter_map(|key| {
+                    let key: &K = key.borrow();
+                    self.inner.get(key).map(|value| (key, value))
+                })
+                .collect(),
+        )
+    }
+}
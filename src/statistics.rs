@@ -0,0 +1,223 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use std::mem::size_of;
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// How many of the largest buckets each index keeps track of.
+const HEAVY_HITTERS: usize = 8;
+
+/// Counters kept up to date by every posting added to or dropped from an index.
+#[derive(Clone)]
+pub(crate) struct Statistics<A> {
+    postings: usize,
+    heavy_hitters: Vec<(A, usize)>,
+}
+
+impl<A> Statistics<A>
+where
+    A: Eq + Clone,
+{
+    pub(crate) fn new() -> Statistics<A> {
+        Statistics {
+            postings: 0,
+            heavy_hitters: Vec::new(),
+        }
+    }
+
+    /// Records that a posting was added to the bucket for `index_key`, which now holds `size`.
+    pub(crate) fn added(&mut self, index_key: &A, size: usize) {
+        self.postings += 1;
+        self.resized(index_key, size);
+    }
+
+    /// Statistics for buckets that were built all at once, from each bucket's value and size.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn of_buckets<'a, I>(buckets: I) -> Statistics<A>
+    where
+        A: 'a,
+        I: IntoIterator<Item = (&'a A, usize)>,
+    {
+        let mut statistics = Statistics::new();
+        for (index_key, size) in buckets {
+            statistics.postings += size;
+            statistics.resized(index_key, size);
+        }
+        statistics
+    }
+
+    /// Keeps the heavy hitters current for a bucket that now holds `size` postings.
+    fn resized(&mut self, index_key: &A, size: usize) {
+        if let Some(hitter) = self.heavy_hitters.iter_mut().find(|(a, _)| a == index_key) {
+            hitter.1 = size;
+            return;
+        }
+        if self.heavy_hitters.len() < HEAVY_HITTERS {
+            self.heavy_hitters.push((index_key.clone(), size));
+        } else if let Some(smallest) = self
+            .heavy_hitters
+            .iter_mut()
+            .min_by_key(|(_, count)| *count)
+            .filter(|(_, count)| *count < size)
+        {
+            *smallest = (index_key.clone(), size);
+        }
+    }
+
+    /// Records that a posting was dropped from the bucket for `index_key`, which now holds `size`.
+    pub(crate) fn removed(&mut self, index_key: &A, size: usize) {
+        self.postings -= 1;
+        if let Some(position) = self.heavy_hitters.iter().position(|(a, _)| a == index_key) {
+            if size == 0 {
+                self.heavy_hitters.swap_remove(position);
+            } else {
+                self.heavy_hitters[position].1 = size;
+            }
+        }
+    }
+
+    pub(crate) fn cleared(&mut self) {
+        self.postings = 0;
+        self.heavy_hitters.clear();
+    }
+}
+
+/// The shape of an index, as reported by `index_statistics`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexStatistics<A> {
+    /// Distinct index values, i.e. non-empty buckets.
+    pub distinct: usize,
+    /// Keys across all buckets; an entry in several buckets counts once per bucket.
+    pub postings: usize,
+    /// Entries the index has values for.
+    pub entries: usize,
+    /// The largest buckets seen, largest first. A bucket that shrank may linger here after
+    /// larger ones overtook it, so this is a sample of likely heavy hitters rather than an exact
+    /// ranking.
+    pub heavy_hitters: Vec<(A, usize)>,
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// The statistics of an index, or `None` if it is missing or disabled. They are maintained as
+    /// the index changes, so this does not scan the buckets.
+    pub fn index_statistics<A, H>(&self, index_id: &IndexId<A, H>) -> Option<IndexStatistics<A>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index_state(index_id).map(|state| {
+            let mut heavy_hitters = state.statistics.heavy_hitters.clone();
+            heavy_hitters.sort_by(|(_, a), (_, b)| b.cmp(a));
+            IndexStatistics {
+                distinct: state.index.len(),
+                postings: state.statistics.postings,
+                entries: state.indexed.len(),
+                heavy_hitters,
+            }
+        })
+    }
+
+    /// The names of every index, sorted and without duplicates, including disabled and cold ones.
+    pub fn index_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .indices
+            .iter()
+            .filter(|(_, by_type)| !by_type.is_empty())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The number of distinct index values, i.e. non-empty buckets, or `None` if the index is
+    /// unavailable.
+    pub fn index_len<A, H>(&self, index_id: &IndexId<A, H>) -> Option<usize>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index(index_id).map(HashMap::len)
+    }
+
+    /// Every index value with the number of keys in its bucket, in no particular order.
+    pub fn bucket_sizes<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<impl Iterator<Item = (&A, usize)>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index(index_id)
+            .map(|index| index.iter().map(|(a, keys)| (a, keys.len())))
+    }
+
+    /// How many buckets there are of each size, smallest size first. A skewed index shows up
+    /// as a long tail ending in a few very large buckets.
+    pub fn bucket_size_histogram<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<BTreeMap<usize, usize>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.bucket_sizes(index_id).map(|sizes| {
+            let mut histogram = BTreeMap::new();
+            for (_, size) in sizes {
+                *histogram.entry(size).or_insert(0) += 1;
+            }
+            histogram
+        })
+    }
+
+    /// An estimate in bytes of the memory an index holds: the allocated slots of its buckets and
+    /// of its per-key record of index values. Heap data owned by the index values or keys
+    /// themselves, such as string contents, is not counted.
+    pub fn index_memory<A, H>(&self, index_id: &IndexId<A, H>) -> Option<usize>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.get_index_state(index_id).map(|state| {
+            let buckets = state.index.capacity() * size_of::<(A, HashSet<KS::Key>)>()
+                + state
+                    .index
+                    .values()
+                    .map(|keys| keys.capacity() * size_of::<KS::Key>())
+                    .sum::<usize>();
+            let indexed = state.indexed.capacity() * size_of::<(KS::Key, HashSet<A, H>)>()
+                + state
+                    .indexed
+                    .values()
+                    .map(|values| values.capacity() * size_of::<A>())
+                    .sum::<usize>();
+            buckets + indexed
+        })
+    }
+
+    /// The fraction of entries, from 0 to 1, in the bucket for `index_key`. Lower is more
+    /// selective, so filtering on the lowest first narrows a query fastest. An unavailable index,
+    /// or an empty map, gives 1, as filtering on it cannot be assumed to narrow anything.
+    pub fn selectivity<A, Q, H>(&self, index_id: &IndexId<A, H>, index_key: &Q) -> f64
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        match self.get_index_state(index_id) {
+            Some(state) if !self.inner.is_empty() => {
+                let matching = state.index.get(index_key).map_or(0, |keys| keys.len());
+                matching as f64 / self.inner.len() as f64
+            }
+            _ => 1.0,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, IndexId, IndexedMap, KeyStorage, PostingObserver, QueryError};
+
+/// A change to one entry of a map, as delivered to `on_change` listeners.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MapEvent<K, V> {
+    /// `key` was inserted with `value`.
+    Insert { key: K, value: V },
+    /// The value of `key` was replaced or changed in place from `old` to `new`.
+    Update { key: K, old: V, new: V },
+    /// `key` was removed, and `value` was its last value.
+    Remove { key: K, value: V },
+}
+
+impl<K, V> MapEvent<K, V> {
+    pub fn key(&self) -> &K {
+        match *self {
+            MapEvent::Insert { ref key, .. }
+            | MapEvent::Update { ref key, .. }
+            | MapEvent::Remove { ref key, .. } => key,
+        }
+    }
+
+    /// A clone of the event, copying its values with `copy`.
+    pub(crate) fn copied(&self, copy: fn(&V) -> V) -> MapEvent<K, V>
+    where
+        K: Clone,
+    {
+        match *self {
+            MapEvent::Insert { ref key, ref value } => MapEvent::Insert {
+                key: key.clone(),
+                value: copy(value),
+            },
+            MapEvent::Update {
+                ref key,
+                ref old,
+                ref new,
+            } => MapEvent::Update {
+                key: key.clone(),
+                old: copy(old),
+                new: copy(new),
+            },
+            MapEvent::Remove { ref key, ref value } => MapEvent::Remove {
+                key: key.clone(),
+                value: copy(value),
+            },
+        }
+    }
+}
+
+/// A change to the buckets of one index, as delivered to `on_index_change` listeners.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IndexEvent<A, K> {
+    /// `key` now has `index_key` among its index values.
+    Added { index_key: A, key: K },
+    /// `key` no longer has `index_key` among its index values.
+    Removed { index_key: A, key: K },
+    /// Every posting was dropped at once, e.g. by `clear` or because the index went cold.
+    Cleared,
+}
+
+type IndexListener<A, K> = Box<dyn FnMut(IndexEvent<A, K>)>;
+
+struct Subscriber<A, K> {
+    listener: IndexListener<A, K>,
+}
+
+impl<K, V, A, KS> PostingObserver<K, V, A, KS> for Subscriber<A, K>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    A: 'static + Clone,
+    KS: KeyStorage<K>,
+{
+    fn added(&mut self, index_key: &A, key: &KS::Key, _value: &V) {
+        let key: &K = key.borrow();
+        (self.listener)(IndexEvent::Added {
+            index_key: index_key.clone(),
+            key: key.clone(),
+        });
+    }
+
+    fn removed(&mut self, index_key: &A, key: &K) {
+        (self.listener)(IndexEvent::Removed {
+            index_key: index_key.clone(),
+            key: key.clone(),
+        });
+    }
+
+    /// Listeners belong to the map they were registered on and are not carried over to clones.
+    fn duplicate(&self) -> Option<Box<dyn PostingObserver<K, V, A, KS>>> {
+        None
+    }
+
+    fn cleared(&mut self) {
+        (self.listener)(IndexEvent::Cleared);
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Calls `listener` after every insert, overwrite, in-place update and removal, with the
+    /// entry's old and new values, so caches built on the map can follow it incrementally.
+    /// Listeners run during the write and cannot access the map. While any are registered, values
+    /// changed in place are cloned before the change to report the old value.
+    pub fn on_change<F>(&mut self, listener: F)
+    where
+        F: 'static + FnMut(MapEvent<K, V>),
+    {
+        self.changes.add_listener(Box::new(listener));
+    }
+
+    /// Calls `listener` whenever a key joins or leaves a bucket of the index. Writes retract all
+    /// of an entry's postings before adding the new ones, so an index value that survives an
+    /// update is reported as removed and then added again.
+    pub fn on_index_change<A, H, F>(
+        &mut self,
+        index_id: &IndexId<A, H>,
+        listener: F,
+    ) -> Result<(), QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+        F: 'static + FnMut(IndexEvent<A, K>),
+    {
+        match self.split_index_state_mut(index_id) {
+            Some((_, _, state)) => {
+                state.observers.push(Box::new(Subscriber {
+                    listener: Box::new(listener),
+                }));
+                Ok(())
+            }
+            None => Err(self.missing_index(index_id)),
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! A tabular view of a map for SQL engines.
+//!
+//! `Table` describes an `IndexedMap` as named columns and answers scans with equality filters,
+//! serving filters on indexed columns from the index postings instead of visiting every entry.
+//! This is the part of a table provider that needs to know about the map. It deliberately
+//! implements no engine's provider trait: DataFusion's and GlueSQL's are async and would pull an
+//! async runtime and Arrow into every build with the feature. Binding `Table` to an engine
+//! (converting `Value`s into its row or columnar format and reporting which filters are exact,
+//! which `supports_pushdown` answers) is left to a thin adapter in the application or an
+//! integration crate.
+
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+
+use {Backing, IndexId, IndexedMap, KeyStorage, QueryError};
+
+/// A single cell value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+/// Index key types that can be compared against filter values.
+pub trait ColumnValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+macro_rules! int_column_value {
+    ($($t:ty),*) => {
+        $(
+            impl ColumnValue for $t {
+                fn from_value(value: &Value) -> Option<$t> {
+                    match *value {
+                        Value::Int(i) if i >= <$t>::MIN as i64 && i as i128 <= <$t>::MAX as i128 => {
+                            Some(i as $t)
+                        }
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+int_column_value!(i8, i16, i32, i64, u8, u16, u32, u64, usize, isize);
+
+impl ColumnValue for bool {
+    fn from_value(value: &Value) -> Option<bool> {
+        match *value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl ColumnValue for String {
+    fn from_value(value: &Value) -> Option<String> {
+        match *value {
+            Value::Str(ref s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+type Extractor<K, V> = Box<dyn Fn(&K, &V) -> Value>;
+
+type Lookup<K, V, KS, B> =
+    Box<dyn for<'m> Fn(&'m IndexedMap<K, V, KS, B>, &Value) -> Result<Vec<&'m K>, QueryError>>;
+
+struct Column<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    name: String,
+    extract: Extractor<K, V>,
+    lookup: Option<Lookup<K, V, KS, B>>,
+}
+
+/// Why `Table::scan` could not answer a scan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanError {
+    /// The projection asked for a column position the table does not have.
+    UnknownColumn { position: usize, columns: usize },
+    /// An index backing a filtered column could not be queried.
+    Query(QueryError),
+}
+
+impl From<QueryError> for ScanError {
+    fn from(err: QueryError) -> ScanError {
+        ScanError::Query(err)
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScanError::UnknownColumn { position, columns } => write!(
+                f,
+                "projected column {} is out of range for a table of {} columns",
+                position, columns
+            ),
+            ScanError::Query(ref err) => write!(f, "index lookup failed: {}", err),
+        }
+    }
+}
+
+impl Error for ScanError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ScanError::UnknownColumn { .. } => None,
+            ScanError::Query(ref err) => Some(err),
+        }
+    }
+}
+
+/// The rows returned by `Table::scan`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// Describes a map as a table of named columns.
+pub struct Table<K, V, KS, B>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    columns: Vec<Column<K, V, KS, B>>,
+}
+
+impl<K, V, KS, B> Table<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn new() -> Table<K, V, KS, B> {
+        Table {
+            columns: Vec::new(),
+        }
+    }
+
+    /// Adds a column computed from each entry. Filters on it are evaluated row by row.
+    pub fn column<F>(mut self, name: &str, extract: F) -> Table<K, V, KS, B>
+    where
+        F: 'static + Fn(&K, &V) -> Value,
+    {
+        self.columns.push(Column {
+            name: name.to_string(),
+            extract: Box::new(extract),
+            lookup: None,
+        });
+        self
+    }
+
+    /// Adds a column backed by an index. Equality filters on it are answered from the index, so
+    /// `extract` must produce the value the index is keyed by.
+    pub fn indexed_column<A, F>(
+        mut self,
+        name: &str,
+        index_id: &IndexId<A>,
+        extract: F,
+    ) -> Table<K, V, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone + ColumnValue,
+        F: 'static + Fn(&K, &V) -> Value,
+    {
+        let index_id = index_id.clone();
+        self.columns.push(Column {
+            name: name.to_string(),
+            extract: Box::new(extract),
+            lookup: Some(Box::new(move |map, value| {
+                let index = map.try_get_index(&index_id)?;
+                Ok(A::from_value(value)
+                    .and_then(|a| index.get(&a))
+                    .map(|keys| keys.iter().map(|k| k.borrow()).collect())
+                    .unwrap_or_default())
+            })),
+        });
+        self
+    }
+
+    pub fn column_names(&self) -> Vec<&str> {
+        self.columns.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    /// Reports, for each `column = value` filter, whether it can be served from an index.
+    pub fn supports_pushdown(&self, filters: &[(&str, Value)]) -> Vec<bool> {
+        filters
+            .iter()
+            .map(|&(name, _)| {
+                self.columns
+                    .iter()
+                    .any(|c| c.name == name && c.lookup.is_some())
+            })
+            .collect()
+    }
+
+    /// Returns the rows matching every `column = value` filter, restricted to the `projection`
+    /// column positions if given. The first filter on an indexed column selects the candidate
+    /// entries; the rest are checked against each candidate. Filters on unknown columns match
+    /// nothing, while a projection position past the last column is an error.
+    pub fn scan(
+        &self,
+        map: &IndexedMap<K, V, KS, B>,
+        projection: Option<&[usize]>,
+        filters: &[(&str, Value)],
+    ) -> Result<Rows, ScanError> {
+        let columns = self.columns.len();
+        if let Some(&position) = projection.and_then(|p| p.iter().find(|&&p| p >= columns)) {
+            return Err(ScanError::UnknownColumn { position, columns });
+        }
+        let mut resolved = Vec::with_capacity(filters.len());
+        for &(name, ref value) in filters {
+            match self.columns.iter().position(|c| c.name == name) {
+                Some(position) => resolved.push((position, value)),
+                None => {
+                    return Ok(Rows {
+                        columns: self.projected_names(projection),
+                        rows: Vec::new(),
+                    })
+                }
+            }
+        }
+        let pushed = resolved
+            .iter()
+            .position(|&(position, _)| self.columns[position].lookup.is_some());
+        let candidates: Vec<&K> = match pushed {
+            Some(filter) => {
+                let (position, value) = resolved.remove(filter);
+                let lookup = self.columns[position].lookup.as_ref().unwrap();
+                lookup(map, value)?
+            }
+            None => map.inner.iter().map(|(k, _)| k.borrow()).collect(),
+        };
+        let all: Vec<usize> = (0..self.columns.len()).collect();
+        let projection = projection.unwrap_or(&all);
+        let rows = candidates
+            .into_iter()
+            .filter_map(|k| map.inner.get(k).map(|v| (k, v)))
+            .filter(|&(k, v)| {
+                resolved
+                    .iter()
+                    .all(|&(position, value)| (self.columns[position].extract)(k, v) == *value)
+            })
+            .map(|(k, v)| {
+                projection
+                    .iter()
+                    .map(|&position| (self.columns[position].extract)(k, v))
+                    .collect()
+            })
+            .collect();
+        Ok(Rows {
+            columns: self.projected_names(Some(projection)),
+            rows,
+        })
+    }
+
+    fn projected_names(&self, projection: Option<&[usize]>) -> Vec<String> {
+        match projection {
+            Some(projection) => projection
+                .iter()
+                .map(|&position| self.columns[position].name.clone())
+                .collect(),
+            None => self.columns.iter().map(|c| c.name.clone()).collect(),
+        }
+    }
+}
+
+impl<K, V, KS, B> Default for Table<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn default() -> Table<K, V, KS, B> {
+        Table::new()
+    }
+}
@@ -0,0 +1,126 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::rc::Rc;
+
+use {HasPrefix, IndexId, IndexedMap};
+
+type AddIndex<K, V> = Box<dyn Fn(&mut IndexedMap<K, V>)>;
+
+/// Keeps a separate `IndexedMap` per tenant, with the same indices defined for every tenant.
+/// Keys and index buckets never mix across tenants, and dropping a tenant frees everything it
+/// held, entries and postings alike, in time proportional to the tenant's size.
+pub struct TenantScopedIndexedMap<T, K, V>
+where
+    T: Eq + Hash,
+    K: 'static + Eq + Hash + Clone,
+{
+    tenants: HashMap<T, IndexedMap<K, V>>,
+    definitions: Vec<AddIndex<K, V>>,
+}
+
+impl<T, K, V> TenantScopedIndexedMap<T, K, V>
+where
+    T: Eq + Hash + Clone,
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    pub fn new() -> TenantScopedIndexedMap<T, K, V> {
+        TenantScopedIndexedMap {
+            tenants: HashMap::new(),
+            definitions: Vec::new(),
+        }
+    }
+
+    /// Adds an index to every current tenant and to every tenant created later.
+    pub fn add_index<A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let index_fn = Rc::new(index_fn);
+        let id_name = name.clone();
+        let add = move |map: &mut IndexedMap<K, V>| {
+            let index_fn = index_fn.clone();
+            map.add_index(name.clone(), move |key, value| index_fn(key, value));
+        };
+        self.tenants.values_mut().for_each(&add);
+        self.definitions.push(Box::new(add));
+        IndexId::new(id_name)
+    }
+
+    /// Inserts an entry for `tenant`, creating the tenant with every defined index if needed.
+    pub fn insert(&mut self, tenant: T, key: K, value: V) -> Option<V> {
+        self.tenant_mut(tenant).insert(key, value)
+    }
+
+    pub fn get(&self, tenant: &T, key: &K) -> Option<&V> {
+        self.tenants.get(tenant).and_then(|map| map.get(key))
+    }
+
+    pub fn filter_by_index<A, Q, H>(
+        &self,
+        tenant: &T,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<HashMap<&K, &V>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        self.tenants
+            .get(tenant)
+            .and_then(|map| map.filter_by_index(index_id, index_key))
+    }
+
+    /// The map of one tenant, for any query the facade does not forward.
+    pub fn tenant(&self, tenant: &T) -> Option<&IndexedMap<K, V>> {
+        self.tenants.get(tenant)
+    }
+
+    /// The map of one tenant, created with every defined index if it does not exist yet.
+    pub fn tenant_mut(&mut self, tenant: T) -> &mut IndexedMap<K, V> {
+        let definitions = &self.definitions;
+        self.tenants.entry(tenant).or_insert_with(|| {
+            let mut map = IndexedMap::new();
+            definitions.iter().for_each(|add| add(&mut map));
+            map
+        })
+    }
+
+    pub fn tenants(&self) -> impl Iterator<Item = &T> {
+        self.tenants.keys()
+    }
+
+    /// Removes a tenant with all of its entries and index contents, returning its map.
+    pub fn drop_tenant(&mut self, tenant: &T) -> Option<IndexedMap<K, V>> {
+        self.tenants.remove(tenant)
+    }
+}
+
+impl<T, K, V> TenantScopedIndexedMap<T, K, V>
+where
+    T: Eq + Hash + Clone + HasPrefix,
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    /// Drops every tenant nested under `parent`, e.g. all `(org, team)` tenants of one org,
+    /// returning how many were dropped.
+    pub fn drop_tenants_under(&mut self, parent: &T::Prefix) -> usize {
+        let before = self.tenants.len();
+        self.tenants.retain(|tenant, _| tenant.prefix() != *parent);
+        before - self.tenants.len()
+    }
+}
+
+impl<T, K, V> Default for TenantScopedIndexedMap<T, K, V>
+where
+    T: Eq + Hash + Clone,
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+{
+    fn default() -> TenantScopedIndexedMap<T, K, V> {
+        TenantScopedIndexedMap::new()
+    }
+}
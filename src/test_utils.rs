@@ -0,0 +1,140 @@
+//! Property-testing support for code built on `IndexedMap`.
+//!
+//! `ArbitraryMap` generates maps with random entries and a random set of indices. Maps are built
+//! from their generated entries and index definitions, and shrinking shrinks those inputs, so
+//! every shrunk case is again a map whose indices agree with its entries. `is_index_consistent`
+//! checks that property for any map after the code under test has mutated it.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use proptest::arbitrary::{any, Arbitrary};
+use proptest::collection::vec;
+use proptest::strategy::{BoxedStrategy, Strategy};
+
+use metadata::metadata_of;
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// A generated map along with the ids of its generated indices. Index `i` buckets each entry by
+/// a hash of its value modulo `moduli[i]`, so small moduli produce large buckets and large ones
+/// spread entries out.
+pub struct ArbitraryMap<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    pub map: IndexedMap<K, V>,
+    pub indices: Vec<IndexId<u64>>,
+    pub moduli: Vec<u64>,
+}
+
+impl<K, V> fmt::Debug for ArbitraryMap<K, V>
+where
+    K: Eq + Hash + Clone + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ArbitraryMap")
+            .field("entries", &self.map.inner)
+            .field("moduli", &self.moduli)
+            .finish()
+    }
+}
+
+impl<K, V> ArbitraryMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone + Hash,
+{
+    /// Builds a map from `entries`, then adds one hash-bucket index per modulus.
+    pub fn build(entries: Vec<(K, V)>, moduli: Vec<u64>) -> ArbitraryMap<K, V> {
+        let mut map = IndexedMap::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        let indices = moduli
+            .iter()
+            .enumerate()
+            .map(|(i, &modulus)| {
+                map.add_index(format!("hash_mod_{}", i), move |_, value: &V| {
+                    vec![hash_of(value) % modulus]
+                })
+            })
+            .collect();
+        ArbitraryMap {
+            map,
+            indices,
+            moduli,
+        }
+    }
+
+    /// Generates maps with up to `max_entries` entries and up to `max_indices` indices.
+    pub fn strategy<KG, VG>(
+        keys: KG,
+        values: VG,
+        max_entries: usize,
+        max_indices: usize,
+    ) -> BoxedStrategy<ArbitraryMap<K, V>>
+    where
+        K: fmt::Debug,
+        V: fmt::Debug,
+        KG: 'static + Strategy<Value = K>,
+        VG: 'static + Strategy<Value = V>,
+    {
+        (
+            vec((keys, values), 0..=max_entries),
+            vec(1..64u64, 0..=max_indices),
+        )
+            .prop_map(|(entries, moduli)| ArbitraryMap::build(entries, moduli))
+            .boxed()
+    }
+}
+
+impl<K, V> Arbitrary for ArbitraryMap<K, V>
+where
+    K: 'static + Eq + Hash + Clone + fmt::Debug + Arbitrary,
+    V: 'static + Clone + Hash + fmt::Debug + Arbitrary,
+{
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ArbitraryMap<K, V>>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<ArbitraryMap<K, V>> {
+        ArbitraryMap::strategy(any::<K>(), any::<V>(), 64, 4)
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether an index holds exactly what its index function produces for the current entries,
+/// with no stale postings or empty buckets. Disabled and missing indices count as consistent.
+pub fn is_index_consistent<K, V, KS, B, A>(
+    map: &IndexedMap<K, V, KS, B>,
+    index_id: &IndexId<A>,
+) -> bool
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+    A: 'static + Eq + Hash + Clone,
+{
+    let state = match map.get_index_state(index_id) {
+        Some(state) => state,
+        None => return true,
+    };
+    let mut expected: HashMap<A, HashSet<KS::Key>> = HashMap::new();
+    for (key, value) in map.inner.iter() {
+        let k: &K = key.borrow();
+        let metadata = metadata_of::<K, KS>(&map.metadata, k);
+        for a in (state.index_fn)(k, value, metadata) {
+            expected.entry(a).or_default().insert(key.clone());
+        }
+    }
+    expected == state.index
+}
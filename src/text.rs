@@ -0,0 +1,170 @@
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+enum Split {
+    Whitespace,
+    NGrams(usize),
+}
+
+/// Splits text into the tokens a text index buckets entries by. The same tokenizer is applied
+/// to query text, so a query matches the way the entries were indexed.
+pub struct Tokenizer {
+    split: Split,
+    lowercase: bool,
+}
+
+impl Tokenizer {
+    /// One token per whitespace-separated word.
+    pub fn whitespace() -> Tokenizer {
+        Tokenizer {
+            split: Split::Whitespace,
+            lowercase: false,
+        }
+    }
+
+    /// Every run of `n` characters within each word, for substring search; words shorter than
+    /// `n` are kept whole. A query matches an entry that contains each of its n-grams.
+    pub fn ngrams(n: usize) -> Tokenizer {
+        Tokenizer {
+            split: Split::NGrams(n.max(1)),
+            lowercase: false,
+        }
+    }
+
+    /// Lowercases text before splitting it, for case-insensitive search.
+    pub fn lowercase(mut self) -> Tokenizer {
+        self.lowercase = true;
+        self
+    }
+
+    /// The distinct tokens of `text`, in the order they first appear.
+    pub fn tokenize(&self, text: &str) -> Vec<String> {
+        let text = if self.lowercase {
+            text.to_lowercase()
+        } else {
+            text.to_string()
+        };
+        let mut seen = HashSet::new();
+        let mut tokens = Vec::new();
+        for word in text.split_whitespace() {
+            let chars: Vec<char> = word.chars().collect();
+            match self.split {
+                Split::NGrams(n) if chars.len() > n => {
+                    for gram in chars.windows(n) {
+                        let gram: String = gram.iter().collect();
+                        if seen.insert(gram.clone()) {
+                            tokens.push(gram);
+                        }
+                    }
+                }
+                _ => {
+                    if seen.insert(word.to_string()) {
+                        tokens.push(word.to_string());
+                    }
+                }
+            }
+        }
+        tokens
+    }
+}
+
+/// Whether `search_text` requires an entry to contain every query token or any one of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextMatch {
+    All,
+    Any,
+}
+
+/// Identifies a text index. Derefs to the plain `IndexId`, whose buckets are single tokens.
+pub struct TextIndexId {
+    index_id: IndexId<String>,
+    tokenizer: Rc<Tokenizer>,
+}
+
+impl TextIndexId {
+    pub fn tokenize(&self, text: &str) -> Vec<String> {
+        self.tokenizer.tokenize(text)
+    }
+}
+
+impl Deref for TextIndexId {
+    type Target = IndexId<String>;
+
+    fn deref(&self) -> &IndexId<String> {
+        &self.index_id
+    }
+}
+
+impl Clone for TextIndexId {
+    fn clone(&self) -> TextIndexId {
+        TextIndexId {
+            index_id: self.index_id.clone(),
+            tokenizer: self.tokenizer.clone(),
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an inverted index over the text `text_fn` extracts from each entry, bucketing the
+    /// entry under every token `tokenizer` finds in it.
+    pub fn add_text_index<F>(
+        &mut self,
+        name: String,
+        tokenizer: Tokenizer,
+        text_fn: F,
+    ) -> TextIndexId
+    where
+        F: 'static + Fn(&K, &V) -> String,
+    {
+        let tokenizer = Rc::new(tokenizer);
+        let tokenize = tokenizer.clone();
+        let index_id = self.add_index(name, move |key, value| {
+            tokenize.tokenize(&text_fn(key, value))
+        });
+        TextIndexId {
+            index_id,
+            tokenizer,
+        }
+    }
+
+    /// The entries containing all or any of the tokens of `query`, with the number of distinct
+    /// query tokens each contains, most matches first. Returns `None` if the index is
+    /// unavailable.
+    pub fn search_text(
+        &self,
+        index_id: &TextIndexId,
+        query: &str,
+        mode: TextMatch,
+    ) -> Option<Vec<(&K, &V, usize)>> {
+        let index = self.get_index(index_id)?;
+        let tokens = index_id.tokenize(query);
+        let mut counts: HashMap<&KS::Key, usize> = HashMap::new();
+        for token in &tokens {
+            for key in index.get(token).into_iter().flatten() {
+                *counts.entry(key).or_insert(0) += 1;
+            }
+        }
+        let mut matches: Vec<(&K, &V, usize)> = counts
+            .into_iter()
+            .filter(|&(_, count)| mode == TextMatch::Any || count == tokens.len())
+            .filter_map(|(key, count)| {
+                let key: &K = key.borrow();
+                self.inner.get(key).map(|value| (key, value, count))
+            })
+            .collect();
+        matches.sort_by_key(|&(_, _, count)| Reverse(count));
+        Some(matches)
+    }
+}
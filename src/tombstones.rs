@@ -0,0 +1,148 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use {Backing, IndexId, IndexedMap, InsertError, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Hides the entry under `key` from the map and its indices while keeping it recoverable with
+    /// `restore` until `purge`. Returns whether an entry was hidden. Inserting under a hidden key
+    /// discards the hidden entry.
+    pub fn soft_remove(&mut self, key: &K) -> bool {
+        match self.remove_unchecked(key) {
+            Some((key, value)) => {
+                self.tombstones.insert(KS::store(key), value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Brings a soft-removed entry back into the map and its indices. Returns `Ok(false)` if
+    /// `key` is not soft-removed, or hands the entry back if the map is at its capacity limit or
+    /// the entry clashes with a unique index, in which case it stays soft-removed.
+    pub fn restore(&mut self, key: &K) -> Result<bool, InsertError<K, V>>
+    where
+        V: Clone,
+    {
+        let (stored, value) = match self.tombstones.remove_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        match self.try_insert(KS::into_key(stored), value) {
+            Ok(_) => Ok(true),
+            Err(err) => {
+                match err {
+                    InsertError::CapacityExceeded {
+                        ref key, ref value, ..
+                    }
+                    | InsertError::UniqueViolation {
+                        ref key, ref value, ..
+                    }
+                    | InsertError::IndexFailed {
+                        ref key, ref value, ..
+                    } => self
+                        .tombstones
+                        .insert(KS::store(key.clone()), value.clone()),
+                };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn is_soft_removed(&self, key: &K) -> bool {
+        self.tombstones.contains_key(key)
+    }
+
+    /// Permanently drops every soft-removed entry, returning how many were dropped.
+    pub fn purge(&mut self) -> usize {
+        let purged = self.tombstones.len();
+        self.tombstones = Default::default();
+        purged
+    }
+
+    /// A read-only view that also sees soft-removed entries.
+    pub fn include_deleted(&self) -> WithDeleted<'_, K, V, KS, B> {
+        WithDeleted { map: self }
+    }
+}
+
+/// The entries of a map together with its soft-removed entries, returned by `include_deleted`.
+pub struct WithDeleted<'a, K, V, KS, B>
+where
+    K: 'a + Eq + Hash,
+    V: 'a,
+    KS: 'a + KeyStorage<K>,
+    B: 'a,
+{
+    map: &'a IndexedMap<K, V, KS, B>,
+}
+
+impl<'a, K, V, KS, B> WithDeleted<'a, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn get(&self, key: &K) -> Option<&'a V> {
+        self.map
+            .inner
+            .get(key)
+            .or_else(|| self.map.tombstones.get(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.inner.len() + self.map.tombstones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, &'a V)> {
+        self.map
+            .inner
+            .iter()
+            .chain(self.map.tombstones.iter())
+            .map(|(key, value)| (key.borrow(), value))
+    }
+
+    /// Like `IndexedMap::filter_by_index`, including soft-removed entries. Soft-removed entries
+    /// are not indexed, so each one is run through the index function; this is meant for the
+    /// occasional recovery query, not the hot path. Returns `None` if the index is unavailable.
+    pub fn filter_by_index<A, Q, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+        index_key: &Q,
+    ) -> Option<Vec<(&'a K, &'a V)>>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        H: 'static + BuildHasher + Clone,
+    {
+        let map = self.map;
+        let state = map.get_index_state(index_id)?;
+        let mut entries: Vec<(&K, &V)> = map
+            .filter_by_index(index_id, index_key)?
+            .into_iter()
+            .collect();
+        entries.extend(map.tombstones.iter().filter_map(|(key, value)| {
+            let key: &K = key.borrow();
+            if (state.index_fn)(key, value, None)
+                .iter()
+                .any(|a| a.borrow() == index_key)
+            {
+                Some((key, value))
+            } else {
+                None
+            }
+        }));
+        Some(entries)
+    }
+}
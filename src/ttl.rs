@@ -0,0 +1,71 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use {Backing, IndexedMap, InsertError, KeyStorage};
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Inserts an entry that `expire_stale` removes once `ttl` has passed. Overwriting it with
+    /// `insert_with_ttl` restarts the clock; overwriting it with a plain `insert` or updating it
+    /// in place keeps the expiry it had.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `insert` if the map refuses the entry. Use `try_insert_with_ttl` on maps with
+    /// a capacity limit, unique indices or fallible indices.
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Option<V> {
+        match self.try_insert_with_ttl(key, value, ttl) {
+            Ok(previous) => previous,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Like `insert_with_ttl`, but hands the entry back if the map refuses it, as `try_insert`
+    /// does.
+    pub fn try_insert_with_ttl(
+        &mut self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<Option<V>, InsertError<K, V>> {
+        let expires_at = Instant::now() + ttl;
+        let previous = self.try_insert(key.clone(), value)?;
+        if let Some((stored, _)) = self.inner.get_key_value(&key) {
+            self.expirations.insert(stored.clone(), expires_at);
+        }
+        Ok(previous)
+    }
+
+    /// When the entry under `key` expires, or `None` if it was inserted without a TTL.
+    pub fn expires_at(&self, key: &K) -> Option<Instant> {
+        self.expirations.get(key).cloned()
+    }
+
+    /// Removes every entry whose TTL has run out from the map and all of its indices, and
+    /// returns them. Pinned entries are skipped and expire on the first sweep after they are
+    /// unpinned.
+    pub fn expire_stale(&mut self) -> Vec<(K, V)> {
+        if self.expirations.is_empty() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let stale: Vec<K> = self
+            .expirations
+            .iter()
+            .filter(|&(key, &expires_at)| {
+                expires_at <= now && !self.pinned.contains::<K>(key.borrow())
+            })
+            .map(|(key, _)| Borrow::<K>::borrow(key).clone())
+            .collect();
+        stale
+            .iter()
+            .filter_map(|key| self.remove_unchecked(key))
+            .collect()
+    }
+}
@@ -0,0 +1,182 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::time::Instant;
+
+use bounded::SavedBounds;
+use metadata::metadata_of;
+use {Backing, ClonedKeys, Entries, EntryMetadata, IndexedMap, InsertError, KeyStorage};
+
+enum Op<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+/// Everything the map holds for one key, as it was before a transaction touched it.
+struct Saved<V> {
+    value: Option<V>,
+    tombstone: Option<V>,
+    pinned: bool,
+    expiry: Option<Instant>,
+    metadata: Option<EntryMetadata>,
+    recency: Option<SavedBounds>,
+}
+
+/// Inserts and removals buffered by `IndexedMap::transaction`, applied together by `commit`.
+/// Nothing reaches the map before then; dropping the transaction or calling `rollback`
+/// discards it.
+pub struct Txn<'m, K: 'm, V: 'm, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: &'m mut IndexedMap<K, V, KS, B>,
+    ops: Vec<Op<K, V>>,
+}
+
+impl<'m, K, V, KS, B> Txn<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.ops.push(Op::Insert(key, value));
+        self
+    }
+
+    pub fn remove(&mut self, key: K) -> &mut Self {
+        self.ops.push(Op::Remove(key));
+        self
+    }
+
+    /// The number of buffered operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Applies the operations in order, each insert checked like `try_insert`. If one is
+    /// rejected, every key the transaction touched is put back as it was before the commit,
+    /// including its pin, expiry, metadata, soft-removed entry and recency, and the error is
+    /// returned. Capacity policies evict only once the whole transaction has been applied.
+    /// Listeners and change feeds see the undone writes followed by the writes undoing them.
+    pub fn commit(self) -> Result<(), InsertError<K, V>> {
+        let map = self.map;
+        if let Some(ref mut bounds) = map.bounds {
+            bounds.set_deferred(true);
+        }
+        let mut saved: Vec<(K, Saved<V>)> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut failure = None;
+        for op in self.ops {
+            let key = match op {
+                Op::Insert(ref key, _) | Op::Remove(ref key) => key,
+            };
+            if seen.insert(key.clone()) {
+                saved.push((key.clone(), map.save_key(key)));
+            }
+            let applied = match op {
+                Op::Insert(key, value) => map.try_insert(key, value).map(|_| ()),
+                Op::Remove(key) => {
+                    map.remove_unchecked(&key);
+                    Ok(())
+                }
+            };
+            if let Err(err) = applied {
+                failure = Some(err);
+                break;
+            }
+        }
+        if let Some(ref mut bounds) = map.bounds {
+            bounds.set_deferred(false);
+        }
+        match failure {
+            Some(err) => {
+                for (key, _) in &saved {
+                    map.remove_unchecked(key);
+                }
+                for (key, state) in saved {
+                    map.restore_key(key, state);
+                }
+                Err(err)
+            }
+            None => {
+                map.enforce_bounds();
+                Ok(())
+            }
+        }
+    }
+
+    /// Discards the buffered operations. Dropping the transaction does the same.
+    pub fn rollback(self) {}
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Starts a transaction: a set of inserts and removals that either all land, with the
+    /// indices updated, or leave the map untouched.
+    pub fn transaction(&mut self) -> Txn<'_, K, V, KS, B> {
+        Txn {
+            map: self,
+            ops: Vec::new(),
+        }
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn save_key(&self, key: &K) -> Saved<V> {
+        Saved {
+            value: self.inner.get(key).cloned(),
+            tombstone: self.tombstones.get(key).cloned(),
+            pinned: self.pinned.contains::<K>(key),
+            expiry: self.expirations.get(key).cloned(),
+            metadata: metadata_of::<K, KS>(&self.metadata, key).cloned(),
+            recency: self.bounds.as_ref().and_then(|bounds| bounds.save(key)),
+        }
+    }
+
+    /// Puts back a key saved by `save_key`, once the key has been removed. The entry is indexed
+    /// with its saved metadata rather than recorded as a fresh write.
+    fn restore_key(&mut self, key: K, saved: Saved<V>) {
+        let stored = KS::store(key);
+        if let (Some(by_key), Some(metadata)) = (self.metadata.as_mut(), saved.metadata) {
+            by_key.insert(stored.clone(), metadata);
+        }
+        if let Some(value) = saved.value {
+            let metadata = metadata_of::<K, KS>(&self.metadata, stored.borrow());
+            self.indices
+                .values_mut()
+                .flat_map(|by_type| by_type.values_mut())
+                .for_each(|updater| updater.insert(&stored, &value, metadata));
+            self.store_entry(stored.clone(), value);
+        }
+        if let Some(tombstone) = saved.tombstone {
+            self.tombstones.insert(stored.clone(), tombstone);
+        }
+        if saved.pinned {
+            self.pinned.insert(stored.clone());
+        }
+        if let Some(expiry) = saved.expiry {
+            self.expirations.insert(stored.clone(), expiry);
+        }
+        if let (Some(bounds), Some(recency)) = (self.bounds.as_mut(), saved.recency) {
+            bounds.restore(&stored, recency);
+        }
+    }
+}
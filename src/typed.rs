@@ -0,0 +1,120 @@
+use std::any::Any;
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use {ClonedKeys, IndexId, IndexedMap, KeyStorage};
+
+/// A value of any type. Values are shared behind an `Arc` rather than boxed so that they stay
+/// cheap to clone, which change capture, snapshots and `try_update` need.
+pub type AnyValue = Arc<dyn Any + Send + Sync>;
+
+/// A map holding values of mixed concrete types, such as the components of an entity registry,
+/// with indices defined per concrete type.
+pub struct TypedMap<K, KS = ClonedKeys>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: IndexedMap<K, AnyValue, KS>,
+}
+
+impl<K> TypedMap<K>
+where
+    K: 'static + Eq + Hash + Clone,
+{
+    pub fn new() -> TypedMap<K> {
+        TypedMap {
+            map: IndexedMap::new(),
+        }
+    }
+}
+
+impl<K> Default for TypedMap<K>
+where
+    K: 'static + Eq + Hash + Clone,
+{
+    fn default() -> TypedMap<K> {
+        TypedMap::new()
+    }
+}
+
+impl<K, KS> TypedMap<K, KS>
+where
+    K: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+{
+    pub fn from_map(map: IndexedMap<K, AnyValue, KS>) -> TypedMap<K, KS> {
+        TypedMap { map }
+    }
+
+    /// Inserts a value of any type, returning the previous value under `key` whatever its type.
+    pub fn insert_typed<T>(&mut self, key: K, value: T) -> Option<AnyValue>
+    where
+        T: Any + Send + Sync,
+    {
+        self.map.insert(key, Arc::new(value))
+    }
+
+    /// Returns the value under `key` if there is one and it is a `T`.
+    pub fn get_typed<T>(&self, key: &K) -> Option<&T>
+    where
+        T: Any + Send + Sync,
+    {
+        self.map
+            .inner
+            .get(key)
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    /// Adds an index over the values of type `T`. Values of other types contribute no index keys.
+    pub fn add_typed_index<T, A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
+    where
+        T: Any + Send + Sync,
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &T) -> Vec<A>,
+    {
+        self.map.add_index(name, move |key, value: &AnyValue| {
+            match value.downcast_ref::<T>() {
+                Some(value) => index_fn(key, value),
+                None => Vec::new(),
+            }
+        })
+    }
+
+    /// Returns the entries of type `T` indexed under `index_key`.
+    pub fn filter_typed_by_index<T, A>(&self, index_id: &IndexId<A>, index_key: &A) -> Vec<(&K, &T)>
+    where
+        T: Any + Send + Sync,
+        A: 'static + Eq + Hash + Clone,
+    {
+        self.map
+            .keys_by_index(index_id, index_key)
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|key| {
+                        let key: &K = key.borrow();
+                        self.get_typed::<T>(key).map(|value| (key, value))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn into_inner(self) -> IndexedMap<K, AnyValue, KS> {
+        self.map
+    }
+}
+
+impl<K, KS> Deref for TypedMap<K, KS>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    type Target = IndexedMap<K, AnyValue, KS>;
+
+    fn deref(&self) -> &IndexedMap<K, AnyValue, KS> {
+        &self.map
+    }
+}
@@ -0,0 +1,184 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::Deref;
+
+use metadata::metadata_of;
+use {
+    Backing, ClonedKeys, DefinitionError, Entries, IndexId, IndexState, IndexedMap, InsertError,
+    KeyStorage,
+};
+
+/// Identifies an index in which no two entries share a value. Derefs to the plain `IndexId`.
+pub struct UniqueIndexId<A> {
+    index_id: IndexId<A>,
+}
+
+impl<A> Deref for UniqueIndexId<A> {
+    type Target = IndexId<A>;
+
+    fn deref(&self) -> &IndexId<A> {
+        &self.index_id
+    }
+}
+
+impl<A> Clone for UniqueIndexId<A> {
+    fn clone(&self) -> UniqueIndexId<A> {
+        UniqueIndexId {
+            index_id: self.index_id.clone(),
+        }
+    }
+}
+
+/// The entry holding one value of a unique index, if any, from `entry_by_unique`.
+pub struct UniqueEntry<'m, K: 'm, V: 'm, A, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: &'m mut IndexedMap<K, V, KS, B>,
+    index_key: A,
+    key: Option<K>,
+}
+
+impl<'m, K, V, A, KS, B> UniqueEntry<'m, K, V, A, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    A: 'static + Eq + Hash + Clone,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn index_key(&self) -> &A {
+        &self.index_key
+    }
+
+    /// The key of the entry holding the index value, or `None` if no entry does.
+    pub fn key(&self) -> Option<&K> {
+        self.key.as_ref()
+    }
+
+    /// Updates the entry in place and reindexes it, if there is one, panicking like `update` if
+    /// an index refuses the change.
+    pub fn and_update<F>(self, f: F) -> UniqueEntry<'m, K, V, A, KS, B>
+    where
+        F: FnOnce(&mut V),
+    {
+        if let Some(ref key) = self.key {
+            self.map.update(key, f);
+        }
+        self
+    }
+
+    /// Returns the entry holding the index value, inserting the entry `f` makes if there is none.
+    /// `f` should make an entry with the index value, or the next lookup will not find it.
+    pub fn or_insert_with<F>(self, f: F) -> Result<(&'m K, &'m V), InsertError<K, V>>
+    where
+        F: FnOnce() -> (K, V),
+    {
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                let (key, value) = f();
+                self.map.try_insert(key.clone(), value)?;
+                key
+            }
+        };
+        let (stored, value) = self
+            .map
+            .inner
+            .get_key_value(&key)
+            .expect("entry was just found or inserted");
+        Ok((stored.borrow(), value))
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Adds an index in which each value may belong to at most one entry. Inserts that would
+    /// give a value to a second entry are rejected by `try_insert`. Fails if existing entries
+    /// already share a value, in which case no index is added.
+    ///
+    /// Updates are checked too: `try_update` rejects a change that would move an entry onto
+    /// another entry's value with `UpdateError::Rejected`, restoring the entry, and `update`,
+    /// `get_mut` and the other infallible update methods panic after restoring it.
+    pub fn add_unique_index<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+    ) -> Result<UniqueIndexId<A>, DefinitionError>
+    where
+        V: Clone,
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        index_state.unique = true;
+        self.build_index_state(&name, &mut index_state)?;
+        // The index may refuse a change in place, which is undone from a copy of the value.
+        self.changes.keep_copies();
+        Ok(UniqueIndexId {
+            index_id: self.register_index(name, index_state),
+        })
+    }
+
+    /// The entry holding `index_key` in a unique index.
+    pub fn get_by_unique_index<A, Q>(
+        &self,
+        index_id: &UniqueIndexId<A>,
+        index_key: &Q,
+    ) -> Option<(&K, &V)>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let key: &K = self
+            .keys_by_index(index_id, index_key)?
+            .iter()
+            .next()?
+            .borrow();
+        self.inner.get(key).map(|value| (key, value))
+    }
+
+    /// The unique indices an entry would clash with if inserted, each with the key already
+    /// holding the clashing value. Replacing an entry's own value is never a clash.
+    pub fn unique_conflicts(&self, key: &K, value: &V) -> Vec<(&str, &K)> {
+        let metadata = metadata_of::<K, KS>(&self.metadata, key);
+        let conflicts = self
+            .indices
+            .iter()
+            .flat_map(|(name, by_type)| {
+                by_type
+                    .values()
+                    .filter_map(move |updater| updater.conflict(key, value, metadata))
+                    .map(move |existing| (name.as_str(), existing))
+            })
+            .collect();
+        self.discard_checked();
+        conflicts
+    }
+
+    /// Addresses an entry by its value in a unique index rather than by its key, to read, update
+    /// or create it without resolving the key first.
+    pub fn entry_by_unique<A>(
+        &mut self,
+        index_id: &UniqueIndexId<A>,
+        index_key: A,
+    ) -> UniqueEntry<'_, K, V, A, KS, B>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        let key = self
+            .get_by_unique_index(index_id, &index_key)
+            .map(|(key, _)| key.clone());
+        UniqueEntry {
+            map: self,
+            index_key,
+            key,
+        }
+    }
+}
@@ -0,0 +1,544 @@
+use std::any::{Any, TypeId};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use std::{thread, vec};
+
+use metadata::metadata_of;
+use {
+    Backing, ClonedKeys, Entries, IndexId, IndexedMap, InsertError, KeyStorage, QueryError,
+    UpdateError,
+};
+
+/// Mutable access to one value, from `get_mut` or `Entry`. The entry is reindexed when the guard
+/// is dropped, so indices never see the value half-changed but do see every change.
+///
+/// # Panics
+///
+/// Dropping the guard panics if a unique or fallible index refuses the changed value, after
+/// putting the previous value back.
+pub struct ValueMut<'m, K: 'm, V: 'm, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    map: &'m mut IndexedMap<K, V, KS, B>,
+    key: K,
+    previous: Option<V>,
+}
+
+impl<'m, K, V, KS, B> ValueMut<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn new(map: &'m mut IndexedMap<K, V, KS, B>, key: K) -> ValueMut<'m, K, V, KS, B> {
+        let previous = map.original(&key);
+        ValueMut { map, key, previous }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<'m, K, V, KS, B> Deref for ValueMut<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.map
+            .inner
+            .get(&self.key)
+            .expect("guarded entry is present")
+    }
+}
+
+impl<'m, K, V, KS, B> DerefMut for ValueMut<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.map
+            .inner
+            .get_mut(&self.key)
+            .expect("guarded entry is present")
+    }
+}
+
+impl<'m, K, V, KS, B> Drop for ValueMut<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        if let Err(err) = self.map.reindex(&self.key, previous) {
+            if !thread::panicking() {
+                panic!("{}", err);
+            }
+        }
+    }
+}
+
+/// Mutable access to every value in turn, from `iter_mut_reindex`. Each value handed out is
+/// reindexed when the guard is dropped. Not an `Iterator`, since each value borrows the guard:
+/// loop with `while let Some((key, value)) = values.next_entry()`.
+///
+/// # Panics
+///
+/// Dropping the guard panics if a unique or fallible index refuses one of the changed values,
+/// after putting that entry's previous value back. The other entries are reindexed first.
+pub struct IterMutReindex<'m, K: 'm, V: 'm, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    map: &'m mut IndexedMap<K, V, KS, B>,
+    keys: vec::IntoIter<K>,
+    touched: Vec<(K, Option<V>)>,
+}
+
+impl<'m, K, V, KS, B> IterMutReindex<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn next_entry(&mut self) -> Option<(&K, &mut V)> {
+        let key = self.keys.next()?;
+        let previous = self.map.original(&key);
+        self.touched.push((key, previous));
+        let key = &self.touched[self.touched.len() - 1].0;
+        let value = self.map.inner.get_mut(key)?;
+        Some((key, value))
+    }
+}
+
+impl<'m, K, V, KS, B> Drop for IterMutReindex<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    fn drop(&mut self) {
+        let mut rejected = None;
+        for (key, previous) in self.touched.drain(..) {
+            if let Err(err) = self.map.reindex(&key, previous) {
+                rejected.get_or_insert(err);
+            }
+        }
+        if let Some(err) = rejected {
+            if !thread::panicking() {
+                panic!("{}", err);
+            }
+        }
+    }
+}
+
+/// One key of a map, present or not, from `entry`.
+pub struct Entry<'m, K: 'm, V: 'm, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    map: &'m mut IndexedMap<K, V, KS, B>,
+    key: K,
+}
+
+impl<'m, K, V, KS, B> Entry<'m, K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Updates the value and reindexes it, if the key is present, panicking like `update` if an
+    /// index refuses the change.
+    pub fn and_modify<F>(self, f: F) -> Entry<'m, K, V, KS, B>
+    where
+        F: FnOnce(&mut V),
+    {
+        self.map.update(&self.key, f);
+        self
+    }
+
+    /// Inserts `value` unless the key is present.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `insert` if the map refuses the new entry. Use `or_try_insert_with` on maps
+    /// with a capacity limit, unique indices or fallible indices.
+    pub fn or_insert(self, value: V) -> ValueMut<'m, K, V, KS, B> {
+        self.or_insert_with(|| value)
+    }
+
+    /// Inserts the value `f` makes unless the key is present.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `insert` if the map refuses the new entry. Use `or_try_insert_with` on maps
+    /// with a capacity limit, unique indices or fallible indices.
+    pub fn or_insert_with<F>(self, f: F) -> ValueMut<'m, K, V, KS, B>
+    where
+        F: FnOnce() -> V,
+    {
+        match self.or_try_insert_with(f) {
+            Ok(value) => value,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Like `or_insert_with`, but hands the new entry back if the map refuses it, as
+    /// `try_insert` does.
+    pub fn or_try_insert_with<F>(self, f: F) -> Result<ValueMut<'m, K, V, KS, B>, InsertError<K, V>>
+    where
+        F: FnOnce() -> V,
+    {
+        if !self.map.inner.contains_key(&self.key) {
+            self.map.try_insert(self.key.clone(), f())?;
+        }
+        Ok(ValueMut::new(self.map, self.key))
+    }
+}
+
+/// The index values of one entry before and after an update, for every index of the map.
+pub struct IndexDiff {
+    before: IndexValuesByIndex,
+    after: IndexValuesByIndex,
+}
+
+type IndexValuesByIndex = HashMap<(String, TypeId), Box<dyn Any>>;
+
+impl IndexDiff {
+    /// The change in one index, or `None` if the map has no such index.
+    pub fn for_index<A, H>(&self, index_id: &IndexId<A, H>) -> Option<IndexValueDiff<'_, A>>
+    where
+        A: 'static + Eq,
+    {
+        let id = (index_id.name.clone(), TypeId::of::<A>());
+        let before = self.before.get(&id)?.downcast_ref::<Vec<A>>()?;
+        let after = self.after.get(&id)?.downcast_ref::<Vec<A>>()?;
+        Some(IndexValueDiff { before, after })
+    }
+}
+
+/// The buckets of one index an entry was in before and after an update.
+pub struct IndexValueDiff<'a, A> {
+    pub before: &'a [A],
+    pub after: &'a [A],
+}
+
+impl<'a, A> IndexValueDiff<'a, A>
+where
+    A: Eq,
+{
+    /// Buckets the entry left.
+    pub fn removed(&self) -> Vec<&'a A> {
+        let after = self.after;
+        self.before.iter().filter(|a| !after.contains(a)).collect()
+    }
+
+    /// Buckets the entry joined.
+    pub fn added(&self) -> Vec<&'a A> {
+        let before = self.before;
+        self.after.iter().filter(|a| !before.contains(a)).collect()
+    }
+
+    pub fn changed(&self) -> bool {
+        !self.removed().is_empty() || !self.added().is_empty()
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Mutates the value stored under `key` and reindexes it. Returns `None` if the key is not
+    /// present.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a unique or fallible index refuses the changed value, after putting the
+    /// previous value back. Use `try_update` to handle that gracefully.
+    pub fn update<F, R>(&mut self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        let original = self.original(key);
+        let result = f(self.inner.get_mut(key)?);
+        if let Err(err) = self.reindex(key, original) {
+            panic!("{}", err);
+        }
+        Some(result)
+    }
+
+    /// Mutable access to the value stored under `key`, reindexed when the guard is dropped.
+    /// Dropping the guard panics like `update` if an index refuses the change.
+    pub fn get_mut(&mut self, key: &K) -> Option<ValueMut<'_, K, V, KS, B>> {
+        if self.inner.contains_key(key) {
+            Some(ValueMut::new(self, key.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Mutable access to every value, in the order of the backing map. The values handed out
+    /// are reindexed together when the returned guard is dropped, so stopping early leaves the
+    /// rest untouched. Dropping the guard panics like `update` if an index refuses a change.
+    pub fn iter_mut_reindex(&mut self) -> IterMutReindex<'_, K, V, KS, B> {
+        let keys: Vec<K> = self
+            .inner
+            .iter()
+            .map(|(key, _)| Borrow::<K>::borrow(key).clone())
+            .collect();
+        IterMutReindex {
+            map: self,
+            keys: keys.into_iter(),
+            touched: Vec::new(),
+        }
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, KS, B> {
+        Entry { map: self, key }
+    }
+
+    /// Mutates the value stored under `key` with a fallible closure and reindexes it. If the
+    /// closure fails, or a unique or fallible index refuses the changed value, the value is
+    /// restored to what it was before the call, so neither the entry nor any index changes.
+    /// Returns `Ok(None)` if the key is not present.
+    pub fn try_update<F, R, E>(&mut self, key: &K, f: F) -> Result<Option<R>, UpdateError<K, V, E>>
+    where
+        V: Clone,
+        F: FnOnce(&mut V) -> Result<R, E>,
+    {
+        let (result, original) = match self.inner.get_mut(key) {
+            Some(value) => {
+                let original = value.clone();
+                match f(value) {
+                    Ok(result) => (result, original),
+                    Err(err) => {
+                        *value = original;
+                        return Err(UpdateError::Failed(err));
+                    }
+                }
+            }
+            None => return Ok(None),
+        };
+        self.reindex(key, Some(original))
+            .map_err(UpdateError::Rejected)?;
+        Ok(Some(result))
+    }
+
+    /// Mutates the value stored under `key` and reindexes it only in the indices named in
+    /// `affected`, e.g. the ones reading the field `f` changes, which saves running every index
+    /// function of a wide value with many indices. Indices left out keep the postings they had,
+    /// so they go stale if `f` changes what they index. Returns `Ok(None)` if the key is not
+    /// present, and `QueryError::MissingIndex` without calling `f` if `affected` names an index
+    /// the map does not have, so that a misspelt name cannot silently leave an index stale.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `update` if one of the affected indices is unique or fallible and refuses the
+    /// changed value. Debug builds also run every index left out and panic if `f` changed what
+    /// it indexes.
+    pub fn patch<F, R>(&mut self, key: &K, affected: &[&str], f: F) -> Result<Option<R>, QueryError>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        if let Some(name) = affected
+            .iter()
+            .find(|name| !self.indices.contains_key(**name))
+        {
+            return Err(QueryError::MissingIndex {
+                name: name.to_string(),
+            });
+        }
+        let original = self.original(key);
+        let result = match self.inner.get_mut(key) {
+            Some(value) => f(value),
+            None => return Ok(None),
+        };
+        if let Err(err) = self.reindex_where(key, original, |name| affected.contains(&name)) {
+            panic!("{}", err);
+        }
+        if cfg!(debug_assertions) {
+            self.assert_unaffected(key, affected);
+        }
+        Ok(Some(result))
+    }
+
+    /// Panics if an index left out of a `patch` would index the patched value differently.
+    fn assert_unaffected(&self, key: &K, affected: &[&str]) {
+        let value = match self.inner.get(key) {
+            Some(value) => value,
+            None => return,
+        };
+        let metadata = metadata_of::<K, KS>(&self.metadata, key);
+        let outdated = self
+            .indices
+            .iter()
+            .filter(|&(name, _)| !affected.contains(&name.as_str()))
+            .find(|&(_, by_type)| {
+                by_type
+                    .values()
+                    .any(|updater| updater.outdated(key, value, metadata))
+            });
+        if let Some((name, _)) = outdated {
+            panic!("patch left out index `{}`, which the change affects", name);
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns `Ok(true)`, reindexing the ones it keeps.
+    ///
+    /// Stops at the first error. The entry being visited when `f` failed is restored to its
+    /// previous value; entries visited before it keep whatever `f` did to them, and entries after
+    /// it are not visited.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `update` if a unique or fallible index refuses a kept entry's new value.
+    pub fn try_retain<F, E>(&mut self, mut f: F) -> Result<(), E>
+    where
+        V: Clone,
+        F: FnMut(&K, &mut V) -> Result<bool, E>,
+    {
+        let keys: Vec<KS::Key> = self.inner.iter().map(|(k, _)| k.clone()).collect();
+        for stored in &keys {
+            let key: &K = stored.borrow();
+            let (keep, original) = match self.inner.get_mut(key) {
+                Some(value) => {
+                    let original = value.clone();
+                    match f(key, value) {
+                        Ok(keep) => (keep, original),
+                        Err(err) => {
+                            *value = original;
+                            return Err(err);
+                        }
+                    }
+                }
+                None => continue,
+            };
+            if keep {
+                if let Err(err) = self.reindex(key, Some(original)) {
+                    panic!("{}", err);
+                }
+            } else {
+                self.remove_unchecked(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps only the entries for which `f` returns true, dropping the others from every index.
+    /// Kept entries are not reindexed, as `f` cannot change them; use `try_retain` to modify
+    /// entries while filtering.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let dropped: Vec<K> = self
+            .inner
+            .iter()
+            .map(|(key, value)| (key.borrow(), value))
+            .filter(|&(key, value)| !f(key, value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &dropped {
+            self.remove_unchecked(key);
+        }
+    }
+
+    /// Removes every entry, returning them, and discards the soft-removed ones. Indices are reset
+    /// wholesale rather than entry by entry, but a removal is still recorded for each entry.
+    /// Entries are taken from the back, so that order-preserving backings have nothing to shift.
+    pub fn drain(&mut self) -> Vec<(K, V)> {
+        let keys: Vec<KS::Key> = self.inner.iter().map(|(key, _)| key.clone()).collect();
+        self.indices
+            .values_mut()
+            .flat_map(|by_type| by_type.values_mut())
+            .for_each(|updater| updater.clear());
+        if let Some(ref mut by_key) = self.metadata {
+            by_key.clear();
+        }
+        self.pinned.clear();
+        self.expirations.clear();
+        if let Some(ref mut bounds) = self.bounds {
+            bounds.clear();
+        }
+        self.key_indices.clear();
+        self.tombstones.clear();
+        let mut drained: Vec<(K, V)> = keys
+            .iter()
+            .rev()
+            .filter_map(|stored| self.take_entry(stored.borrow()))
+            .map(|(key, value)| (KS::into_key(key), value))
+            .collect();
+        drained.reverse();
+        drained
+    }
+
+    pub fn clear(&mut self) {
+        self.drain();
+    }
+
+    /// Like `try_update`, but also reports which buckets of every index the entry moved between,
+    /// e.g. to invalidate exactly the downstream caches keyed by those index values.
+    pub fn try_update_with_diff<F, R, E>(
+        &mut self,
+        key: &K,
+        f: F,
+    ) -> Result<Option<(R, IndexDiff)>, UpdateError<K, V, E>>
+    where
+        V: Clone,
+        F: FnOnce(&mut V) -> Result<R, E>,
+    {
+        if !self.inner.contains_key(key) {
+            return Ok(None);
+        }
+        let before = self.indexed_values_of(key);
+        let result = match self.try_update(key, f)? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let after = self.indexed_values_of(key);
+        Ok(Some((result, IndexDiff { before, after })))
+    }
+
+    fn indexed_values_of(&self, key: &K) -> IndexValuesByIndex {
+        self.indices
+            .iter()
+            .flat_map(|(name, by_type)| {
+                by_type.iter().map(move |(type_id, updater)| {
+                    ((name.clone(), *type_id), updater.indexed_values(key))
+                })
+            })
+            .collect()
+    }
+}
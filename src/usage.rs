@@ -0,0 +1,132 @@
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, Instant};
+
+use metadata::metadata_of;
+use {Backing, Buckets, IndexId, IndexedMap, KeyStorage, QueryError};
+
+/// How often an index has been looked up, kept in cells so read-only queries can count.
+#[derive(Clone)]
+pub(crate) struct Usage {
+    queries: Cell<u64>,
+    last_used: Cell<Instant>,
+    pub(crate) cold: bool,
+}
+
+impl Usage {
+    pub(crate) fn new() -> Usage {
+        Usage {
+            queries: Cell::new(0),
+            last_used: Cell::new(Instant::now()),
+            cold: false,
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        self.queries.set(self.queries.get() + 1);
+        self.last_used.set(Instant::now());
+    }
+}
+
+/// How much an index has been used, as reported by `index_usage`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexUsage {
+    pub name: String,
+    pub value_type: &'static str,
+    /// Lookups since the index was added.
+    pub queries: u64,
+    /// Time since the last lookup, or since the index was added if it was never queried.
+    pub idle: Duration,
+    /// Whether the index was dropped by `drop_idle_indices` and not rebuilt since.
+    pub cold: bool,
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Reports how much each index has been queried, ordered by name, to find indices that cost
+    /// memory and write time without serving reads.
+    pub fn index_usage(&self) -> Vec<IndexUsage> {
+        let mut usage: Vec<IndexUsage> = self
+            .indices
+            .iter()
+            .flat_map(|(name, by_type)| {
+                by_type.values().map(move |updater| {
+                    let usage = updater.usage();
+                    IndexUsage {
+                        name: name.clone(),
+                        value_type: updater.value_type(),
+                        queries: usage.queries.get(),
+                        idle: usage.last_used.get().elapsed(),
+                        cold: usage.cold,
+                    }
+                })
+            })
+            .collect();
+        usage.sort_by(|a, b| (&a.name, a.value_type).cmp(&(&b.name, b.value_type)));
+        usage
+    }
+
+    /// Drops the contents of every enabled index not queried for at least `idle`, keeping its
+    /// definition so it can be rebuilt with `warm_index` or `get_index_warming`. Returns the
+    /// names of the dropped indices. Queries against a cold index fail with `QueryError::Cold`.
+    pub fn drop_idle_indices(&mut self, idle: Duration) -> Vec<String> {
+        let mut dropped = Vec::new();
+        for (name, by_type) in self.indices.iter_mut() {
+            for updater in by_type.values_mut() {
+                let usage = updater.usage();
+                if updater.is_enabled() && !usage.cold && usage.last_used.get().elapsed() >= idle {
+                    updater.set_cold(true);
+                    dropped.push(name.clone());
+                }
+            }
+        }
+        dropped.sort();
+        dropped.dedup();
+        dropped
+    }
+
+    /// Rebuilds the cold indices named `name` from the current entries. Returns whether any index
+    /// was rebuilt.
+    pub fn warm_index(&mut self, name: &str) -> bool {
+        let inner = &self.inner;
+        let metadata = &self.metadata;
+        let mut warmed = Vec::new();
+        for (type_id, updater) in self.indices.get_mut(name).into_iter().flatten() {
+            if updater.usage().cold {
+                updater.set_cold(false);
+                for (key, value) in inner.iter() {
+                    updater.insert(key, value, metadata_of::<K, KS>(metadata, key.borrow()));
+                }
+                warmed.push(*type_id);
+            }
+        }
+        for type_id in &warmed {
+            self.forget_index_misses(name, *type_id);
+        }
+        !warmed.is_empty()
+    }
+
+    /// Like `try_get_index`, but first rebuilds the index if it was dropped while unused.
+    pub fn get_index_warming<A, H>(
+        &mut self,
+        index_id: &IndexId<A, H>,
+    ) -> Result<&Buckets<KS::Key, A, H>, QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        if self
+            .find_index_state(index_id)
+            .is_some_and(|state| state.usage.cold)
+        {
+            self.warm_index(&index_id.name);
+        }
+        self.try_get_index(index_id)
+    }
+}
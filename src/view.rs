@@ -0,0 +1,172 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+use {Backing, Buckets, ClonedKeys, Entries, IndexId, IndexedMap, KeyStorage, UniqueIndexId};
+
+/// An index seen as a read-only map from index value to the entries under it, from
+/// `view_by_index`. It reads the map it borrows, so it always reflects the current entries.
+pub struct IndexView<
+    'm,
+    A: 'm,
+    K: 'm,
+    V: 'm,
+    KS: 'm = ClonedKeys,
+    B: 'm = Entries<K, V, KS>,
+    H: 'm = RandomState,
+> where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    index: &'m Buckets<KS::Key, A, H>,
+    inner: &'m B,
+    _value: PhantomData<&'m V>,
+}
+
+impl<'m, A, K, V, KS, B, H> IndexView<'m, A, K, V, KS, B, H>
+where
+    A: Eq + Hash,
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+    H: BuildHasher,
+{
+    /// The number of distinct index values.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, index_key: &Q) -> bool
+    where
+        A: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.index.contains_key(index_key)
+    }
+
+    /// The entries under `index_key`, in no particular order.
+    pub fn get<Q>(&self, index_key: &Q) -> Option<Vec<(&'m K, &'m V)>>
+    where
+        A: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.index.get(index_key).map(|keys| self.entries(keys))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'m A> {
+        self.index.keys()
+    }
+
+    /// Every index value with its entries, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'m A, Vec<(&'m K, &'m V)>)> + '_ {
+        self.index
+            .iter()
+            .map(move |(index_key, keys)| (index_key, self.entries(keys)))
+    }
+
+    fn entries(&self, keys: &'m HashSet<KS::Key, H>) -> Vec<(&'m K, &'m V)> {
+        let inner = self.inner;
+        keys.iter()
+            .filter_map(|key| {
+                let key: &K = key.borrow();
+                inner.get(key).map(|value| (key, value))
+            })
+            .collect()
+    }
+}
+
+/// A unique index seen as a read-only map from index value to its one entry, from
+/// `view_by_unique_index`.
+pub struct UniqueIndexView<'m, A: 'm, K: 'm, V: 'm, KS: 'm = ClonedKeys, B: 'm = Entries<K, V, KS>>
+where
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+{
+    view: IndexView<'m, A, K, V, KS, B>,
+}
+
+impl<'m, A, K, V, KS, B> UniqueIndexView<'m, A, K, V, KS, B>
+where
+    A: Eq + Hash,
+    K: Eq + Hash,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    pub fn len(&self) -> usize {
+        self.view.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.view.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, index_key: &Q) -> bool
+    where
+        A: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.view.contains_key(index_key)
+    }
+
+    pub fn get<Q>(&self, index_key: &Q) -> Option<(&'m K, &'m V)>
+    where
+        A: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let key: &K = self.view.index.get(index_key)?.iter().next()?.borrow();
+        self.view.inner.get(key).map(|value| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'m A> {
+        self.view.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'m A, (&'m K, &'m V))> + '_ {
+        self.view
+            .iter()
+            .filter_map(|(index_key, entries)| entries.into_iter().next().map(|e| (index_key, e)))
+    }
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// The index as a read-only map keyed by index value, for code written against map-like
+    /// lookups. Returns `None` if the index is unavailable.
+    pub fn view_by_index<A, H>(
+        &self,
+        index_id: &IndexId<A, H>,
+    ) -> Option<IndexView<'_, A, K, V, KS, B, H>>
+    where
+        A: 'static + Eq + Hash + Clone,
+        H: 'static + BuildHasher + Clone,
+    {
+        Some(IndexView {
+            index: self.get_index(index_id)?,
+            inner: &self.inner,
+            _value: PhantomData,
+        })
+    }
+
+    /// Like `view_by_index`, with one entry per index value.
+    pub fn view_by_unique_index<A>(
+        &self,
+        index_id: &UniqueIndexId<A>,
+    ) -> Option<UniqueIndexView<'_, A, K, V, KS, B>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        self.view_by_index(index_id)
+            .map(|view| UniqueIndexView { view })
+    }
+}
@@ -0,0 +1,361 @@
+//! A durable map that logs every write to an append-only file before applying it.
+//!
+//! `DurableMap` wraps an `IndexedMap` and owns its write-ahead log. Opening a path replays the
+//! log into a fresh map whose indices were registered first, so indices are rebuilt from the
+//! replayed entries rather than stored. Writes go through `DurableMap` and are appended to the
+//! log before they reach the map; reads and queries go to the map through `Deref`.
+//!
+//! Each record carries its length and a checksum. A crash can leave the last record half
+//! written, so replay stops at the first record that is truncated or fails its checksum and cuts
+//! the log back to the last complete record. Keys and values are stored as bytes produced by a
+//! caller-supplied `WalCodec`.
+//!
+//! Only writes made through `DurableMap` are logged. Entries the map's capacity policy evicts
+//! are not, and neither are `touch` calls, which only reach the map through `map_mut`; a map
+//! reopened from the log holds the evicted entries again, unless `register` sets the same
+//! policy, in which case replay evicts by the order of the logged writes rather than by use.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use {IndexedMap, InsertError};
+
+const MAGIC: &[u8; 4] = b"IMWL";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: u64 = 8;
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+
+/// Converts keys and values to and from the bytes stored in the log.
+pub trait WalCodec<K, V> {
+    fn encode_key(&self, key: &K) -> Vec<u8>;
+
+    fn decode_key(&self, bytes: &[u8]) -> Option<K>;
+
+    fn encode_value(&self, value: &V) -> Vec<u8>;
+
+    fn decode_value(&self, bytes: &[u8]) -> Option<V>;
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash: u32, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let field = bytes.get(at..at + 4)?;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+/// Why a `DurableMap` write was not applied. Nothing is applied to the map when either is
+/// returned, and a rejected insert is not logged.
+#[derive(Debug)]
+pub enum WriteError<K, V> {
+    /// The log could not be written.
+    Io(io::Error),
+    /// The map refused the insert, as `try_insert` would.
+    Rejected(InsertError<K, V>),
+}
+
+impl<K, V> From<io::Error> for WriteError<K, V> {
+    fn from(err: io::Error) -> WriteError<K, V> {
+        WriteError::Io(err)
+    }
+}
+
+impl<K, V> fmt::Display for WriteError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::Io(ref err) => write!(f, "write-ahead log failed: {}", err),
+            WriteError::Rejected(ref err) => write!(f, "insert rejected: {}", err),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> Error for WriteError<K, V> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            WriteError::Io(ref err) => Some(err),
+            WriteError::Rejected(_) => None,
+        }
+    }
+}
+
+/// An `IndexedMap` whose writes are logged to a file first, so it can be rebuilt after a restart
+/// or crash.
+pub struct DurableMap<K, V, C>
+where
+    K: Eq + Hash + Clone,
+{
+    map: IndexedMap<K, V>,
+    codec: C,
+    path: PathBuf,
+    log: File,
+    /// The offset just past the last complete record, which a failed append is cut back to.
+    end: u64,
+    /// Set when a failed append could not be cut back, leaving the log in an unknown state.
+    poisoned: bool,
+    sync: bool,
+}
+
+impl<K, V, C> DurableMap<K, V, C>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static + Clone,
+    C: WalCodec<K, V>,
+{
+    /// Opens the log at `path`, creating it if it does not exist, and replays it into a map on
+    /// which `register` has added the indices.
+    pub fn open<P, F>(path: P, codec: C, register: F) -> io::Result<DurableMap<K, V, C>>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut IndexedMap<K, V>),
+    {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut map = IndexedMap::new();
+        register(&mut map);
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let end = if bytes.is_empty() {
+            file.write_all(MAGIC)?;
+            file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+            HEADER_LEN
+        } else {
+            if bytes.len() < HEADER_LEN as usize || &bytes[..4] != MAGIC {
+                return Err(invalid("not a write-ahead log"));
+            }
+            if read_u32(&bytes, 4) != Some(FORMAT_VERSION) {
+                return Err(invalid("unsupported write-ahead log version"));
+            }
+            Self::replay(&mut map, &codec, &bytes)?
+        };
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok(DurableMap {
+            map,
+            codec,
+            path,
+            log: file,
+            end,
+            poisoned: false,
+            sync: false,
+        })
+    }
+
+    /// Applies every complete record in `bytes` and returns the offset just past the last one.
+    /// Fails if the map refuses a logged insert, e.g. because `register` added a unique index
+    /// the logged entries do not satisfy.
+    fn replay(map: &mut IndexedMap<K, V>, codec: &C, bytes: &[u8]) -> io::Result<u64> {
+        let mut at = HEADER_LEN as usize;
+        while let (Some(len), Some(sum)) = (read_u32(bytes, at), read_u32(bytes, at + 4)) {
+            let payload = match bytes.get(at + 8..at + 8 + len as usize) {
+                Some(payload) if checksum(payload) == sum => payload,
+                _ => break,
+            };
+            let key_len = read_u32(payload, 1).ok_or_else(|| invalid("short record"))? as usize;
+            let key = payload
+                .get(5..5 + key_len)
+                .and_then(|bytes| codec.decode_key(bytes))
+                .ok_or_else(|| invalid("undecodable key"))?;
+            match payload[0] {
+                INSERT => {
+                    let value = codec
+                        .decode_value(&payload[5 + key_len..])
+                        .ok_or_else(|| invalid("undecodable value"))?;
+                    map.try_insert(key, value).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("logged insert rejected: {}", err),
+                        )
+                    })?;
+                }
+                REMOVE => {
+                    map.remove(&key);
+                }
+                _ => return Err(invalid("unknown record type")),
+            }
+            at += 8 + len as usize;
+        }
+        Ok(at as u64)
+    }
+
+    /// Makes every write wait until its record has reached the disk, not just the operating
+    /// system, so acknowledged writes survive a power failure. Off by default.
+    pub fn sync_writes(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    fn record(&self, tag: u8, key: &K, value: Option<&V>) -> Vec<u8> {
+        let key = self.codec.encode_key(key);
+        let mut payload = Vec::with_capacity(5 + key.len());
+        payload.push(tag);
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&key);
+        if let Some(value) = value {
+            payload.extend_from_slice(&self.codec.encode_value(value));
+        }
+        let mut record = Vec::with_capacity(8 + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    /// Writes one record at the end of the log. If the write fails, the log is cut back to the
+    /// last complete record so the next append does not follow a torn one; if even that fails,
+    /// the log is poisoned and refuses every later write until it is reopened.
+    fn append(&mut self, tag: u8, key: &K, value: Option<&V>) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other(
+                "write-ahead log is poisoned by an earlier failed write; reopen it",
+            ));
+        }
+        let record = self.record(tag, key, value);
+        let written = self.log.write_all(&record).and_then(|_| {
+            if self.sync {
+                self.log.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        match written {
+            Ok(()) => {
+                self.end += record.len() as u64;
+                Ok(())
+            }
+            Err(err) => {
+                let end = self.end;
+                let rewound = self
+                    .log
+                    .set_len(end)
+                    .and_then(|_| self.log.seek(SeekFrom::Start(end)));
+                if rewound.is_err() {
+                    self.poisoned = true;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Logs the insert, then applies it. An insert the map would refuse, by its capacity limit
+    /// or a unique or fallible index, is handed back without being logged. If logging fails the
+    /// map is left unchanged.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, WriteError<K, V>> {
+        if let Some(rejection) = self.map.admission(&key, &value) {
+            self.map.discard_checked();
+            return Err(WriteError::Rejected(rejection.into_error(key, value)));
+        }
+        if let Err(err) = self.append(INSERT, &key, Some(&value)) {
+            self.map.discard_checked();
+            return Err(WriteError::Io(err));
+        }
+        let previous = self.map.insert_unchecked(key, value);
+        self.map.discard_checked();
+        self.map.enforce_bounds();
+        Ok(previous)
+    }
+
+    /// Logs the removal, then applies it. Removing a missing key logs nothing.
+    pub fn remove(&mut self, key: &K) -> io::Result<Option<V>> {
+        if !self.map.contains_key(key) {
+            return Ok(None);
+        }
+        self.append(REMOVE, key, None)?;
+        Ok(self.map.remove(key))
+    }
+
+    /// Rewrites the log with one insert per current entry, dropping overwritten and removed
+    /// records. The new log replaces the old one atomically, and the directory holding it is
+    /// synced so the replacement itself survives a power failure; a crash during compaction
+    /// leaves one log or the other.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut staging = self.path.clone().into_os_string();
+        staging.push(".compact");
+        let staging = PathBuf::from(staging);
+        let written = self.write_compacted(&staging);
+        let (file, end) = match written.and_then(|compacted| {
+            fs::rename(&staging, &self.path)?;
+            Ok(compacted)
+        }) {
+            Ok(compacted) => compacted,
+            Err(err) => {
+                let _ = fs::remove_file(&staging);
+                return Err(err);
+            }
+        };
+        self.log = file;
+        self.end = end;
+        self.poisoned = false;
+        sync_parent(&self.path)
+    }
+
+    /// Writes every current entry to a fresh log at `staging` and syncs it, returning the file
+    /// positioned at its end and the end offset.
+    fn write_compacted(&self, staging: &Path) -> io::Result<(File, u64)> {
+        let mut out = BufWriter::new(File::create(staging)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        let mut end = HEADER_LEN;
+        for (key, value) in self.map.iter() {
+            let record = self.record(INSERT, key, Some(value));
+            out.write_all(&record)?;
+            end += record.len() as u64;
+        }
+        let file = out.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok((file, end))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The in-memory map, for registering or dropping indices. Writes through it bypass the log
+    /// and are lost on restart.
+    pub fn map_mut(&mut self) -> &mut IndexedMap<K, V> {
+        &mut self.map
+    }
+}
+
+/// Syncs the directory holding `path`, so a file renamed into it stays renamed after a power
+/// failure. Directories cannot be opened for syncing on every platform; elsewhere this does
+/// nothing.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl<K, V, C> Deref for DurableMap<K, V, C>
+where
+    K: Eq + Hash + Clone,
+{
+    type Target = IndexedMap<K, V>;
+
+    fn deref(&self) -> &IndexedMap<K, V> {
+        &self.map
+    }
+}
@@ -0,0 +1,132 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use metadata::metadata_of;
+use {Backing, IndexId, IndexState, IndexedMap, KeyStorage, QueryError};
+
+/// The index values computed for every entry of an index, as exported by `export_index`.
+///
+/// Persisting a snapshot next to the entries lets a restarted process register the index with
+/// `add_index_from_snapshot` instead of re-running an expensive index function over every entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexSnapshot<K, A>
+where
+    K: Eq + Hash,
+{
+    pub values: HashMap<K, Vec<A>>,
+}
+
+/// Progress made by a call to `validate_index`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Validation {
+    /// Entries whose snapshot values were compared against a fresh run of the index function.
+    pub checked: usize,
+    /// Checked entries whose snapshot values were stale and have been recomputed.
+    pub repaired: usize,
+    /// Entries still indexed from the snapshot without having been checked.
+    pub remaining: usize,
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Exports the index values of every entry, or `None` if the index is missing or disabled.
+    pub fn export_index<A>(&self, index_id: &IndexId<A>) -> Option<IndexSnapshot<K, A>>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        self.get_index_state(index_id).map(|state| IndexSnapshot {
+            values: state
+                .indexed
+                .iter()
+                .map(|(k, values)| {
+                    let k: &K = k.borrow();
+                    (k.clone(), values.iter().cloned().collect())
+                })
+                .collect(),
+        })
+    }
+
+    /// Registers an index, taking the values of entries covered by `snapshot` from it rather than
+    /// from `index_fn`. Entries missing from the snapshot are indexed normally and snapshot
+    /// values for keys not in the map are ignored.
+    ///
+    /// Snapshot values are trusted until checked: entries are verified as they are rewritten, or
+    /// explicitly in batches through `validate_index`.
+    pub fn add_index_from_snapshot<A, F>(
+        &mut self,
+        name: String,
+        index_fn: F,
+        mut snapshot: IndexSnapshot<K, A>,
+    ) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &V) -> Vec<A>,
+    {
+        let mut index_state = IndexState::<K, V, A, KS>::empty(index_fn);
+        for (key, value) in self.inner.iter() {
+            match snapshot.values.remove(key.borrow()) {
+                Some(values) => {
+                    index_state.insert_values(key, value, values);
+                    index_state.unverified.insert(key.clone());
+                }
+                None => index_state.insert(
+                    key,
+                    value,
+                    metadata_of::<K, KS>(&self.metadata, key.borrow()),
+                ),
+            }
+        }
+        self.register_index(name, index_state)
+    }
+
+    /// Checks up to `limit` entries that are still indexed from a snapshot, recomputing any whose
+    /// snapshot values differ from what the index function produces now.
+    pub fn validate_index<A>(
+        &mut self,
+        index_id: &IndexId<A>,
+        limit: usize,
+    ) -> Result<Validation, QueryError>
+    where
+        A: 'static + Eq + Hash + Clone,
+    {
+        let (inner, metadata, state) = match self.split_index_state_mut(index_id) {
+            Some((_, _, ref state)) if !state.enabled => {
+                return Err(QueryError::Disabled {
+                    name: index_id.name.clone(),
+                })
+            }
+            Some(split) => split,
+            None => return Err(self.missing_index(index_id)),
+        };
+        let batch: Vec<KS::Key> = state.unverified.iter().take(limit).cloned().collect();
+        let mut repaired = 0;
+        for stored in &batch {
+            let key: &K = stored.borrow();
+            state.unverified.remove(key);
+            let value = match inner.get(key) {
+                Some(value) => value,
+                None => continue,
+            };
+            let fresh: HashSet<A> =
+                (state.index_fn)(key, value, metadata_of::<K, KS>(metadata, key))
+                    .into_iter()
+                    .collect();
+            if state.indexed.get(key) != Some(&fresh) {
+                state.remove(key);
+                state.insert_values(stored, value, fresh);
+                repaired += 1;
+            }
+        }
+        Ok(Validation {
+            checked: batch.len(),
+            repaired,
+            remaining: state.unverified.len(),
+        })
+    }
+}
@@ -0,0 +1,137 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::sync::{Arc, Weak};
+
+use {Backing, IndexId, IndexedMap, KeyStorage};
+
+/// Collects the dead entries of a map of `Weak` values as writes happen. A sweep runs once as
+/// many writes as there are entries have gone by since the last one, which keeps the dead
+/// entries from piling up while costing each write a constant amount on average.
+pub(crate) struct Reaper<V> {
+    dead: fn(&V) -> bool,
+    writes: usize,
+}
+
+impl<V> Clone for Reaper<V> {
+    fn clone(&self) -> Reaper<V> {
+        Reaper {
+            dead: self.dead,
+            writes: self.writes,
+        }
+    }
+}
+
+fn is_dead<T>(value: &Weak<T>) -> bool {
+    value.strong_count() == 0
+}
+
+impl<K, V, KS, B> IndexedMap<K, V, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    V: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, V>,
+{
+    /// Counts a write toward the next sweep for dead entries, and sweeps if one is due.
+    pub(crate) fn reap_dead(&mut self) {
+        let dead = match self.reaper {
+            Some(ref mut reaper) => {
+                reaper.writes += 1;
+                if reaper.writes < self.inner.len() {
+                    return;
+                }
+                reaper.writes = 0;
+                reaper.dead
+            }
+            None => return,
+        };
+        self.remove_where(dead);
+    }
+
+    fn remove_where(&mut self, dead: fn(&V) -> bool) -> usize {
+        let dead: Vec<KS::Key> = self
+            .inner
+            .iter()
+            .filter(|&(_, v)| dead(v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &dead {
+            self.remove_unchecked(key.borrow());
+        }
+        dead.len()
+    }
+}
+
+/// Maps holding `Weak` values index objects owned elsewhere without keeping them alive. Entries
+/// whose value has been dropped are skipped by the `live_*` queries, and once the map has a weak
+/// index they are removed from it and its indices as writes happen, or by `purge_dead` at once.
+impl<K, T, KS, B> IndexedMap<K, Weak<T>, KS, B>
+where
+    K: 'static + Eq + Hash + Clone,
+    T: 'static,
+    KS: KeyStorage<K>,
+    B: Backing<K, KS::Key, Weak<T>>,
+{
+    /// Adds an index whose function sees the upgraded value. Entries that are already dead when
+    /// indexed contribute no index keys, and from now on writes collect dead entries.
+    pub fn add_weak_index<A, F>(&mut self, name: String, index_fn: F) -> IndexId<A>
+    where
+        A: 'static + Eq + Hash + Clone,
+        F: 'static + Fn(&K, &T) -> Vec<A>,
+    {
+        if self.reaper.is_none() {
+            self.reaper = Some(Reaper {
+                dead: is_dead,
+                writes: 0,
+            });
+        }
+        self.add_index(name, move |key, value: &Weak<T>| match value.upgrade() {
+            Some(value) => index_fn(key, &value),
+            None => Vec::new(),
+        })
+    }
+
+    /// Returns the value for `key` if it is present and still alive.
+    pub fn upgrade(&self, key: &K) -> Option<Arc<T>> {
+        self.inner.get(key).and_then(Weak::upgrade)
+    }
+
+    /// Returns the keys indexed under `index_key` whose value is still alive. Unlike
+    /// `keys_by_index`, it leaves out dead entries that no write has collected yet.
+    pub fn live_keys_by_index<A, Q>(&self, index_id: &IndexId<A>, index_key: &Q) -> Vec<&K>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.keys_by_index(index_id, index_key)
+            .map(|keys| {
+                keys.iter()
+                    .map(|k| k.borrow())
+                    .filter(|k| self.inner.get(*k).is_some_and(|v| !is_dead(v)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the live entries indexed under `index_key`, skipping entries whose value has been
+    /// dropped.
+    pub fn live_by_index<A, Q>(&self, index_id: &IndexId<A>, index_key: &Q) -> Vec<(&K, Arc<T>)>
+    where
+        A: 'static + Eq + Hash + Clone + Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.keys_by_index(index_id, index_key)
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|k| self.upgrade(k.borrow()).map(|v| (k.borrow(), v)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes every entry whose value has been dropped from the map and all of its indices,
+    /// returning how many entries were collected.
+    pub fn purge_dead(&mut self) -> usize {
+        self.remove_where(is_dead)
+    }
+}